@@ -1,5 +1,7 @@
+use futures::future::BoxFuture;
 use pci_ids::{Device as DeviceInfo, Subclass as SubclassInfo};
 use pci_types::{capability::PciCapability, Bar, PciAddress};
+use spin::Mutex;
 
 bitflags::bitflags! {
     #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
@@ -98,3 +100,28 @@ impl PciDevice {
         }
     }
 }
+
+/// A file the bootloader loaded next to the kernel.
+#[derive(Debug, Clone)]
+pub struct BootModule {
+    pub path: String,
+    pub cmdline: String,
+    pub data: &'static [u8],
+}
+
+type PowerOffHook = fn() -> BoxFuture<'static, ()>;
+
+static POWER_OFF_HOOKS: Mutex<Vec<PowerOffHook>> = Mutex::new(Vec::new());
+
+/// Have `hook` run, and finish, before shutting down or rebooting. Hooks run
+/// in the reverse of the order they were added.
+pub fn on_power_off(hook: PowerOffHook) {
+    POWER_OFF_HOOKS.lock().push(hook);
+}
+
+pub async fn run_power_off_hooks() {
+    let hooks = POWER_OFF_HOOKS.lock().clone();
+    for hook in hooks.iter().rev() {
+        hook().await;
+    }
+}
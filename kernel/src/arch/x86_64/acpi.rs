@@ -9,7 +9,13 @@ use acpi::{
     AcpiHandler, AcpiTables, PhysicalMapping,
 };
 use conquer_once::spin::OnceCell;
-use core::convert::TryInto;
+use core::{
+    convert::TryInto,
+    future::poll_fn,
+    sync::atomic::{AtomicBool, Ordering},
+    task::Poll,
+};
+use futures::task::AtomicWaker;
 use x86_64::{instructions::port::Port, VirtAddr};
 
 #[derive(thiserror::Error, Debug)]
@@ -62,13 +68,32 @@ pub fn late_init() {
 
     lai::enable_acpi(lai::PICMethod::APIC);
 
+    // shutting down waits on the power off hooks, which can't happen in
+    // the interrupt handler
+    crate::task::spawn(async {
+        poll_fn(|cx| {
+            POWER_BUTTON.register(cx.waker());
+            if POWER_BUTTON_PRESSED.load(Ordering::Acquire) {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        })
+        .await;
+        shutdown().await;
+    });
+
     debug!("[ACPI] late initialized");
 }
 
+static POWER_BUTTON: AtomicWaker = AtomicWaker::new();
+static POWER_BUTTON_PRESSED: AtomicBool = AtomicBool::new(false);
+
 fn handle_interrupt() {
     let event = lai::get_sci_event();
     if event.contains(lai::SciEvent::POWER_BUTTON) {
-        shutdown();
+        POWER_BUTTON_PRESSED.store(true, Ordering::Release);
+        POWER_BUTTON.wake();
     }
 }
 
@@ -84,11 +109,13 @@ pub fn pci_route_pin(device: &PciDevice) -> Result<u8, Error> {
     Ok(resource.base as _)
 }
 
-pub fn shutdown() {
+pub async fn shutdown() {
+    crate::arch::run_power_off_hooks().await;
     lai::enter_sleep(lai::SleepState::Shutdown).unwrap();
 }
 
-pub fn reboot() {
+pub async fn reboot() {
+    crate::arch::run_power_off_hooks().await;
     lai::reset().unwrap();
 }
 
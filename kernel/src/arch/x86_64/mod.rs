@@ -9,13 +9,17 @@ mod pit;
 mod time;
 
 use conquer_once::spin::OnceCell;
-use limine::{HhdmRequest, MemmapRequest, RsdpRequest, SmpInfo, SmpRequest};
+use limine::{
+    HhdmRequest, KernelFileRequest, MemmapRequest, ModuleRequest, RsdpRequest, SmpInfo, SmpRequest,
+};
 use x86_64::{registers::model_specific::Msr, VirtAddr};
 
 static HHDM: HhdmRequest = HhdmRequest::new(0);
 static MEMMAP: MemmapRequest = MemmapRequest::new(0);
 static RSDP: RsdpRequest = RsdpRequest::new(0);
 static SMP: SmpRequest = SmpRequest::new(0);
+static MODULES: ModuleRequest = ModuleRequest::new(0);
+static KERNEL_FILE: KernelFileRequest = KernelFileRequest::new(0);
 
 pub fn init() {
     init_sse();
@@ -145,6 +149,46 @@ pub fn get_pid() -> u64 {
     pid
 }
 
+/// Every module listed in `limine.cfg`. They're in memory the kernel never
+/// reclaims, so they can be used for as long as it runs.
+pub fn boot_modules() -> Vec<super::BootModule> {
+    let Some(response) = MODULES.get_response().get() else {
+        return vec![];
+    };
+    let string = |s: &limine::Ptr<core::ffi::c_char>| {
+        s.to_str()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    };
+    response
+        .modules()
+        .iter()
+        .map(|file| super::BootModule {
+            path: string(&file.path),
+            cmdline: string(&file.cmdline),
+            data: unsafe {
+                core::slice::from_raw_parts(file.base.as_ptr().unwrap(), file.length as usize)
+            },
+        })
+        .collect()
+}
+
+/// The `KERNEL_CMDLINE` from `limine.cfg`.
+pub fn cmdline() -> String {
+    KERNEL_FILE
+        .get_response()
+        .get()
+        .and_then(|response| response.kernel_file.get())
+        .and_then(|file| file.cmdline.to_str())
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Number of processors, including the bootstrap processor.
+pub fn cpu_count() -> usize {
+    AP_INFO.try_get().map_or(1, |aps| aps.len())
+}
+
 pub use acpi::get_pci_config_regions;
 pub use acpi::pci_route_pin;
 pub use acpi::reboot;
@@ -165,6 +209,26 @@ pub fn halt_loop() -> ! {
     }
 }
 
+/// Sleep until `addr` is written to or an interrupt arrives, unless
+/// `waiting` says otherwise once the monitor is armed. Wakeups can be
+/// spurious, so callers check again.
+#[inline(always)]
+pub fn wait_for_write(addr: *const u8, waiting: impl FnOnce() -> bool) {
+    // with a single core, whoever is meant to write has nowhere to run
+    if AP_INFO.try_get().is_err() {
+        core::hint::spin_loop();
+        return;
+    }
+    unsafe {
+        asm!("monitor", in("rax") addr, in("rcx") 0, in("rdx") 0);
+    }
+    if waiting() {
+        unsafe {
+            asm!("mwait", in("rax") 0, in("rcx") 0);
+        }
+    }
+}
+
 #[inline(always)]
 pub fn enable_interrupts_and_halt() {
     if let Ok(aps) = AP_INFO.try_get() {
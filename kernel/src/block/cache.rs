@@ -0,0 +1,502 @@
+//! A page cache in front of each disk. Reads are served from memory where
+//! possible and read ahead when they look sequential, writes only dirty the
+//! cache and reach the disk in the background, on `flush`, or before the
+//! machine powers off.
+
+use super::{check_range, BlockDevice, Error};
+use alloc::{
+    collections::BTreeMap,
+    sync::{Arc, Weak},
+};
+use core::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use futures::future::BoxFuture;
+use spin::Mutex;
+
+const PAGE_SIZE: usize = 4096;
+/// Pages cached for a single disk, 64 MiB worth.
+const MAX_PAGES: usize = 16384;
+/// Dirty pages a single disk may have before writes wait for write-back.
+const DIRTY_LIMIT: usize = 1024;
+/// Furthest a sequential reader is read ahead of, in pages.
+const MAX_READ_AHEAD: u64 = 32;
+const WRITE_BACK_INTERVAL: Duration = Duration::from_secs(5);
+
+static CACHES: Mutex<Vec<Weak<Cache>>> = Mutex::new(Vec::new());
+
+/// Write back every cache now and then, and before powering off.
+pub(super) fn init() {
+    crate::arch::on_power_off(|| {
+        Box::pin(async {
+            for cache in caches() {
+                if let Err(e) = cache.flush().await {
+                    warn!("block: flush before power off failed: {e}");
+                }
+            }
+        })
+    });
+
+    crate::task::spawn(async {
+        loop {
+            maitake::time::sleep(WRITE_BACK_INTERVAL).await;
+            if let Err(e) = sync_all().await {
+                warn!("block: write-back failed: {e}");
+            }
+        }
+    });
+}
+
+fn caches() -> Vec<Arc<Cache>> {
+    CACHES
+        .lock()
+        .iter()
+        .filter_map(|cache| cache.upgrade())
+        .collect()
+}
+
+async fn sync_all() -> Result<(), Error> {
+    for cache in caches() {
+        cache.write_back().await?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+pub struct Stats {
+    /// Pages read that were already cached.
+    pub hits: u64,
+    pub misses: u64,
+    /// Pages read before anyone asked for them.
+    pub read_ahead: u64,
+    pub written_back: u64,
+    pub cached: usize,
+    pub dirty: usize,
+}
+
+struct Page {
+    data: Box<[u8]>,
+    dirty: bool,
+    /// Being written back. The page can't be evicted until that's done, or
+    /// it could be read back from the disk without the write.
+    writing: bool,
+    /// When the page was last used, its key in [`State::lru`].
+    tick: u64,
+}
+
+#[derive(Default)]
+struct State {
+    pages: BTreeMap<u64, Page>,
+    /// Pages by when they were last used.
+    lru: BTreeMap<u64, u64>,
+    tick: u64,
+    dirty: usize,
+    /// Where a sequential read would start, and how far to read ahead of
+    /// it. The window doubles with every sequential read.
+    next_lba: u64,
+    window: u64,
+    /// Bumped whenever the disk may have changed under a page that is then
+    /// free to be evicted: when a write-back finishes, and on discard. A
+    /// read from the disk that started before the bump may be older than
+    /// the cache was, so it must not be cached.
+    changes: u64,
+}
+
+impl State {
+    fn touch(&mut self, index: u64) {
+        let Some(page) = self.pages.get_mut(&index) else {
+            return;
+        };
+        self.lru.remove(&page.tick);
+        self.tick += 1;
+        page.tick = self.tick;
+        self.lru.insert(self.tick, index);
+    }
+
+    fn insert(&mut self, index: u64, data: Box<[u8]>, dirty: bool) {
+        self.tick += 1;
+        self.lru.insert(self.tick, index);
+        self.pages.insert(
+            index,
+            Page {
+                data,
+                dirty,
+                writing: false,
+                tick: self.tick,
+            },
+        );
+        if dirty {
+            self.dirty += 1;
+        }
+        self.evict();
+    }
+
+    /// Copy `data` into page `index` at `offset`, if it's cached.
+    fn modify(&mut self, index: u64, offset: usize, data: &[u8]) -> bool {
+        let Some(page) = self.pages.get_mut(&index) else {
+            return false;
+        };
+        page.data[offset..offset + data.len()].copy_from_slice(data);
+        if !page.dirty {
+            page.dirty = true;
+            self.dirty += 1;
+        }
+        self.touch(index);
+        true
+    }
+
+    /// Drop the least recently used clean pages until the cache fits.
+    /// Dirty pages stay until they've been written back.
+    fn evict(&mut self) {
+        while self.pages.len() > MAX_PAGES {
+            let Some((&tick, &index)) = self.lru.iter().find(|(_, index)| {
+                let page = &self.pages[index];
+                !page.dirty && !page.writing
+            }) else {
+                return;
+            };
+            self.lru.remove(&tick);
+            self.pages.remove(&index);
+        }
+    }
+}
+
+pub struct Cache {
+    device: Arc<dyn BlockDevice>,
+    /// Device blocks per page.
+    page_blocks: u64,
+    state: Mutex<State>,
+    /// Held while writing back, so an older copy of a page can never reach
+    /// the disk after a newer one.
+    writing: maitake::sync::Mutex<()>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    read_ahead: AtomicU64,
+    written_back: AtomicU64,
+}
+
+impl Cache {
+    pub fn new(device: Arc<dyn BlockDevice>) -> Arc<Self> {
+        let cache = Arc::new(Self {
+            page_blocks: (PAGE_SIZE / device.block_size()).max(1) as u64,
+            device,
+            state: Mutex::new(State::default()),
+            writing: maitake::sync::Mutex::new(()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            read_ahead: AtomicU64::new(0),
+            written_back: AtomicU64::new(0),
+        });
+        CACHES.lock().push(Arc::downgrade(&cache));
+        cache
+    }
+
+    fn page_count(&self) -> u64 {
+        self.device.block_count().div_ceil(self.page_blocks)
+    }
+
+    /// Bytes in page `index`, only the last one can be short.
+    fn page_len(&self, index: u64) -> usize {
+        let blocks = self
+            .page_blocks
+            .min(self.device.block_count() - index * self.page_blocks);
+        blocks as usize * self.device.block_size()
+    }
+
+    /// The part of page `index` that a transfer of `len` bytes at `lba`
+    /// covers, as (offset in the page, offset in the transfer, length).
+    fn overlap(&self, index: u64, lba: u64, len: usize) -> (usize, usize, usize) {
+        let block_size = self.device.block_size() as u64;
+        let page_start = index * self.page_blocks * block_size;
+        let page_end = page_start + self.page_len(index) as u64;
+        let start = (lba * block_size).max(page_start);
+        let end = (lba * block_size + len as u64).min(page_end);
+        (
+            (start - page_start) as usize,
+            (start - lba * block_size) as usize,
+            (end - start) as usize,
+        )
+    }
+
+    /// Read pages `first..end` from the device and cache any that still
+    /// aren't. Returns their contents, with pages cached in the meantime
+    /// taken from the cache, as those may be newer than the disk.
+    async fn fill(&self, first: u64, end: u64) -> Result<Vec<u8>, Error> {
+        let lba = first * self.page_blocks;
+        let blocks = (end * self.page_blocks).min(self.device.block_count()) - lba;
+        let mut data = vec![0; blocks as usize * self.device.block_size()];
+        let changes = self.state.lock().changes;
+        self.device.read_blocks(lba, &mut data).await?;
+
+        let mut state = self.state.lock();
+        let stale = state.changes != changes;
+        let mut offset = 0;
+        for index in first..end {
+            let len = self.page_len(index);
+            let page = &mut data[offset..offset + len];
+            match state.pages.get(&index) {
+                Some(cached) => page.copy_from_slice(&cached.data),
+                None if !stale => state.insert(index, Box::from(&*page), false),
+                None => {}
+            }
+            offset += len;
+        }
+        Ok(data)
+    }
+
+    /// Copy whatever of `buf` page `index` holds, if it's cached.
+    fn copy_out(&self, index: u64, lba: u64, buf: &mut [u8]) -> bool {
+        let mut state = self.state.lock();
+        let Some(page) = state.pages.get(&index) else {
+            return false;
+        };
+        let (in_page, in_buf, len) = self.overlap(index, lba, buf.len());
+        buf[in_buf..in_buf + len].copy_from_slice(&page.data[in_page..in_page + len]);
+        state.touch(index);
+        true
+    }
+
+    async fn read(&self, lba: u64, buf: &mut [u8]) -> Result<(), Error> {
+        check_range(self, lba, buf.len())?;
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let count = (buf.len() / self.device.block_size()) as u64;
+        let first = lba / self.page_blocks;
+        let last = (lba + count - 1) / self.page_blocks;
+
+        let window = {
+            let mut state = self.state.lock();
+            state.window = if lba == state.next_lba && lba != 0 {
+                (state.window * 2).clamp(1, MAX_READ_AHEAD)
+            } else {
+                0
+            };
+            state.next_lba = lba + count;
+            state.window
+        };
+
+        let block_size = self.device.block_size() as u64;
+        let mut index = first;
+        while index <= last {
+            if self.copy_out(index, lba, buf) {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                index += 1;
+                continue;
+            }
+
+            let mut end = index + 1;
+            {
+                let state = self.state.lock();
+                while end <= last && !state.pages.contains_key(&end) {
+                    end += 1;
+                }
+            }
+            let mut fetch_end = end;
+            if end > last {
+                let limit = (end + window).min(self.page_count());
+                let state = self.state.lock();
+                while fetch_end < limit && !state.pages.contains_key(&fetch_end) {
+                    fetch_end += 1;
+                }
+            }
+            let data = self.fill(index, fetch_end).await?;
+            self.misses.fetch_add(end - index, Ordering::Relaxed);
+            self.read_ahead
+                .fetch_add(fetch_end - end, Ordering::Relaxed);
+
+            // served from what was just read, the pages may be gone already
+            let data_start = index * self.page_blocks * block_size;
+            let buf_start = lba * block_size;
+            let start = data_start.max(buf_start);
+            let stop = (data_start + data.len() as u64).min(buf_start + buf.len() as u64);
+            buf[(start - buf_start) as usize..(stop - buf_start) as usize].copy_from_slice(
+                &data[(start - data_start) as usize..(stop - data_start) as usize],
+            );
+            index = end;
+        }
+        Ok(())
+    }
+
+    async fn write(&self, lba: u64, buf: &[u8]) -> Result<(), Error> {
+        if self.device.read_only() {
+            return Err(Error::ReadOnly);
+        }
+        check_range(self, lba, buf.len())?;
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let count = (buf.len() / self.device.block_size()) as u64;
+        let first = lba / self.page_blocks;
+        let last = (lba + count - 1) / self.page_blocks;
+
+        for index in first..=last {
+            let (in_page, in_buf, len) = self.overlap(index, lba, buf.len());
+            let data = &buf[in_buf..in_buf + len];
+            let page_len = self.page_len(index);
+            loop {
+                let changes = {
+                    let mut state = self.state.lock();
+                    if state.modify(index, in_page, data) {
+                        break;
+                    }
+                    state.changes
+                };
+
+                let page = if len == page_len {
+                    data.into()
+                } else {
+                    // the rest of the page has to come from the disk first
+                    let mut page = vec![0; page_len];
+                    self.device
+                        .read_blocks(index * self.page_blocks, &mut page)
+                        .await?;
+                    page[in_page..in_page + len].copy_from_slice(data);
+                    page.into_boxed_slice()
+                };
+                let mut state = self.state.lock();
+                // someone else may have cached it in the meantime
+                if state.modify(index, in_page, data) {
+                    break;
+                }
+                // or cached, written back and evicted it, and what was read
+                // may be from before that
+                if len == page_len || state.changes == changes {
+                    state.insert(index, page, true);
+                    break;
+                }
+            }
+        }
+
+        if self.state.lock().dirty > DIRTY_LIMIT {
+            self.write_back().await?;
+        }
+        Ok(())
+    }
+
+    /// Write every dirty page to the device, merging neighbouring pages
+    /// into one write.
+    pub async fn write_back(&self) -> Result<(), Error> {
+        let _writing = self.writing.lock().await;
+
+        let mut runs: Vec<(u64, Vec<u8>, u64)> = Vec::new();
+        {
+            let mut state = self.state.lock();
+            let State { pages, dirty, .. } = &mut *state;
+            for (&index, page) in pages.iter_mut().filter(|(_, page)| page.dirty) {
+                page.dirty = false;
+                page.writing = true;
+                *dirty -= 1;
+                match runs.last_mut() {
+                    Some((first, data, count)) if *first + *count == index => {
+                        data.extend_from_slice(&page.data);
+                        *count += 1;
+                    }
+                    _ => runs.push((index, page.data.to_vec(), 1)),
+                }
+            }
+        }
+
+        let mut result = Ok(());
+        for (first, data, count) in &runs {
+            if result.is_ok() {
+                result = self
+                    .device
+                    .write_blocks(first * self.page_blocks, data)
+                    .await;
+                if result.is_ok() {
+                    self.written_back.fetch_add(*count, Ordering::Relaxed);
+                }
+            }
+
+            // whatever didn't make it is dirty again
+            let mut state = self.state.lock();
+            state.changes += 1;
+            let State { pages, dirty, .. } = &mut *state;
+            for index in *first..first + count {
+                if let Some(page) = pages.get_mut(&index) {
+                    page.writing = false;
+                    if result.is_err() && !page.dirty {
+                        page.dirty = true;
+                        *dirty += 1;
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    pub fn stats(&self) -> Stats {
+        let state = self.state.lock();
+        Stats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            read_ahead: self.read_ahead.load(Ordering::Relaxed),
+            written_back: self.written_back.load(Ordering::Relaxed),
+            cached: state.pages.len(),
+            dirty: state.dirty,
+        }
+    }
+}
+
+impl BlockDevice for Cache {
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.device.block_count()
+    }
+
+    fn read_only(&self) -> bool {
+        self.device.read_only()
+    }
+
+    fn read_blocks<'a>(&'a self, lba: u64, buf: &'a mut [u8]) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(self.read(lba, buf))
+    }
+
+    fn write_blocks<'a>(&'a self, lba: u64, buf: &'a [u8]) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(self.write(lba, buf))
+    }
+
+    fn flush(&self) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(async {
+            self.write_back().await?;
+            self.device.flush().await
+        })
+    }
+
+    fn discard(&self, lba: u64, count: u64) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(async move {
+            check_range(self, lba, count as usize * self.device.block_size())?;
+            // only pages entirely inside the range can be forgotten, the
+            // rest still has live data
+            let first = lba.div_ceil(self.page_blocks);
+            let end = (lba + count) / self.page_blocks;
+            {
+                let mut state = self.state.lock();
+                state.changes += 1;
+                let discarded = state
+                    .pages
+                    .range(first..end.max(first))
+                    .map(|(&index, _)| index)
+                    .collect::<Vec<_>>();
+                for index in discarded {
+                    if let Some(page) = state.pages.remove(&index) {
+                        state.lru.remove(&page.tick);
+                        if page.dirty {
+                            state.dirty -= 1;
+                        }
+                    }
+                }
+            }
+            self.device.discard(lba, count).await
+        })
+    }
+
+    fn cache_stats(&self) -> Option<Stats> {
+        Some(self.stats())
+    }
+}
@@ -0,0 +1,180 @@
+//! Storage devices, addressed in fixed-size blocks. Drivers register each
+//! disk they find under a name like `nvme0n1`, and everything above them,
+//! partition tables and filesystems, only ever sees a [`BlockDevice`].
+
+use alloc::{collections::BTreeMap, sync::Arc};
+use futures::future::BoxFuture;
+use spin::Mutex;
+
+pub mod cache;
+pub mod partition;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("no such device")]
+    NoSuchDevice,
+    #[error("device already exists")]
+    DeviceExists,
+    #[error("buffer is not a multiple of the block size")]
+    Unaligned,
+    #[error("out of range")]
+    OutOfRange,
+    #[error("device is read-only")]
+    ReadOnly,
+    /// The driver failed the request, described in its own terms.
+    #[error("device error: {0}")]
+    Device(String),
+}
+
+pub trait BlockDevice: Send + Sync {
+    fn block_size(&self) -> usize;
+
+    fn block_count(&self) -> u64;
+
+    fn read_only(&self) -> bool {
+        false
+    }
+
+    /// Read `buf.len() / block_size` blocks starting at `lba`.
+    fn read_blocks<'a>(&'a self, lba: u64, buf: &'a mut [u8]) -> BoxFuture<'a, Result<(), Error>>;
+
+    /// Write `buf.len() / block_size` blocks starting at `lba`.
+    fn write_blocks<'a>(&'a self, lba: u64, buf: &'a [u8]) -> BoxFuture<'a, Result<(), Error>>;
+
+    /// Wait until everything written so far is on stable storage.
+    fn flush(&self) -> BoxFuture<'_, Result<(), Error>>;
+
+    /// Tell the device `count` blocks from `lba` no longer hold anything
+    /// useful. Only a hint, so devices that can't use it just succeed.
+    fn discard(&self, lba: u64, count: u64) -> BoxFuture<'_, Result<(), Error>> {
+        let _ = (lba, count);
+        Box::pin(async { Ok(()) })
+    }
+
+    /// Where this device sits on its disk, if it is a partition.
+    fn partition(&self) -> Option<&partition::Info> {
+        None
+    }
+
+    /// How the cache in front of this device is doing, if it has one.
+    fn cache_stats(&self) -> Option<cache::Stats> {
+        None
+    }
+}
+
+/// Check that a transfer of `len` bytes at `lba` is whole blocks and lies
+/// within `device`.
+pub fn check_range(device: &dyn BlockDevice, lba: u64, len: usize) -> Result<(), Error> {
+    if len % device.block_size() != 0 {
+        return Err(Error::Unaligned);
+    }
+    let count = (len / device.block_size()) as u64;
+    if lba
+        .checked_add(count)
+        .is_none_or(|end| end > device.block_count())
+    {
+        return Err(Error::OutOfRange);
+    }
+    Ok(())
+}
+
+/// Read `buf.len()` bytes from byte `offset`, which needn't be block aligned.
+pub async fn read_at(device: &dyn BlockDevice, offset: u64, buf: &mut [u8]) -> Result<(), Error> {
+    if buf.is_empty() {
+        return Ok(());
+    }
+    let block_size = device.block_size() as u64;
+    let first = offset / block_size;
+    let skip = (offset % block_size) as usize;
+    if skip == 0 && buf.len() as u64 % block_size == 0 {
+        return device.read_blocks(first, buf).await;
+    }
+
+    let end = (offset + buf.len() as u64).div_ceil(block_size);
+    let mut blocks = vec![0; ((end - first) * block_size) as usize];
+    device.read_blocks(first, &mut blocks).await?;
+    buf.copy_from_slice(&blocks[skip..skip + buf.len()]);
+    Ok(())
+}
+
+/// Write `buf` at byte `offset`, which needn't be block aligned. Blocks only
+/// partly covered are read first so the rest of them is preserved.
+pub async fn write_at(device: &dyn BlockDevice, offset: u64, buf: &[u8]) -> Result<(), Error> {
+    if buf.is_empty() {
+        return Ok(());
+    }
+    let block_size = device.block_size() as u64;
+    let first = offset / block_size;
+    let skip = (offset % block_size) as usize;
+    if skip == 0 && buf.len() as u64 % block_size == 0 {
+        return device.write_blocks(first, buf).await;
+    }
+
+    let end = (offset + buf.len() as u64).div_ceil(block_size);
+    let mut blocks = vec![0; ((end - first) * block_size) as usize];
+    let len = blocks.len();
+    let block_size = block_size as usize;
+    if skip != 0 {
+        device.read_blocks(first, &mut blocks[..block_size]).await?;
+    }
+    if (skip + buf.len()) % block_size != 0 {
+        device
+            .read_blocks(end - 1, &mut blocks[len - block_size..])
+            .await?;
+    }
+    blocks[skip..skip + buf.len()].copy_from_slice(buf);
+    device.write_blocks(first, &blocks).await
+}
+
+static DEVICES: Mutex<BTreeMap<String, Arc<dyn BlockDevice>>> = Mutex::new(BTreeMap::new());
+
+pub fn init() {
+    cache::init();
+}
+
+/// Add a device. Whole disks get a [`cache::Cache`] in front of them, and
+/// partitions go through their disk's.
+pub fn register(name: String, device: Arc<dyn BlockDevice>) -> Result<(), Error> {
+    let mut devices = DEVICES.lock();
+    if devices.contains_key(&name) {
+        return Err(Error::DeviceExists);
+    }
+    let device: Arc<dyn BlockDevice> = match device.partition() {
+        Some(_) => device,
+        None => cache::Cache::new(device),
+    };
+    let ro = if device.read_only() {
+        ", read-only"
+    } else {
+        ""
+    };
+    info!(
+        "block: {name} ({} blocks of {} bytes{ro})",
+        device.block_count(),
+        device.block_size(),
+    );
+    devices.insert(name.clone(), device.clone());
+    drop(devices);
+
+    if device.partition().is_none() {
+        crate::task::spawn(async move {
+            if let Err(e) = partition::scan(name.clone(), device).await {
+                warn!("block: failed to read partition table on {name}: {e}");
+            }
+        });
+    }
+    Ok(())
+}
+
+pub fn get(name: &str) -> Result<Arc<dyn BlockDevice>, Error> {
+    DEVICES.lock().get(name).cloned().ok_or(Error::NoSuchDevice)
+}
+
+/// Every registered device, sorted by name.
+pub fn devices() -> Vec<(String, Arc<dyn BlockDevice>)> {
+    DEVICES
+        .lock()
+        .iter()
+        .map(|(name, device)| (name.clone(), device.clone()))
+        .collect()
+}
@@ -0,0 +1,450 @@
+//! GPT and MBR partition tables. Every whole disk is scanned when it is
+//! registered, and each partition found becomes a block device of its own,
+//! named after the disk the way Linux does it: `nvme0n1p1`, `sda1`.
+
+use super::{check_range, BlockDevice, Error};
+use alloc::sync::Arc;
+use core::fmt;
+use futures::future::BoxFuture;
+
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const MBR_TABLE: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+
+const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xee;
+const MBR_TYPES_EXTENDED: &[u8] = &[0x05, 0x0f, 0x85];
+/// Logical partitions start at 5, after the 4 primary slots.
+const MBR_FIRST_LOGICAL: usize = 5;
+/// Stop following an extended partition's chain after this many links, in
+/// case it loops.
+const MBR_MAX_LOGICAL: usize = 128;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_HEADER_MIN_SIZE: usize = 92;
+const GPT_ENTRY_MIN_SIZE: usize = 128;
+/// Refuse entry arrays bigger than this rather than trusting a corrupt header.
+const GPT_MAX_ENTRIES_SIZE: usize = 1 << 20;
+
+/// A GUID, kept in its on-disk mixed-endian layout.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    fn is_nil(&self) -> bool {
+        self.0 == [0; 16]
+    }
+
+    /// The well known name of a GPT partition type.
+    pub fn type_name(&self) -> Option<&'static str> {
+        const TYPES: &[(&str, &str)] = &[
+            ("c12a7328-f81f-11d2-ba4b-00a0c93ec93b", "EFI System"),
+            ("21686148-6449-6e6f-744e-656564454649", "BIOS boot"),
+            (
+                "ebd0a0a2-b9e5-4433-87c0-68b6b72699c7",
+                "Microsoft basic data",
+            ),
+            ("0fc63daf-8483-4772-8e79-3d69d8477de4", "Linux filesystem"),
+            ("0657fd6d-a4ab-43c4-84e5-0933c84b4f4f", "Linux swap"),
+            (
+                "4f68bce3-e8cd-4db1-96e7-fbcaf984b709",
+                "Linux root (x86-64)",
+            ),
+        ];
+        let guid = format!("{self}");
+        TYPES
+            .iter()
+            .find(|(g, _)| *g == guid)
+            .map(|(_, name)| *name)
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:08x}-{:04x}-{:04x}-{:02x}{:02x}-",
+            u32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+            u16::from_le_bytes([b[4], b[5]]),
+            u16::from_le_bytes([b[6], b[7]]),
+            b[8],
+            b[9],
+        )?;
+        for byte in &b[10..] {
+            write!(f, "{byte:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Gpt(Guid),
+    Mbr(u8),
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Gpt(guid) => match guid.type_name() {
+                Some(name) => f.write_str(name),
+                None => write!(f, "{guid}"),
+            },
+            Self::Mbr(kind) => {
+                let name = match kind {
+                    0x01 => "FAT12",
+                    0x04 | 0x06 | 0x0e => "FAT16",
+                    0x07 => "NTFS/exFAT",
+                    0x0b | 0x0c => "FAT32",
+                    0x82 => "Linux swap",
+                    0x83 => "Linux",
+                    0xef => "EFI System",
+                    _ => return write!(f, "0x{kind:02x}"),
+                };
+                f.write_str(name)
+            }
+        }
+    }
+}
+
+/// What the partition table says about a partition.
+#[derive(Debug, Clone)]
+pub struct Info {
+    /// The disk the partition is on.
+    pub disk: String,
+    pub number: usize,
+    pub kind: Kind,
+    /// The GPT unique partition GUID.
+    pub guid: Option<Guid>,
+    /// The GPT partition name, empty for MBR.
+    pub name: String,
+}
+
+/// A range of blocks on another device.
+pub struct Partition {
+    device: Arc<dyn BlockDevice>,
+    start: u64,
+    blocks: u64,
+    info: Info,
+}
+
+impl BlockDevice for Partition {
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.blocks
+    }
+
+    fn read_only(&self) -> bool {
+        self.device.read_only()
+    }
+
+    fn read_blocks<'a>(&'a self, lba: u64, buf: &'a mut [u8]) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            check_range(self, lba, buf.len())?;
+            self.device.read_blocks(self.start + lba, buf).await
+        })
+    }
+
+    fn write_blocks<'a>(&'a self, lba: u64, buf: &'a [u8]) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            check_range(self, lba, buf.len())?;
+            self.device.write_blocks(self.start + lba, buf).await
+        })
+    }
+
+    fn flush(&self) -> BoxFuture<'_, Result<(), Error>> {
+        self.device.flush()
+    }
+
+    fn discard(&self, lba: u64, count: u64) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(async move {
+            if lba.checked_add(count).is_none_or(|end| end > self.blocks) {
+                return Err(Error::OutOfRange);
+            }
+            self.device.discard(self.start + lba, count).await
+        })
+    }
+
+    fn partition(&self) -> Option<&Info> {
+        Some(&self.info)
+    }
+}
+
+/// Table entry, before it is checked against the disk.
+struct Entry {
+    number: usize,
+    start: u64,
+    blocks: u64,
+    kind: Kind,
+    guid: Option<Guid>,
+    name: String,
+}
+
+/// Find the partitions on `device` and register each one.
+pub async fn scan(disk: String, device: Arc<dyn BlockDevice>) -> Result<(), Error> {
+    let entries = match read_gpt(&*device).await? {
+        Some(entries) => entries,
+        None => read_mbr(&*device).await?,
+    };
+
+    for entry in entries {
+        if entry.blocks == 0
+            || entry
+                .start
+                .checked_add(entry.blocks)
+                .is_none_or(|end| end > device.block_count())
+        {
+            warn!("{disk}: partition {} is outside the disk", entry.number);
+            continue;
+        }
+
+        let separator = if disk.ends_with(|c: char| c.is_ascii_digit()) {
+            "p"
+        } else {
+            ""
+        };
+        let name = format!("{disk}{separator}{}", entry.number);
+        let partition = Partition {
+            device: device.clone(),
+            start: entry.start,
+            blocks: entry.blocks,
+            info: Info {
+                disk: disk.clone(),
+                number: entry.number,
+                kind: entry.kind,
+                guid: entry.guid,
+                name: entry.name,
+            },
+        };
+        super::register(name, Arc::new(partition))?;
+    }
+
+    Ok(())
+}
+
+async fn read_block(device: &dyn BlockDevice, lba: u64) -> Result<Vec<u8>, Error> {
+    let mut buf = vec![0; device.block_size()];
+    device.read_blocks(lba, &mut buf).await?;
+    Ok(buf)
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+fn guid_at(buf: &[u8], offset: usize) -> Guid {
+    Guid(buf[offset..offset + 16].try_into().unwrap())
+}
+
+/// The four primary slots of an MBR or EBR, or `None` without a boot
+/// signature. Unused slots are skipped.
+fn mbr_slots(block: &[u8]) -> Option<impl Iterator<Item = (usize, u8, u64, u64)> + '_> {
+    if block.len() < 512 || block[510..512] != MBR_SIGNATURE {
+        return None;
+    }
+    Some((0..4).filter_map(move |i| {
+        let entry = &block[MBR_TABLE + i * MBR_ENTRY_SIZE..][..MBR_ENTRY_SIZE];
+        let kind = entry[4];
+        let start = u32_at(entry, 8) as u64;
+        let blocks = u32_at(entry, 12) as u64;
+        (kind != 0 && blocks != 0).then_some((i, kind, start, blocks))
+    }))
+}
+
+async fn read_mbr(device: &dyn BlockDevice) -> Result<Vec<Entry>, Error> {
+    let block = read_block(device, 0).await?;
+    let Some(slots) = mbr_slots(&block) else {
+        return Ok(vec![]);
+    };
+
+    let mut entries = Vec::new();
+    let mut extended = None;
+    for (i, kind, start, blocks) in slots {
+        if MBR_TYPES_EXTENDED.contains(&kind) {
+            extended = Some(start);
+            continue;
+        }
+        entries.push(Entry {
+            number: i + 1,
+            start,
+            blocks,
+            kind: Kind::Mbr(kind),
+            guid: None,
+            name: String::new(),
+        });
+    }
+
+    // logical partitions are a chain of EBRs, each describing one partition
+    // relative to itself and the next EBR relative to the extended partition
+    if let Some(base) = extended {
+        let mut ebr = base;
+        for number in MBR_FIRST_LOGICAL..MBR_FIRST_LOGICAL + MBR_MAX_LOGICAL {
+            let block = read_block(device, ebr).await?;
+            let Some(slots) = mbr_slots(&block) else {
+                break;
+            };
+            let mut next = None;
+            for (i, kind, start, blocks) in slots {
+                match i {
+                    0 => entries.push(Entry {
+                        number,
+                        start: ebr + start,
+                        blocks,
+                        kind: Kind::Mbr(kind),
+                        guid: None,
+                        name: String::new(),
+                    }),
+                    1 if MBR_TYPES_EXTENDED.contains(&kind) => next = Some(base + start),
+                    _ => {}
+                }
+            }
+            match next {
+                Some(lba) if lba != ebr => ebr = lba,
+                _ => break,
+            }
+        }
+    }
+
+    Ok(entries)
+}
+
+/// The GPT partitions on `device`, or `None` if it isn't GPT partitioned.
+async fn read_gpt(device: &dyn BlockDevice) -> Result<Option<Vec<Entry>>, Error> {
+    let mbr = read_block(device, 0).await?;
+    let protective = mbr_slots(&mbr)
+        .is_some_and(|mut slots| slots.any(|(_, kind, ..)| kind == MBR_TYPE_GPT_PROTECTIVE));
+    if !protective {
+        return Ok(None);
+    }
+
+    let last = device.block_count().saturating_sub(1);
+    let entries = match read_gpt_at(device, 1).await? {
+        Some(entries) => entries,
+        None => {
+            warn!("block: primary GPT header is damaged, using the backup");
+            match read_gpt_at(device, last).await? {
+                Some(entries) => entries,
+                None => {
+                    warn!("block: backup GPT header is damaged too");
+                    return Ok(None);
+                }
+            }
+        }
+    };
+    Ok(Some(entries))
+}
+
+/// Read the GPT header at `lba` and its entry array, or `None` if either
+/// fails validation.
+async fn read_gpt_at(device: &dyn BlockDevice, lba: u64) -> Result<Option<Vec<Entry>>, Error> {
+    let header = read_block(device, lba).await?;
+    if &header[0..8] != GPT_SIGNATURE {
+        return Ok(None);
+    }
+
+    let header_size = u32_at(&header, 12) as usize;
+    if !(GPT_HEADER_MIN_SIZE..=header.len()).contains(&header_size) {
+        return Ok(None);
+    }
+    let mut zeroed = header[..header_size].to_vec();
+    zeroed[16..20].fill(0);
+    if crc32(&zeroed) != u32_at(&header, 16) || u64_at(&header, 24) != lba {
+        return Ok(None);
+    }
+
+    let entries_lba = u64_at(&header, 72);
+    let count = u32_at(&header, 80) as usize;
+    let entry_size = u32_at(&header, 84) as usize;
+    let entries_crc = u32_at(&header, 88);
+    if entry_size < GPT_ENTRY_MIN_SIZE || entry_size % 8 != 0 {
+        return Ok(None);
+    }
+    let size = match count.checked_mul(entry_size) {
+        Some(size) if size <= GPT_MAX_ENTRIES_SIZE => size,
+        _ => return Ok(None),
+    };
+
+    let block_size = device.block_size();
+    let mut array = vec![0; size.div_ceil(block_size) * block_size];
+    if check_range(device, entries_lba, array.len()).is_err() {
+        return Ok(None);
+    }
+    device.read_blocks(entries_lba, &mut array).await?;
+    let array = &array[..size];
+    if crc32(array) != entries_crc {
+        return Ok(None);
+    }
+
+    let entries = array
+        .chunks(entry_size)
+        .enumerate()
+        .filter_map(|(i, entry)| {
+            let kind = guid_at(entry, 0);
+            if kind.is_nil() {
+                return None;
+            }
+            let first = u64_at(entry, 32);
+            let last = u64_at(entry, 40);
+            let name = entry[56..128]
+                .chunks(2)
+                .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                .take_while(|&c| c != 0);
+            Some(Entry {
+                number: i + 1,
+                start: first,
+                // an empty entry is dropped by scan, like any that runs off the disk
+                blocks: last
+                    .checked_add(1)
+                    .and_then(|end| end.checked_sub(first))
+                    .unwrap_or(0),
+                kind: Kind::Gpt(kind),
+                guid: Some(guid_at(entry, 16)),
+                name: char::decode_utf16(name)
+                    .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                    .collect(),
+            })
+        })
+        .collect();
+
+    Ok(Some(entries))
+}
+
+/// CRC-32 as GPT uses it (IEEE 802.3, reflected).
+fn crc32(data: &[u8]) -> u32 {
+    const TABLE: [u32; 256] = {
+        let mut table = [0; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut crc = i as u32;
+            let mut bit = 0;
+            while bit < 8 {
+                crc = if crc & 1 != 0 {
+                    (crc >> 1) ^ 0xedb8_8320
+                } else {
+                    crc >> 1
+                };
+                bit += 1;
+            }
+            table[i] = crc;
+            i += 1;
+        }
+        table
+    };
+
+    !data.iter().fold(!0, |crc, &byte| {
+        TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
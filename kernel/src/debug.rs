@@ -11,6 +11,27 @@ static PRINT: AtomicPtr<()> = AtomicPtr::new(core::ptr::null_mut());
 
 type PrintFn = fn(core::fmt::Arguments);
 
+/// A whole log event, for sinks that want structure rather than text.
+pub struct Record<'a> {
+    pub level: tracing::Level,
+    pub target: &'a str,
+    pub message: &'a str,
+}
+
+static SINK: AtomicPtr<()> = AtomicPtr::new(core::ptr::null_mut());
+
+type SinkFn = fn(&Record);
+
+fn sink() -> Option<SinkFn> {
+    let sink = SINK.load(Ordering::Relaxed);
+    if sink.is_null() {
+        None
+    } else {
+        // SAFETY: value is a non-null pointer
+        Some(unsafe { core::mem::transmute::<*mut (), SinkFn>(sink) })
+    }
+}
+
 fn print(args: core::fmt::Arguments) {
     crate::arch::print(format_args!("{args}"));
 
@@ -69,6 +90,31 @@ impl Collect for Collector {
         event.record(&mut Visitor(true));
 
         print(format_args!("\n"));
+
+        if let Some(sink) = sink() {
+            struct Message(String);
+            impl Visit for Message {
+                fn record_debug(&mut self, field: &Field, value: &dyn core::fmt::Debug) {
+                    use core::fmt::Write;
+                    if !self.0.is_empty() {
+                        self.0.push_str("; ");
+                    }
+                    if field.name() == "message" {
+                        let _ = write!(self.0, "{:?}", value);
+                    } else {
+                        let _ = write!(self.0, "{} = {:?}", field.name(), value);
+                    }
+                }
+            }
+
+            let mut message = Message(String::new());
+            event.record(&mut message);
+            sink(&Record {
+                level: *level,
+                target: meta.target(),
+                message: &message.0,
+            });
+        }
     }
 
     fn enter(&self, _span: &Id) {}
@@ -88,6 +134,20 @@ impl log::Log for Collector {
     fn log(&self, record: &log::Record) {
         if log::Log::enabled(self, record.metadata()) {
             print(format_args!("{} - {}\n", record.level(), record.args()));
+
+            if let Some(sink) = sink() {
+                sink(&Record {
+                    level: match record.level() {
+                        log::Level::Error => tracing::Level::ERROR,
+                        log::Level::Warn => tracing::Level::WARN,
+                        log::Level::Info => tracing::Level::INFO,
+                        log::Level::Debug => tracing::Level::DEBUG,
+                        log::Level::Trace => tracing::Level::TRACE,
+                    },
+                    target: record.target(),
+                    message: &format!("{}", record.args()),
+                });
+            }
         }
     }
 
@@ -106,3 +166,9 @@ pub fn init() {
 pub fn set_print(f: PrintFn) {
     PRINT.store(f as _, Ordering::Relaxed);
 }
+
+/// Also hand every event to `f`. Called with the collector locked, so `f`
+/// must not log.
+pub fn set_sink(f: SinkFn) {
+    SINK.store(f as _, Ordering::Relaxed);
+}
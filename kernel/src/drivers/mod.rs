@@ -3,8 +3,8 @@ mod e1000;
 #[cfg(target_arch = "x86_64")]
 pub mod i8042;
 pub mod keyboard;
-mod virtio;
-// mod nvme;
+pub mod nvme;
+pub mod virtio;
 
 use crate::arch::PciDevice;
 
@@ -14,7 +14,7 @@ static DRIVER_INITS: &[DriverInit] = &[
     e1000::init,
     virtio::net::init,
     virtio::sock::init,
-    // nvme::init,
+    nvme::init,
 ];
 
 pub fn init() {
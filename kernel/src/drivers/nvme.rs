@@ -1,13 +1,21 @@
 use super::dma::Dma;
 use super::PciDevice;
 use crate::arch::PciCommand;
-use crate::arch::{map_address, translate_virt_addr};
-use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::arch::{map_address, translate_virt_addr, InterruptGuard};
+use crate::block::{self, BlockDevice};
+use alloc::sync::Arc;
+use core::{
+    future::poll_fn,
+    sync::atomic::{fence, AtomicUsize, Ordering},
+    task::{Context, Poll, Waker},
+};
+use futures::{future::BoxFuture, task::AtomicWaker};
 use pci_types::Bar;
+use spin::Mutex;
 use x86_64::{PhysAddr, VirtAddr};
 
 #[derive(thiserror::Error, Debug)]
-enum Error {
+pub enum Error {
     #[error("Unknown bar")]
     UnknownBar,
     #[error("Not supported")]
@@ -16,6 +24,22 @@ enum Error {
     Fatal,
     #[error("Command error: {0}")]
     CommandError(u16),
+    #[error("No free interrupt")]
+    NoInterrupt,
+    #[error("Buffer is not a multiple of the block size")]
+    Unaligned,
+    #[error("Out of range")]
+    OutOfRange,
+}
+
+impl From<Error> for block::Error {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::Unaligned => block::Error::Unaligned,
+            Error::OutOfRange => block::Error::OutOfRange,
+            err => block::Error::Device(format!("nvme: {err}")),
+        }
+    }
 }
 
 trait Register: Sized + Copy {
@@ -184,65 +208,173 @@ struct CompletionQueueEntry {
     pub status: u16,     // Reason why the command failed, if it did.
 }
 
-static QUEUE_ID: AtomicUsize = AtomicUsize::new(0);
+/// Entries in the admin queue and in each I/O queue. 64 submission entries
+/// fill exactly one page, so every queue is physically contiguous.
+const QUEUE_SIZE: usize = 64;
+
+const CQ_PHYSICALLY_CONTIGUOUS: u16 = 1 << 0;
+const CQ_INTERRUPTS_ENABLED: u16 = 1 << 1;
+const SQ_PHYSICALLY_CONTIGUOUS: u16 = 1 << 0;
+
+const FEATURE_NUMBER_OF_QUEUES: u32 = 0x07;
+
+/// Optional NVM Command Support: Dataset Management
+const ONCS_DSM: u16 = 1 << 2;
+/// Dataset Management attribute: deallocate the ranges
+const DSM_DEALLOCATE: u32 = 1 << 2;
+
+/// Namespace attribute: write protected
+const NSATTR_WRITE_PROTECTED: u8 = 1 << 0;
+
+/// Numbers controllers for device names, `nvme0`, `nvme1`...
+static CONTROLLERS: AtomicUsize = AtomicUsize::new(0);
+
+/// A command handed to the controller. Its buffers live here until it
+/// completes, so dropping the future that submitted it can't free memory
+/// the controller is still using.
+struct Request {
+    buffer: Option<Dma<[u8]>>,
+    _prp_list: Option<Dma<[u64]>>,
+    waker: Option<Waker>,
+    completion: Option<CompletionQueueEntry>,
+    /// Nobody is waiting for it any more, free the slot once it completes.
+    abandoned: bool,
+}
+
+impl Request {
+    fn new(buffer: Option<Dma<[u8]>>, prp_list: Option<Dma<[u64]>>) -> Self {
+        Self {
+            buffer,
+            _prp_list: prp_list,
+            waker: None,
+            completion: None,
+            abandoned: false,
+        }
+    }
+}
 
 struct QueuePair {
     registers: Registers,
-    queue_id: usize,
-    command_id: u16,
+    queue_id: u16,
+    /// Bytes between doorbell registers, from CAP.DSTRD.
+    doorbell_stride: usize,
     submission: Dma<[Command]>,
-    submission_index: usize,
+    submission_tail: usize,
+    submission_head: usize,
     completion: Dma<[CompletionQueueEntry]>,
-    completion_index: usize,
+    completion_head: usize,
     phase: bool,
+    /// Commands in flight, indexed by command id.
+    requests: Vec<Option<Request>>,
+    /// Tasks waiting for a free slot.
+    waiting: Vec<Waker>,
 }
 
 impl QueuePair {
-    fn new(registers: Registers, size: usize) -> Self {
-        let queue_id = QUEUE_ID.fetch_add(1, Ordering::SeqCst);
+    fn new(registers: Registers, queue_id: u16, doorbell_stride: usize, size: usize) -> Self {
         Self {
             registers,
             queue_id,
-            command_id: 0,
+            doorbell_stride,
             submission: Dma::new_zeroed_slice(size, 4096),
-            submission_index: 0,
+            submission_tail: 0,
+            submission_head: 0,
             completion: Dma::new_zeroed_slice(size, 4096),
-            completion_index: 0,
+            completion_head: 0,
             phase: true,
+            requests: (0..size).map(|_| None).collect(),
+            waiting: vec![],
         }
     }
 
-    fn submit(&mut self, mut command: Command) -> Result<CompletionQueueEntry, Error> {
-        unsafe {
-            *(&mut command as *mut Command as *mut u16).offset(1) = self.command_id;
+    /// A free command id, if the submission queue has room.
+    fn free_slot(&self) -> Option<u16> {
+        if (self.submission_tail + 1) % self.submission.len() == self.submission_head {
+            return None;
         }
-        self.command_id += 1;
-        self.submission[self.submission_index] = command;
-        self.submission_index = (self.submission_index + 1) % self.submission.len();
-        self.set_doorbell(0, self.submission_index as _);
+        self.requests
+            .iter()
+            .position(Option::is_none)
+            .map(|id| id as u16)
+    }
 
-        let cmd = &self.completion[self.completion_index];
-        while (cmd.status & 0x1) != self.phase as u16 {
-            core::hint::spin_loop();
+    fn push(&mut self, id: u16, mut command: Command, request: Request) {
+        command.common.command_id = id;
+        self.requests[id as usize] = Some(request);
+        self.submission[self.submission_tail] = command;
+        self.submission_tail = (self.submission_tail + 1) % self.submission.len();
+        // the entry has to be in memory before the controller hears about it
+        fence(Ordering::SeqCst);
+        self.set_doorbell(0, self.submission_tail as _);
+    }
+
+    fn release(&mut self, id: usize) -> Option<Request> {
+        let request = self.requests[id].take();
+        for waker in self.waiting.drain(..) {
+            waker.wake();
         }
-        let cmd = self.completion[self.completion_index];
-        let status = cmd.status >> 1;
-        if status != 0 {
-            return Err(Error::CommandError(status));
+        request
+    }
+
+    /// Take every new entry off the completion queue and hand it to its
+    /// request.
+    fn reap(&mut self) {
+        let mut reaped = false;
+        loop {
+            let entry = unsafe { core::ptr::read_volatile(&self.completion[self.completion_head]) };
+            if (entry.status & 0x1 == 1) != self.phase {
+                break;
+            }
+            reaped = true;
+
+            self.completion_head = (self.completion_head + 1) % self.completion.len();
+            if self.completion_head == 0 {
+                self.phase = !self.phase;
+            }
+            self.submission_head = entry.sq_head as usize;
+
+            let id = entry.command_id as usize;
+            let Some(Some(request)) = self.requests.get_mut(id) else {
+                warn!("nvme: completion for unknown command {id}");
+                continue;
+            };
+            if request.abandoned {
+                self.release(id);
+            } else {
+                request.completion = Some(entry);
+                if let Some(waker) = request.waker.take() {
+                    waker.wake();
+                }
+            }
         }
-        self.completion_index = (self.completion_index + 1) % self.completion.len();
-        if self.completion_index == 0 {
-            self.phase = !self.phase;
+
+        if reaped {
+            self.set_doorbell(1, self.completion_head as _);
         }
-        self.set_doorbell(1, self.completion_index as _);
+    }
 
-        Ok(cmd)
+    /// Run a command to completion by spinning. Only the admin queue uses
+    /// this: it has no interrupt and only sees commands while the controller
+    /// is brought up.
+    fn submit_polled(&mut self, command: Command) -> Result<CompletionQueueEntry, Error> {
+        let id = self
+            .free_slot()
+            .expect("admin commands are issued one at a time");
+        self.push(id, command, Request::new(None, None));
+        loop {
+            self.reap();
+            let request = self.requests[id as usize].as_mut().unwrap();
+            if let Some(entry) = request.completion.take() {
+                self.release(id as usize);
+                return check(entry);
+            }
+            core::hint::spin_loop();
+        }
     }
 
     fn set_doorbell(&mut self, doorbell: usize, index: u32) {
-        let cap = self.registers.read::<Capabilities>();
-        let doorbell_stride = cap.get(Capabilities::DSTRD);
-        let offset = 0x1000 + (((self.queue_id * 2) + doorbell) * (4 << doorbell_stride));
+        let offset = REG_Q_DBL_BASE as usize
+            + (((self.queue_id as usize * 2) + doorbell) * self.doorbell_stride);
         let doorbell_addr = self.registers.addr + offset;
         unsafe {
             core::ptr::write_volatile(doorbell_addr.as_u64() as *mut u32, index);
@@ -250,6 +382,52 @@ impl QueuePair {
     }
 }
 
+fn check(entry: CompletionQueueEntry) -> Result<CompletionQueueEntry, Error> {
+    match entry.status >> 1 {
+        0 => Ok(entry),
+        status => Err(Error::CommandError(status)),
+    }
+}
+
+/// A command submitted to an I/O queue. If this is dropped before the
+/// command completes, the slot is left for [`QueuePair::reap`] to free.
+struct Submitted<'a> {
+    queue: &'a Mutex<QueuePair>,
+    id: usize,
+    done: bool,
+}
+
+impl Submitted<'_> {
+    fn poll(&mut self, cx: &mut Context) -> Poll<(CompletionQueueEntry, Option<Dma<[u8]>>)> {
+        let mut queue = self.queue.lock();
+        queue.reap();
+        let request = queue.requests[self.id].as_mut().unwrap();
+        let Some(entry) = request.completion else {
+            request.waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        };
+        let request = queue.release(self.id).unwrap();
+        self.done = true;
+        Poll::Ready((entry, request.buffer))
+    }
+}
+
+impl Drop for Submitted<'_> {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        let mut queue = self.queue.lock();
+        let request = queue.requests[self.id].as_mut().unwrap();
+        if request.completion.is_some() {
+            queue.release(self.id);
+        } else {
+            request.abandoned = true;
+            request.waker = None;
+        }
+    }
+}
+
 #[derive(Clone, Copy)]
 struct Registers {
     addr: VirtAddr,
@@ -285,6 +463,12 @@ impl Registers {
         self.write(c);
     }
 
+    fn set_page_size(&self, mps: u8) {
+        let mut c = self.read::<ControllerConfiguration>();
+        c.set(ControllerConfiguration::MPS, mps as _);
+        self.write(c);
+    }
+
     fn set_queue_entry_sizes(&self, iosqes: u8, iocqes: u8) {
         let mut c = self.read::<ControllerConfiguration>();
         c.set(ControllerConfiguration::IOSQES, iosqes as _);
@@ -309,12 +493,27 @@ impl Registers {
 }
 
 struct Controller {
-    registers: Registers,
+    admin: Mutex<QueuePair>,
+    /// One I/O queue pair per core, or as many as the controller allows.
+    io: Vec<Mutex<QueuePair>>,
+    /// Woken by the interrupt shared by every I/O completion queue.
+    waker: Arc<AtomicWaker>,
+    page_size: usize,
+    /// Largest transfer a single command can describe, in bytes.
+    max_transfer: usize,
+    namespace_count: u32,
+    /// Supports deallocating blocks with Dataset Management.
+    discard: bool,
+    _guard: InterruptGuard,
 }
 
+unsafe impl Send for Controller {}
+unsafe impl Sync for Controller {}
+
 impl Controller {
-    fn new(device: &PciDevice) -> Result<Self, Error> {
-        device.set_command(PciCommand::IO_SPACE | PciCommand::BUS_MASTER | PciCommand::MEMORY_SPACE);
+    fn new(device: &PciDevice) -> Result<Arc<Self>, Error> {
+        device
+            .set_command(PciCommand::IO_SPACE | PciCommand::BUS_MASTER | PciCommand::MEMORY_SPACE);
 
         let (registers_addr, registers_size) = match device.bars[0] {
             Some(Bar::Memory64 { address, size, .. }) => (PhysAddr::new(address), size as usize),
@@ -335,27 +534,21 @@ impl Controller {
 
         registers.set_enable(false);
 
-        // TODO: msix
-
-        let queue_size = cap.get(Capabilities::MQES);
+        let mps = cap.get(Capabilities::MPSMIN) as u8;
+        let page_size = 1 << (12 + mps as usize);
+        // MQES is 0's based
+        let queue_size = QUEUE_SIZE.min(cap.get(Capabilities::MQES) as usize + 1);
+        let doorbell_stride = 4 << cap.get(Capabilities::DSTRD) as usize;
 
-        let mut admin_queue = QueuePair::new(registers, queue_size as _);
+        let mut admin = QueuePair::new(registers, 0, doorbell_stride, queue_size);
 
-        registers.set_admin_queue_attributes(queue_size, queue_size);
-
-        registers.write(AdminSubmissionQueue(
-            translate_virt_addr(VirtAddr::new(admin_queue.submission.as_mut_ptr() as _))
-                .unwrap()
-                .as_u64(),
-        ));
-        registers.write(AdminCompletionQueue(
-            translate_virt_addr(VirtAddr::new(admin_queue.completion.as_mut_ptr() as _))
-                .unwrap()
-                .as_u64(),
-        ));
+        registers.set_admin_queue_attributes((queue_size - 1) as _, (queue_size - 1) as _);
+        registers.write(AdminSubmissionQueue(admin.submission.phys_addr() as _));
+        registers.write(AdminCompletionQueue(admin.completion.phys_addr() as _));
 
         registers.set_css(ControllerCommandSet::Nvm);
         registers.set_ams(ArbitrationMechanism::RoundRobin);
+        registers.set_page_size(mps);
         registers.set_queue_entry_sizes(6, 4);
 
         registers.set_enable(true);
@@ -367,125 +560,423 @@ impl Controller {
             return Err(Error::Fatal);
         }
 
-        let mut identity = Dma::<IdentifyController>::new_zeroed(4096);
+        let identity = Dma::<IdentifyController>::new_zeroed(4096);
         let identify = IdentifyCommand {
-            controller_id: 0,
-            command_id: 0,
-            flags: 0,
-            nsid: 0,
             opcode: AdminOpcode::Identify as u8,
             cns: IdentifyCns::Controller as u8,
             data_ptr: DataPointer {
                 prp1: identity.phys_addr() as _,
                 prp2: 0,
             },
-            reserved2: [0; 2],
-            reserved3: 0,
-            reserved11: [0; 5],
+            ..Default::default()
         };
-        admin_queue.submit(Command { identify })?;
-
-        let mut io_queue = QueuePair::new(registers, queue_size as _);
-
-        let create_cq = CreateCQCommand {
-            opcode: AdminOpcode::CreateCq as _,
-            flags: 0,
-            command_id: 1,
-            reserved1: [0; 5],
-            prp1: io_queue.completion.phys_addr() as _,
-            prp2: 0,
-            cqid: io_queue.queue_id as _,
-            q_size: (io_queue.completion.len() - 1) as _,
-            cq_flags: 1,
-            irq_vector: 0,
-            reserved2: [0; 4],
+        admin.submit_polled(Command { identify })?;
+
+        // ask for a queue pair per core, the controller may grant fewer
+        let wanted = crate::arch::cpu_count().clamp(1, 1 << 16) as u32;
+        let set_features = CommonCommand {
+            opcode: AdminOpcode::SetFeatures as u8,
+            cdw10: FEATURE_NUMBER_OF_QUEUES,
+            cdw11: (wanted - 1) | ((wanted - 1) << 16),
+            ..Default::default()
         };
-        admin_queue.submit(Command { create_cq })?;
-
-        let create_sq = CreateSQCommand {
-            opcode: AdminOpcode::CreateSq as _,
-            flags: 0,
-            command_id: 3,
-            reserved1: [0; 5],
-            prp1: io_queue.submission.phys_addr() as _,
-            prp2: 0,
-            sqid: io_queue.queue_id as _,
-            q_size: (io_queue.submission.len() - 1) as _,
-            sq_flags: 1,
-            cqid: io_queue.queue_id as _,
-            reserved2: [0; 4],
+        let granted = admin.submit_polled(Command {
+            common: set_features,
+        })?;
+        let count = (wanted as usize)
+            .min((granted.result & 0xffff) as usize + 1)
+            .min((granted.result >> 16) as usize + 1);
+
+        let waker = Arc::new(AtomicWaker::new());
+        let guard = {
+            let waker = waker.clone();
+            crate::arch::set_interrupt_msi(device.clone(), Box::new(move || waker.wake()))
+                .ok_or(Error::NoInterrupt)?
         };
-        admin_queue.submit(Command { create_sq })?;
 
-        let shift = 12 + registers.read::<Capabilities>().get(Capabilities::MPSMIN) as usize;
+        let mut io = Vec::with_capacity(count);
+        for queue_id in 1..=count as u16 {
+            let queue = QueuePair::new(registers, queue_id, doorbell_stride, queue_size);
+
+            let create_cq = CreateCQCommand {
+                opcode: AdminOpcode::CreateCq as _,
+                prp1: queue.completion.phys_addr() as _,
+                cqid: queue_id,
+                q_size: (queue.completion.len() - 1) as _,
+                cq_flags: CQ_PHYSICALLY_CONTIGUOUS | CQ_INTERRUPTS_ENABLED,
+                // every queue shares the one vector set_interrupt_msi programs
+                irq_vector: 0,
+                ..Default::default()
+            };
+            admin.submit_polled(Command { create_cq })?;
+
+            let create_sq = CreateSQCommand {
+                opcode: AdminOpcode::CreateSq as _,
+                prp1: queue.submission.phys_addr() as _,
+                sqid: queue_id,
+                q_size: (queue.submission.len() - 1) as _,
+                sq_flags: SQ_PHYSICALLY_CONTIGUOUS,
+                cqid: queue_id,
+                ..Default::default()
+            };
+            admin.submit_polled(Command { create_sq })?;
+
+            io.push(Mutex::new(queue));
+        }
+
+        // MDTS is in units of the minimum page size. PRP1 covers one page
+        // and PRP2 points at a single page of list entries for the rest.
         let max_transfer_shift = if identity.mdts != 0 {
-            shift + identity.mdts as usize
+            12 + mps as usize + identity.mdts as usize
         } else {
             20
         };
+        let max_prps = ((1 << max_transfer_shift) / page_size).min(page_size / 8 + 1);
+
+        debug!("nvme: {count} I/O queues of {queue_size}, {max_prps} PRPs per command");
+
+        Ok(Arc::new(Self {
+            admin: Mutex::new(admin),
+            io,
+            waker,
+            page_size,
+            max_transfer: max_prps * page_size,
+            namespace_count: identity.nn,
+            discard: identity.oncs & ONCS_DSM != 0,
+            _guard: guard,
+        }))
+    }
+
+    fn namespaces(self: &Arc<Self>) -> Result<Vec<Namespace>, Error> {
+        let mut admin = self.admin.lock();
+
+        // the active namespace list is always a full page of ids
+        let nsids = Dma::<[u32]>::new_zeroed_slice(1024, 4096);
+        let identify = IdentifyCommand {
+            opcode: AdminOpcode::Identify as u8,
+            cns: IdentifyCns::ActivateList as u8,
+            data_ptr: DataPointer {
+                prp1: nsids.phys_addr() as _,
+                prp2: 0,
+            },
+            ..Default::default()
+        };
+        admin.submit_polled(Command { identify })?;
 
+        let mut namespaces = Vec::new();
+        for &nsid in nsids
+            .iter()
+            .take(self.namespace_count as usize)
+            .take_while(|&&nsid| nsid != 0)
         {
-            let nsids = Dma::<[u32]>::new_zeroed_slice(identity.nn as usize, 4096);
+            let identity = Dma::<IdentifyNamespace>::new_zeroed(4096);
             let identify = IdentifyCommand {
-                controller_id: 0,
-                command_id: 4,
-                flags: 0,
-                nsid: 0,
-                opcode: AdminOpcode::Identify as u8,
-                cns: IdentifyCns::ActivateList as u8,
+                nsid,
+                opcode: AdminOpcode::Identify as _,
+                cns: IdentifyCns::Namespace as _,
                 data_ptr: DataPointer {
-                    prp1: nsids.phys_addr() as _,
+                    prp1: identity.phys_addr() as _,
                     prp2: 0,
                 },
-                reserved2: [0; 2],
-                reserved3: 0,
-                reserved11: [0; 5],
+                ..Default::default()
             };
-            admin_queue.submit(Command { identify })?;
+            admin.submit_polled(Command { identify })?;
+
+            let blocks = identity.nsze;
+            let block_size = 1 << identity.lbaf[(identity.flbas & 0xf) as usize].ds;
+            // NLB is a 0's based u16
+            let max_blocks = (self.max_transfer / block_size).clamp(1, 1 << 16);
+            let read_only = identity.nsattr & NSATTR_WRITE_PROTECTED != 0;
+
+            info!(
+                "nvme: identified namespace (nsid={}, blocks={}, block_size={}, size={})",
+                nsid,
+                blocks,
+                block_size,
+                blocks * block_size as u64,
+            );
+
+            namespaces.push(Namespace {
+                controller: self.clone(),
+                nsid,
+                blocks,
+                block_size,
+                max_blocks,
+                read_only,
+            });
+        }
 
-            for nsid in nsids.into_iter() {
-                if *nsid == 0 {
-                    continue;
-                }
+        Ok(namespaces)
+    }
 
-                info!("nsid={nsid}");
-
-                let identity = Dma::<IdentifyNamespace>::new_zeroed(4096);
-                let identify = IdentifyCommand {
-                    controller_id: 0,
-                    command_id: 5,
-                    flags: 0,
-                    nsid: *nsid,
-                    opcode: AdminOpcode::Identify as _,
-                    cns: IdentifyCns::Namespace as _,
-                    data_ptr: DataPointer {
-                        prp1: identity.phys_addr() as _,
-                        prp2: 0,
-                    },
-                    reserved2: [0; 2],
-                    reserved3: 0,
-                    reserved11: [0; 5],
+    /// Describe `data` to the controller: PRP1 is its first page, PRP2 either
+    /// the second page or a list of every page after the first.
+    fn prps(&self, data: &Dma<[u8]>) -> (DataPointer, Option<Dma<[u64]>>) {
+        let page = |i: usize| {
+            let addr = VirtAddr::new(data.as_ptr() as u64 + (i * self.page_size) as u64);
+            translate_virt_addr(addr).unwrap().as_u64()
+        };
+        match data.len().div_ceil(self.page_size) {
+            0 | 1 => (
+                DataPointer {
+                    prp1: page(0),
+                    prp2: 0,
+                },
+                None,
+            ),
+            2 => (
+                DataPointer {
+                    prp1: page(0),
+                    prp2: page(1),
+                },
+                None,
+            ),
+            pages => {
+                let mut list = Dma::<[u64]>::new_zeroed_slice(pages - 1, self.page_size);
+                for (i, entry) in list.iter_mut().enumerate() {
+                    *entry = page(i + 1);
+                }
+                let pointer = DataPointer {
+                    prp1: page(0),
+                    prp2: list.phys_addr() as _,
                 };
-                admin_queue.submit(Command { identify })?;
-
-                let blocks = identity.nsze as usize;
-                let block_size = 1 << identity.lbaf[(identity.flbas & 0b11111) as usize].ds;
-
-                // The maximum transfer size is in units of 2^(min page size)
-                let lba_shift = identity.lbaf[(identity.flbas & 0xf) as usize].ds;
-                let max_lbas = 1 << (max_transfer_shift - lba_shift as usize);
-                let max_prps = (max_lbas * (1 << lba_shift)) / 4096;
-
-                info!(
-                    "nvme: identified namespace (blocks={}, block_size={}, size={})",
-                    blocks,
-                    block_size,
-                    blocks * block_size,
-                )
+                (pointer, Some(list))
             }
         }
+    }
+
+    /// Queue a command on the current core's I/O queue and wait for it to
+    /// complete, handing back its data buffer.
+    async fn submit(
+        &self,
+        command: Command,
+        buffer: Option<Dma<[u8]>>,
+        prp_list: Option<Dma<[u64]>>,
+    ) -> Result<Option<Dma<[u8]>>, Error> {
+        let queue = &self.io[crate::arch::get_pid() as usize % self.io.len()];
+
+        let mut request = Some(Request::new(buffer, prp_list));
+        let id = poll_fn(|cx| {
+            let mut queue = queue.lock();
+            queue.reap();
+            match queue.free_slot() {
+                Some(id) => {
+                    queue.push(id, command, request.take().unwrap());
+                    Poll::Ready(id)
+                }
+                None => {
+                    queue.waiting.push(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        })
+        .await;
 
-        Ok(Self { registers })
+        let mut submitted = Submitted {
+            queue,
+            id: id as usize,
+            done: false,
+        };
+        let (entry, buffer) = poll_fn(|cx| submitted.poll(cx)).await;
+        check(entry)?;
+        Ok(buffer)
+    }
+}
+
+/// Hands completions to the tasks waiting for them whenever the controller
+/// raises its interrupt.
+async fn run(controller: Arc<Controller>) {
+    poll_fn(|cx| {
+        controller.waker.register(cx.waker());
+        for queue in &controller.io {
+            queue.lock().reap();
+        }
+        Poll::<()>::Pending
+    })
+    .await
+}
+
+/// An NVM namespace, addressed in blocks of [`Namespace::block_size`] bytes.
+pub struct Namespace {
+    controller: Arc<Controller>,
+    nsid: u32,
+    blocks: u64,
+    block_size: usize,
+    /// Most blocks a single command can move.
+    max_blocks: usize,
+    read_only: bool,
+}
+
+impl Namespace {
+    pub fn nsid(&self) -> u32 {
+        self.nsid
+    }
+
+    pub fn blocks(&self) -> u64 {
+        self.blocks
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn check_range(&self, lba: u64, len: usize) -> Result<(), Error> {
+        if len % self.block_size != 0 {
+            return Err(Error::Unaligned);
+        }
+        self.check_blocks(lba, (len / self.block_size) as u64)
+    }
+
+    fn check_blocks(&self, lba: u64, count: u64) -> Result<(), Error> {
+        if lba.checked_add(count).is_none_or(|end| end > self.blocks) {
+            return Err(Error::OutOfRange);
+        }
+        Ok(())
+    }
+
+    /// Read `buf.len() / block_size` blocks starting at `lba`.
+    pub async fn read_blocks(&self, lba: u64, buf: &mut [u8]) -> Result<(), Error> {
+        self.check_range(lba, buf.len())?;
+        let chunk_size = self.max_blocks * self.block_size;
+        for (i, chunk) in buf.chunks_mut(chunk_size).enumerate() {
+            let data = Dma::new_zeroed_slice(chunk.len(), self.controller.page_size);
+            let lba = lba + (i * self.max_blocks) as u64;
+            let data = self.transfer(IoOpcode::Read, lba, data).await?;
+            chunk.copy_from_slice(&data);
+        }
+        Ok(())
+    }
+
+    /// Write `buf.len() / block_size` blocks starting at `lba`.
+    pub async fn write_blocks(&self, lba: u64, buf: &[u8]) -> Result<(), Error> {
+        self.check_range(lba, buf.len())?;
+        let chunk_size = self.max_blocks * self.block_size;
+        for (i, chunk) in buf.chunks(chunk_size).enumerate() {
+            let mut data = Dma::new_zeroed_slice(chunk.len(), self.controller.page_size);
+            data.copy_from_slice(chunk);
+            let lba = lba + (i * self.max_blocks) as u64;
+            self.transfer(IoOpcode::Write, lba, data).await?;
+        }
+        Ok(())
+    }
+
+    /// Commit everything written so far to non-volatile media.
+    pub async fn flush(&self) -> Result<(), Error> {
+        let flush = CommonCommand {
+            opcode: IoOpcode::Flush as u8,
+            namespace_id: self.nsid,
+            ..Default::default()
+        };
+        self.controller
+            .submit(Command { common: flush }, None, None)
+            .await?;
+        Ok(())
+    }
+
+    /// Deallocate `count` blocks from `lba`. A no-op on controllers without
+    /// Dataset Management.
+    pub async fn discard(&self, mut lba: u64, mut count: u64) -> Result<(), Error> {
+        self.check_blocks(lba, count)?;
+        if !self.controller.discard {
+            return Ok(());
+        }
+        while count > 0 {
+            let n = count.min(u32::MAX as u64);
+            // one range: context attributes, length in blocks, starting LBA
+            let mut range = Dma::<[u8]>::new_zeroed_slice(16, self.controller.page_size);
+            range[4..8].copy_from_slice(&(n as u32).to_le_bytes());
+            range[8..16].copy_from_slice(&lba.to_le_bytes());
+            let (data_ptr, _) = self.controller.prps(&range);
+            let dsm = CommonCommand {
+                opcode: IoOpcode::DatasetManagement as u8,
+                namespace_id: self.nsid,
+                data_ptr,
+                // number of ranges, 0's based
+                cdw10: 0,
+                cdw11: DSM_DEALLOCATE,
+                ..Default::default()
+            };
+            self.controller
+                .submit(Command { common: dsm }, Some(range), None)
+                .await?;
+            lba += n;
+            count -= n;
+        }
+        Ok(())
+    }
+
+    async fn transfer(
+        &self,
+        opcode: IoOpcode,
+        lba: u64,
+        data: Dma<[u8]>,
+    ) -> Result<Dma<[u8]>, Error> {
+        let (data_ptr, prp_list) = self.controller.prps(&data);
+        let rw = ReadWriteCommand {
+            opcode: opcode as u8,
+            nsid: self.nsid,
+            data_ptr,
+            start_lba: lba,
+            length: (data.len() / self.block_size - 1) as u16,
+            ..Default::default()
+        };
+        let data = self
+            .controller
+            .submit(Command { rw }, Some(data), prp_list)
+            .await?;
+        Ok(data.unwrap())
+    }
+}
+
+impl BlockDevice for Namespace {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        self.blocks
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn read_blocks<'a>(
+        &'a self,
+        lba: u64,
+        buf: &'a mut [u8],
+    ) -> BoxFuture<'a, Result<(), block::Error>> {
+        Box::pin(async move { Ok(Namespace::read_blocks(self, lba, buf).await?) })
+    }
+
+    fn write_blocks<'a>(
+        &'a self,
+        lba: u64,
+        buf: &'a [u8],
+    ) -> BoxFuture<'a, Result<(), block::Error>> {
+        Box::pin(async move {
+            if self.read_only {
+                return Err(block::Error::ReadOnly);
+            }
+            Ok(Namespace::write_blocks(self, lba, buf).await?)
+        })
+    }
+
+    fn flush(&self) -> BoxFuture<'_, Result<(), block::Error>> {
+        Box::pin(async move { Ok(Namespace::flush(self).await?) })
+    }
+
+    fn discard(&self, lba: u64, count: u64) -> BoxFuture<'_, Result<(), block::Error>> {
+        Box::pin(async move {
+            if self.read_only {
+                return Err(block::Error::ReadOnly);
+            }
+            Ok(Namespace::discard(self, lba, count).await?)
+        })
     }
 }
 
@@ -494,7 +985,16 @@ pub fn init(device: &PciDevice) -> Result<bool, anyhow::Error> {
         return Ok(false);
     }
 
-    Controller::new(device)?;
+    let controller = Controller::new(device)?;
+    let namespaces = controller.namespaces()?;
+
+    crate::task::spawn(run(controller));
+
+    let index = CONTROLLERS.fetch_add(1, Ordering::Relaxed);
+    for namespace in namespaces {
+        let name = format!("nvme{index}n{}", namespace.nsid);
+        block::register(name, Arc::new(namespace))?;
+    }
 
     Ok(true)
 }
@@ -505,11 +1005,21 @@ pub enum AdminOpcode {
     CreateSq = 0x1,
     CreateCq = 0x5,
     Identify = 0x6,
+    SetFeatures = 0x9,
 
     #[default]
     Unknown = u8::MAX,
 }
 
+#[repr(u8)]
+#[derive(Copy, Clone)]
+pub enum IoOpcode {
+    Flush = 0x0,
+    Write = 0x1,
+    Read = 0x2,
+    DatasetManagement = 0x9,
+}
+
 #[repr(u8)]
 #[derive(Default, Copy, Clone)]
 pub enum IdentifyCns {
@@ -612,6 +1122,7 @@ pub struct CreateCQCommand {
     pub reserved2: [u32; 4],
 }
 
+#[derive(Copy, Clone)]
 #[repr(C)]
 pub union Command {
     common: CommonCommand,
@@ -1,17 +1,430 @@
 use super::{create_transport, Hal};
-use crate::arch::PciDevice;
-use virtio_drivers::{device::socket::VirtIOSocket, transport::pci::PciTransport};
+use crate::arch::{InterruptGuard, PciDevice};
+use conquer_once::spin::OnceCell;
+use core::{
+    future::poll_fn,
+    task::{Poll, Waker},
+};
+use futures::task::AtomicWaker;
+use spin::{Mutex, MutexGuard};
+use virtio_drivers::{
+    device::socket::{SocketError, VirtIOSocket, VsockConnectionManager, VsockEventType},
+    transport::pci::PciTransport,
+};
 
-type Device = VirtIOSocket<Hal, PciTransport, 512>;
+pub use virtio_drivers::device::socket::VsockAddr;
+
+const RX_BUFFER_SIZE: usize = 512;
+
+type Device = VirtIOSocket<Hal, PciTransport, RX_BUFFER_SIZE>;
+type Manager = VsockConnectionManager<Hal, PciTransport, RX_BUFFER_SIZE>;
+
+/// CID of the host, which is where any vsock peer lives.
+pub const HOST_CID: u64 = 2;
+
+const EPHEMERAL_PORTS: core::ops::Range<u32> = 49152..65536;
+
+/// Largest chunk handed to the device at once, well below any peer's buffer.
+const MAX_SEND: usize = 1024;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("no vsock device")]
+    NoDevice,
+    #[error("{0}")]
+    Virtio(virtio_drivers::Error),
+    #[error("connection refused")]
+    ConnectionRefused,
+    #[error("connection closed")]
+    Closed,
+    #[error("address in use")]
+    AddressInUse,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum State {
+    Connecting,
+    Connected,
+    Closed,
+}
+
+struct Connection {
+    peer: VsockAddr,
+    port: u32,
+    state: State,
+    rx_waker: Option<Waker>,
+    tx_waker: Option<Waker>,
+}
+
+impl Connection {
+    fn new(peer: VsockAddr, port: u32, state: State) -> Self {
+        Self {
+            peer,
+            port,
+            state,
+            rx_waker: None,
+            tx_waker: None,
+        }
+    }
+
+    fn wake(&mut self) {
+        if let Some(waker) = self.rx_waker.take() {
+            waker.wake();
+        }
+        if let Some(waker) = self.tx_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+struct Listener {
+    port: u32,
+    backlog: Vec<VsockAddr>,
+    waker: Option<Waker>,
+}
+
+struct Inner {
+    manager: Manager,
+    connections: Vec<Connection>,
+    listeners: Vec<Listener>,
+    next_port: u32,
+    _guard: InterruptGuard,
+}
+
+static DEVICE: OnceCell<Mutex<Inner>> = OnceCell::uninit();
+
+/// Woken from the interrupt handler, so it must not touch the device lock.
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+impl Inner {
+    fn connection(&mut self, peer: VsockAddr, port: u32) -> Option<&mut Connection> {
+        self.connections
+            .iter_mut()
+            .find(|c| c.peer == peer && c.port == port)
+    }
+
+    fn allocate_port(&mut self) -> u32 {
+        loop {
+            let port = self.next_port;
+            self.next_port += 1;
+            if !EPHEMERAL_PORTS.contains(&self.next_port) {
+                self.next_port = EPHEMERAL_PORTS.start;
+            }
+            if !self.connections.iter().any(|c| c.port == port)
+                && !self.listeners.iter().any(|l| l.port == port)
+            {
+                return port;
+            }
+        }
+    }
+
+    fn handle_events(&mut self) {
+        loop {
+            let event = match self.manager.poll() {
+                Ok(Some(event)) => event,
+                Ok(None) => break,
+                Err(e) => {
+                    error!("[VSOCK] poll error {e}");
+                    break;
+                }
+            };
+
+            let peer = event.source;
+            let port = event.destination.port;
+            match event.event_type {
+                VsockEventType::ConnectionRequest => {
+                    // the manager has already accepted it if we listen on the port
+                    if let Some(listener) = self.listeners.iter_mut().find(|l| l.port == port) {
+                        listener.backlog.push(peer);
+                        if let Some(waker) = listener.waker.take() {
+                            waker.wake();
+                        }
+                        self.connections
+                            .push(Connection::new(peer, port, State::Connected));
+                    }
+                }
+                VsockEventType::Connected => {
+                    if let Some(connection) = self.connection(peer, port) {
+                        connection.state = State::Connected;
+                        connection.wake();
+                    }
+                }
+                VsockEventType::Disconnected { .. } => {
+                    if let Some(connection) = self.connection(peer, port) {
+                        connection.state = State::Closed;
+                        connection.wake();
+                    }
+                }
+                VsockEventType::Received { .. } => {
+                    if let Some(waker) = self.connection(peer, port).and_then(|c| c.rx_waker.take())
+                    {
+                        waker.wake();
+                    }
+                }
+                VsockEventType::CreditUpdate => {
+                    if let Some(waker) = self.connection(peer, port).and_then(|c| c.tx_waker.take())
+                    {
+                        waker.wake();
+                    }
+                }
+                // answered by the manager
+                VsockEventType::CreditRequest => {}
+            }
+        }
+    }
+}
+
+fn device() -> Result<MutexGuard<'static, Inner>, Error> {
+    DEVICE.get().map(|d| d.lock()).ok_or(Error::NoDevice)
+}
+
+pub struct VsockStream {
+    peer: VsockAddr,
+    port: u32,
+}
+
+impl VsockStream {
+    pub async fn connect(peer: VsockAddr) -> Result<Self, Error> {
+        let port = {
+            let mut inner = device()?;
+            let port = inner.allocate_port();
+            inner.manager.connect(peer, port).map_err(Error::Virtio)?;
+            inner
+                .connections
+                .push(Connection::new(peer, port, State::Connecting));
+            port
+        };
+
+        // dropping this on failure forgets the connection
+        let stream = Self { peer, port };
+
+        poll_fn(|cx| {
+            let mut inner = device()?;
+            let Some(connection) = inner.connection(peer, port) else {
+                return Poll::Ready(Err(Error::ConnectionRefused));
+            };
+            match connection.state {
+                State::Connecting => {
+                    connection.tx_waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+                State::Connected => Poll::Ready(Ok(())),
+                State::Closed => Poll::Ready(Err(Error::ConnectionRefused)),
+            }
+        })
+        .await?;
+
+        Ok(stream)
+    }
+
+    pub fn peer_addr(&self) -> VsockAddr {
+        self.peer
+    }
+
+    pub fn local_port(&self) -> u32 {
+        self.port
+    }
+
+    /// Read some bytes, returning 0 once the peer has shut down and
+    /// everything it sent has been read.
+    pub async fn read(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        poll_fn(|cx| {
+            let mut inner = device()?;
+            let inner = &mut *inner;
+            match inner.manager.recv(self.peer, self.port, buf) {
+                Ok(0) => {}
+                Ok(n) => {
+                    // tell the peer it has room to send again
+                    let _ = inner.manager.update_credit(self.peer, self.port);
+                    return Poll::Ready(Ok(n));
+                }
+                Err(virtio_drivers::Error::SocketDeviceError(SocketError::NotConnected)) => {
+                    return Poll::Ready(Ok(0));
+                }
+                Err(e) => return Poll::Ready(Err(Error::Virtio(e))),
+            }
+            match inner.connection(self.peer, self.port) {
+                Some(connection) if connection.state != State::Closed => {
+                    connection.rx_waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+                _ => Poll::Ready(Ok(0)),
+            }
+        })
+        .await
+    }
+
+    pub async fn write(&self, buf: &[u8]) -> Result<usize, Error> {
+        let buf = &buf[..buf.len().min(MAX_SEND)];
+        poll_fn(|cx| {
+            let mut inner = device()?;
+            let inner = &mut *inner;
+            if !inner
+                .connection(self.peer, self.port)
+                .is_some_and(|c| c.state == State::Connected)
+            {
+                return Poll::Ready(Err(Error::Closed));
+            }
+            match inner.manager.send(self.peer, self.port, buf) {
+                Ok(()) => Poll::Ready(Ok(buf.len())),
+                Err(virtio_drivers::Error::SocketDeviceError(
+                    SocketError::InsufficientBufferSpaceInPeer,
+                )) => {
+                    if let Some(connection) = inner.connection(self.peer, self.port) {
+                        connection.tx_waker = Some(cx.waker().clone());
+                    }
+                    Poll::Pending
+                }
+                Err(e) => Poll::Ready(Err(Error::Virtio(e))),
+            }
+        })
+        .await
+    }
+
+    pub async fn write_all(&self, mut buf: &[u8]) -> Result<(), Error> {
+        while !buf.is_empty() {
+            let n = self.write(buf).await?;
+            buf = &buf[n..];
+        }
+        Ok(())
+    }
+
+    /// Tell the peer we are done; it resets the connection once it agrees.
+    pub fn close(&self) {
+        let Ok(mut inner) = device() else {
+            return;
+        };
+        let _ = inner.manager.shutdown(self.peer, self.port);
+        if let Some(connection) = inner.connection(self.peer, self.port) {
+            connection.state = State::Closed;
+            connection.wake();
+        }
+    }
+}
+
+impl Drop for VsockStream {
+    fn drop(&mut self) {
+        let Ok(mut inner) = device() else {
+            return;
+        };
+        if let Some(index) = inner
+            .connections
+            .iter()
+            .position(|c| c.peer == self.peer && c.port == self.port)
+        {
+            let connection = inner.connections.swap_remove(index);
+            if connection.state != State::Closed {
+                let _ = inner.manager.force_close(self.peer, self.port);
+            }
+        }
+    }
+}
+
+pub struct VsockListener {
+    port: u32,
+}
+
+impl VsockListener {
+    pub fn bind(port: u32) -> Result<Self, Error> {
+        let mut inner = device()?;
+        if inner.listeners.iter().any(|l| l.port == port) {
+            return Err(Error::AddressInUse);
+        }
+        inner.manager.listen(port);
+        inner.listeners.push(Listener {
+            port,
+            backlog: vec![],
+            waker: None,
+        });
+        Ok(Self { port })
+    }
+
+    pub async fn accept(&self) -> Result<VsockStream, Error> {
+        poll_fn(|cx| {
+            let mut inner = device()?;
+            let listener = inner
+                .listeners
+                .iter_mut()
+                .find(|l| l.port == self.port)
+                .unwrap();
+            if listener.backlog.is_empty() {
+                listener.waker = Some(cx.waker().clone());
+                Poll::Pending
+            } else {
+                let peer = listener.backlog.remove(0);
+                Poll::Ready(Ok(VsockStream {
+                    peer,
+                    port: self.port,
+                }))
+            }
+        })
+        .await
+    }
+}
+
+impl Drop for VsockListener {
+    fn drop(&mut self) {
+        let Ok(mut inner) = device() else {
+            return;
+        };
+        inner.manager.unlisten(self.port);
+        let Some(index) = inner.listeners.iter().position(|l| l.port == self.port) else {
+            return;
+        };
+        let listener = inner.listeners.swap_remove(index);
+        // nobody is going to accept these
+        for peer in listener.backlog {
+            let _ = inner.manager.force_close(peer, self.port);
+            inner
+                .connections
+                .retain(|c| !(c.peer == peer && c.port == self.port));
+        }
+    }
+}
+
+async fn run() {
+    poll_fn(|cx| {
+        WAKER.register(cx.waker());
+        DEVICE.get().unwrap().lock().handle_events();
+        Poll::<()>::Pending
+    })
+    .await
+}
 
 pub fn init(header: &PciDevice) -> Result<bool, anyhow::Error> {
     if header.vendor_id != 0x1af4 || header.device_id != 0x1053 {
         return Ok(false);
     }
 
+    if DEVICE.is_initialized() {
+        warn!("[VSOCK] ignoring additional device");
+        return Ok(true);
+    }
+
     let transport = create_transport(header)?;
 
-    let _device = Device::new(transport)?;
+    let mut device = Device::new(transport)?;
+
+    let guard = crate::arch::set_interrupt_msi(header.clone(), Box::new(|| WAKER.wake()))
+        .ok_or_else(|| anyhow::anyhow!("no free interrupt"))?;
+
+    crate::arch::without_interrupts(|| {
+        device.transport_mut().set_queue_msix_vector(0x00);
+    });
+
+    debug!("[VSOCK] guest cid {}", device.guest_cid());
+
+    DEVICE.init_once(|| {
+        Mutex::new(Inner {
+            manager: VsockConnectionManager::new(device),
+            connections: vec![],
+            listeners: vec![],
+            next_port: EPHEMERAL_PORTS.start,
+            _guard: guard,
+        })
+    });
+
+    crate::task::spawn(run());
 
     Ok(true)
 }
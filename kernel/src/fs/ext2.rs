@@ -0,0 +1,587 @@
+//! ext2, read-only. Also reads ext3 and ext4 images, as long as they don't
+//! need anything more than extents: the journal is ignored, and hashed
+//! directories are read as the plain lists they also are.
+
+use super::{DirEntry, Error, FileType, Inode, Stat, Usage};
+use crate::block::{self, BlockDevice};
+use alloc::sync::Arc;
+use futures::future::BoxFuture;
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const MAGIC: u16 = 0xef53;
+const ROOT_INODE: u32 = 2;
+
+const INCOMPAT_COMPRESSION: u32 = 0x1;
+const INCOMPAT_FILETYPE: u32 = 0x2;
+const INCOMPAT_RECOVER: u32 = 0x4;
+const INCOMPAT_JOURNAL_DEV: u32 = 0x8;
+const INCOMPAT_META_BG: u32 = 0x10;
+const INCOMPAT_EXTENTS: u32 = 0x40;
+const INCOMPAT_64BIT: u32 = 0x80;
+const INCOMPAT_DIRDATA: u32 = 0x1000;
+const INCOMPAT_INLINE_DATA: u32 = 0x8000;
+const INCOMPAT_ENCRYPT: u32 = 0x10000;
+/// Features that change how files are laid out in ways we can't read.
+const INCOMPAT_UNSUPPORTED: u32 = INCOMPAT_COMPRESSION
+    | INCOMPAT_JOURNAL_DEV
+    | INCOMPAT_DIRDATA
+    | INCOMPAT_INLINE_DATA
+    | INCOMPAT_ENCRYPT;
+const RO_COMPAT_SPARSE_SUPER: u32 = 0x1;
+
+const MODE_TYPE: u16 = 0o170000;
+const MODE_DIRECTORY: u16 = 0o040000;
+const MODE_SYMLINK: u16 = 0o120000;
+
+const FLAG_EXTENTS: u32 = 0x80000;
+
+const DIRECT_BLOCKS: u64 = 12;
+const EXTENT_MAGIC: u16 = 0xf30a;
+/// Deeper than any real extent tree, so a corrupt one can't loop forever.
+const MAX_EXTENT_DEPTH: u16 = 5;
+/// Extents longer than this are allocated but unwritten, and read as zeros.
+const EXTENT_UNWRITTEN: u16 = 32768;
+
+fn u16_at(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+pub fn probe(
+    device: Arc<dyn BlockDevice>,
+) -> BoxFuture<'static, Result<Arc<dyn super::FileSystem>, Error>> {
+    Box::pin(async move { Ok(FileSystem::mount(device).await? as Arc<dyn super::FileSystem>) })
+}
+
+pub struct FileSystem {
+    device: Arc<dyn BlockDevice>,
+    block_size: u64,
+    blocks: u64,
+    free_blocks: u64,
+    inodes: u32,
+    inodes_per_group: u32,
+    inode_size: u64,
+    /// Where each group's inode table starts, in blocks.
+    inode_tables: Vec<u64>,
+    extents: bool,
+    /// Directory entries say what they point to, without reading the inode.
+    filetype: bool,
+}
+
+impl FileSystem {
+    pub async fn mount(device: Arc<dyn BlockDevice>) -> Result<Arc<Self>, Error> {
+        let mut sb = [0; SUPERBLOCK_SIZE];
+        block::read_at(&*device, SUPERBLOCK_OFFSET, &mut sb).await?;
+        if u16_at(&sb, 56) != MAGIC {
+            return Err(Error::UnknownFilesystem);
+        }
+
+        let incompat = u32_at(&sb, 96);
+        if incompat & INCOMPAT_UNSUPPORTED != 0 {
+            warn!(
+                "ext2: unsupported features {:#x}",
+                incompat & INCOMPAT_UNSUPPORTED
+            );
+            return Err(Error::NotSupported);
+        }
+        if incompat & INCOMPAT_RECOVER != 0 {
+            warn!("ext2: the journal needs recovery, recent changes may be missing");
+        }
+        let ro_compat = u32_at(&sb, 100);
+        let is_64bit = incompat & INCOMPAT_64BIT != 0;
+
+        let log_block_size = u32_at(&sb, 24);
+        if log_block_size > 6 {
+            return Err(Error::Corrupt("bad block size"));
+        }
+        let block_size = 1024u64 << log_block_size;
+        let first_data_block = u32_at(&sb, 20) as u64;
+        let blocks_per_group = u32_at(&sb, 32) as u64;
+        let inodes_per_group = u32_at(&sb, 40);
+        let (inode_size, desc_size) = match u32_at(&sb, 76) {
+            0 => (128, 32),
+            _ => (
+                u16_at(&sb, 88) as u64,
+                if is_64bit {
+                    u16_at(&sb, 254) as u64
+                } else {
+                    32
+                },
+            ),
+        };
+        let (blocks, free_blocks) = if is_64bit {
+            (
+                ((u32_at(&sb, 336) as u64) << 32) | u32_at(&sb, 4) as u64,
+                ((u32_at(&sb, 344) as u64) << 32) | u32_at(&sb, 12) as u64,
+            )
+        } else {
+            (u32_at(&sb, 4) as u64, u32_at(&sb, 12) as u64)
+        };
+        if blocks <= first_data_block
+            || free_blocks > blocks
+            || blocks_per_group == 0
+            || inodes_per_group == 0
+            || inode_size < 128
+            || !inode_size.is_power_of_two()
+            || inode_size > block_size
+            || desc_size < 32
+            || !desc_size.is_power_of_two()
+            || desc_size > block_size
+        {
+            return Err(Error::Corrupt("bad superblock"));
+        }
+        let device_size = device.block_count() * device.block_size() as u64;
+        if blocks
+            .checked_mul(block_size)
+            .is_none_or(|size| size > device_size)
+        {
+            return Err(Error::Corrupt("filesystem is larger than the device"));
+        }
+
+        // every group has a descriptor, which with META_BG are spread out
+        // over the disk rather than all following the superblock
+        let groups = (blocks - first_data_block).div_ceil(blocks_per_group);
+        let per_block = block_size / desc_size;
+        let first_meta_bg = match incompat & INCOMPAT_META_BG {
+            0 => u64::MAX,
+            _ => u32_at(&sb, 260) as u64,
+        };
+        let has_super = |group: u64| {
+            let power_of = |mut n: u64, base: u64| {
+                while n % base == 0 {
+                    n /= base;
+                }
+                n == 1
+            };
+            ro_compat & RO_COMPAT_SPARSE_SUPER == 0
+                || group <= 1
+                || power_of(group, 3)
+                || power_of(group, 5)
+                || power_of(group, 7)
+        };
+
+        let mut inode_tables = Vec::with_capacity(groups as usize);
+        let mut table = vec![0; block_size as usize];
+        let mut table_block = None;
+        for group in 0..groups {
+            let meta_group = group / per_block;
+            let block = if meta_group >= first_meta_bg {
+                let first = meta_group * per_block;
+                first_data_block + first * blocks_per_group + has_super(first) as u64
+            } else {
+                first_data_block + 1 + meta_group
+            };
+            if table_block != Some(block) {
+                block::read_at(&*device, block * block_size, &mut table).await?;
+                table_block = Some(block);
+            }
+            let desc = &table[((group % per_block) * desc_size) as usize..];
+            let mut inode_table = u32_at(desc, 8) as u64;
+            if is_64bit && desc_size >= 64 {
+                inode_table |= (u32_at(desc, 0x28) as u64) << 32;
+            }
+            if inode_table >= blocks {
+                return Err(Error::Corrupt("inode table is outside the filesystem"));
+            }
+            inode_tables.push(inode_table);
+        }
+
+        debug!(
+            "ext2: {blocks} blocks of {block_size} bytes in {groups} groups, features {incompat:#x}"
+        );
+
+        let fs = Arc::new(Self {
+            device,
+            block_size,
+            blocks,
+            free_blocks,
+            inodes: u32_at(&sb, 0),
+            inodes_per_group,
+            inode_size,
+            inode_tables,
+            extents: incompat & INCOMPAT_EXTENTS != 0,
+            filetype: incompat & INCOMPAT_FILETYPE != 0,
+        });
+        if fs.inode(ROOT_INODE).await?.kind() != FileType::Directory {
+            return Err(Error::Corrupt("root is not a directory"));
+        }
+        Ok(fs)
+    }
+
+    async fn inode(&self, ino: u32) -> Result<RawInode, Error> {
+        if ino == 0 || ino > self.inodes {
+            return Err(Error::Corrupt("bad inode number"));
+        }
+        let group = ((ino - 1) / self.inodes_per_group) as usize;
+        let index = ((ino - 1) % self.inodes_per_group) as u64;
+        let table = *self
+            .inode_tables
+            .get(group)
+            .ok_or(Error::Corrupt("bad inode number"))?;
+
+        let mut raw = [0; 128];
+        let offset = table * self.block_size + index * self.inode_size;
+        block::read_at(&*self.device, offset, &mut raw).await?;
+        let flags = u32_at(&raw, 32);
+        if flags & FLAG_EXTENTS != 0 && !self.extents {
+            return Err(Error::Corrupt("extents used without the extents feature"));
+        }
+        Ok(RawInode {
+            ino,
+            mode: u16_at(&raw, 0),
+            size: ((u32_at(&raw, 108) as u64) << 32) | u32_at(&raw, 4) as u64,
+            modified: u32_at(&raw, 16) as u64,
+            sectors: u32_at(&raw, 28) as u64,
+            flags,
+            xattr_block: u32_at(&raw, 104) as u64,
+            block: raw[40..100].try_into().unwrap(),
+        })
+    }
+
+    async fn read_u32(&self, block: u64, index: u64) -> Result<u32, Error> {
+        let mut raw = [0; 4];
+        block::read_at(&*self.device, block * self.block_size + index * 4, &mut raw).await?;
+        Ok(u32::from_le_bytes(raw))
+    }
+
+    /// Where the file's block `logical` is, and how many blocks after it
+    /// follow on disk. `None` for a hole.
+    async fn map(&self, inode: &RawInode, logical: u64) -> Result<Option<(u64, u64)>, Error> {
+        if inode.flags & FLAG_EXTENTS != 0 {
+            return self.map_extent(inode, logical).await;
+        }
+
+        let per_block = self.block_size / 4;
+        let pointer = |i: usize| u32_at(&inode.block, i * 4) as u64;
+        let mut n = logical;
+        if n < DIRECT_BLOCKS {
+            let block = pointer(n as usize);
+            return Ok((block != 0).then_some((block, 1)));
+        }
+        n -= DIRECT_BLOCKS;
+
+        // each level of indirection multiplies what a pointer covers
+        let mut span = 1;
+        for level in 0..3 {
+            span *= per_block;
+            if n >= span {
+                n -= span;
+                continue;
+            }
+            let mut block = pointer(DIRECT_BLOCKS as usize + level);
+            let mut span = span;
+            while span > 1 && block != 0 {
+                span /= per_block;
+                block = self.read_u32(block, n / span).await? as u64;
+                n %= span;
+            }
+            return Ok((block != 0).then_some((block, 1)));
+        }
+        Err(Error::TooLarge)
+    }
+
+    async fn map_extent(
+        &self,
+        inode: &RawInode,
+        logical: u64,
+    ) -> Result<Option<(u64, u64)>, Error> {
+        let mut node = inode.block.to_vec();
+        for _ in 0..=MAX_EXTENT_DEPTH {
+            if u16_at(&node, 0) != EXTENT_MAGIC {
+                return Err(Error::Corrupt("bad extent header"));
+            }
+            let entries = u16_at(&node, 2) as usize;
+            let depth = u16_at(&node, 6);
+            if depth > MAX_EXTENT_DEPTH || 12 + entries * 12 > node.len() {
+                return Err(Error::Corrupt("bad extent header"));
+            }
+
+            // entries are sorted, the one that matters starts at or before
+            // the block
+            let Some(entry) = (0..entries)
+                .map(|i| &node[12 + i * 12..24 + i * 12])
+                .take_while(|entry| u32_at(entry, 0) as u64 <= logical)
+                .last()
+            else {
+                return Ok(None);
+            };
+
+            if depth == 0 {
+                let first = u32_at(entry, 0) as u64;
+                let len = u16_at(entry, 4);
+                let (len, unwritten) = match len > EXTENT_UNWRITTEN {
+                    true => (len - EXTENT_UNWRITTEN, true),
+                    false => (len, false),
+                };
+                let start = ((u16_at(entry, 6) as u64) << 32) | u32_at(entry, 8) as u64;
+                let within = logical - first;
+                if within >= len as u64 || unwritten {
+                    return Ok(None);
+                }
+                return Ok(Some((start + within, len as u64 - within)));
+            }
+
+            let child = ((u16_at(entry, 8) as u64) << 32) | u32_at(entry, 4) as u64;
+            if child >= self.blocks {
+                return Err(Error::Corrupt("extent points outside the filesystem"));
+            }
+            node = vec![0; self.block_size as usize];
+            block::read_at(&*self.device, child * self.block_size, &mut node).await?;
+        }
+        Err(Error::Corrupt("extent tree is too deep"))
+    }
+
+    async fn read(&self, inode: &RawInode, offset: u64, buf: &mut [u8]) -> Result<usize, Error> {
+        if offset >= inode.size {
+            return Ok(0);
+        }
+        let len = buf.len().min((inode.size - offset) as usize);
+
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let logical = pos / self.block_size;
+            let within = pos % self.block_size;
+            let mapped = self.map(inode, logical).await?;
+            let run = mapped.map_or(1, |(_, run)| run);
+            let n = ((run * self.block_size - within) as usize).min(len - done);
+            let out = &mut buf[done..done + n];
+            match mapped {
+                Some((block, _)) => {
+                    if block + run > self.blocks {
+                        return Err(Error::Corrupt("block is outside the filesystem"));
+                    }
+                    block::read_at(&*self.device, block * self.block_size + within, out).await?
+                }
+                None => out.fill(0),
+            }
+            done += n;
+        }
+        Ok(len)
+    }
+
+    /// A block at a time, as entries never cross a block boundary.
+    async fn read_dir(&self, inode: &RawInode) -> Result<Vec<(String, u32, u8)>, Error> {
+        let mut entries = Vec::new();
+        let mut data = vec![0; self.block_size as usize];
+        let mut pos = 0;
+        while pos < inode.size {
+            let len = (inode.size - pos).min(self.block_size) as usize;
+            self.read(inode, pos, &mut data[..len]).await?;
+            self.parse_dir(&data[..len], &mut entries)?;
+            pos += len as u64;
+        }
+        Ok(entries)
+    }
+
+    fn parse_dir(&self, data: &[u8], entries: &mut Vec<(String, u32, u8)>) -> Result<(), Error> {
+        let mut offset = 0;
+        while offset + 8 <= data.len() {
+            let ino = u32_at(data, offset);
+            let rec_len = u16_at(data, offset + 4) as usize;
+            let (name_len, file_type) = match self.filetype {
+                true => (data[offset + 6] as usize, data[offset + 7]),
+                false => (u16_at(data, offset + 6) as usize, 0),
+            };
+            if rec_len < 8 || offset + rec_len > data.len() || 8 + name_len > rec_len {
+                return Err(Error::Corrupt("bad directory entry"));
+            }
+            let name = &data[offset + 8..offset + 8 + name_len];
+            // unused space, checksum tails and hash tree nodes all have
+            // no inode
+            if ino != 0 && name != b"." && name != b".." {
+                entries.push((String::from_utf8_lossy(name).into_owned(), ino, file_type));
+            }
+            offset += rec_len;
+        }
+        Ok(())
+    }
+}
+
+impl super::FileSystem for FileSystem {
+    fn name(&self) -> &'static str {
+        "ext2"
+    }
+
+    fn root(self: Arc<Self>) -> Arc<dyn Inode> {
+        Arc::new(RootInode(self))
+    }
+
+    fn usage(&self) -> BoxFuture<'_, Result<Usage, Error>> {
+        Box::pin(async {
+            Ok(Usage {
+                block_size: self.block_size,
+                blocks: self.blocks,
+                free: self.free_blocks,
+            })
+        })
+    }
+}
+
+#[derive(Clone)]
+struct RawInode {
+    ino: u32,
+    mode: u16,
+    size: u64,
+    modified: u64,
+    /// Space used, in 512 byte sectors.
+    sectors: u64,
+    flags: u32,
+    xattr_block: u64,
+    /// Block pointers, an extent tree, or a short symlink's target.
+    block: [u8; 60],
+}
+
+impl RawInode {
+    fn kind(&self) -> FileType {
+        match self.mode & MODE_TYPE {
+            MODE_DIRECTORY => FileType::Directory,
+            MODE_SYMLINK => FileType::Symlink,
+            _ => FileType::File,
+        }
+    }
+}
+
+/// The root directory. `root` can't wait on the disk, so its inode is read
+/// again for each operation.
+struct RootInode(Arc<FileSystem>);
+
+impl RootInode {
+    async fn get(&self) -> Result<ExtInode, Error> {
+        Ok(ExtInode {
+            inode: self.0.inode(ROOT_INODE).await?,
+            fs: self.0.clone(),
+        })
+    }
+}
+
+impl Inode for RootInode {
+    fn kind(&self) -> FileType {
+        FileType::Directory
+    }
+
+    fn stat(&self) -> BoxFuture<'_, Result<Stat, Error>> {
+        Box::pin(async { self.get().await?.stat().await })
+    }
+
+    fn lookup<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Arc<dyn Inode>, Error>> {
+        Box::pin(async { self.get().await?.lookup(name).await })
+    }
+
+    fn read_dir(&self) -> BoxFuture<'_, Result<Vec<DirEntry>, Error>> {
+        Box::pin(async { self.get().await?.read_dir().await })
+    }
+
+    fn read_at<'a>(&'a self, _: u64, _: &'a mut [u8]) -> BoxFuture<'a, Result<usize, Error>> {
+        Box::pin(async { Err(Error::IsADirectory) })
+    }
+}
+
+struct ExtInode {
+    fs: Arc<FileSystem>,
+    inode: RawInode,
+}
+
+impl Inode for ExtInode {
+    fn kind(&self) -> FileType {
+        self.inode.kind()
+    }
+
+    fn stat(&self) -> BoxFuture<'_, Result<Stat, Error>> {
+        Box::pin(async {
+            Ok(Stat {
+                kind: self.inode.kind(),
+                inode: self.inode.ino as u64,
+                size: self.inode.size,
+                modified: self.inode.modified,
+            })
+        })
+    }
+
+    fn lookup<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Arc<dyn Inode>, Error>> {
+        Box::pin(async move {
+            if self.inode.kind() != FileType::Directory {
+                return Err(Error::NotADirectory);
+            }
+            let (_, ino, _) = self
+                .fs
+                .read_dir(&self.inode)
+                .await?
+                .into_iter()
+                .find(|(entry, _, _)| entry == name)
+                .ok_or(Error::NotFound)?;
+            Ok(Arc::new(ExtInode {
+                fs: self.fs.clone(),
+                inode: self.fs.inode(ino).await?,
+            }) as Arc<dyn Inode>)
+        })
+    }
+
+    fn read_dir(&self) -> BoxFuture<'_, Result<Vec<DirEntry>, Error>> {
+        Box::pin(async {
+            if self.inode.kind() != FileType::Directory {
+                return Err(Error::NotADirectory);
+            }
+            let mut entries = Vec::new();
+            for (name, ino, file_type) in self.fs.read_dir(&self.inode).await? {
+                let kind = match file_type {
+                    1 => FileType::File,
+                    2 => FileType::Directory,
+                    7 => FileType::Symlink,
+                    // devices, fifos and sockets show up as plain files
+                    3..=6 => FileType::File,
+                    _ => self.fs.inode(ino).await?.kind(),
+                };
+                entries.push(DirEntry { name, kind });
+            }
+            Ok(entries)
+        })
+    }
+
+    fn read_at<'a>(
+        &'a self,
+        offset: u64,
+        buf: &'a mut [u8],
+    ) -> BoxFuture<'a, Result<usize, Error>> {
+        Box::pin(async move {
+            if self.inode.kind() == FileType::Directory {
+                return Err(Error::IsADirectory);
+            }
+            self.fs.read(&self.inode, offset, buf).await
+        })
+    }
+
+    fn read_link(&self) -> BoxFuture<'_, Result<String, Error>> {
+        Box::pin(async {
+            let inode = &self.inode;
+            if inode.kind() != FileType::Symlink {
+                return Err(Error::InvalidArgument);
+            }
+            // short targets are kept in the inode itself, where the block
+            // pointers would be, and then use no blocks besides any for
+            // extended attributes
+            let xattr_sectors = match inode.xattr_block {
+                0 => 0,
+                _ => self.fs.block_size / 512,
+            };
+            if inode.size > self.fs.block_size {
+                return Err(Error::Corrupt("symlink target is too long"));
+            }
+            let target = if inode.size < inode.block.len() as u64
+                && inode.sectors <= xattr_sectors
+                && inode.flags & FLAG_EXTENTS == 0
+            {
+                inode.block[..inode.size as usize].to_vec()
+            } else {
+                let mut target = vec![0; inode.size as usize];
+                self.fs.read(inode, 0, &mut target).await?;
+                target
+            };
+            Ok(String::from_utf8_lossy(&target).into_owned())
+        })
+    }
+}
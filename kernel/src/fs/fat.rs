@@ -0,0 +1,1489 @@
+//! FAT12, FAT16 and FAT32, with long file names.
+//!
+//! A [`Node`] remembers where its directory entry is rather than trusting a
+//! copy of it: every operation reads the entry again, so two handles to the
+//! same file never disagree about its size or clusters. Entries never move
+//! once written, directories only ever grow.
+
+use super::{DirEntry, Error, FileType, Inode, Stat, Usage};
+use crate::block::{self, BlockDevice};
+use alloc::sync::Arc;
+use core::ops::Range;
+use futures::future::BoxFuture;
+use maitake::sync::Mutex;
+
+const ENTRY_SIZE: usize = 32;
+
+const ATTR_READ_ONLY: u8 = 0x01;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = 0x0f;
+
+const ENTRY_END: u8 = 0x00;
+const ENTRY_DELETED: u8 = 0xe5;
+/// A short name really starting with 0xe5 is stored with this instead.
+const ENTRY_KANJI_E5: u8 = 0x05;
+
+const LFN_LAST: u8 = 0x40;
+const LFN_SEQUENCE: u8 = 0x1f;
+/// Byte offsets of the 13 UTF-16 units in a long name entry.
+const LFN_OFFSETS: [usize; 13] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+const MAX_NAME: usize = 255;
+
+/// NT case flags in a short entry, so `readme.txt` needs no long name.
+const CASE_LOWER_BASE: u8 = 0x08;
+const CASE_LOWER_EXT: u8 = 0x10;
+
+/// Characters allowed in a short name besides letters and digits.
+const SHORT_SPECIAL: &[u8] = b"$%'-_@~`!(){}^#&";
+const LONG_FORBIDDEN: &[char] = &['"', '*', '/', ':', '<', '>', '?', '\\', '|'];
+
+/// Largest directory the spec allows, in entries.
+const MAX_DIR_ENTRIES: usize = 65536;
+
+const FSINFO_LEAD: u32 = 0x4161_5252;
+const FSINFO_STRUCT: u32 = 0x6141_7272;
+const FSINFO_FREE: u64 = 488;
+const FSINFO_UNKNOWN: u32 = 0xffff_ffff;
+
+/// Most clusters a FAT32 volume can have, higher numbers mark bad clusters
+/// and the ends of chains.
+const MAX_FAT32_CLUSTERS: u64 = 0x0fff_fff5;
+
+/// FAT entries read at a time when walking chains or looking for free space.
+const FAT_WINDOW: u32 = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl FatType {
+    fn end_of_chain(self) -> u32 {
+        match self {
+            Self::Fat12 => 0xfff,
+            Self::Fat16 => 0xffff,
+            Self::Fat32 => 0x0fff_ffff,
+        }
+    }
+
+    /// Values from here up mark a bad cluster or the end of a chain.
+    fn bad(self) -> u32 {
+        self.end_of_chain() - 8
+    }
+
+    /// Bytes of FAT read to get one entry.
+    fn entry_width(self) -> usize {
+        match self {
+            Self::Fat32 => 4,
+            _ => 2,
+        }
+    }
+}
+
+/// A directory, as somewhere entries are stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Dir {
+    /// The fixed root directory region of FAT12 and FAT16.
+    Root,
+    Cluster(u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Location {
+    /// Byte offset of the short entry on the device.
+    offset: u64,
+}
+
+/// A file or directory.
+#[derive(Debug, Clone)]
+pub struct Node {
+    /// `None` for the root directory, which has no entry.
+    location: Option<Location>,
+    name: String,
+    entry: ShortEntry,
+}
+
+impl Node {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Where the node's entry is on the device, which is unique and never
+    /// changes. 0 for the root directory.
+    pub fn id(&self) -> u64 {
+        self.location.map_or(0, |l| l.offset)
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.location.is_none() || self.entry.attr() & ATTR_DIRECTORY != 0
+    }
+
+    /// Size in bytes, as of when this node was looked up.
+    pub fn size(&self) -> u64 {
+        self.entry.size() as u64
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.entry.attr() & ATTR_READ_ONLY != 0
+    }
+
+    /// Last modification, in seconds since the Unix epoch.
+    pub fn modified(&self) -> u64 {
+        self.entry.modified()
+    }
+}
+
+/// The 32 byte entry that holds a file's 8.3 name, attributes, first
+/// cluster and size.
+#[derive(Debug, Clone, Copy)]
+struct ShortEntry([u8; ENTRY_SIZE]);
+
+impl ShortEntry {
+    fn new(name: [u8; 11], case: u8, attr: u8) -> Self {
+        let mut entry = Self([0; ENTRY_SIZE]);
+        entry.0[..11].copy_from_slice(&name);
+        entry.0[11] = attr;
+        entry.0[12] = case;
+        let (date, time) = fat_time();
+        entry.0[14..16].copy_from_slice(&time.to_le_bytes());
+        entry.0[16..18].copy_from_slice(&date.to_le_bytes());
+        entry.0[18..20].copy_from_slice(&date.to_le_bytes());
+        entry.touch();
+        entry
+    }
+
+    fn name(&self) -> [u8; 11] {
+        self.0[..11].try_into().unwrap()
+    }
+
+    fn attr(&self) -> u8 {
+        self.0[11]
+    }
+
+    fn cluster(&self) -> u32 {
+        let hi = u16::from_le_bytes([self.0[20], self.0[21]]) as u32;
+        let lo = u16::from_le_bytes([self.0[26], self.0[27]]) as u32;
+        (hi << 16) | lo
+    }
+
+    fn set_cluster(&mut self, cluster: u32) {
+        self.0[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+        self.0[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+    }
+
+    fn size(&self) -> u32 {
+        u32::from_le_bytes(self.0[28..32].try_into().unwrap())
+    }
+
+    fn set_size(&mut self, size: u32) {
+        self.0[28..32].copy_from_slice(&size.to_le_bytes());
+    }
+
+    fn touch(&mut self) {
+        let (date, time) = fat_time();
+        self.0[22..24].copy_from_slice(&time.to_le_bytes());
+        self.0[24..26].copy_from_slice(&date.to_le_bytes());
+    }
+
+    fn modified(&self) -> u64 {
+        use chrono::TimeZone;
+
+        let time = u16::from_le_bytes([self.0[22], self.0[23]]) as u32;
+        let date = u16::from_le_bytes([self.0[24], self.0[25]]) as u32;
+        chrono::Utc
+            .with_ymd_and_hms(
+                1980 + (date >> 9) as i32,
+                (date >> 5) & 0xf,
+                date & 0x1f,
+                time >> 11,
+                (time >> 5) & 0x3f,
+                (time & 0x1f) * 2,
+            )
+            .single()
+            .map_or(0, |t| t.timestamp() as u64)
+    }
+
+    fn is_dot(&self) -> bool {
+        let name = self.name();
+        name == *b".          " || name == *b"..         "
+    }
+
+    /// The 8.3 name as it would be displayed, `README.TXT`.
+    fn display_name(&self) -> String {
+        let mut name = self.name();
+        if name[0] == ENTRY_KANJI_E5 {
+            name[0] = ENTRY_DELETED;
+        }
+        let case = self.0[12];
+        let part = |bytes: &[u8], lower: bool| {
+            let s = bytes
+                .iter()
+                .map(|&b| b as char)
+                .collect::<String>()
+                .trim_end()
+                .to_owned();
+            if lower {
+                s.to_lowercase()
+            } else {
+                s
+            }
+        };
+        let base = part(&name[..8], case & CASE_LOWER_BASE != 0);
+        let ext = part(&name[8..], case & CASE_LOWER_EXT != 0);
+        if ext.is_empty() {
+            base
+        } else {
+            format!("{base}.{ext}")
+        }
+    }
+}
+
+/// FAT dates and times are local time with 2 second resolution, we have no
+/// time zones so they're UTC.
+fn fat_time() -> (u16, u16) {
+    use chrono::{Datelike, TimeZone, Timelike};
+
+    let now = chrono::Utc
+        .timestamp_opt(crate::arch::timestamp().as_secs() as i64, 0)
+        .unwrap();
+    let year = (now.year().clamp(1980, 2107) - 1980) as u16;
+    let date = (year << 9) | ((now.month() as u16) << 5) | now.day() as u16;
+    let time =
+        ((now.hour() as u16) << 11) | ((now.minute() as u16) << 5) | (now.second() as u16 / 2);
+    (date, time)
+}
+
+fn checksum(name: &[u8; 11]) -> u8 {
+    name.iter()
+        .fold(0u8, |sum, &b| sum.rotate_right(1).wrapping_add(b))
+}
+
+/// A directory's entries, read into memory.
+struct DirData {
+    dir: Dir,
+    bytes: Vec<u8>,
+    /// The clusters `bytes` came from, empty for the fixed root.
+    clusters: Vec<u32>,
+}
+
+/// An entry in a [`DirData`], with its long name if it has one.
+struct Parsed {
+    /// Index of the first slot, the first long name entry if there are any.
+    first: usize,
+    /// Index of the short entry.
+    index: usize,
+    name: String,
+    entry: ShortEntry,
+}
+
+impl Parsed {
+    fn matches(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name) || self.entry.display_name().eq_ignore_ascii_case(name)
+    }
+}
+
+struct LongName {
+    first: usize,
+    /// The sequence number the next entry should have.
+    next: u8,
+    checksum: u8,
+    units: Vec<u16>,
+}
+
+fn parse(data: &DirData) -> Vec<Parsed> {
+    let mut entries = Vec::new();
+    let mut long: Option<LongName> = None;
+    for (index, raw) in data.bytes.chunks_exact(ENTRY_SIZE).enumerate() {
+        match raw[0] {
+            ENTRY_END => break,
+            ENTRY_DELETED => {
+                long = None;
+                continue;
+            }
+            _ => {}
+        }
+
+        if raw[11] & 0x3f == ATTR_LONG_NAME {
+            let sequence = raw[0] & LFN_SEQUENCE;
+            if raw[0] & LFN_LAST != 0 {
+                long = Some(LongName {
+                    first: index,
+                    next: sequence,
+                    checksum: raw[13],
+                    units: vec![0; sequence as usize * LFN_OFFSETS.len()],
+                });
+            }
+            long = long.filter(|l| sequence != 0 && sequence == l.next && raw[13] == l.checksum);
+            if let Some(l) = &mut long {
+                let base = (sequence as usize - 1) * LFN_OFFSETS.len();
+                for (i, &offset) in LFN_OFFSETS.iter().enumerate() {
+                    l.units[base + i] = u16::from_le_bytes([raw[offset], raw[offset + 1]]);
+                }
+                l.next -= 1;
+            }
+            continue;
+        }
+
+        let entry = ShortEntry(raw.try_into().unwrap());
+        let long = long
+            .take()
+            .filter(|l| l.next == 0 && l.checksum == checksum(&entry.name()));
+        if entry.attr() & ATTR_VOLUME_ID != 0 || entry.is_dot() {
+            continue;
+        }
+        let (first, name) = match long {
+            Some(l) => {
+                let units = l.units.into_iter().take_while(|&u| u != 0 && u != 0xffff);
+                let name = char::decode_utf16(units)
+                    .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                    .collect();
+                (l.first, name)
+            }
+            None => (index, entry.display_name()),
+        };
+        entries.push(Parsed {
+            first,
+            index,
+            name,
+            entry,
+        });
+    }
+    entries
+}
+
+fn validate_name(name: &str) -> Result<(), Error> {
+    if name.is_empty()
+        || name == "."
+        || name == ".."
+        || name.encode_utf16().count() > MAX_NAME
+        || name.chars().any(|c| c < ' ' || LONG_FORBIDDEN.contains(&c))
+    {
+        return Err(Error::InvalidName);
+    }
+    Ok(())
+}
+
+fn is_short_char(b: u8) -> bool {
+    b.is_ascii_uppercase() || b.is_ascii_digit() || SHORT_SPECIAL.contains(&b)
+}
+
+/// `name` as an 8.3 name with its case flags, if it can be stored as one
+/// exactly, without a long name.
+fn exact_short_name(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, ext) = name.split_once('.').unwrap_or((name, ""));
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 || ext.contains('.') {
+        return None;
+    }
+
+    let mut case = 0;
+    let mut short = [b' '; 11];
+    for (part, out, flag) in [
+        (base, &mut short[..8], CASE_LOWER_BASE),
+        (ext, &mut short[8..], CASE_LOWER_EXT),
+    ] {
+        let lower = part.bytes().any(|b| b.is_ascii_lowercase());
+        let upper = part.bytes().any(|b| b.is_ascii_uppercase());
+        if lower && upper {
+            return None;
+        }
+        if lower {
+            case |= flag;
+        }
+        for (o, b) in out.iter_mut().zip(part.bytes()) {
+            let b = b.to_ascii_uppercase();
+            if !is_short_char(b) {
+                return None;
+            }
+            *o = b;
+        }
+    }
+    if short[0] == ENTRY_DELETED {
+        short[0] = ENTRY_KANJI_E5;
+    }
+    Some((short, case))
+}
+
+/// A `BASIS~N.EXT` alias for a name that needs a long entry, unique among
+/// `existing`.
+fn alias_short_name(name: &str, existing: &[Parsed]) -> Result<[u8; 11], Error> {
+    let clean = |s: &str| {
+        s.chars()
+            .filter(|&c| c != ' ' && c != '.')
+            .map(|c| {
+                let b = c.to_ascii_uppercase();
+                if b.is_ascii() && is_short_char(b as u8) {
+                    b as u8
+                } else {
+                    b'_'
+                }
+            })
+            .collect::<Vec<_>>()
+    };
+    let (base, ext) = match name.rsplit_once('.') {
+        Some((base, ext)) if !base.trim_start_matches('.').is_empty() => (clean(base), clean(ext)),
+        _ => (clean(name), vec![]),
+    };
+
+    let mut short = [b' '; 11];
+    for (o, &b) in short[8..].iter_mut().zip(ext.iter()) {
+        *o = b;
+    }
+    for n in 1..1_000_000u32 {
+        let tail = format!("~{n}");
+        let keep = base.len().min(8 - tail.len());
+        let mut candidate = short;
+        candidate[..keep].copy_from_slice(&base[..keep]);
+        candidate[keep..keep + tail.len()].copy_from_slice(tail.as_bytes());
+        candidate[keep + tail.len()..8].fill(b' ');
+        if candidate[0] == ENTRY_DELETED {
+            candidate[0] = ENTRY_KANJI_E5;
+        }
+        if !existing.iter().any(|p| p.entry.name() == candidate) {
+            return Ok(candidate);
+        }
+    }
+    Err(Error::Exists)
+}
+
+/// The long name entries for `name`, in the order they're stored.
+fn long_entries(name: &str, checksum: u8) -> Vec<[u8; ENTRY_SIZE]> {
+    let mut units = name.encode_utf16().collect::<Vec<_>>();
+    let count = units.len().div_ceil(LFN_OFFSETS.len());
+    if units.len() % LFN_OFFSETS.len() != 0 {
+        units.push(0);
+    }
+    units.resize(count * LFN_OFFSETS.len(), 0xffff);
+
+    (0..count)
+        .rev()
+        .map(|i| {
+            let mut entry = [0; ENTRY_SIZE];
+            entry[0] = (i + 1) as u8 | if i == count - 1 { LFN_LAST } else { 0 };
+            entry[11] = ATTR_LONG_NAME;
+            entry[13] = checksum;
+            for (j, &offset) in LFN_OFFSETS.iter().enumerate() {
+                let unit = units[i * LFN_OFFSETS.len() + j];
+                entry[offset..offset + 2].copy_from_slice(&unit.to_le_bytes());
+            }
+            entry
+        })
+        .collect()
+}
+
+struct State {
+    /// Free cluster count, if known.
+    free: Option<u32>,
+    /// Where to start looking for a free cluster.
+    next_free: u32,
+    /// Last cluster a file read or write got to.
+    cursor: Option<Cursor>,
+}
+
+/// A position in a cluster chain, so the next access to the same file can
+/// carry on from there instead of walking the chain from its start.
+#[derive(Clone, Copy)]
+struct Cursor {
+    first: u32,
+    index: usize,
+    cluster: u32,
+}
+
+pub struct FileSystem {
+    device: Arc<dyn BlockDevice>,
+    fat_type: FatType,
+    cluster_size: usize,
+    cluster_count: u32,
+    /// Byte offsets and sizes of the FAT region, and of the data region.
+    fat_offset: u64,
+    fat_size: u64,
+    fat_count: u64,
+    data_offset: u64,
+    /// The fixed root directory of FAT12 and FAT16.
+    root_offset: u64,
+    root_entries: usize,
+    /// The root directory's first cluster on FAT32.
+    root_cluster: u32,
+    fsinfo_offset: Option<u64>,
+    /// Held by every operation, FAT updates aren't atomic.
+    state: Mutex<State>,
+}
+
+fn u16_at(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+impl FileSystem {
+    pub async fn mount(device: Arc<dyn BlockDevice>) -> Result<Arc<Self>, Error> {
+        let mut boot = [0; 512];
+        block::read_at(&*device, 0, &mut boot).await?;
+        if boot[510..512] != [0x55, 0xaa] {
+            return Err(Error::UnknownFilesystem);
+        }
+
+        let bytes_per_sector = u16_at(&boot, 11) as u64;
+        let sectors_per_cluster = boot[13] as u64;
+        let reserved_sectors = u16_at(&boot, 14) as u64;
+        let fat_count = boot[16] as u64;
+        let root_entries = u16_at(&boot, 17) as usize;
+        let total_sectors = match u16_at(&boot, 19) {
+            0 => u32_at(&boot, 32) as u64,
+            n => n as u64,
+        };
+        let fat_sectors = match u16_at(&boot, 22) {
+            0 => u32_at(&boot, 36) as u64,
+            n => n as u64,
+        };
+        if ![512, 1024, 2048, 4096].contains(&bytes_per_sector)
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved_sectors == 0
+            || fat_count == 0
+            || fat_sectors == 0
+        {
+            return Err(Error::UnknownFilesystem);
+        }
+
+        let root_sectors = (root_entries * ENTRY_SIZE).div_ceil(bytes_per_sector as usize) as u64;
+        let data_sector = reserved_sectors + fat_count * fat_sectors + root_sectors;
+        let cluster_count = total_sectors
+            .checked_sub(data_sector)
+            .ok_or(Error::Corrupt("data region is past the end of the volume"))?
+            / sectors_per_cluster;
+        let fat_type = match cluster_count {
+            0..4085 => FatType::Fat12,
+            4085..65525 => FatType::Fat16,
+            _ => FatType::Fat32,
+        };
+        if total_sectors * bytes_per_sector > device.block_count() * device.block_size() as u64 {
+            return Err(Error::Corrupt("volume is larger than the device"));
+        }
+
+        // the type goes by the count above, but clusters the FAT has no
+        // entry for can't be used
+        let fat_bytes = fat_sectors * bytes_per_sector;
+        let fat_entries = match fat_type {
+            FatType::Fat12 => fat_bytes * 2 / 3,
+            FatType::Fat16 => fat_bytes / 2,
+            FatType::Fat32 => fat_bytes / 4,
+        };
+        let cluster_count = cluster_count
+            .min(fat_entries.saturating_sub(2))
+            .min(MAX_FAT32_CLUSTERS);
+
+        let (root_cluster, fsinfo_sector) = match fat_type {
+            FatType::Fat32 => (u32_at(&boot, 44), u16_at(&boot, 48) as u64),
+            _ => (0, 0),
+        };
+
+        let mut fs = Self {
+            device,
+            fat_type,
+            cluster_size: (sectors_per_cluster * bytes_per_sector) as usize,
+            cluster_count: cluster_count as u32,
+            fat_offset: reserved_sectors * bytes_per_sector,
+            fat_size: fat_sectors * bytes_per_sector,
+            fat_count,
+            data_offset: data_sector * bytes_per_sector,
+            root_offset: (reserved_sectors + fat_count * fat_sectors) * bytes_per_sector,
+            root_entries,
+            root_cluster,
+            fsinfo_offset: None,
+            state: Mutex::new(State {
+                free: None,
+                next_free: 2,
+                cursor: None,
+            }),
+        };
+        if fat_type == FatType::Fat32 && !fs.is_cluster(root_cluster) {
+            return Err(Error::Corrupt("bad root cluster"));
+        }
+
+        // FSInfo only holds hints, so a missing or damaged one is no problem
+        if fsinfo_sector != 0 && fsinfo_sector != 0xffff {
+            let offset = fsinfo_sector * bytes_per_sector;
+            let mut fsinfo = [0; 512];
+            block::read_at(&*fs.device, offset, &mut fsinfo).await?;
+            if u32_at(&fsinfo, 0) == FSINFO_LEAD && u32_at(&fsinfo, 484) == FSINFO_STRUCT {
+                let state = fs.state.get_mut();
+                let free = u32_at(&fsinfo, FSINFO_FREE as usize);
+                if free <= fs.cluster_count {
+                    state.free = Some(free);
+                }
+                let next = u32_at(&fsinfo, FSINFO_FREE as usize + 4);
+                if fs.is_cluster(next) {
+                    state.next_free = next;
+                }
+                fs.fsinfo_offset = Some(offset);
+            }
+        }
+
+        debug!(
+            "fat: {:?}, {} clusters of {} bytes",
+            fs.fat_type, fs.cluster_count, fs.cluster_size
+        );
+
+        Ok(Arc::new(fs))
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.fat_type
+    }
+
+    pub fn cluster_size(&self) -> usize {
+        self.cluster_size
+    }
+
+    pub fn cluster_count(&self) -> u32 {
+        self.cluster_count
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.device.read_only()
+    }
+
+    pub fn root(&self) -> Node {
+        let mut entry = ShortEntry([0; ENTRY_SIZE]);
+        entry.0[11] = ATTR_DIRECTORY;
+        entry.set_cluster(self.root_cluster);
+        Node {
+            location: None,
+            name: String::new(),
+            entry,
+        }
+    }
+
+    /// Free clusters, counting them the first time if FSInfo didn't say.
+    pub async fn free_clusters(&self) -> Result<u32, Error> {
+        let mut state = self.state.lock().await;
+        if let Some(free) = state.free {
+            return Ok(free);
+        }
+        let mut free = 0;
+        let mut cluster = 2;
+        while cluster < self.cluster_count + 2 {
+            let count = FAT_WINDOW.min(self.cluster_count + 2 - cluster);
+            let entries = self.fat_range(cluster, count).await?;
+            free += entries.iter().filter(|&&e| e == 0).count() as u32;
+            cluster += count;
+        }
+        state.free = Some(free);
+        Ok(free)
+    }
+
+    /// Write out the FSInfo hints and flush the device.
+    pub async fn sync(&self) -> Result<(), Error> {
+        let state = self.state.lock().await;
+        self.write_fsinfo(&state).await?;
+        self.device.flush().await?;
+        Ok(())
+    }
+
+    /// A fresh copy of `node`, with its current size and clusters.
+    pub async fn refresh(&self, node: &Node) -> Result<Node, Error> {
+        let _state = self.state.lock().await;
+        let Some(location) = node.location else {
+            return Ok(node.clone());
+        };
+        Ok(Node {
+            location: node.location,
+            name: node.name.clone(),
+            entry: self.load_entry(location).await?,
+        })
+    }
+
+    pub async fn lookup(&self, dir: &Node, name: &str) -> Result<Node, Error> {
+        let _state = self.state.lock().await;
+        let data = self.load_dir(self.dir_of(dir).await?).await?;
+        let parsed = parse(&data)
+            .into_iter()
+            .find(|p| p.matches(name))
+            .ok_or(Error::NotFound)?;
+        Ok(self.node(&data, parsed))
+    }
+
+    pub async fn read_dir(&self, dir: &Node) -> Result<Vec<Node>, Error> {
+        let _state = self.state.lock().await;
+        let data = self.load_dir(self.dir_of(dir).await?).await?;
+        Ok(parse(&data)
+            .into_iter()
+            .map(|p| self.node(&data, p))
+            .collect())
+    }
+
+    /// Read from `offset` into `buf`, returning how much was read, 0 at the
+    /// end of the file.
+    pub async fn read(&self, file: &Node, offset: u64, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut state = self.state.lock().await;
+        let (_, entry) = self.file_entry(file).await?;
+        let size = entry.size() as u64;
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = buf.len().min((size - offset) as usize);
+        let cluster_size = self.cluster_size as u64;
+        let skip = (offset / cluster_size) as usize;
+        let count = (offset + len as u64).div_ceil(cluster_size) as usize - skip;
+        let part = self
+            .chain_part(&mut state, entry.cluster(), skip, count)
+            .await?;
+        let base = skip as u64 * cluster_size;
+        self.read_clusters(&part, offset - base, &mut buf[..len])
+            .await?;
+        Ok(len)
+    }
+
+    /// Write `data` at `offset`, growing the file if needed. Any gap between
+    /// the old end of the file and `offset` reads back as zeros.
+    pub async fn write(&self, file: &Node, offset: u64, data: &[u8]) -> Result<usize, Error> {
+        let mut state = self.state.lock().await;
+        self.write_locked(&mut state, file, Some(offset), data)
+            .await
+    }
+
+    pub async fn append(&self, file: &Node, data: &[u8]) -> Result<usize, Error> {
+        let mut state = self.state.lock().await;
+        self.write_locked(&mut state, file, None, data).await
+    }
+
+    /// Set the file's length, freeing clusters past it or zero filling up to
+    /// it.
+    pub async fn truncate(&self, file: &Node, len: u64) -> Result<(), Error> {
+        self.writable()?;
+        let len = u32::try_from(len).map_err(|_| Error::TooLarge)?;
+        let mut state = self.state.lock().await;
+        let (location, mut entry) = self.file_entry(file).await?;
+        let size = entry.size();
+
+        let mut chain = self.chain(entry.cluster()).await?;
+        if len > size {
+            self.grow(&mut state, &mut chain, len as u64).await?;
+            entry.set_cluster(chain[0]);
+            self.zero_clusters(&chain, size as u64..len as u64).await?;
+        } else {
+            let keep = (len as usize).div_ceil(self.cluster_size);
+            if keep < chain.len() {
+                match keep {
+                    0 => entry.set_cluster(0),
+                    _ => {
+                        self.set_fat(chain[keep - 1], self.fat_type.end_of_chain())
+                            .await?
+                    }
+                }
+                self.free(&mut state, &chain[keep..]).await?;
+            }
+        }
+
+        entry.set_size(len);
+        entry.touch();
+        self.store_entry(location, &entry).await?;
+        self.write_fsinfo(&state).await
+    }
+
+    /// Create an empty file or directory called `name` in `dir`.
+    pub async fn create(&self, dir: &Node, name: &str, directory: bool) -> Result<Node, Error> {
+        self.writable()?;
+        validate_name(name)?;
+        let mut state = self.state.lock().await;
+        let dir_id = self.dir_of(dir).await?;
+        let mut data = self.load_dir(dir_id).await?;
+        let parsed = parse(&data);
+        if parsed.iter().any(|p| p.matches(name)) {
+            return Err(Error::Exists);
+        }
+
+        let (short, case, long) = match exact_short_name(name) {
+            Some((short, case)) => (short, case, vec![]),
+            None => {
+                let short = alias_short_name(name, &parsed)?;
+                (short, 0, long_entries(name, checksum(&short)))
+            }
+        };
+        let attr = if directory {
+            ATTR_DIRECTORY
+        } else {
+            ATTR_ARCHIVE
+        };
+        let mut entry = ShortEntry::new(short, case, attr);
+
+        if directory {
+            let cluster = self.alloc(&mut state, None, true).await?;
+            entry.set_cluster(cluster);
+            let parent = match dir_id {
+                Dir::Cluster(c) if c != self.root_cluster => c,
+                _ => 0,
+            };
+            let mut dot = ShortEntry::new(*b".          ", 0, ATTR_DIRECTORY);
+            dot.set_cluster(cluster);
+            let mut dotdot = ShortEntry::new(*b"..         ", 0, ATTR_DIRECTORY);
+            dotdot.set_cluster(parent);
+            let mut dots = [0; 2 * ENTRY_SIZE];
+            dots[..ENTRY_SIZE].copy_from_slice(&dot.0);
+            dots[ENTRY_SIZE..].copy_from_slice(&dotdot.0);
+            block::write_at(&*self.device, self.cluster_offset(cluster), &dots).await?;
+        }
+
+        let slots = long.len() + 1;
+        let index = loop {
+            if let Some(index) = free_run(&data, slots) {
+                break index;
+            }
+            if let Err(e) = self.extend_dir(&mut state, &mut data).await {
+                if directory {
+                    self.free(&mut state, &[entry.cluster()]).await?;
+                }
+                return Err(e);
+            }
+        };
+        for (i, raw) in long.iter().chain([&entry.0]).enumerate() {
+            data.bytes[(index + i) * ENTRY_SIZE..][..ENTRY_SIZE].copy_from_slice(raw);
+        }
+        self.store_entries(&data, index..index + slots).await?;
+        self.write_fsinfo(&state).await?;
+
+        Ok(Node {
+            location: Some(Location {
+                offset: self.entry_offset(&data, index + slots - 1),
+            }),
+            name: name.to_owned(),
+            entry,
+        })
+    }
+
+    /// Delete the file or empty directory called `name` in `dir`.
+    pub async fn remove(&self, dir: &Node, name: &str) -> Result<(), Error> {
+        self.writable()?;
+        let mut state = self.state.lock().await;
+        let mut data = self.load_dir(self.dir_of(dir).await?).await?;
+        let parsed = parse(&data)
+            .into_iter()
+            .find(|p| p.matches(name))
+            .ok_or(Error::NotFound)?;
+
+        let cluster = parsed.entry.cluster();
+        if parsed.entry.attr() & ATTR_DIRECTORY != 0 {
+            let children = self.load_dir(Dir::Cluster(cluster)).await?;
+            if !parse(&children).is_empty() {
+                return Err(Error::NotEmpty);
+            }
+        }
+
+        for index in parsed.first..=parsed.index {
+            data.bytes[index * ENTRY_SIZE] = ENTRY_DELETED;
+        }
+        self.store_entries(&data, parsed.first..parsed.index + 1)
+            .await?;
+        if cluster != 0 {
+            let chain = self.chain(cluster).await?;
+            self.free(&mut state, &chain).await?;
+        }
+        self.write_fsinfo(&state).await
+    }
+
+    fn writable(&self) -> Result<(), Error> {
+        if self.read_only() {
+            return Err(Error::ReadOnly);
+        }
+        Ok(())
+    }
+
+    fn is_cluster(&self, cluster: u32) -> bool {
+        (2..self.cluster_count + 2).contains(&cluster)
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.data_offset + (cluster - 2) as u64 * self.cluster_size as u64
+    }
+
+    /// Byte offset of `cluster`'s entry within a FAT.
+    fn fat_entry_offset(&self, cluster: u32) -> u64 {
+        let cluster = cluster as u64;
+        match self.fat_type {
+            FatType::Fat12 => cluster + cluster / 2,
+            FatType::Fat16 => cluster * 2,
+            FatType::Fat32 => cluster * 4,
+        }
+    }
+
+    fn decode_fat(&self, cluster: u32, bytes: &[u8]) -> u32 {
+        match self.fat_type {
+            FatType::Fat12 => {
+                let value = u16_at(bytes, 0) as u32;
+                if cluster & 1 == 1 {
+                    value >> 4
+                } else {
+                    value & 0xfff
+                }
+            }
+            FatType::Fat16 => u16_at(bytes, 0) as u32,
+            FatType::Fat32 => u32_at(bytes, 0) & 0x0fff_ffff,
+        }
+    }
+
+    /// `count` FAT entries starting at `first`.
+    async fn fat_range(&self, first: u32, count: u32) -> Result<Vec<u32>, Error> {
+        let start = self.fat_entry_offset(first);
+        let end = self.fat_entry_offset(first + count - 1) + self.fat_type.entry_width() as u64;
+        let mut bytes = vec![0; (end - start) as usize];
+        block::read_at(&*self.device, self.fat_offset + start, &mut bytes).await?;
+        Ok((first..first + count)
+            .map(|c| {
+                let offset = (self.fat_entry_offset(c) - start) as usize;
+                self.decode_fat(c, &bytes[offset..])
+            })
+            .collect())
+    }
+
+    /// Set `cluster`'s entry in every copy of the FAT.
+    async fn set_fat(&self, cluster: u32, value: u32) -> Result<(), Error> {
+        let width = self.fat_type.entry_width();
+        for copy in 0..self.fat_count {
+            let offset = self.fat_offset + copy * self.fat_size + self.fat_entry_offset(cluster);
+            let mut bytes = [0; 4];
+            let bytes = &mut bytes[..width];
+            match self.fat_type {
+                FatType::Fat12 => {
+                    block::read_at(&*self.device, offset, bytes).await?;
+                    let old = u16_at(bytes, 0);
+                    let new = if cluster & 1 == 1 {
+                        (old & 0x000f) | ((value as u16) << 4)
+                    } else {
+                        (old & 0xf000) | (value as u16 & 0xfff)
+                    };
+                    bytes.copy_from_slice(&new.to_le_bytes());
+                }
+                FatType::Fat16 => bytes.copy_from_slice(&(value as u16).to_le_bytes()),
+                FatType::Fat32 => {
+                    // the top 4 bits are reserved and must be kept
+                    block::read_at(&*self.device, offset, bytes).await?;
+                    let new = (u32_at(bytes, 0) & 0xf000_0000) | (value & 0x0fff_ffff);
+                    bytes.copy_from_slice(&new.to_le_bytes());
+                }
+            }
+            block::write_at(&*self.device, offset, bytes).await?;
+        }
+        Ok(())
+    }
+
+    /// Every cluster of the chain starting at `first`, empty if it's 0.
+    async fn chain(&self, first: u32) -> Result<Vec<u32>, Error> {
+        let mut chain = Vec::new();
+        let mut window = (0, Vec::new());
+        let mut cluster = first;
+        while cluster != 0 {
+            self.check_link(cluster, chain.len())?;
+            chain.push(cluster);
+            cluster = self.next_cluster(&mut window, cluster).await?;
+        }
+        Ok(chain)
+    }
+
+    /// Clusters `skip..skip + count` of the chain starting at `first`, fewer
+    /// if the chain ends before that. Walks from the state's cursor when it
+    /// is on this chain and not past `skip`, and leaves it at the last
+    /// cluster returned.
+    async fn chain_part(
+        &self,
+        state: &mut State,
+        first: u32,
+        skip: usize,
+        count: usize,
+    ) -> Result<Vec<u32>, Error> {
+        if count == 0 {
+            return Ok(vec![]);
+        }
+        let (mut index, mut cluster) = match state.cursor {
+            Some(c) if c.first == first && c.index <= skip => (c.index, c.cluster),
+            _ => (0, first),
+        };
+        let mut part = Vec::with_capacity(count);
+        let mut window = (0, Vec::new());
+        while cluster != 0 {
+            self.check_link(cluster, index)?;
+            if index >= skip {
+                part.push(cluster);
+                state.cursor = Some(Cursor {
+                    first,
+                    index,
+                    cluster,
+                });
+                if part.len() == count {
+                    break;
+                }
+            }
+            cluster = self.next_cluster(&mut window, cluster).await?;
+            index += 1;
+        }
+        Ok(part)
+    }
+
+    /// Check `cluster` can be the `index`th link of a chain.
+    fn check_link(&self, cluster: u32, index: usize) -> Result<(), Error> {
+        if !self.is_cluster(cluster) {
+            return Err(Error::Corrupt("cluster chain points outside the volume"));
+        }
+        if index >= self.cluster_count as usize {
+            return Err(Error::Corrupt("cluster chain loops"));
+        }
+        Ok(())
+    }
+
+    /// The cluster after `cluster`, or 0 at the end of the chain. `window`
+    /// holds the FAT entries read last, as (first cluster, entries).
+    async fn next_cluster(&self, window: &mut (u32, Vec<u32>), cluster: u32) -> Result<u32, Error> {
+        let (start, entries) = window;
+        if !(*start..*start + entries.len() as u32).contains(&cluster) {
+            *start = cluster - cluster % FAT_WINDOW;
+            let count = FAT_WINDOW.min(self.cluster_count + 2 - *start);
+            *entries = self.fat_range(*start, count).await?;
+        }
+        match entries[(cluster - *start) as usize] {
+            next if next >= self.fat_type.bad() => Ok(0),
+            0 => Err(Error::Corrupt("cluster chain runs into a free cluster")),
+            next => Ok(next),
+        }
+    }
+
+    /// Claim a free cluster, linking it after `prev`.
+    async fn alloc(&self, state: &mut State, prev: Option<u32>, zero: bool) -> Result<u32, Error> {
+        if state.free == Some(0) {
+            return Err(Error::NoSpace);
+        }
+
+        let end = self.cluster_count + 2;
+        let mut cluster = if self.is_cluster(state.next_free) {
+            state.next_free
+        } else {
+            2
+        };
+        let mut scanned = 0;
+        while scanned < self.cluster_count {
+            let count = FAT_WINDOW.min(end - cluster);
+            let entries = self.fat_range(cluster, count).await?;
+            if let Some(i) = entries.iter().position(|&e| e == 0) {
+                let found = cluster + i as u32;
+                if zero {
+                    let zeros = vec![0; self.cluster_size];
+                    block::write_at(&*self.device, self.cluster_offset(found), &zeros).await?;
+                }
+                self.set_fat(found, self.fat_type.end_of_chain()).await?;
+                if let Some(prev) = prev {
+                    self.set_fat(prev, found).await?;
+                }
+                state.next_free = found + 1;
+                state.free = state.free.map(|n| n.saturating_sub(1));
+                return Ok(found);
+            }
+            scanned += count;
+            cluster += count;
+            if cluster == end {
+                cluster = 2;
+            }
+        }
+
+        state.free = Some(0);
+        Err(Error::NoSpace)
+    }
+
+    async fn free(&self, state: &mut State, clusters: &[u32]) -> Result<(), Error> {
+        // it may point into what is being freed
+        state.cursor = None;
+        for &cluster in clusters {
+            self.set_fat(cluster, 0).await?;
+            state.free = state.free.map(|n| n + 1);
+        }
+        if let Some(&first) = clusters.first() {
+            state.next_free = state.next_free.min(first);
+        }
+        Ok(())
+    }
+
+    /// Extend `chain` until it holds `len` bytes. If space runs out, the
+    /// clusters added so far are given back.
+    async fn grow(&self, state: &mut State, chain: &mut Vec<u32>, len: u64) -> Result<(), Error> {
+        let needed = len.div_ceil(self.cluster_size as u64) as usize;
+        let had = chain.len();
+        while chain.len() < needed {
+            match self.alloc(state, chain.last().copied(), false).await {
+                Ok(cluster) => chain.push(cluster),
+                Err(e) => {
+                    if had > 0 && chain.len() > had {
+                        self.set_fat(chain[had - 1], self.fat_type.end_of_chain())
+                            .await?;
+                    }
+                    self.free(state, &chain[had..]).await?;
+                    chain.truncate(had);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Split the byte range `range` of a chain into device transfers, as
+    /// (device offset, offset into the range, length).
+    fn pieces(&self, chain: &[u32], range: Range<u64>) -> Result<Vec<(u64, usize, usize)>, Error> {
+        let cluster_size = self.cluster_size as u64;
+        let mut pieces = Vec::new();
+        let mut pos = range.start;
+        while pos < range.end {
+            let cluster = *chain
+                .get((pos / cluster_size) as usize)
+                .ok_or(Error::Corrupt("cluster chain is shorter than the file"))?;
+            let within = pos % cluster_size;
+            let len = (cluster_size - within).min(range.end - pos);
+            let offset = self.cluster_offset(cluster) + within;
+            // clusters next to each other on disk become one transfer
+            match pieces.last_mut() {
+                Some((last, _, last_len)) if *last + *last_len as u64 == offset => {
+                    *last_len += len as usize
+                }
+                _ => pieces.push((offset, (pos - range.start) as usize, len as usize)),
+            }
+            pos += len;
+        }
+        Ok(pieces)
+    }
+
+    async fn read_clusters(&self, chain: &[u32], offset: u64, buf: &mut [u8]) -> Result<(), Error> {
+        for (at, start, len) in self.pieces(chain, offset..offset + buf.len() as u64)? {
+            block::read_at(&*self.device, at, &mut buf[start..start + len]).await?;
+        }
+        Ok(())
+    }
+
+    async fn write_clusters(&self, chain: &[u32], offset: u64, buf: &[u8]) -> Result<(), Error> {
+        for (at, start, len) in self.pieces(chain, offset..offset + buf.len() as u64)? {
+            block::write_at(&*self.device, at, &buf[start..start + len]).await?;
+        }
+        Ok(())
+    }
+
+    async fn zero_clusters(&self, chain: &[u32], range: Range<u64>) -> Result<(), Error> {
+        let zeros = vec![0; self.cluster_size];
+        let mut pos = range.start;
+        while pos < range.end {
+            let len = (range.end - pos).min(self.cluster_size as u64);
+            self.write_clusters(chain, pos, &zeros[..len as usize])
+                .await?;
+            pos += len;
+        }
+        Ok(())
+    }
+
+    async fn write_locked(
+        &self,
+        state: &mut State,
+        file: &Node,
+        offset: Option<u64>,
+        data: &[u8],
+    ) -> Result<usize, Error> {
+        self.writable()?;
+        let (location, mut entry) = self.file_entry(file).await?;
+        let size = entry.size() as u64;
+        let offset = offset.unwrap_or(size);
+        if data.is_empty() {
+            return Ok(0);
+        }
+        let end = offset
+            .checked_add(data.len() as u64)
+            .filter(|&end| end <= u32::MAX as u64)
+            .ok_or(Error::TooLarge)?;
+
+        // from the cluster before the first one touched, so that when the
+        // chain has to grow, the cluster to link after is known
+        let cluster_size = self.cluster_size as u64;
+        let from = (offset.min(size) / cluster_size).saturating_sub(1) as usize;
+        let count = end.div_ceil(cluster_size) as usize - from;
+        let mut part = self.chain_part(state, entry.cluster(), from, count).await?;
+        if part.is_empty() && entry.cluster() != 0 {
+            return Err(Error::Corrupt("cluster chain is shorter than the file"));
+        }
+        let base = from as u64 * cluster_size;
+        self.grow(state, &mut part, end - base).await?;
+        if entry.cluster() == 0 {
+            entry.set_cluster(part[0]);
+        }
+        if offset > size {
+            self.zero_clusters(&part, size - base..offset - base)
+                .await?;
+        }
+        self.write_clusters(&part, offset - base, data).await?;
+
+        entry.set_size(size.max(end) as u32);
+        entry.touch();
+        self.store_entry(location, &entry).await?;
+        self.write_fsinfo(state).await?;
+        Ok(data.len())
+    }
+
+    async fn write_fsinfo(&self, state: &State) -> Result<(), Error> {
+        let Some(offset) = self.fsinfo_offset else {
+            return Ok(());
+        };
+        let mut hints = [0; 8];
+        hints[..4].copy_from_slice(&state.free.unwrap_or(FSINFO_UNKNOWN).to_le_bytes());
+        hints[4..].copy_from_slice(&state.next_free.to_le_bytes());
+        block::write_at(&*self.device, offset + FSINFO_FREE, &hints).await?;
+        Ok(())
+    }
+
+    async fn dir_of(&self, node: &Node) -> Result<Dir, Error> {
+        if node.location.is_none() {
+            return Ok(match self.fat_type {
+                FatType::Fat32 => Dir::Cluster(self.root_cluster),
+                _ => Dir::Root,
+            });
+        }
+        let entry = self.load_entry(node.location.unwrap()).await?;
+        if entry.attr() & ATTR_DIRECTORY == 0 {
+            return Err(Error::NotADirectory);
+        }
+        Ok(Dir::Cluster(entry.cluster()))
+    }
+
+    /// The current entry of a regular file.
+    async fn file_entry(&self, node: &Node) -> Result<(Location, ShortEntry), Error> {
+        let location = node.location.ok_or(Error::IsADirectory)?;
+        let entry = self.load_entry(location).await?;
+        if entry.attr() & ATTR_DIRECTORY != 0 {
+            return Err(Error::IsADirectory);
+        }
+        Ok((location, entry))
+    }
+
+    async fn load_entry(&self, location: Location) -> Result<ShortEntry, Error> {
+        let mut entry = ShortEntry([0; ENTRY_SIZE]);
+        block::read_at(&*self.device, location.offset, &mut entry.0).await?;
+        if matches!(entry.0[0], ENTRY_END | ENTRY_DELETED) {
+            // it was removed since the node was looked up
+            return Err(Error::NotFound);
+        }
+        Ok(entry)
+    }
+
+    async fn store_entry(&self, location: Location, entry: &ShortEntry) -> Result<(), Error> {
+        block::write_at(&*self.device, location.offset, &entry.0).await?;
+        Ok(())
+    }
+
+    async fn load_dir(&self, dir: Dir) -> Result<DirData, Error> {
+        match dir {
+            Dir::Root => {
+                let mut bytes = vec![0; self.root_entries * ENTRY_SIZE];
+                block::read_at(&*self.device, self.root_offset, &mut bytes).await?;
+                Ok(DirData {
+                    dir,
+                    bytes,
+                    clusters: vec![],
+                })
+            }
+            Dir::Cluster(first) => {
+                let clusters = self.chain(first).await?;
+                let mut bytes = vec![0; clusters.len() * self.cluster_size];
+                self.read_clusters(&clusters, 0, &mut bytes).await?;
+                Ok(DirData {
+                    dir,
+                    bytes,
+                    clusters,
+                })
+            }
+        }
+    }
+
+    fn entry_offset(&self, data: &DirData, index: usize) -> u64 {
+        let byte = index * ENTRY_SIZE;
+        match data.dir {
+            Dir::Root => self.root_offset + byte as u64,
+            Dir::Cluster(_) => {
+                let cluster = data.clusters[byte / self.cluster_size];
+                self.cluster_offset(cluster) + (byte % self.cluster_size) as u64
+            }
+        }
+    }
+
+    async fn store_entries(&self, data: &DirData, indices: Range<usize>) -> Result<(), Error> {
+        for index in indices {
+            let raw = &data.bytes[index * ENTRY_SIZE..][..ENTRY_SIZE];
+            block::write_at(&*self.device, self.entry_offset(data, index), raw).await?;
+        }
+        Ok(())
+    }
+
+    /// Add a zeroed cluster to a directory.
+    async fn extend_dir(&self, state: &mut State, data: &mut DirData) -> Result<(), Error> {
+        let Dir::Cluster(_) = data.dir else {
+            return Err(Error::NoSpace);
+        };
+        if data.bytes.len() + self.cluster_size > MAX_DIR_ENTRIES * ENTRY_SIZE {
+            return Err(Error::NoSpace);
+        }
+        let cluster = self
+            .alloc(state, data.clusters.last().copied(), true)
+            .await?;
+        data.clusters.push(cluster);
+        data.bytes.resize(data.bytes.len() + self.cluster_size, 0);
+        Ok(())
+    }
+
+    fn node(&self, data: &DirData, parsed: Parsed) -> Node {
+        Node {
+            location: Some(Location {
+                offset: self.entry_offset(data, parsed.index),
+            }),
+            name: parsed.name,
+            entry: parsed.entry,
+        }
+    }
+}
+
+/// The first of `count` consecutive unused slots.
+fn free_run(data: &DirData, count: usize) -> Option<usize> {
+    let mut run = 0;
+    for (index, raw) in data.bytes.chunks_exact(ENTRY_SIZE).enumerate() {
+        if matches!(raw[0], ENTRY_END | ENTRY_DELETED) {
+            run += 1;
+            if run == count {
+                return Some(index + 1 - count);
+            }
+        } else {
+            run = 0;
+        }
+    }
+    None
+}
+
+pub fn probe(
+    device: Arc<dyn BlockDevice>,
+) -> BoxFuture<'static, Result<Arc<dyn super::FileSystem>, Error>> {
+    Box::pin(async move { Ok(FileSystem::mount(device).await? as Arc<dyn super::FileSystem>) })
+}
+
+impl super::FileSystem for FileSystem {
+    fn name(&self) -> &'static str {
+        "vfat"
+    }
+
+    fn root(self: Arc<Self>) -> Arc<dyn Inode> {
+        let node = FileSystem::root(&self);
+        Arc::new(FatInode { fs: self, node })
+    }
+
+    fn usage(&self) -> BoxFuture<'_, Result<Usage, Error>> {
+        Box::pin(async {
+            Ok(Usage {
+                block_size: self.cluster_size as u64,
+                blocks: self.cluster_count as u64,
+                free: self.free_clusters().await? as u64,
+            })
+        })
+    }
+
+    fn sync(&self) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(FileSystem::sync(self))
+    }
+}
+
+struct FatInode {
+    fs: Arc<FileSystem>,
+    node: Node,
+}
+
+impl FatInode {
+    fn child(&self, node: Node) -> Arc<dyn Inode> {
+        Arc::new(Self {
+            fs: self.fs.clone(),
+            node,
+        })
+    }
+}
+
+fn kind(node: &Node) -> FileType {
+    if node.is_dir() {
+        FileType::Directory
+    } else {
+        FileType::File
+    }
+}
+
+impl Inode for FatInode {
+    fn kind(&self) -> FileType {
+        kind(&self.node)
+    }
+
+    fn stat(&self) -> BoxFuture<'_, Result<Stat, Error>> {
+        Box::pin(async {
+            let node = self.fs.refresh(&self.node).await?;
+            Ok(Stat {
+                kind: kind(&node),
+                inode: node.id(),
+                size: node.size(),
+                modified: node.modified(),
+            })
+        })
+    }
+
+    fn lookup<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Arc<dyn Inode>, Error>> {
+        Box::pin(async { Ok(self.child(self.fs.lookup(&self.node, name).await?)) })
+    }
+
+    fn read_dir(&self) -> BoxFuture<'_, Result<Vec<DirEntry>, Error>> {
+        Box::pin(async {
+            Ok(self
+                .fs
+                .read_dir(&self.node)
+                .await?
+                .into_iter()
+                .map(|node| DirEntry {
+                    kind: kind(&node),
+                    name: node.name,
+                })
+                .collect())
+        })
+    }
+
+    fn read_at<'a>(
+        &'a self,
+        offset: u64,
+        buf: &'a mut [u8],
+    ) -> BoxFuture<'a, Result<usize, Error>> {
+        Box::pin(self.fs.read(&self.node, offset, buf))
+    }
+
+    fn write_at<'a>(&'a self, offset: u64, buf: &'a [u8]) -> BoxFuture<'a, Result<usize, Error>> {
+        Box::pin(self.fs.write(&self.node, offset, buf))
+    }
+
+    fn truncate(&self, len: u64) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(self.fs.truncate(&self.node, len))
+    }
+
+    fn create<'a>(
+        &'a self,
+        name: &'a str,
+        kind: FileType,
+    ) -> BoxFuture<'a, Result<Arc<dyn Inode>, Error>> {
+        Box::pin(async move {
+            let directory = match kind {
+                FileType::File => false,
+                FileType::Directory => true,
+                FileType::Symlink => return Err(Error::NotSupported),
+            };
+            Ok(self.child(self.fs.create(&self.node, name, directory).await?))
+        })
+    }
+
+    fn symlink<'a>(&'a self, _: &'a str, _: &'a str) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async { Err(Error::NotSupported) })
+    }
+
+    fn remove<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(self.fs.remove(&self.node, name))
+    }
+}
@@ -0,0 +1,217 @@
+//! Open files, and the per-task tables that hold them.
+
+use super::{vfs, Error, FileType, Inode, Stat};
+use crate::local::Local;
+use alloc::sync::Arc;
+use core::{
+    cell::RefCell,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use pin_project::pin_project;
+use spin::{Lazy, Mutex};
+
+bitflags::bitflags! {
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    pub struct OpenFlags: u8 {
+        const READ = 1 << 0;
+        const WRITE = 1 << 1;
+        /// Create the file if it doesn't exist.
+        const CREATE = 1 << 2;
+        const TRUNCATE = 1 << 3;
+        /// Every write goes to the end of the file.
+        const APPEND = 1 << 4;
+    }
+}
+
+pub type Fd = usize;
+
+#[derive(Debug, Clone, Copy)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+struct OpenFile {
+    inode: Arc<dyn Inode>,
+    flags: OpenFlags,
+    /// Held for the whole of a read or write, so they don't interleave.
+    offset: maitake::sync::Mutex<u64>,
+}
+
+/// A working directory and a table of open files.
+pub struct Files {
+    cwd: Mutex<String>,
+    open: Mutex<Vec<Option<Arc<OpenFile>>>>,
+}
+
+impl Files {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            cwd: Mutex::new("/".to_owned()),
+            open: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Close every file still open.
+    pub fn close_all(&self) {
+        self.open.lock().clear();
+    }
+
+    fn get(&self, fd: Fd) -> Result<Arc<OpenFile>, Error> {
+        self.open
+            .lock()
+            .get(fd)
+            .cloned()
+            .flatten()
+            .ok_or(Error::BadDescriptor)
+    }
+
+    /// Store `file` at the lowest free descriptor.
+    fn insert(&self, file: OpenFile) -> Fd {
+        let mut open = self.open.lock();
+        let file = Some(Arc::new(file));
+        match open.iter().position(|f| f.is_none()) {
+            Some(fd) => {
+                open[fd] = file;
+                fd
+            }
+            None => {
+                open.push(file);
+                open.len() - 1
+            }
+        }
+    }
+}
+
+/// Shared by everything not running in a [`scope`].
+static KERNEL: Lazy<Arc<Files>> = Lazy::new(Files::new);
+
+static CURRENT: Local<RefCell<Option<Arc<Files>>>> = Local::new(|| RefCell::new(None));
+
+fn current() -> Arc<Files> {
+    CURRENT
+        .with(|current| current.borrow().clone())
+        .unwrap_or_else(|| KERNEL.clone())
+}
+
+/// Run `future` with `files` as its working directory and file table.
+pub fn scope<F: Future>(files: Arc<Files>, future: F) -> Scoped<F> {
+    Scoped { files, future }
+}
+
+#[pin_project]
+pub struct Scoped<F> {
+    files: Arc<Files>,
+    #[pin]
+    future: F,
+}
+
+impl<F: Future> Future for Scoped<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // tasks move between cores, so the table is only current while
+        // this task is being polled
+        struct Restore(Option<Arc<Files>>);
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                CURRENT.with(|current| *current.borrow_mut() = self.0.take());
+            }
+        }
+
+        let this = self.project();
+        let _restore = Restore(CURRENT.with(|current| current.replace(Some(this.files.clone()))));
+        this.future.poll(cx)
+    }
+}
+
+pub async fn open(path: &str, flags: OpenFlags) -> Result<Fd, Error> {
+    let inode = match vfs::resolve(path, true).await {
+        Ok((_, inode)) => inode,
+        Err(Error::NotFound) if flags.contains(OpenFlags::CREATE) => {
+            vfs::create(path, FileType::File).await?
+        }
+        Err(e) => return Err(e),
+    };
+    if inode.kind() == FileType::Directory
+        && flags.intersects(OpenFlags::WRITE | OpenFlags::TRUNCATE | OpenFlags::APPEND)
+    {
+        return Err(Error::IsADirectory);
+    }
+    if flags.contains(OpenFlags::TRUNCATE) {
+        inode.truncate(0).await?;
+    }
+
+    Ok(current().insert(OpenFile {
+        inode,
+        flags,
+        offset: maitake::sync::Mutex::new(0),
+    }))
+}
+
+pub fn close(fd: Fd) -> Result<(), Error> {
+    let files = current();
+    let mut open = files.open.lock();
+    open.get_mut(fd)
+        .and_then(|f| f.take())
+        .ok_or(Error::BadDescriptor)?;
+    Ok(())
+}
+
+pub async fn read(fd: Fd, buf: &mut [u8]) -> Result<usize, Error> {
+    let file = current().get(fd)?;
+    if !file.flags.contains(OpenFlags::READ) {
+        return Err(Error::BadDescriptor);
+    }
+    let mut offset = file.offset.lock().await;
+    let n = file.inode.read_at(*offset, buf).await?;
+    *offset += n as u64;
+    Ok(n)
+}
+
+pub async fn write(fd: Fd, buf: &[u8]) -> Result<usize, Error> {
+    let file = current().get(fd)?;
+    if !file.flags.intersects(OpenFlags::WRITE | OpenFlags::APPEND) {
+        return Err(Error::BadDescriptor);
+    }
+    let mut offset = file.offset.lock().await;
+    if file.flags.contains(OpenFlags::APPEND) {
+        *offset = file.inode.stat().await?.size;
+    }
+    let n = file.inode.write_at(*offset, buf).await?;
+    *offset += n as u64;
+    Ok(n)
+}
+
+/// Move the file offset, returning where it ends up.
+pub async fn seek(fd: Fd, pos: SeekFrom) -> Result<u64, Error> {
+    let file = current().get(fd)?;
+    let mut offset = file.offset.lock().await;
+    let new = match pos {
+        SeekFrom::Start(n) => Some(n),
+        SeekFrom::Current(n) => offset.checked_add_signed(n),
+        SeekFrom::End(n) => file.inode.stat().await?.size.checked_add_signed(n),
+    };
+    *offset = new.ok_or(Error::InvalidArgument)?;
+    Ok(*offset)
+}
+
+pub async fn fstat(fd: Fd) -> Result<Stat, Error> {
+    current().get(fd)?.inode.stat().await
+}
+
+pub async fn chdir(path: &str) -> Result<(), Error> {
+    let (path, inode) = vfs::resolve(path, true).await?;
+    if inode.kind() != FileType::Directory {
+        return Err(Error::NotADirectory);
+    }
+    *current().cwd.lock() = path;
+    Ok(())
+}
+
+pub fn cwd() -> String {
+    current().cwd.lock().clone()
+}
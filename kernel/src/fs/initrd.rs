@@ -0,0 +1,411 @@
+//! A read-only filesystem built at boot, from a tar or cpio archive the
+//! bootloader loaded and from the boot modules themselves. File contents
+//! are never copied, they point straight into the module.
+
+use super::{DirEntry, Error, FileType, Inode, Stat, Usage};
+use alloc::{collections::BTreeMap, sync::Arc};
+use futures::future::BoxFuture;
+
+const TAR_BLOCK: usize = 512;
+const CPIO_HEADER: usize = 110;
+const CPIO_TRAILER: &str = "TRAILER!!!";
+
+const MODE_TYPE: u32 = 0o170000;
+const MODE_DIRECTORY: u32 = 0o040000;
+const MODE_FILE: u32 = 0o100000;
+const MODE_SYMLINK: u32 = 0o120000;
+
+enum Entry {
+    File(&'static [u8]),
+    Directory(BTreeMap<String, Built>),
+    Symlink(String),
+}
+
+struct Built {
+    modified: u64,
+    entry: Entry,
+}
+
+/// Collects files into a tree before it becomes an [`Initrd`].
+pub struct Builder {
+    root: Built,
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Self {
+            root: Built {
+                modified: 0,
+                entry: Entry::Directory(BTreeMap::new()),
+            },
+        }
+    }
+}
+
+impl Builder {
+    /// Put `entry` at `path`, creating any missing directories on the way.
+    /// A directory that already exists keeps its contents.
+    fn insert(&mut self, path: &str, modified: u64, entry: Entry) {
+        let components = path
+            .split('/')
+            .filter(|c| !c.is_empty() && *c != ".")
+            .collect::<Vec<_>>();
+        if components.contains(&"..") {
+            warn!("initrd: skipping {path}");
+            return;
+        }
+        let Some((name, parents)) = components.split_last() else {
+            return;
+        };
+
+        let mut dir = &mut self.root;
+        for parent in parents {
+            if !matches!(dir.entry, Entry::Directory(_)) {
+                dir.entry = Entry::Directory(BTreeMap::new());
+            }
+            let Entry::Directory(entries) = &mut dir.entry else {
+                unreachable!()
+            };
+            dir = entries.entry((*parent).to_owned()).or_insert(Built {
+                modified,
+                entry: Entry::Directory(BTreeMap::new()),
+            });
+        }
+        if !matches!(dir.entry, Entry::Directory(_)) {
+            dir.entry = Entry::Directory(BTreeMap::new());
+        }
+        let Entry::Directory(entries) = &mut dir.entry else {
+            unreachable!()
+        };
+        if let Entry::Directory(_) = entry {
+            if let Some(existing) = entries.get_mut(*name) {
+                if let Entry::Directory(_) = existing.entry {
+                    existing.modified = modified;
+                    return;
+                }
+            }
+        }
+        entries.insert((*name).to_owned(), Built { modified, entry });
+    }
+
+    pub fn add_file(&mut self, path: &str, modified: u64, data: &'static [u8]) {
+        self.insert(path, modified, Entry::File(data));
+    }
+
+    pub fn add_dir(&mut self, path: &str, modified: u64) {
+        self.insert(path, modified, Entry::Directory(BTreeMap::new()));
+    }
+
+    /// Add everything in a ustar or newc cpio archive.
+    pub fn unpack(&mut self, archive: &'static [u8]) -> Result<(), Error> {
+        if archive.starts_with(b"070701") || archive.starts_with(b"070702") {
+            self.unpack_cpio(archive)
+        } else if archive.len() >= TAR_BLOCK && tar_checksum_ok(&archive[..TAR_BLOCK]) {
+            self.unpack_tar(archive)
+        } else {
+            Err(Error::UnknownFilesystem)
+        }
+    }
+
+    fn unpack_tar(&mut self, archive: &'static [u8]) -> Result<(), Error> {
+        // set by GNU and pax extension headers, for the entry that follows
+        let mut long_name = None;
+        let mut long_link = None;
+
+        let mut offset = 0;
+        while offset + TAR_BLOCK <= archive.len() {
+            let header = &archive[offset..offset + TAR_BLOCK];
+            if header.iter().all(|&b| b == 0) {
+                break;
+            }
+            if !tar_checksum_ok(header) {
+                return Err(Error::Corrupt("bad tar header checksum"));
+            }
+
+            let size = octal(&header[124..136])? as usize;
+            let modified = octal(&header[136..148])?;
+            let start = offset + TAR_BLOCK;
+            let data = archive
+                .get(start..start + size)
+                .ok_or(Error::Corrupt("tar entry runs past the end"))?;
+            offset = start + size.div_ceil(TAR_BLOCK) * TAR_BLOCK;
+
+            let name = long_name.take().unwrap_or_else(|| {
+                let name = c_string(&header[0..100]);
+                let prefix = c_string(&header[345..500]);
+                if &header[257..262] == b"ustar" && !prefix.is_empty() {
+                    format!("{prefix}/{name}")
+                } else {
+                    name
+                }
+            });
+            let link = long_link
+                .take()
+                .unwrap_or_else(|| c_string(&header[157..257]));
+
+            match header[156] {
+                b'0' | 0 | b'7' => self.add_file(&name, modified, data),
+                b'5' => self.add_dir(&name, modified),
+                b'2' => self.insert(&name, modified, Entry::Symlink(link)),
+                b'L' => long_name = Some(c_string(data)),
+                b'K' => long_link = Some(c_string(data)),
+                b'x' => {
+                    for (key, value) in pax_records(data) {
+                        match key {
+                            "path" => long_name = Some(value.to_owned()),
+                            "linkpath" => long_link = Some(value.to_owned()),
+                            _ => {}
+                        }
+                    }
+                }
+                kind => debug!("initrd: skipping {name} of type {:?}", kind as char),
+            }
+        }
+        Ok(())
+    }
+
+    fn unpack_cpio(&mut self, archive: &'static [u8]) -> Result<(), Error> {
+        let mut offset = 0;
+        loop {
+            let header = archive
+                .get(offset..offset + CPIO_HEADER)
+                .ok_or(Error::Corrupt("cpio archive has no trailer"))?;
+            if !header.starts_with(b"070701") && !header.starts_with(b"070702") {
+                return Err(Error::Corrupt("bad cpio header"));
+            }
+            let field = |i: usize| {
+                core::str::from_utf8(&header[6 + i * 8..14 + i * 8])
+                    .ok()
+                    .and_then(|s| u32::from_str_radix(s, 16).ok())
+                    .ok_or(Error::Corrupt("bad cpio header"))
+            };
+            let mode = field(1)?;
+            let modified = field(5)? as u64;
+            let size = field(6)? as usize;
+            let name_size = field(11)? as usize;
+
+            let name_start = offset + CPIO_HEADER;
+            let name = archive
+                .get(name_start..name_start + name_size)
+                .map(c_string)
+                .ok_or(Error::Corrupt("cpio entry runs past the end"))?;
+            let start = (name_start + name_size).next_multiple_of(4);
+            let data = archive
+                .get(start..start + size)
+                .ok_or(Error::Corrupt("cpio entry runs past the end"))?;
+            offset = (start + size).next_multiple_of(4);
+
+            if name == CPIO_TRAILER {
+                return Ok(());
+            }
+            match mode & MODE_TYPE {
+                MODE_FILE => self.add_file(&name, modified, data),
+                MODE_DIRECTORY => self.add_dir(&name, modified),
+                MODE_SYMLINK => {
+                    let target = String::from_utf8_lossy(data).into_owned();
+                    self.insert(&name, modified, Entry::Symlink(target));
+                }
+                kind => debug!("initrd: skipping {name} of mode {kind:o}"),
+            }
+        }
+    }
+
+    pub fn build(self) -> Arc<Initrd> {
+        let mut next_id = 1;
+        let mut size = 0;
+        let root = freeze(self.root, &mut next_id, &mut size);
+        Arc::new(Initrd { root, size })
+    }
+}
+
+fn freeze(built: Built, next_id: &mut u64, size: &mut u64) -> Arc<Node> {
+    let id = *next_id;
+    *next_id += 1;
+    let data = match built.entry {
+        Entry::File(data) => {
+            *size += data.len() as u64;
+            Data::File(data)
+        }
+        Entry::Directory(entries) => Data::Directory(
+            entries
+                .into_iter()
+                .map(|(name, built)| (name, freeze(built, next_id, size)))
+                .collect(),
+        ),
+        Entry::Symlink(target) => Data::Symlink(target),
+    };
+    Arc::new(Node {
+        id,
+        modified: built.modified,
+        data,
+    })
+}
+
+/// The checksum is the sum of the header bytes, counting its own field as
+/// spaces.
+fn tar_checksum_ok(header: &[u8]) -> bool {
+    let Ok(expected) = octal(&header[148..156]) else {
+        return false;
+    };
+    let sum = header
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| (if (148..156).contains(&i) { b' ' } else { b }) as u64)
+        .sum::<u64>();
+    sum == expected
+}
+
+fn octal(field: &[u8]) -> Result<u64, Error> {
+    let digits = c_string(field);
+    let digits = digits.trim_matches([' ', '\0']);
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(digits, 8).map_err(|_| Error::Corrupt("bad number in tar header"))
+}
+
+fn c_string(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+/// The `key=value` records of a pax extended header, each written as
+/// `<length> <key>=<value>\n`.
+fn pax_records(data: &[u8]) -> Vec<(&str, &str)> {
+    let mut records = Vec::new();
+    let mut rest = data;
+    while let Some(space) = rest.iter().position(|&b| b == b' ') {
+        let Some(len) = core::str::from_utf8(&rest[..space])
+            .ok()
+            .and_then(|s| s.parse::<usize>().ok())
+            .filter(|&len| len > space + 1 && len <= rest.len())
+        else {
+            break;
+        };
+        let record = &rest[space + 1..len - 1];
+        if let Some((key, value)) = core::str::from_utf8(record)
+            .ok()
+            .and_then(|r| r.split_once('='))
+        {
+            records.push((key, value));
+        }
+        rest = &rest[len..];
+    }
+    records
+}
+
+pub struct Initrd {
+    root: Arc<Node>,
+    /// Total size of every file.
+    size: u64,
+}
+
+impl super::FileSystem for Initrd {
+    fn name(&self) -> &'static str {
+        "initrd"
+    }
+
+    fn root(self: Arc<Self>) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+
+    fn usage(&self) -> BoxFuture<'_, Result<Usage, Error>> {
+        Box::pin(async {
+            Ok(Usage {
+                block_size: TAR_BLOCK as u64,
+                blocks: self.size.div_ceil(TAR_BLOCK as u64),
+                free: 0,
+            })
+        })
+    }
+}
+
+enum Data {
+    File(&'static [u8]),
+    Directory(BTreeMap<String, Arc<Node>>),
+    Symlink(String),
+}
+
+struct Node {
+    id: u64,
+    modified: u64,
+    data: Data,
+}
+
+impl Inode for Node {
+    fn kind(&self) -> FileType {
+        match self.data {
+            Data::File(_) => FileType::File,
+            Data::Directory(_) => FileType::Directory,
+            Data::Symlink(_) => FileType::Symlink,
+        }
+    }
+
+    fn stat(&self) -> BoxFuture<'_, Result<Stat, Error>> {
+        Box::pin(async {
+            let size = match &self.data {
+                Data::File(data) => data.len(),
+                Data::Directory(_) => 0,
+                Data::Symlink(target) => target.len(),
+            };
+            Ok(Stat {
+                kind: self.kind(),
+                inode: self.id,
+                size: size as u64,
+                modified: self.modified,
+            })
+        })
+    }
+
+    fn lookup<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Arc<dyn Inode>, Error>> {
+        Box::pin(async {
+            let Data::Directory(entries) = &self.data else {
+                return Err(Error::NotADirectory);
+            };
+            match entries.get(name) {
+                Some(node) => Ok(node.clone() as Arc<dyn Inode>),
+                None => Err(Error::NotFound),
+            }
+        })
+    }
+
+    fn read_dir(&self) -> BoxFuture<'_, Result<Vec<DirEntry>, Error>> {
+        Box::pin(async {
+            let Data::Directory(entries) = &self.data else {
+                return Err(Error::NotADirectory);
+            };
+            Ok(entries
+                .iter()
+                .map(|(name, node)| DirEntry {
+                    name: name.clone(),
+                    kind: node.kind(),
+                })
+                .collect())
+        })
+    }
+
+    fn read_at<'a>(
+        &'a self,
+        offset: u64,
+        buf: &'a mut [u8],
+    ) -> BoxFuture<'a, Result<usize, Error>> {
+        Box::pin(async move {
+            let Data::File(data) = self.data else {
+                return Err(Error::IsADirectory);
+            };
+            let rest = &data[offset.min(data.len() as u64) as usize..];
+            let n = buf.len().min(rest.len());
+            buf[..n].copy_from_slice(&rest[..n]);
+            Ok(n)
+        })
+    }
+
+    fn read_link(&self) -> BoxFuture<'_, Result<String, Error>> {
+        Box::pin(async {
+            match &self.data {
+                Data::Symlink(target) => Ok(target.clone()),
+                _ => Err(Error::InvalidArgument),
+            }
+        })
+    }
+}
@@ -0,0 +1,227 @@
+//! Files. Each filesystem exposes its files and directories as [`Inode`]s,
+//! and filesystems are mounted into one tree. Paths are resolved a component
+//! at a time across mounts, and open files live in a per-task [`Files`]
+//! table.
+
+use alloc::sync::Arc;
+use futures::future::BoxFuture;
+
+pub mod ext2;
+pub mod fat;
+mod file;
+pub mod initrd;
+pub mod tmpfs;
+mod vfs;
+
+pub use file::{
+    chdir, close, cwd, fstat, open, read, scope, seek, write, Fd, Files, OpenFlags, SeekFrom,
+};
+pub use vfs::{
+    create_dir, lstat, mount, mount_device, mounts, read_dir, read_link, remove, stat, symlink,
+    Mount,
+};
+
+/// Without an initrd the root is a tmpfs, mostly holding mount points.
+const ROOT_SIZE: u64 = 16 * 1024 * 1024;
+/// Largest the tmpfs at `/tmp` may grow.
+const TMP_SIZE: u64 = 64 * 1024 * 1024;
+/// Directories the root needs for the mounts made at boot.
+const MOUNT_POINTS: &[&str] = &["/boot", "/tmp"];
+
+/// Set up the root filesystem, the boot modules at `/boot` and scratch space
+/// at `/tmp`. The root is the archive loaded as the module with the command
+/// line `initrd`, if there is one. Every filesystem is synced before the
+/// machine powers off.
+pub fn init() {
+    // the block layer's hook flushes the disk caches, and having been
+    // registered before this one, runs after it
+    crate::arch::on_power_off(|| {
+        Box::pin(async {
+            for mount in mounts() {
+                if let Err(e) = mount.fs.sync().await {
+                    warn!("fs: syncing {} before power off failed: {e}", mount.path);
+                }
+            }
+        })
+    });
+
+    let modules = crate::arch::boot_modules();
+    let now = crate::arch::timestamp().as_secs();
+
+    let mut boot = initrd::Builder::default();
+    for module in &modules {
+        let name = module.path.rsplit('/').next().unwrap_or_default();
+        boot.add_file(name, now, module.data);
+    }
+
+    let root = modules
+        .iter()
+        .find(|module| module.cmdline == "initrd")
+        .and_then(|module| {
+            let mut root = initrd::Builder::default();
+            if let Err(e) = root.unpack(module.data) {
+                warn!("fs: failed to unpack {}: {e}", module.path);
+                return None;
+            }
+            for dir in MOUNT_POINTS {
+                root.add_dir(dir, now);
+            }
+            Some((root.build(), module.path.clone()))
+        });
+
+    crate::task::spawn(async move {
+        let result = async {
+            match root {
+                Some((root, source)) => mount(root, &source, "/").await?,
+                None => {
+                    mount(tmpfs::TmpFs::new(ROOT_SIZE), "rootfs", "/").await?;
+                    for dir in MOUNT_POINTS {
+                        create_dir(dir).await?;
+                    }
+                }
+            }
+            mount(boot.build(), "modules", "/boot").await?;
+            mount(tmpfs::TmpFs::new(TMP_SIZE), "tmpfs", "/tmp").await
+        };
+        if let Err(e) = result.await {
+            warn!("fs: failed to set up the root filesystem: {e}");
+        }
+    });
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("no such file or directory")]
+    NotFound,
+    #[error("file exists")]
+    Exists,
+    #[error("not a directory")]
+    NotADirectory,
+    #[error("is a directory")]
+    IsADirectory,
+    #[error("directory not empty")]
+    NotEmpty,
+    #[error("invalid file name")]
+    InvalidName,
+    #[error("invalid argument")]
+    InvalidArgument,
+    #[error("no space left on device")]
+    NoSpace,
+    #[error("file too large")]
+    TooLarge,
+    #[error("read-only filesystem")]
+    ReadOnly,
+    #[error("operation not supported")]
+    NotSupported,
+    #[error("bad file descriptor")]
+    BadDescriptor,
+    #[error("too many levels of symbolic links")]
+    TooManyLinks,
+    #[error("device or resource busy")]
+    Busy,
+    #[error("unrecognized filesystem")]
+    UnknownFilesystem,
+    #[error("filesystem is corrupt: {0}")]
+    Corrupt(&'static str),
+    #[error("{0}")]
+    Block(#[from] crate::block::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Directory,
+    Symlink,
+}
+
+#[derive(Debug, Clone)]
+pub struct Stat {
+    pub kind: FileType,
+    /// Unique within the filesystem.
+    pub inode: u64,
+    pub size: u64,
+    /// Seconds since the Unix epoch.
+    pub modified: u64,
+}
+
+/// Space on a filesystem, in blocks of whatever size it allocates in.
+#[derive(Debug, Clone)]
+pub struct Usage {
+    pub block_size: u64,
+    pub blocks: u64,
+    pub free: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub name: String,
+    pub kind: FileType,
+}
+
+pub trait FileSystem: Send + Sync {
+    /// The filesystem type, as `mount` shows it.
+    fn name(&self) -> &'static str;
+
+    fn root(self: Arc<Self>) -> Arc<dyn Inode>;
+
+    fn usage(&self) -> BoxFuture<'_, Result<Usage, Error>>;
+
+    /// Write out anything the filesystem is holding on to.
+    fn sync(&self) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// A file, directory or symlink. Directories never list or look up `.` and
+/// `..`, those are handled while resolving paths.
+///
+/// Operations that modify anything default to failing with
+/// [`Error::ReadOnly`].
+pub trait Inode: Send + Sync {
+    fn kind(&self) -> FileType;
+
+    fn stat(&self) -> BoxFuture<'_, Result<Stat, Error>>;
+
+    fn lookup<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Arc<dyn Inode>, Error>>;
+
+    fn read_dir(&self) -> BoxFuture<'_, Result<Vec<DirEntry>, Error>>;
+
+    /// Read from `offset`, returning how much was read, 0 at the end.
+    fn read_at<'a>(&'a self, offset: u64, buf: &'a mut [u8])
+        -> BoxFuture<'a, Result<usize, Error>>;
+
+    fn write_at<'a>(&'a self, offset: u64, buf: &'a [u8]) -> BoxFuture<'a, Result<usize, Error>> {
+        let _ = (offset, buf);
+        Box::pin(async { Err(Error::ReadOnly) })
+    }
+
+    fn truncate(&self, len: u64) -> BoxFuture<'_, Result<(), Error>> {
+        let _ = len;
+        Box::pin(async { Err(Error::ReadOnly) })
+    }
+
+    /// Create an empty file or directory.
+    fn create<'a>(
+        &'a self,
+        name: &'a str,
+        kind: FileType,
+    ) -> BoxFuture<'a, Result<Arc<dyn Inode>, Error>> {
+        let _ = (name, kind);
+        Box::pin(async { Err(Error::ReadOnly) })
+    }
+
+    fn symlink<'a>(&'a self, name: &'a str, target: &'a str) -> BoxFuture<'a, Result<(), Error>> {
+        let _ = (name, target);
+        Box::pin(async { Err(Error::ReadOnly) })
+    }
+
+    /// Remove a file, symlink or empty directory.
+    fn remove<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<(), Error>> {
+        let _ = name;
+        Box::pin(async { Err(Error::ReadOnly) })
+    }
+
+    fn read_link(&self) -> BoxFuture<'_, Result<String, Error>> {
+        Box::pin(async { Err(Error::InvalidArgument) })
+    }
+}
@@ -0,0 +1,349 @@
+//! Filesystems that live on the kernel heap. File contents are kept as a map
+//! of pages, so a file only takes up space where it has been written.
+
+use super::{DirEntry, Error, FileType, Inode, Stat, Usage};
+use alloc::{collections::BTreeMap, sync::Arc};
+use core::sync::atomic::{AtomicU64, Ordering};
+use futures::future::BoxFuture;
+use spin::Mutex;
+
+const PAGE_SIZE: usize = 4096;
+const MAX_NAME: usize = 255;
+/// Keeps offsets and page indices well away from overflowing.
+const MAX_FILE_SIZE: u64 = 1 << 48;
+
+/// State shared by every node of one filesystem.
+struct Shared {
+    /// Bytes of file pages allowed, and in use.
+    limit: u64,
+    used: AtomicU64,
+    next_id: AtomicU64,
+}
+
+impl Shared {
+    fn reserve(&self, pages: usize) -> Result<(), Error> {
+        let bytes = (pages * PAGE_SIZE) as u64;
+        self.used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                used.checked_add(bytes).filter(|&used| used <= self.limit)
+            })
+            .map(|_| ())
+            .map_err(|_| Error::NoSpace)
+    }
+
+    fn release(&self, pages: usize) {
+        self.used
+            .fetch_sub((pages * PAGE_SIZE) as u64, Ordering::AcqRel);
+    }
+}
+
+pub struct TmpFs {
+    shared: Arc<Shared>,
+    root: Arc<Node>,
+}
+
+impl TmpFs {
+    /// An empty filesystem that holds at most `limit` bytes of file data.
+    pub fn new(limit: u64) -> Arc<Self> {
+        let shared = Arc::new(Shared {
+            limit,
+            used: AtomicU64::new(0),
+            next_id: AtomicU64::new(1),
+        });
+        let root = Node::new(&shared, Data::Directory(BTreeMap::new()));
+        Arc::new(Self { shared, root })
+    }
+}
+
+impl super::FileSystem for TmpFs {
+    fn name(&self) -> &'static str {
+        "tmpfs"
+    }
+
+    fn root(self: Arc<Self>) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+
+    fn usage(&self) -> BoxFuture<'_, Result<Usage, Error>> {
+        Box::pin(async {
+            let used = self.shared.used.load(Ordering::Acquire);
+            Ok(Usage {
+                block_size: PAGE_SIZE as u64,
+                blocks: self.shared.limit / PAGE_SIZE as u64,
+                free: (self.shared.limit - used) / PAGE_SIZE as u64,
+            })
+        })
+    }
+}
+
+enum Data {
+    File {
+        size: u64,
+        pages: BTreeMap<u64, Box<[u8]>>,
+    },
+    Directory(BTreeMap<String, Arc<Node>>),
+    Symlink(String),
+}
+
+struct Inner {
+    modified: u64,
+    data: Data,
+}
+
+struct Node {
+    id: u64,
+    kind: FileType,
+    shared: Arc<Shared>,
+    inner: Mutex<Inner>,
+}
+
+impl Node {
+    fn new(shared: &Arc<Shared>, data: Data) -> Arc<Self> {
+        let kind = match data {
+            Data::File { .. } => FileType::File,
+            Data::Directory(_) => FileType::Directory,
+            Data::Symlink(_) => FileType::Symlink,
+        };
+        Arc::new(Self {
+            id: shared.next_id.fetch_add(1, Ordering::Relaxed),
+            kind,
+            shared: shared.clone(),
+            inner: Mutex::new(Inner {
+                modified: now(),
+                data,
+            }),
+        })
+    }
+
+    fn add(&self, name: &str, data: Data) -> Result<Arc<Node>, Error> {
+        if name.is_empty()
+            || name == "."
+            || name == ".."
+            || name.len() > MAX_NAME
+            || name.contains(['/', '\0'])
+        {
+            return Err(Error::InvalidName);
+        }
+        let mut inner = self.inner.lock();
+        let Data::Directory(entries) = &mut inner.data else {
+            return Err(Error::NotADirectory);
+        };
+        if entries.contains_key(name) {
+            return Err(Error::Exists);
+        }
+        let node = Node::new(&self.shared, data);
+        entries.insert(name.to_owned(), node.clone());
+        inner.modified = now();
+        Ok(node)
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        // open files outlive being removed, so their pages are only
+        // given back once the last handle is gone
+        if let Data::File { pages, .. } = &self.inner.get_mut().data {
+            self.shared.release(pages.len());
+        }
+    }
+}
+
+fn now() -> u64 {
+    crate::arch::timestamp().as_secs()
+}
+
+impl Inode for Node {
+    fn kind(&self) -> FileType {
+        self.kind
+    }
+
+    fn stat(&self) -> BoxFuture<'_, Result<Stat, Error>> {
+        Box::pin(async {
+            let inner = self.inner.lock();
+            let size = match &inner.data {
+                Data::File { size, .. } => *size,
+                Data::Directory(_) => 0,
+                Data::Symlink(target) => target.len() as u64,
+            };
+            Ok(Stat {
+                kind: self.kind,
+                inode: self.id,
+                size,
+                modified: inner.modified,
+            })
+        })
+    }
+
+    fn lookup<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Arc<dyn Inode>, Error>> {
+        Box::pin(async {
+            let Data::Directory(entries) = &self.inner.lock().data else {
+                return Err(Error::NotADirectory);
+            };
+            match entries.get(name) {
+                Some(node) => Ok(node.clone() as Arc<dyn Inode>),
+                None => Err(Error::NotFound),
+            }
+        })
+    }
+
+    fn read_dir(&self) -> BoxFuture<'_, Result<Vec<DirEntry>, Error>> {
+        Box::pin(async {
+            let Data::Directory(entries) = &self.inner.lock().data else {
+                return Err(Error::NotADirectory);
+            };
+            Ok(entries
+                .iter()
+                .map(|(name, node)| DirEntry {
+                    name: name.clone(),
+                    kind: node.kind,
+                })
+                .collect())
+        })
+    }
+
+    fn read_at<'a>(
+        &'a self,
+        offset: u64,
+        buf: &'a mut [u8],
+    ) -> BoxFuture<'a, Result<usize, Error>> {
+        Box::pin(async move {
+            let inner = self.inner.lock();
+            let Data::File { size, pages } = &inner.data else {
+                return Err(Error::IsADirectory);
+            };
+            if offset >= *size {
+                return Ok(0);
+            }
+            let len = buf.len().min((*size - offset) as usize);
+
+            let mut done = 0;
+            while done < len {
+                let pos = offset + done as u64;
+                let within = (pos % PAGE_SIZE as u64) as usize;
+                let n = (PAGE_SIZE - within).min(len - done);
+                let out = &mut buf[done..done + n];
+                match pages.get(&(pos / PAGE_SIZE as u64)) {
+                    Some(page) => out.copy_from_slice(&page[within..within + n]),
+                    // a hole
+                    None => out.fill(0),
+                }
+                done += n;
+            }
+            Ok(len)
+        })
+    }
+
+    fn write_at<'a>(&'a self, offset: u64, buf: &'a [u8]) -> BoxFuture<'a, Result<usize, Error>> {
+        Box::pin(async move {
+            let mut inner = self.inner.lock();
+            let Data::File { size, pages } = &mut inner.data else {
+                return Err(Error::IsADirectory);
+            };
+            if buf.is_empty() {
+                return Ok(0);
+            }
+            let end = offset
+                .checked_add(buf.len() as u64)
+                .filter(|&end| end <= MAX_FILE_SIZE)
+                .ok_or(Error::TooLarge)?;
+
+            let range = offset / PAGE_SIZE as u64..end.div_ceil(PAGE_SIZE as u64);
+            let missing = range
+                .clone()
+                .filter(|index| !pages.contains_key(index))
+                .count();
+            self.shared.reserve(missing)?;
+
+            let mut done = 0;
+            for index in range {
+                let page = pages
+                    .entry(index)
+                    .or_insert_with(|| vec![0; PAGE_SIZE].into_boxed_slice());
+                let pos = offset + done as u64;
+                let within = (pos % PAGE_SIZE as u64) as usize;
+                let n = (PAGE_SIZE - within).min(buf.len() - done);
+                page[within..within + n].copy_from_slice(&buf[done..done + n]);
+                done += n;
+            }
+            *size = (*size).max(end);
+            inner.modified = now();
+            Ok(buf.len())
+        })
+    }
+
+    fn truncate(&self, len: u64) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(async move {
+            if len > MAX_FILE_SIZE {
+                return Err(Error::TooLarge);
+            }
+            let mut inner = self.inner.lock();
+            let Data::File { size, pages } = &mut inner.data else {
+                return Err(Error::IsADirectory);
+            };
+            if len < *size {
+                let dropped = pages.split_off(&len.div_ceil(PAGE_SIZE as u64));
+                self.shared.release(dropped.len());
+                // growing the file again has to read back zeros
+                let within = (len % PAGE_SIZE as u64) as usize;
+                if let Some(page) = pages.get_mut(&(len / PAGE_SIZE as u64)) {
+                    page[within..].fill(0);
+                }
+            }
+            *size = len;
+            inner.modified = now();
+            Ok(())
+        })
+    }
+
+    fn create<'a>(
+        &'a self,
+        name: &'a str,
+        kind: FileType,
+    ) -> BoxFuture<'a, Result<Arc<dyn Inode>, Error>> {
+        Box::pin(async move {
+            let data = match kind {
+                FileType::File => Data::File {
+                    size: 0,
+                    pages: BTreeMap::new(),
+                },
+                FileType::Directory => Data::Directory(BTreeMap::new()),
+                FileType::Symlink => return Err(Error::InvalidArgument),
+            };
+            Ok(self.add(name, data)? as Arc<dyn Inode>)
+        })
+    }
+
+    fn symlink<'a>(&'a self, name: &'a str, target: &'a str) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            self.add(name, Data::Symlink(target.to_owned()))?;
+            Ok(())
+        })
+    }
+
+    fn remove<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let mut inner = self.inner.lock();
+            let Data::Directory(entries) = &mut inner.data else {
+                return Err(Error::NotADirectory);
+            };
+            let node = entries.get(name).ok_or(Error::NotFound)?;
+            if let Data::Directory(children) = &node.inner.lock().data {
+                if !children.is_empty() {
+                    return Err(Error::NotEmpty);
+                }
+            }
+            entries.remove(name);
+            inner.modified = now();
+            Ok(())
+        })
+    }
+
+    fn read_link(&self) -> BoxFuture<'_, Result<String, Error>> {
+        Box::pin(async {
+            match &self.inner.lock().data {
+                Data::Symlink(target) => Ok(target.clone()),
+                _ => Err(Error::InvalidArgument),
+            }
+        })
+    }
+}
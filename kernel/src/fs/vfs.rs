@@ -0,0 +1,230 @@
+//! The mount table, and resolving paths through it.
+
+use super::{DirEntry, Error, FileSystem, FileType, Inode, Stat};
+use crate::block::BlockDevice;
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+};
+use futures::future::BoxFuture;
+use spin::Mutex;
+
+/// Symlinks followed while resolving one path before giving up.
+const MAX_LINKS: usize = 40;
+
+type Probe = fn(Arc<dyn BlockDevice>) -> BoxFuture<'static, Result<Arc<dyn FileSystem>, Error>>;
+
+/// Filesystems that can be mounted from a block device, tried in order.
+/// A probe fails with [`Error::UnknownFilesystem`] if the device isn't one
+/// of its own.
+const FILESYSTEMS: &[(&str, Probe)] = &[("ext2", super::ext2::probe), ("vfat", super::fat::probe)];
+
+#[derive(Clone)]
+pub struct Mount {
+    pub path: String,
+    /// Where the filesystem came from, usually a block device.
+    pub source: String,
+    pub fs: Arc<dyn FileSystem>,
+}
+
+static MOUNTS: Mutex<BTreeMap<String, Mount>> = Mutex::new(BTreeMap::new());
+
+/// One step of a resolved path.
+struct Dentry {
+    name: String,
+    inode: Arc<dyn Inode>,
+}
+
+/// The absolute path of a resolved path, without any symlinks or dots.
+fn canonical(stack: &[Dentry]) -> String {
+    if stack.len() == 1 {
+        return "/".to_owned();
+    }
+    stack[1..].iter().fold(String::new(), |mut path, dentry| {
+        path.push('/');
+        path.push_str(&dentry.name);
+        path
+    })
+}
+
+fn mounted(path: &str) -> Option<Arc<dyn FileSystem>> {
+    MOUNTS.lock().get(path).map(|mount| mount.fs.clone())
+}
+
+/// Resolve `path` one component at a time, from the root or the working
+/// directory. `..` steps back out of whatever was actually entered, so it
+/// leaves symlinks and mounts the way it came in. The last component is
+/// only followed if it's a symlink and `follow` is set.
+async fn walk(path: &str, follow: bool) -> Result<Vec<Dentry>, Error> {
+    let root = mounted("/").ok_or(Error::NotFound)?;
+    let mut stack = vec![Dentry {
+        name: String::new(),
+        inode: root.root(),
+    }];
+
+    let mut queue = VecDeque::new();
+    if !path.starts_with('/') {
+        queue.extend(super::cwd().split('/').map(|c| c.to_owned()));
+    }
+    queue.extend(path.split('/').map(|c| c.to_owned()));
+
+    let mut links = 0;
+    while let Some(name) = queue.pop_front() {
+        match name.as_str() {
+            "" | "." => continue,
+            ".." => {
+                if stack.len() > 1 {
+                    stack.pop();
+                }
+                continue;
+            }
+            _ => {}
+        }
+
+        let parent = &stack.last().unwrap().inode;
+        if parent.kind() != FileType::Directory {
+            return Err(Error::NotADirectory);
+        }
+        let mut inode = parent.lookup(&name).await?;
+        stack.push(Dentry {
+            name,
+            inode: inode.clone(),
+        });
+        if let Some(fs) = mounted(&canonical(&stack)) {
+            inode = fs.root();
+            stack.last_mut().unwrap().inode = inode.clone();
+        }
+
+        if inode.kind() == FileType::Symlink && (follow || !queue.is_empty()) {
+            links += 1;
+            if links > MAX_LINKS {
+                return Err(Error::TooManyLinks);
+            }
+            let target = inode.read_link().await?;
+            stack.pop();
+            if target.starts_with('/') {
+                stack.truncate(1);
+            }
+            for component in target.split('/').rev() {
+                queue.push_front(component.to_owned());
+            }
+        }
+    }
+    Ok(stack)
+}
+
+/// The canonical path of `path` and what it refers to.
+pub(super) async fn resolve(path: &str, follow: bool) -> Result<(String, Arc<dyn Inode>), Error> {
+    let stack = walk(path, follow).await?;
+    Ok((canonical(&stack), stack.last().unwrap().inode.clone()))
+}
+
+/// Resolve the directory `path` is in, returning it and the last component.
+async fn walk_parent(path: &str) -> Result<(Vec<Dentry>, String), Error> {
+    let path = path.trim_end_matches('/');
+    let (dir, name) = match path.rsplit_once('/') {
+        Some(("", name)) => ("/", name),
+        Some((dir, name)) => (dir, name),
+        None => (".", path),
+    };
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(Error::InvalidName);
+    }
+    Ok((walk(dir, true).await?, name.to_owned()))
+}
+
+pub(super) async fn create(path: &str, kind: FileType) -> Result<Arc<dyn Inode>, Error> {
+    let (stack, name) = walk_parent(path).await?;
+    stack.last().unwrap().inode.create(&name, kind).await
+}
+
+pub async fn stat(path: &str) -> Result<Stat, Error> {
+    resolve(path, true).await?.1.stat().await
+}
+
+/// Like [`stat`], but about a symlink itself rather than its target.
+pub async fn lstat(path: &str) -> Result<Stat, Error> {
+    resolve(path, false).await?.1.stat().await
+}
+
+pub async fn read_dir(path: &str) -> Result<Vec<DirEntry>, Error> {
+    resolve(path, true).await?.1.read_dir().await
+}
+
+pub async fn create_dir(path: &str) -> Result<(), Error> {
+    create(path, FileType::Directory).await?;
+    Ok(())
+}
+
+pub async fn symlink(target: &str, path: &str) -> Result<(), Error> {
+    let (stack, name) = walk_parent(path).await?;
+    stack.last().unwrap().inode.symlink(&name, target).await
+}
+
+pub async fn read_link(path: &str) -> Result<String, Error> {
+    resolve(path, false).await?.1.read_link().await
+}
+
+/// Remove a file, symlink or empty directory.
+pub async fn remove(path: &str) -> Result<(), Error> {
+    let (stack, name) = walk_parent(path).await?;
+    let dir = canonical(&stack);
+    let path = match dir.as_str() {
+        "/" => format!("/{name}"),
+        _ => format!("{dir}/{name}"),
+    };
+    if MOUNTS.lock().contains_key(&path) {
+        return Err(Error::Busy);
+    }
+    stack.last().unwrap().inode.remove(&name).await
+}
+
+/// Mount `fs` on the directory `path`. The first mount has to be `/`.
+pub async fn mount(fs: Arc<dyn FileSystem>, source: &str, path: &str) -> Result<(), Error> {
+    let path = if path.trim_matches('/').is_empty() {
+        "/".to_owned()
+    } else {
+        let (path, inode) = resolve(path, true).await?;
+        if inode.kind() != FileType::Directory {
+            return Err(Error::NotADirectory);
+        }
+        path
+    };
+
+    let mut mounts = MOUNTS.lock();
+    if mounts.contains_key(&path) {
+        return Err(Error::Busy);
+    }
+    info!("fs: mounted {source} ({}) on {path}", fs.name());
+    mounts.insert(
+        path.clone(),
+        Mount {
+            path,
+            source: source.to_owned(),
+            fs,
+        },
+    );
+    Ok(())
+}
+
+/// Mount the block device `device` on `path`, as the filesystem `kind` or
+/// whichever one recognizes it.
+pub async fn mount_device(device: &str, path: &str, kind: Option<&str>) -> Result<(), Error> {
+    let block = crate::block::get(device)?;
+    for (name, probe) in FILESYSTEMS {
+        if kind.is_some_and(|kind| kind != *name) {
+            continue;
+        }
+        match probe(block.clone()).await {
+            Ok(fs) => return mount(fs, device, path).await,
+            Err(Error::UnknownFilesystem) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(Error::UnknownFilesystem)
+}
+
+/// Every mount, sorted by path.
+pub fn mounts() -> Vec<Mount> {
+    MOUNTS.lock().values().cloned().collect()
+}
@@ -37,9 +37,11 @@ use prelude::*;
 
 mod allocator;
 mod arch;
+mod block;
 mod debug;
 mod drivers;
 mod framebuffer;
+mod fs;
 mod local;
 mod net;
 mod panic;
@@ -61,6 +63,14 @@ fn start() -> ! {
 
     arch::init();
 
+    net::syslog::init();
+
+    net::init();
+
+    block::init();
+
+    fs::init();
+
     shell::start();
 
     drivers::init();
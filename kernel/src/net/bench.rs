@@ -0,0 +1,165 @@
+//! TCP throughput over loopback interfaces, which measures the stack itself:
+//! with no NIC in the way, what's left is socket and interface locking,
+//! copies and smoltcp's own processing.
+//!
+//! Every stream gets a loopback interface of its own, so with per-interface
+//! locks they only meet in the executor. Running the same streams with every
+//! interface lock also taking one global lock shows what that buys.
+
+use super::{lock, loopback::Loopback, Error, InterfaceRef, TaskHandle, TcpSocket, INTERFACES};
+use alloc::sync::Arc;
+use core::{
+    net::{Ipv4Addr, SocketAddr},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use futures::future::join_all;
+use maitake::time::Instant;
+
+const PORT: u16 = 5202;
+
+const BLOCK_SIZE: usize = 16 * 1024;
+
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// One interface each, numbered into `127.1.0.0/16`.
+pub const MAX_STREAMS: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Locking {
+    /// Each interface has its own lock, as the stack normally runs.
+    PerInterface,
+    /// Every interface lock also takes one global lock.
+    Global,
+}
+
+#[derive(Debug)]
+pub struct Report {
+    pub locking: Locking,
+    pub streams: usize,
+    pub bytes: u64,
+    pub elapsed: Duration,
+    /// Interface lock activity during the run, from every task in the
+    /// system, not just the benchmark.
+    pub locks: lock::Stats,
+}
+
+impl Report {
+    pub fn mbits_per_sec(&self) -> f64 {
+        self.bytes as f64 * 8.0 / self.elapsed.as_secs_f64().max(1e-6) / 1e6
+    }
+}
+
+/// Loopback interfaces that go away again with the benchmark.
+struct Interfaces(Vec<(InterfaceRef, TaskHandle)>);
+
+impl Interfaces {
+    fn new(count: usize) -> Self {
+        Self(
+            (0..count)
+                .map(|i| {
+                    let (iface, task) = super::add_interface(format!("bench{i}"), Loopback::new());
+                    let mut inner = iface.lock();
+                    inner.loopback = true;
+                    inner.iface.update_ip_addrs(|addrs| {
+                        let _ = addrs.push(smoltcp::wire::IpCidr::new(address(i).into(), 32));
+                    });
+                    drop(inner);
+                    (iface, task)
+                })
+                .collect(),
+        )
+    }
+}
+
+impl Drop for Interfaces {
+    fn drop(&mut self) {
+        INTERFACES
+            .lock()
+            .retain(|i| !self.0.iter().any(|(iface, _)| Arc::ptr_eq(i, iface)));
+        for (_, task) in &self.0 {
+            task.cancel();
+        }
+    }
+}
+
+fn address(stream: usize) -> Ipv4Addr {
+    let n = stream + 1;
+    Ipv4Addr::new(127, 1, (n >> 8) as u8, n as u8)
+}
+
+/// Push data through `streams` loopback connections for `duration`. Every
+/// sender and receiver is its own task, so they spread over the cores.
+pub async fn loopback(
+    streams: usize,
+    duration: Duration,
+    locking: Locking,
+) -> Result<Report, Error> {
+    let streams = streams.clamp(1, MAX_STREAMS);
+    let interfaces = Interfaces::new(streams);
+
+    let mut pairs = Vec::new();
+    for i in 0..streams {
+        let addr = SocketAddr::from((address(i), PORT));
+        let server = TcpSocket::with_buffer_sizes(BUFFER_SIZE, BUFFER_SIZE)?;
+        server.listen(addr)?;
+        let client = TcpSocket::with_buffer_sizes(BUFFER_SIZE, BUFFER_SIZE)?;
+        // binding ties the client to the stream's interface
+        client.bind((address(i), 0))?;
+        let (accepted, connected) = futures::join!(server.accept(), client.connect(addr));
+        accepted?;
+        connected?;
+        pairs.push((Arc::new(server), Arc::new(client)));
+    }
+
+    let serialized = (locking == Locking::Global).then(lock::serialize);
+    let received = Arc::new(AtomicU64::new(0));
+    let before = lock::stats();
+    let start = Instant::now();
+
+    let mut tasks = Vec::new();
+    for (server, client) in &pairs {
+        let client = client.clone();
+        tasks.push(crate::task::spawn(async move {
+            let buf = vec![0xa5; BLOCK_SIZE];
+            while start.elapsed() < duration {
+                if client.write(&buf).await.is_err() {
+                    break;
+                }
+            }
+            client.shutdown();
+        }));
+
+        let server = server.clone();
+        let received = received.clone();
+        tasks.push(crate::task::spawn(async move {
+            let mut buf = vec![0; BLOCK_SIZE];
+            while let Ok(n @ 1..) = server.read(&mut buf).await {
+                received.fetch_add(n as u64, Ordering::Relaxed);
+            }
+        }));
+    }
+    join_all(tasks).await;
+
+    let elapsed = start.elapsed();
+    let after = lock::stats();
+    drop(serialized);
+
+    for (server, client) in &pairs {
+        server.close().await;
+        client.close().await;
+    }
+    drop(pairs);
+    drop(interfaces);
+
+    Ok(Report {
+        locking,
+        streams,
+        bytes: received.load(Ordering::Relaxed),
+        elapsed,
+        locks: lock::Stats {
+            acquired: after.acquired - before.acquired,
+            contended: after.contended - before.contended,
+        },
+    })
+}
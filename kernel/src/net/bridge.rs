@@ -0,0 +1,433 @@
+//! Learning Ethernet bridges.
+//!
+//! A bridge takes over the [`Ports`] of its member interfaces: everything
+//! they receive is forwarded by MAC address, and the members' own interfaces
+//! go quiet until they leave the bridge. The bridge has an interface of its
+//! own (`br0`, ...) which starts out unaddressed, so it only takes part in
+//! the network once given an address or DHCP.
+//!
+//! Both of our NIC drivers already receive in promiscuous mode.
+
+use super::{vlan::Ports, Error, InterfaceRef, TaskHandle, INTERFACES};
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+};
+use core::{
+    sync::atomic::{AtomicUsize, Ordering},
+    task::{Context, Poll},
+    time::Duration,
+};
+use futures::task::AtomicWaker;
+use maitake::time::Instant;
+use rand::{rngs::OsRng, RngCore};
+use spin::Mutex;
+
+/// How long a learned address is trusted without hearing from it again.
+const AGEING_TIME: Duration = Duration::from_secs(300);
+
+const MAX_ENTRIES: usize = 1024;
+
+const QUEUE_LEN: usize = 64;
+
+const MTU: usize = 1500;
+
+type Mac = [u8; 6];
+
+fn is_multicast(mac: &[u8]) -> bool {
+    mac[0] & 1 != 0
+}
+
+struct Member {
+    name: String,
+    iface: InterfaceRef,
+    ports: Arc<Ports>,
+    /// What the member was running before it joined, to restore on leave.
+    dhcp4: bool,
+    auto6: bool,
+}
+
+struct Learned {
+    ports: Arc<Ports>,
+    seen: Instant,
+}
+
+pub(super) struct Bridge {
+    mac: Mac,
+    members: Mutex<Vec<Member>>,
+    table: Mutex<BTreeMap<Mac, Learned>>,
+    rx: Mutex<VecDeque<Vec<u8>>>,
+    waker: AtomicWaker,
+}
+
+impl Bridge {
+    fn learn(&self, mac: Mac, ports: Arc<Ports>) {
+        let now = Instant::now();
+        let mut table = self.table.lock();
+        if table.len() >= MAX_ENTRIES && !table.contains_key(&mac) {
+            table.retain(|_, l| l.seen.elapsed() < AGEING_TIME);
+            if table.len() >= MAX_ENTRIES {
+                return;
+            }
+        }
+        table.insert(mac, Learned { ports, seen: now });
+    }
+
+    /// The member a unicast address was last seen behind.
+    fn lookup(&self, mac: &Mac) -> Option<Arc<Ports>> {
+        let table = self.table.lock();
+        let learned = table.get(mac)?;
+        if learned.seen.elapsed() >= AGEING_TIME {
+            return None;
+        }
+        Some(learned.ports.clone())
+    }
+
+    fn deliver(&self, frame: &[u8]) {
+        let mut rx = self.rx.lock();
+        if rx.len() < QUEUE_LEN {
+            rx.push_back(frame.to_vec());
+            drop(rx);
+            self.waker.wake();
+        }
+    }
+
+    /// Send to every member except the one it came in on.
+    fn flood(&self, from: Option<&Ports>, frame: &[u8]) {
+        for member in self.members.lock().iter() {
+            if !from.is_some_and(|f| core::ptr::eq(f, &*member.ports)) {
+                member.ports.send(frame.to_vec());
+            }
+        }
+    }
+
+    /// Forward a frame received by one of the members.
+    pub(super) fn input(&self, from: &Ports, frame: &[u8]) {
+        if frame.len() < 14 {
+            return;
+        }
+        let dst: Mac = frame[0..6].try_into().unwrap();
+        let src: Mac = frame[6..12].try_into().unwrap();
+
+        if !is_multicast(&src) {
+            let ports = self
+                .members
+                .lock()
+                .iter()
+                .find(|m| core::ptr::eq(&*m.ports, from))
+                .map(|m| m.ports.clone());
+            if let Some(ports) = ports {
+                self.learn(src, ports);
+            }
+        }
+
+        if is_multicast(&dst) {
+            self.deliver(frame);
+            self.flood(Some(from), frame);
+        } else if dst == self.mac {
+            self.deliver(frame);
+        } else {
+            match self.lookup(&dst) {
+                Some(ports) if !core::ptr::eq(&*ports, from) => ports.send(frame.to_vec()),
+                // already on the segment it came from
+                Some(_) => {}
+                None => self.flood(Some(from), frame),
+            }
+        }
+    }
+
+    /// Forward a frame sent by the bridge's own interface.
+    fn output(&self, frame: Vec<u8>) {
+        if frame.len() < 14 {
+            return;
+        }
+        let dst: Mac = frame[0..6].try_into().unwrap();
+        if is_multicast(&dst) {
+            self.flood(None, &frame);
+            return;
+        }
+        match self.lookup(&dst) {
+            Some(ports) => ports.send(frame),
+            None => self.flood(None, &frame),
+        }
+    }
+}
+
+struct Frame(Vec<u8>);
+
+impl smoltcp::phy::RxToken for Frame {
+    fn consume<R, F>(self, f: F) -> R
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        f(&self.0)
+    }
+}
+
+struct TxToken {
+    bridge: Arc<Bridge>,
+}
+
+impl smoltcp::phy::TxToken for TxToken {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut frame = vec![0; len];
+        let result = f(&mut frame);
+        self.bridge.output(frame);
+        result
+    }
+}
+
+/// The bridge's own interface, as seen from the stack.
+struct Device {
+    bridge: Arc<Bridge>,
+}
+
+impl smoltcp::phy::Device for Device {
+    type RxToken<'a>
+        = Frame
+    where
+        Self: 'a;
+    type TxToken<'a>
+        = TxToken
+    where
+        Self: 'a;
+
+    fn receive(
+        &mut self,
+        _timestamp: smoltcp::time::Instant,
+    ) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        let frame = self.bridge.rx.lock().pop_front()?;
+        Some((
+            Frame(frame),
+            TxToken {
+                bridge: self.bridge.clone(),
+            },
+        ))
+    }
+
+    fn transmit(&mut self, _timestamp: smoltcp::time::Instant) -> Option<Self::TxToken<'_>> {
+        Some(TxToken {
+            bridge: self.bridge.clone(),
+        })
+    }
+
+    fn capabilities(&self) -> smoltcp::phy::DeviceCapabilities {
+        let mut caps = smoltcp::phy::DeviceCapabilities::default();
+        caps.medium = smoltcp::phy::Medium::Ethernet;
+        caps.max_transmission_unit = MTU;
+        caps
+    }
+}
+
+impl super::Driver for Device {
+    fn address(&self) -> smoltcp::wire::HardwareAddress {
+        smoltcp::wire::EthernetAddress(self.bridge.mac).into()
+    }
+
+    fn poll(&self, cx: &mut Context) -> Poll<()> {
+        self.bridge.waker.register(cx.waker());
+        if self.bridge.rx.lock().is_empty() {
+            Poll::Pending
+        } else {
+            Poll::Ready(())
+        }
+    }
+}
+
+struct Entry {
+    name: String,
+    bridge: Arc<Bridge>,
+    iface: InterfaceRef,
+    task: TaskHandle,
+}
+
+lazy_static::lazy_static! {
+    static ref BRIDGES: Mutex<Vec<Entry>> = Mutex::new(vec![]);
+}
+
+static BRIDGE_INDEX: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug)]
+pub struct BridgeConfig {
+    pub name: String,
+    pub members: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct FdbEntry {
+    pub mac: [u8; 6],
+    pub port: String,
+    pub age: Duration,
+}
+
+fn find(name: &str) -> Result<Arc<Bridge>, Error> {
+    BRIDGES
+        .lock()
+        .iter()
+        .find(|b| b.name == name)
+        .map(|b| b.bridge.clone())
+        .ok_or(Error::NoSuchInterface)
+}
+
+/// Create a new, empty bridge and return its name.
+pub fn create() -> String {
+    let mut mac = [0; 6];
+    OsRng.fill_bytes(&mut mac);
+    // locally administered unicast
+    mac[0] = (mac[0] & 0xfe) | 0x02;
+
+    let bridge = Arc::new(Bridge {
+        mac,
+        members: Mutex::new(vec![]),
+        table: Mutex::new(BTreeMap::new()),
+        rx: Mutex::new(VecDeque::new()),
+        waker: AtomicWaker::new(),
+    });
+    let name = format!("br{}", BRIDGE_INDEX.fetch_add(1, Ordering::Relaxed));
+    let device = Device {
+        bridge: bridge.clone(),
+    };
+    let (iface, task) = super::add_interface(name.clone(), device);
+
+    BRIDGES.lock().push(Entry {
+        name: name.clone(),
+        bridge,
+        iface,
+        task,
+    });
+
+    debug!("[NET] {name} registered");
+
+    name
+}
+
+pub fn destroy(name: &str) -> Result<(), Error> {
+    let entry = {
+        let mut bridges = BRIDGES.lock();
+        let index = bridges
+            .iter()
+            .position(|b| b.name == name)
+            .ok_or(Error::NoSuchInterface)?;
+        bridges.remove(index)
+    };
+
+    let members = core::mem::take(&mut *entry.bridge.members.lock());
+    for member in members {
+        release(member);
+    }
+
+    super::stop_dhcp4(&entry.iface);
+    super::stop_auto6(&entry.iface);
+    INTERFACES.lock().retain(|i| !Arc::ptr_eq(i, &entry.iface));
+    entry.task.cancel();
+
+    debug!("[NET] {name} removed");
+
+    Ok(())
+}
+
+/// Make the Ethernet interface `port` a member of `name`.
+pub fn add_port(name: &str, port: &str) -> Result<(), Error> {
+    let bridge = find(name)?;
+    let iface = super::find_interface(port)?;
+    let ports = iface
+        .lock()
+        .vlan_ports
+        .clone()
+        .filter(|p| matches!(p.mac(), smoltcp::wire::HardwareAddress::Ethernet(_)))
+        .ok_or(Error::NotEthernet)?;
+
+    {
+        let mut current = ports.bridge.lock();
+        if current.is_some() {
+            return Err(Error::InterfaceExists);
+        }
+        *current = Some(bridge.clone());
+    }
+
+    let (dhcp4, auto6) = {
+        let inner = iface.lock();
+        (inner.dhcp4.is_some(), inner.auto6.is_some())
+    };
+    // the member's own addresses stop working while it is bridged
+    super::stop_dhcp4(&iface);
+    super::stop_auto6(&iface);
+
+    bridge.members.lock().push(Member {
+        name: port.to_owned(),
+        iface,
+        ports,
+        dhcp4,
+        auto6,
+    });
+
+    Ok(())
+}
+
+pub fn remove_port(name: &str, port: &str) -> Result<(), Error> {
+    let bridge = find(name)?;
+    let member = {
+        let mut members = bridge.members.lock();
+        let index = members
+            .iter()
+            .position(|m| m.name == port)
+            .ok_or(Error::NoSuchInterface)?;
+        members.remove(index)
+    };
+    bridge
+        .table
+        .lock()
+        .retain(|_, l| !Arc::ptr_eq(&l.ports, &member.ports));
+    release(member);
+    Ok(())
+}
+
+fn release(member: Member) {
+    *member.ports.bridge.lock() = None;
+    if member.dhcp4 {
+        super::start_dhcp4(&member.iface);
+    }
+    if member.auto6 {
+        super::start_auto6(&member.iface);
+    }
+}
+
+pub fn list() -> Vec<BridgeConfig> {
+    BRIDGES
+        .lock()
+        .iter()
+        .map(|b| BridgeConfig {
+            name: b.name.clone(),
+            members: b
+                .bridge
+                .members
+                .lock()
+                .iter()
+                .map(|m| m.name.clone())
+                .collect(),
+        })
+        .collect()
+}
+
+/// The live entries of the bridge's MAC table.
+pub fn fdb(name: &str) -> Result<Vec<FdbEntry>, Error> {
+    let bridge = find(name)?;
+    let members = bridge.members.lock();
+    let table = bridge.table.lock();
+    Ok(table
+        .iter()
+        .filter(|(_, l)| l.seen.elapsed() < AGEING_TIME)
+        .map(|(mac, l)| FdbEntry {
+            mac: *mac,
+            port: members
+                .iter()
+                .find(|m| Arc::ptr_eq(&m.ports, &l.ports))
+                .map(|m| m.name.clone())
+                .unwrap_or_default(),
+            age: l.seen.elapsed(),
+        })
+        .collect())
+}
@@ -0,0 +1,560 @@
+//! DHCPv6 client (RFC 8415). Started by `auto6` when a router advertisement
+//! sets the MANAGED (stateful) or OTHER (stateless) flag.
+
+use super::{InterfaceRef, UdpSocket};
+use core::time::Duration;
+use maitake::time::Instant;
+use rand::{rngs::OsRng, Rng};
+use smoltcp::wire::Ipv6Address;
+
+const CLIENT_PORT: u16 = 546;
+const SERVER_PORT: u16 = 547;
+const ALL_DHCP_AGENTS: Ipv6Address = Ipv6Address::new(0xff02, 0, 0, 0, 0, 0, 1, 2);
+
+const MSG_SOLICIT: u8 = 1;
+const MSG_ADVERTISE: u8 = 2;
+const MSG_REQUEST: u8 = 3;
+const MSG_RENEW: u8 = 5;
+const MSG_REBIND: u8 = 6;
+const MSG_REPLY: u8 = 7;
+const MSG_INFORMATION_REQUEST: u8 = 11;
+
+const OPTION_CLIENTID: u16 = 1;
+const OPTION_SERVERID: u16 = 2;
+const OPTION_IA_NA: u16 = 3;
+const OPTION_IAADDR: u16 = 5;
+const OPTION_ORO: u16 = 6;
+const OPTION_PREFERENCE: u16 = 7;
+const OPTION_ELAPSED_TIME: u16 = 8;
+const OPTION_STATUS_CODE: u16 = 13;
+const OPTION_DNS_SERVERS: u16 = 23;
+const OPTION_INFORMATION_REFRESH_TIME: u16 = 32;
+
+const STATUS_SUCCESS: u16 = 0;
+
+const IRT_DEFAULT: Duration = Duration::from_secs(86400);
+const IRT_MINIMUM: Duration = Duration::from_secs(600);
+
+struct Retransmit {
+    irt: Duration,
+    mrt: Duration,
+    mrc: Option<u32>,
+    mrd: Option<Duration>,
+}
+
+const SOLICIT: Retransmit = Retransmit {
+    irt: Duration::from_secs(1),
+    mrt: Duration::from_secs(3600),
+    mrc: None,
+    mrd: None,
+};
+
+const REQUEST: Retransmit = Retransmit {
+    irt: Duration::from_secs(1),
+    mrt: Duration::from_secs(30),
+    mrc: Some(10),
+    mrd: None,
+};
+
+const INFORMATION_REQUEST: Retransmit = Retransmit {
+    irt: Duration::from_secs(1),
+    mrt: Duration::from_secs(3600),
+    mrc: None,
+    mrd: None,
+};
+
+const RENEW_TIMEOUT: Duration = Duration::from_secs(10);
+const RENEW_MAX_RT: Duration = Duration::from_secs(600);
+const REBIND_TIMEOUT: Duration = Duration::from_secs(10);
+const REBIND_MAX_RT: Duration = Duration::from_secs(600);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Addresses and configuration are leased from a server.
+    Stateful,
+    /// Addresses come from SLAAC, only configuration is fetched.
+    Stateless,
+}
+
+#[derive(Default)]
+pub(super) struct Lease {
+    pub(super) addresses: Vec<Ipv6Address>,
+    pub(super) dns_servers: Vec<Ipv6Address>,
+}
+
+pub(super) struct State {
+    pub(super) mode: Mode,
+    pub(super) task: super::TaskHandle,
+    pub(super) lease: Lease,
+}
+
+#[derive(Clone, Copy)]
+struct IaAddress {
+    address: Ipv6Address,
+    preferred: u32,
+    valid: u32,
+}
+
+struct IaNa {
+    t1: u32,
+    t2: u32,
+    status: u16,
+    addresses: Vec<IaAddress>,
+}
+
+#[derive(Default)]
+struct Reply {
+    msg_type: u8,
+    client_id: Option<Vec<u8>>,
+    server_id: Option<Vec<u8>>,
+    status: u16,
+    preference: u8,
+    ia_na: Option<IaNa>,
+    dns_servers: Vec<Ipv6Address>,
+    refresh_time: Option<u32>,
+}
+
+fn options(mut data: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    core::iter::from_fn(move || {
+        if data.len() < 4 {
+            return None;
+        }
+        let code = u16::from_be_bytes([data[0], data[1]]);
+        let len = u16::from_be_bytes([data[2], data[3]]) as usize;
+        let value = data.get(4..4 + len)?;
+        data = &data[4 + len..];
+        Some((code, value))
+    })
+}
+
+fn read_u32(data: &[u8]) -> u32 {
+    u32::from_be_bytes(data[..4].try_into().unwrap())
+}
+
+fn read_address(data: &[u8]) -> Ipv6Address {
+    <[u8; 16]>::try_from(&data[..16]).unwrap().into()
+}
+
+fn status_code(value: &[u8]) -> u16 {
+    match value {
+        [a, b, ..] => u16::from_be_bytes([*a, *b]),
+        _ => STATUS_SUCCESS,
+    }
+}
+
+impl IaNa {
+    fn parse(value: &[u8]) -> Option<Self> {
+        if value.len() < 12 {
+            return None;
+        }
+        let mut ia = IaNa {
+            t1: read_u32(&value[4..]),
+            t2: read_u32(&value[8..]),
+            status: STATUS_SUCCESS,
+            addresses: vec![],
+        };
+        for (code, value) in options(&value[12..]) {
+            match code {
+                OPTION_IAADDR if value.len() >= 24 => {
+                    // the server can refuse a single address with a nested status
+                    if options(&value[24..])
+                        .any(|(c, v)| c == OPTION_STATUS_CODE && status_code(v) != STATUS_SUCCESS)
+                    {
+                        continue;
+                    }
+                    ia.addresses.push(IaAddress {
+                        address: read_address(value),
+                        preferred: read_u32(&value[16..]),
+                        valid: read_u32(&value[20..]),
+                    });
+                }
+                OPTION_STATUS_CODE => ia.status = status_code(value),
+                _ => {}
+            }
+        }
+        Some(ia)
+    }
+
+    /// Leased addresses, or `None` if the server did not hand any out.
+    fn bound(&self) -> Option<&[IaAddress]> {
+        let usable = self.addresses.iter().any(|a| a.valid > 0);
+        (self.status == STATUS_SUCCESS && usable).then_some(&self.addresses[..])
+    }
+
+    /// T1, T2 and the shortest valid lifetime, all relative to the reply.
+    fn timers(&self) -> (Duration, Duration, Duration) {
+        let preferred = self
+            .addresses
+            .iter()
+            .map(|a| a.preferred)
+            .min()
+            .unwrap_or(0);
+        let valid = self.addresses.iter().map(|a| a.valid).min().unwrap_or(0);
+        // zero leaves the choice to the client
+        let t1 = if self.t1 == 0 { preferred / 2 } else { self.t1 };
+        let t2 = if self.t2 == 0 {
+            preferred / 5 * 4
+        } else {
+            self.t2
+        };
+        let t2 = t2.max(t1);
+        (
+            Duration::from_secs(t1 as u64),
+            Duration::from_secs(t2 as u64),
+            Duration::from_secs(valid.max(t2) as u64),
+        )
+    }
+}
+
+impl Reply {
+    fn parse(data: &[u8], xid: [u8; 3]) -> Option<Self> {
+        if data.len() < 4 || data[1..4] != xid {
+            return None;
+        }
+        let mut reply = Reply {
+            msg_type: data[0],
+            ..Default::default()
+        };
+        for (code, value) in options(&data[4..]) {
+            match code {
+                OPTION_CLIENTID => reply.client_id = Some(value.to_vec()),
+                OPTION_SERVERID => reply.server_id = Some(value.to_vec()),
+                OPTION_STATUS_CODE => reply.status = status_code(value),
+                OPTION_PREFERENCE => reply.preference = value.first().copied().unwrap_or(0),
+                OPTION_IA_NA if reply.ia_na.is_none() => reply.ia_na = IaNa::parse(value),
+                OPTION_DNS_SERVERS => reply
+                    .dns_servers
+                    .extend(value.chunks_exact(16).map(read_address)),
+                OPTION_INFORMATION_REFRESH_TIME if value.len() >= 4 => {
+                    reply.refresh_time = Some(read_u32(value))
+                }
+                _ => {}
+            }
+        }
+        Some(reply)
+    }
+
+    fn bound(&self) -> Option<&[IaAddress]> {
+        if self.status != STATUS_SUCCESS {
+            return None;
+        }
+        self.ia_na.as_ref()?.bound()
+    }
+}
+
+struct Builder {
+    buf: Vec<u8>,
+}
+
+impl Builder {
+    fn new(msg_type: u8, xid: [u8; 3]) -> Self {
+        let mut buf = vec![msg_type];
+        buf.extend_from_slice(&xid);
+        Self { buf }
+    }
+
+    fn option(mut self, code: u16, value: &[u8]) -> Self {
+        put_option(&mut self.buf, code, value);
+        self
+    }
+
+    fn finish(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+fn put_option(buf: &mut Vec<u8>, code: u16, value: &[u8]) {
+    buf.extend_from_slice(&code.to_be_bytes());
+    buf.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    buf.extend_from_slice(value);
+}
+
+fn ia_na(iaid: u32, addresses: &[IaAddress]) -> Vec<u8> {
+    let mut value = vec![];
+    value.extend_from_slice(&iaid.to_be_bytes());
+    // T1 and T2 are left to the server
+    value.extend_from_slice(&[0; 8]);
+    for address in addresses {
+        let mut iaaddr = address.address.octets().to_vec();
+        iaaddr.extend_from_slice(&[0; 8]);
+        put_option(&mut value, OPTION_IAADDR, &iaaddr);
+    }
+    value
+}
+
+/// RT +/- 10%, as every retransmission timeout is randomized.
+fn randomize(rt: Duration) -> Duration {
+    let ms = rt.as_millis() as i64;
+    Duration::from_millis((ms + ms * OsRng.gen_range(-100..=100) / 1000) as u64)
+}
+
+struct Client {
+    sock: UdpSocket,
+    duid: Vec<u8>,
+    iaid: u32,
+    mode: Mode,
+}
+
+impl Client {
+    fn message(&self, msg_type: u8, xid: [u8; 3], elapsed: Duration) -> Builder {
+        let elapsed = (elapsed.as_millis() / 10).min(0xffff) as u16;
+        let mut oro = OPTION_DNS_SERVERS.to_be_bytes().to_vec();
+        if self.mode == Mode::Stateless {
+            oro.extend_from_slice(&OPTION_INFORMATION_REFRESH_TIME.to_be_bytes());
+        }
+        Builder::new(msg_type, xid)
+            .option(OPTION_CLIENTID, &self.duid)
+            .option(OPTION_ELAPSED_TIME, &elapsed.to_be_bytes())
+            .option(OPTION_ORO, &oro)
+    }
+
+    /// Send a message and retransmit it with exponential backoff until a
+    /// reply passes `accept` or the retransmission limits run out.
+    async fn exchange(
+        &self,
+        msg_type: u8,
+        params: &Retransmit,
+        build: impl Fn(Builder) -> Builder,
+        accept: impl Fn(&Reply) -> bool,
+    ) -> Option<Reply> {
+        let xid = {
+            let xid = OsRng.gen::<u32>().to_be_bytes();
+            [xid[1], xid[2], xid[3]]
+        };
+        let start = Instant::now();
+        let mut rt = randomize(params.irt);
+        let mut count = 0;
+
+        loop {
+            let elapsed = start.elapsed();
+            let remaining = match params.mrd {
+                Some(mrd) if elapsed >= mrd => return None,
+                Some(mrd) => mrd - elapsed,
+                None => Duration::MAX,
+            };
+
+            let packet = build(self.message(msg_type, xid, elapsed)).finish();
+            if let Err(e) = self
+                .sock
+                .send_to(&packet, (ALL_DHCP_AGENTS, SERVER_PORT))
+                .await
+            {
+                error!("[DHCPv6] send error {e}");
+            }
+            count += 1;
+
+            let deadline = Instant::now() + rt.min(remaining);
+            while let Some(wait) = deadline.checked_duration_since(Instant::now()) {
+                let mut buf = [0; 1500];
+                match maitake::time::timeout(wait, self.sock.recv_from(&mut buf)).await {
+                    Ok(Ok((n, _))) => {
+                        if let Some(reply) = Reply::parse(&buf[..n], xid) {
+                            if reply.client_id.as_deref() == Some(&self.duid[..]) && accept(&reply)
+                            {
+                                return Some(reply);
+                            }
+                        }
+                    }
+                    Ok(Err(e)) => info!("[DHCPv6] recv error {e}"),
+                    Err(_) => break,
+                }
+            }
+
+            if params.mrc.is_some_and(|mrc| count >= mrc) {
+                return None;
+            }
+            rt = if rt * 2 > params.mrt {
+                randomize(params.mrt)
+            } else {
+                randomize(rt * 2)
+            };
+        }
+    }
+
+    /// Install `lease` in place of the previous one. Returns false if the
+    /// client has been stopped in the meantime.
+    fn apply(&self, iface: &InterfaceRef, lease: Lease) -> bool {
+        let mut inner = iface.lock();
+        let Some(state) = inner.dhcp6.as_mut() else {
+            return false;
+        };
+        let old = core::mem::replace(&mut state.lease, Lease::default());
+        inner.remove_dhcp6(&old);
+        inner.apply_dhcp6(&lease);
+        if let Some(state) = inner.dhcp6.as_mut() {
+            state.lease = lease;
+        }
+        true
+    }
+
+    async fn stateless(&self, iface: &InterfaceRef) {
+        loop {
+            let Some(reply) = self
+                .exchange(
+                    MSG_INFORMATION_REQUEST,
+                    &INFORMATION_REQUEST,
+                    |m| m,
+                    |r| r.msg_type == MSG_REPLY,
+                )
+                .await
+            else {
+                continue;
+            };
+
+            let refresh = reply
+                .refresh_time
+                .map(|t| Duration::from_secs(t as u64).max(IRT_MINIMUM))
+                .unwrap_or(IRT_DEFAULT);
+
+            let lease = Lease {
+                addresses: vec![],
+                dns_servers: reply.dns_servers,
+            };
+            if !self.apply(iface, lease) {
+                return;
+            }
+
+            super::sleep_until(Instant::now() + refresh).await;
+        }
+    }
+
+    async fn stateful(&self, iface: &InterfaceRef) {
+        loop {
+            // take the first usable advertise rather than collecting them
+            let Some(advertise) = self
+                .exchange(
+                    MSG_SOLICIT,
+                    &SOLICIT,
+                    |m| m.option(OPTION_IA_NA, &ia_na(self.iaid, &[])),
+                    |r| r.msg_type == MSG_ADVERTISE && r.server_id.is_some() && r.bound().is_some(),
+                )
+                .await
+            else {
+                continue;
+            };
+            debug!(
+                "[DHCPv6] advertise with preference {}",
+                advertise.preference
+            );
+
+            let mut server_id = advertise.server_id.clone().unwrap();
+            let offered = advertise.bound().unwrap().to_vec();
+            let Some(mut reply) = self
+                .exchange(
+                    MSG_REQUEST,
+                    &REQUEST,
+                    |m| {
+                        m.option(OPTION_SERVERID, &server_id)
+                            .option(OPTION_IA_NA, &ia_na(self.iaid, &offered))
+                    },
+                    |r| r.msg_type == MSG_REPLY && r.server_id.as_deref() == Some(&server_id[..]),
+                )
+                .await
+            else {
+                continue;
+            };
+
+            while let Some(addresses) = reply.bound() {
+                let addresses = addresses.to_vec();
+                let (t1, t2, valid) = reply.ia_na.as_ref().unwrap().timers();
+                let bound_at = Instant::now();
+
+                let lease = Lease {
+                    addresses: addresses
+                        .iter()
+                        .filter(|a| a.valid > 0)
+                        .map(|a| a.address)
+                        .collect(),
+                    dns_servers: core::mem::take(&mut reply.dns_servers),
+                };
+                if !self.apply(iface, lease) {
+                    return;
+                }
+
+                super::sleep_until(bound_at + t1).await;
+
+                let renew = Retransmit {
+                    irt: RENEW_TIMEOUT,
+                    mrt: RENEW_MAX_RT,
+                    mrc: None,
+                    mrd: Some(t2 - t1),
+                };
+                let next = match self
+                    .exchange(
+                        MSG_RENEW,
+                        &renew,
+                        |m| {
+                            m.option(OPTION_SERVERID, &server_id)
+                                .option(OPTION_IA_NA, &ia_na(self.iaid, &addresses))
+                        },
+                        |r| {
+                            r.msg_type == MSG_REPLY
+                                && r.server_id.as_deref() == Some(&server_id[..])
+                        },
+                    )
+                    .await
+                {
+                    Some(reply) => Some(reply),
+                    None => {
+                        // our server is gone, ask any server to extend the lease
+                        let rebind = Retransmit {
+                            irt: REBIND_TIMEOUT,
+                            mrt: REBIND_MAX_RT,
+                            mrc: None,
+                            mrd: Some(valid.saturating_sub(bound_at.elapsed())),
+                        };
+                        self.exchange(
+                            MSG_REBIND,
+                            &rebind,
+                            |m| m.option(OPTION_IA_NA, &ia_na(self.iaid, &addresses)),
+                            |r| r.msg_type == MSG_REPLY && r.server_id.is_some(),
+                        )
+                        .await
+                    }
+                };
+
+                let Some(next) = next else {
+                    break;
+                };
+                server_id = next.server_id.clone().unwrap();
+                reply = next;
+            }
+
+            info!("[DHCPv6] lease lost");
+            if !self.apply(iface, Lease::default()) {
+                return;
+            }
+        }
+    }
+}
+
+pub(super) async fn client(iface: InterfaceRef, mode: Mode) {
+    let mac = match iface.lock().iface.hardware_addr() {
+        smoltcp::wire::HardwareAddress::Ethernet(e) => e.0,
+        smoltcp::wire::HardwareAddress::Ip => return,
+    };
+
+    let sock = match UdpSocket::bind_on(&iface, CLIENT_PORT) {
+        Ok(sock) => sock,
+        Err(e) => {
+            error!("[DHCPv6] bind error {e}");
+            return;
+        }
+    };
+
+    // DUID-LL (type 3, hardware type 1)
+    let mut duid = vec![0, 3, 0, 1];
+    duid.extend_from_slice(&mac);
+
+    let client = Client {
+        sock,
+        duid,
+        iaid: u32::from_be_bytes([mac[2], mac[3], mac[4], mac[5]]),
+        mode,
+    };
+
+    match mode {
+        Mode::Stateful => client.stateful(&iface).await,
+        Mode::Stateless => client.stateless(&iface).await,
+    }
+}
@@ -0,0 +1,484 @@
+//! Packet filter.
+//!
+//! Every driver is wrapped in a [`Filter`], which runs frames through one
+//! global, ordered rule table on the way in and out. The first rule that
+//! matches with `accept`, `drop` or `limit` decides; `count` rules only
+//! count. Anything left over is accepted. With no rules, frames are passed
+//! through untouched.
+//!
+//! The filter sits below the VLAN trunk, so VLAN and bridge traffic is seen
+//! on the NIC it uses. Tagged frames match the name of their VLAN interface.
+//! A bridge has no filter of its own, its traffic is matched on its ports,
+//! and rules naming an interface without a filter are refused.
+
+use super::{
+    vlan::{Frame, RxToken},
+    Error,
+};
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll},
+    time::Duration,
+};
+use maitake::time::Instant;
+use smoltcp::wire::{EthernetAddress, IpAddress, IpCidr, IpProtocol};
+use spin::Mutex;
+
+const VLAN_TPID: u16 = 0x8100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    In,
+    Out,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+    /// ICMP or ICMPv6.
+    Icmp,
+    Other(u8),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Action {
+    #[default]
+    Accept,
+    Drop,
+    Count,
+    /// Accept up to this many packets per second and drop the rest.
+    Limit(u32),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Rule {
+    pub direction: Option<Direction>,
+    pub iface: Option<String>,
+    pub src_mac: Option<EthernetAddress>,
+    pub dst_mac: Option<EthernetAddress>,
+    pub src: Option<IpCidr>,
+    pub dst: Option<IpCidr>,
+    pub protocol: Option<Protocol>,
+    pub src_port: Option<(u16, u16)>,
+    pub dst_port: Option<(u16, u16)>,
+    pub action: Action,
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.direction {
+            Some(Direction::In) => write!(f, "in ")?,
+            Some(Direction::Out) => write!(f, "out ")?,
+            None => {}
+        }
+        if let Some(iface) = &self.iface {
+            write!(f, "iface {iface} ")?;
+        }
+        if let Some(mac) = self.src_mac {
+            write!(f, "smac {mac} ")?;
+        }
+        if let Some(mac) = self.dst_mac {
+            write!(f, "dmac {mac} ")?;
+        }
+        if let Some(cidr) = self.src {
+            write!(f, "src {cidr} ")?;
+        }
+        if let Some(cidr) = self.dst {
+            write!(f, "dst {cidr} ")?;
+        }
+        match self.protocol {
+            Some(Protocol::Tcp) => write!(f, "proto tcp ")?,
+            Some(Protocol::Udp) => write!(f, "proto udp ")?,
+            Some(Protocol::Icmp) => write!(f, "proto icmp ")?,
+            Some(Protocol::Other(n)) => write!(f, "proto {n} ")?,
+            None => {}
+        }
+        for (name, range) in [("sport", self.src_port), ("dport", self.dst_port)] {
+            match range {
+                Some((lo, hi)) if lo == hi => write!(f, "{name} {lo} ")?,
+                Some((lo, hi)) => write!(f, "{name} {lo}-{hi} ")?,
+                None => {}
+            }
+        }
+        match self.action {
+            Action::Accept => write!(f, "accept"),
+            Action::Drop => write!(f, "drop"),
+            Action::Count => write!(f, "count"),
+            Action::Limit(rate) => write!(f, "limit {rate}/s"),
+        }
+    }
+}
+
+/// What a rule can match on, pulled out of a frame.
+#[derive(Default)]
+struct Summary {
+    vid: Option<u16>,
+    src_mac: Option<EthernetAddress>,
+    dst_mac: Option<EthernetAddress>,
+    src: Option<IpAddress>,
+    dst: Option<IpAddress>,
+    protocol: Option<IpProtocol>,
+    src_port: Option<u16>,
+    dst_port: Option<u16>,
+}
+
+impl Summary {
+    fn parse(medium: smoltcp::phy::Medium, frame: &[u8]) -> Self {
+        use smoltcp::wire::{EthernetFrame, EthernetProtocol};
+
+        let mut summary = Self::default();
+        let packet = match medium {
+            smoltcp::phy::Medium::Ethernet => {
+                let Ok(eth) = EthernetFrame::new_checked(frame) else {
+                    return summary;
+                };
+                summary.src_mac = Some(eth.src_addr());
+                summary.dst_mac = Some(eth.dst_addr());
+                let (ethertype, offset) = match eth.ethertype() {
+                    EthernetProtocol::Unknown(VLAN_TPID) if frame.len() >= 18 => {
+                        summary.vid = Some(u16::from_be_bytes([frame[14], frame[15]]) & 0xfff);
+                        (u16::from_be_bytes([frame[16], frame[17]]).into(), 18)
+                    }
+                    ethertype => (ethertype, 14),
+                };
+                match ethertype {
+                    EthernetProtocol::Ipv4 | EthernetProtocol::Ipv6 => &frame[offset..],
+                    _ => return summary,
+                }
+            }
+            _ => frame,
+        };
+        summary.parse_ip(packet);
+        summary
+    }
+
+    fn parse_ip(&mut self, packet: &[u8]) {
+        use smoltcp::wire::{Ipv4Packet, Ipv6Packet, TcpPacket, UdpPacket};
+
+        let (protocol, payload) = match packet.first().map(|b| b >> 4) {
+            Some(4) => {
+                let Ok(ip) = Ipv4Packet::new_checked(packet) else {
+                    return;
+                };
+                self.src = Some(ip.src_addr().into());
+                self.dst = Some(ip.dst_addr().into());
+                self.protocol = Some(ip.next_header());
+                // only the first fragment carries ports
+                if ip.frag_offset() != 0 {
+                    return;
+                }
+                (ip.next_header(), &packet[ip.header_len() as usize..])
+            }
+            Some(6) => {
+                let Ok(ip) = Ipv6Packet::new_checked(packet) else {
+                    return;
+                };
+                self.src = Some(ip.src_addr().into());
+                self.dst = Some(ip.dst_addr().into());
+                self.protocol = Some(ip.next_header());
+                (ip.next_header(), &packet[40..])
+            }
+            _ => return,
+        };
+
+        match protocol {
+            IpProtocol::Tcp => {
+                if let Ok(tcp) = TcpPacket::new_checked(payload) {
+                    self.src_port = Some(tcp.src_port());
+                    self.dst_port = Some(tcp.dst_port());
+                }
+            }
+            IpProtocol::Udp => {
+                if let Ok(udp) = UdpPacket::new_checked(payload) {
+                    self.src_port = Some(udp.src_port());
+                    self.dst_port = Some(udp.dst_port());
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn in_range(port: Option<u16>, range: Option<(u16, u16)>) -> bool {
+    match range {
+        Some((lo, hi)) => port.is_some_and(|p| (lo..=hi).contains(&p)),
+        None => true,
+    }
+}
+
+impl Rule {
+    fn matches(&self, direction: Direction, iface: &str, summary: &Summary) -> bool {
+        let protocol = match self.protocol {
+            None => true,
+            Some(Protocol::Tcp) => summary.protocol == Some(IpProtocol::Tcp),
+            Some(Protocol::Udp) => summary.protocol == Some(IpProtocol::Udp),
+            Some(Protocol::Icmp) => matches!(
+                summary.protocol,
+                Some(IpProtocol::Icmp | IpProtocol::Icmpv6)
+            ),
+            Some(Protocol::Other(n)) => summary.protocol == Some(IpProtocol::from(n)),
+        };
+        let cidr = |cidr: Option<IpCidr>, addr: Option<IpAddress>| match cidr {
+            Some(cidr) => addr.is_some_and(|a| cidr.contains_addr(&a)),
+            None => true,
+        };
+        self.direction.is_none_or(|d| d == direction)
+            && self.iface.as_deref().is_none_or(|i| match summary.vid {
+                Some(vid) => i
+                    .strip_prefix(iface)
+                    .and_then(|i| i.strip_prefix('.'))
+                    .is_some_and(|i| i.parse() == Ok(vid)),
+                None => i == iface,
+            })
+            && self.src_mac.is_none_or(|m| summary.src_mac == Some(m))
+            && self.dst_mac.is_none_or(|m| summary.dst_mac == Some(m))
+            && cidr(self.src, summary.src)
+            && cidr(self.dst, summary.dst)
+            && protocol
+            && in_range(summary.src_port, self.src_port)
+            && in_range(summary.dst_port, self.dst_port)
+    }
+}
+
+struct Entry {
+    rule: Rule,
+    packets: u64,
+    bytes: u64,
+    window: Instant,
+    window_packets: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct RuleStats {
+    pub rule: Rule,
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+lazy_static::lazy_static! {
+    static ref RULES: Mutex<Vec<Entry>> = Mutex::new(vec![]);
+}
+
+/// Interfaces with a [`Filter`] in front of their driver.
+static FILTERED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Whether frames of `iface`, or of a VLAN on top of it, reach a filter.
+fn is_filtered(iface: &str) -> bool {
+    FILTERED.lock().iter().any(|nic| {
+        iface == nic
+            || iface
+                .strip_prefix(nic.as_str())
+                .and_then(|i| i.strip_prefix('.'))
+                .is_some_and(|vid| vid.parse::<u16>().is_ok())
+    })
+}
+
+/// Set while there are rules, so the common case skips the table entirely.
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Whether a frame may pass.
+fn evaluate(direction: Direction, iface: &str, medium: smoltcp::phy::Medium, frame: &[u8]) -> bool {
+    let summary = Summary::parse(medium, frame);
+    let mut rules = RULES.lock();
+    for entry in rules.iter_mut() {
+        if !entry.rule.matches(direction, iface, &summary) {
+            continue;
+        }
+        entry.packets += 1;
+        entry.bytes += frame.len() as u64;
+        match entry.rule.action {
+            Action::Accept => return true,
+            Action::Drop => return false,
+            Action::Count => {}
+            Action::Limit(rate) => {
+                if entry.window.elapsed() >= Duration::from_secs(1) {
+                    entry.window = Instant::now();
+                    entry.window_packets = 0;
+                }
+                entry.window_packets += 1;
+                return entry.window_packets <= rate;
+            }
+        }
+    }
+    true
+}
+
+pub fn add(rule: Rule) -> Result<(), Error> {
+    if rule.iface.as_deref().is_some_and(|i| !is_filtered(i)) {
+        return Err(Error::NotFiltered);
+    }
+    RULES.lock().push(Entry {
+        rule,
+        packets: 0,
+        bytes: 0,
+        window: Instant::now(),
+        window_packets: 0,
+    });
+    ACTIVE.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+pub fn remove(index: usize) -> Result<Rule, Error> {
+    let mut rules = RULES.lock();
+    if index >= rules.len() {
+        return Err(Error::NoSuchRule);
+    }
+    let entry = rules.remove(index);
+    ACTIVE.store(!rules.is_empty(), Ordering::Relaxed);
+    Ok(entry.rule)
+}
+
+pub fn flush() {
+    RULES.lock().clear();
+    ACTIVE.store(false, Ordering::Relaxed);
+}
+
+pub fn rules() -> Vec<RuleStats> {
+    RULES
+        .lock()
+        .iter()
+        .map(|e| RuleStats {
+            rule: e.rule.clone(),
+            packets: e.packets,
+            bytes: e.bytes,
+        })
+        .collect()
+}
+
+pub(super) struct TxToken<'a, T> {
+    inner: T,
+    iface: &'a str,
+    medium: smoltcp::phy::Medium,
+}
+
+impl<T: smoltcp::phy::TxToken> smoltcp::phy::TxToken for TxToken<'_, T> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        if !ACTIVE.load(Ordering::Relaxed) {
+            return self.inner.consume(len, f);
+        }
+        let mut frame = vec![0; len];
+        let result = f(&mut frame);
+        if evaluate(Direction::Out, self.iface, self.medium, &frame) {
+            self.inner.consume(len, |buf| buf.copy_from_slice(&frame));
+        }
+        result
+    }
+}
+
+pub(super) struct Filter<D> {
+    device: D,
+    iface: String,
+    medium: smoltcp::phy::Medium,
+    /// An accepted frame we could not hand up for lack of a tx token.
+    pending: Option<Vec<u8>>,
+}
+
+impl<D: super::Driver> Filter<D> {
+    pub(super) fn new(iface: String, device: D) -> Self {
+        let medium = device.capabilities().medium;
+        FILTERED.lock().push(iface.clone());
+        Self {
+            device,
+            iface,
+            medium,
+            pending: None,
+        }
+    }
+}
+
+impl<D> Drop for Filter<D> {
+    fn drop(&mut self) {
+        let mut filtered = FILTERED.lock();
+        if let Some(i) = filtered.iter().position(|i| *i == self.iface) {
+            filtered.swap_remove(i);
+        }
+    }
+}
+
+impl<D: super::Driver> smoltcp::phy::Device for Filter<D> {
+    type RxToken<'a>
+        = RxToken<D::RxToken<'a>>
+    where
+        Self: 'a;
+    type TxToken<'a>
+        = TxToken<'a, D::TxToken<'a>>
+    where
+        Self: 'a;
+
+    fn receive(
+        &mut self,
+        timestamp: smoltcp::time::Instant,
+    ) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        use smoltcp::phy::RxToken as _;
+
+        if self.pending.is_none() && !ACTIVE.load(Ordering::Relaxed) {
+            return self.device.receive(timestamp).map(|(rx, tx)| {
+                (
+                    RxToken::Device(rx),
+                    TxToken {
+                        inner: tx,
+                        iface: &self.iface,
+                        medium: self.medium,
+                    },
+                )
+            });
+        }
+
+        let frame = match self.pending.take() {
+            Some(frame) => frame,
+            None => loop {
+                let (rx, _) = self.device.receive(timestamp)?;
+                let accepted = rx.consume(|frame| {
+                    evaluate(Direction::In, &self.iface, self.medium, frame).then(|| frame.to_vec())
+                });
+                if let Some(frame) = accepted {
+                    break frame;
+                }
+            },
+        };
+        match self.device.transmit(timestamp) {
+            Some(tx) => Some((
+                RxToken::Owned(Frame(frame)),
+                TxToken {
+                    inner: tx,
+                    iface: &self.iface,
+                    medium: self.medium,
+                },
+            )),
+            None => {
+                self.pending = Some(frame);
+                None
+            }
+        }
+    }
+
+    fn transmit(&mut self, timestamp: smoltcp::time::Instant) -> Option<Self::TxToken<'_>> {
+        self.device.transmit(timestamp).map(|tx| TxToken {
+            inner: tx,
+            iface: &self.iface,
+            medium: self.medium,
+        })
+    }
+
+    fn capabilities(&self) -> smoltcp::phy::DeviceCapabilities {
+        self.device.capabilities()
+    }
+}
+
+impl<D: super::Driver> super::Driver for Filter<D> {
+    fn address(&self) -> smoltcp::wire::HardwareAddress {
+        self.device.address()
+    }
+
+    fn poll(&self, cx: &mut Context) -> Poll<()> {
+        if self.pending.is_some() {
+            return Poll::Ready(());
+        }
+        self.device.poll(cx)
+    }
+}
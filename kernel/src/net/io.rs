@@ -0,0 +1,142 @@
+//! Buffered I/O over anything implementing the `embedded-io-async` traits,
+//! such as [`TcpSocket`](super::TcpSocket).
+
+use embedded_io_async::{BufRead, ErrorType, Read, Write};
+
+const DEFAULT_CAPACITY: usize = 4096;
+
+/// Buffers both directions of a stream. Writes are only guaranteed to reach
+/// the stream once [`flush`](Write::flush) is called; dropping the wrapper
+/// discards anything still buffered.
+pub struct BufStream<S> {
+    inner: S,
+    read_buf: Box<[u8]>,
+    pos: usize,
+    filled: usize,
+    write_buf: Vec<u8>,
+}
+
+impl<S> BufStream<S> {
+    pub fn new(inner: S) -> Self {
+        Self::with_capacity(DEFAULT_CAPACITY, inner)
+    }
+
+    pub fn with_capacity(capacity: usize, inner: S) -> Self {
+        Self {
+            inner,
+            read_buf: vec![0; capacity].into_boxed_slice(),
+            pos: 0,
+            filled: 0,
+            write_buf: Vec::with_capacity(capacity),
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    /// Unwrap the stream, dropping any buffered data in either direction.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    /// Bytes that have been read from the stream but not consumed.
+    pub fn buffer(&self) -> &[u8] {
+        &self.read_buf[self.pos..self.filled]
+    }
+}
+
+impl<S: Read> BufStream<S> {
+    /// Read up to and including `byte`, appending to `buf`. Returns the
+    /// number of bytes read, which is 0 at end of stream.
+    pub async fn read_until(&mut self, byte: u8, buf: &mut Vec<u8>) -> Result<usize, S::Error> {
+        let mut read = 0;
+        loop {
+            let available = self.fill_buf().await?;
+            if available.is_empty() {
+                return Ok(read);
+            }
+            let (done, used) = match available.iter().position(|b| *b == byte) {
+                Some(i) => (true, i + 1),
+                None => (false, available.len()),
+            };
+            buf.extend_from_slice(&available[..used]);
+            self.consume(used);
+            read += used;
+            if done {
+                return Ok(read);
+            }
+        }
+    }
+
+    /// Read a line, including its `\n`, appending it to `buf`. Invalid UTF-8
+    /// is replaced rather than treated as an error.
+    pub async fn read_line(&mut self, buf: &mut String) -> Result<usize, S::Error> {
+        let mut line = Vec::new();
+        let n = self.read_until(b'\n', &mut line).await?;
+        buf.push_str(&String::from_utf8_lossy(&line));
+        Ok(n)
+    }
+}
+
+impl<S: Write> BufStream<S> {
+    async fn flush_buf(&mut self) -> Result<(), S::Error> {
+        let result = self.inner.write_all(&self.write_buf).await;
+        self.write_buf.clear();
+        result
+    }
+}
+
+impl<S: ErrorType> ErrorType for BufStream<S> {
+    type Error = S::Error;
+}
+
+impl<S: Read> Read for BufStream<S> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, S::Error> {
+        // nothing to gain from copying a large read through the buffer
+        if self.pos == self.filled && buf.len() >= self.read_buf.len() {
+            return self.inner.read(buf).await;
+        }
+        let available = self.fill_buf().await?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<S: Read> BufRead for BufStream<S> {
+    async fn fill_buf(&mut self) -> Result<&[u8], S::Error> {
+        if self.pos == self.filled {
+            self.filled = self.inner.read(&mut self.read_buf).await?;
+            self.pos = 0;
+        }
+        Ok(&self.read_buf[self.pos..self.filled])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos = (self.pos + amt).min(self.filled);
+    }
+}
+
+impl<S: Write> Write for BufStream<S> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, S::Error> {
+        if self.write_buf.len() + buf.len() > self.write_buf.capacity() {
+            self.flush_buf().await?;
+        }
+        if buf.len() >= self.write_buf.capacity() {
+            return self.inner.write(buf).await;
+        }
+        self.write_buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    async fn flush(&mut self) -> Result<(), S::Error> {
+        self.flush_buf().await?;
+        self.inner.flush().await
+    }
+}
@@ -0,0 +1,467 @@
+//! TCP throughput tests speaking the iperf3 protocol, so either end can be a
+//! stock iperf3.
+//!
+//! Parameters and results are exchanged as JSON. We only ever need a handful
+//! of flat fields out of what the peer sends, so they are picked out by key
+//! rather than parsed properly.
+
+use super::{Error, TcpSocket};
+use core::{net::SocketAddr, time::Duration};
+use futures::{future::join_all, FutureExt};
+use maitake::time::Instant;
+use rand::{rngs::OsRng, Rng};
+
+pub const PORT: u16 = 5201;
+
+const COOKIE_SIZE: usize = 37;
+
+const TEST_START: u8 = 1;
+const TEST_RUNNING: u8 = 2;
+const TEST_END: u8 = 4;
+const PARAM_EXCHANGE: u8 = 9;
+const CREATE_STREAMS: u8 = 10;
+const SERVER_TERMINATE: u8 = 11;
+const CLIENT_TERMINATE: u8 = 12;
+const EXCHANGE_RESULTS: u8 = 13;
+const DISPLAY_RESULTS: u8 = 14;
+const IPERF_DONE: u8 = 16;
+const ACCESS_DENIED: u8 = -1i8 as u8;
+const SERVER_ERROR: u8 = -2i8 as u8;
+
+const MAX_STREAMS: usize = 8;
+const MAX_JSON: usize = 64 * 1024;
+
+/// Block size used when sending.
+const BLOCK_SIZE: usize = 128 * 1024;
+
+/// Socket buffering for data streams. The default is sized for
+/// request/response traffic and caps the window at one segment.
+const STREAM_BUFFER_SIZE: usize = 64 * 1024;
+
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy)]
+pub struct Options {
+    pub time: Duration,
+    /// Have the server send and us receive.
+    pub reverse: bool,
+    pub parallel: usize,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            time: Duration::from_secs(10),
+            reverse: false,
+            parallel: 1,
+        }
+    }
+}
+
+/// Traffic over some stretch of a test, summed over all streams.
+#[derive(Debug, Clone, Copy)]
+pub struct Report {
+    pub start: Duration,
+    pub end: Duration,
+    pub bytes: u64,
+    /// Only known when the sender is a stock iperf3 that can see its own
+    /// TCP counters.
+    pub retransmits: Option<u64>,
+}
+
+impl Report {
+    pub fn mbits_per_sec(&self) -> f64 {
+        let secs = (self.end - self.start).as_secs_f64();
+        if secs == 0.0 {
+            return 0.0;
+        }
+        self.bytes as f64 * 8.0 / secs / 1_000_000.0
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Summary {
+    pub peer: SocketAddr,
+    pub sender: Report,
+    pub receiver: Report,
+}
+
+async fn read_state(sock: &TcpSocket) -> Result<u8, Error> {
+    let mut state = [0];
+    sock.read_exact(&mut state).await?;
+    Ok(state[0])
+}
+
+async fn write_state(sock: &TcpSocket, state: u8) -> Result<(), Error> {
+    sock.write_all(&[state]).await
+}
+
+async fn read_json(sock: &TcpSocket) -> Result<String, Error> {
+    let mut len = [0; 4];
+    sock.read_exact(&mut len).await?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_JSON {
+        return Err(Error::Iperf("oversized json"));
+    }
+    let mut json = vec![0; len];
+    sock.read_exact(&mut json).await?;
+    String::from_utf8(json).map_err(|_| Error::Iperf("invalid json"))
+}
+
+async fn write_json(sock: &TcpSocket, json: &str) -> Result<(), Error> {
+    sock.write_all(&(json.len() as u32).to_be_bytes()).await?;
+    sock.write_all(json.as_bytes()).await
+}
+
+/// The raw text of the first value for `key`.
+fn json_value<'a>(json: &'a str, key: &str) -> Option<&'a str> {
+    let pattern = format!("\"{key}\"");
+    let rest = &json[json.find(&pattern)? + pattern.len()..];
+    let rest = rest.trim_start().strip_prefix(':')?.trim_start();
+    let end = rest
+        .find(|c: char| c == ',' || c == '}' || c == ']')
+        .unwrap_or(rest.len());
+    Some(rest[..end].trim())
+}
+
+fn json_u64(json: &str, key: &str) -> Option<u64> {
+    json_value(json, key)?.parse::<f64>().ok().map(|v| v as u64)
+}
+
+fn json_f64(json: &str, key: &str) -> Option<f64> {
+    json_value(json, key)?.parse().ok()
+}
+
+fn json_bool(json: &str, key: &str) -> bool {
+    json_value(json, key) == Some("true")
+}
+
+/// Total bytes and retransmits over every stream in a results object. The
+/// peer's numbers aren't trusted to add up without overflowing.
+fn parse_results(json: &str) -> (u64, Option<u64>, f64) {
+    let mut bytes = 0;
+    let mut retransmits = 0;
+    let mut end = 0.0f64;
+    let streams = json.find("\"streams\"").map(|i| &json[i..]).unwrap_or("");
+    for stream in streams.split('{').skip(1) {
+        bytes = bytes.saturating_add(json_u64(stream, "bytes").unwrap_or(0));
+        retransmits = retransmits.saturating_add(json_u64(stream, "retransmits").unwrap_or(0));
+        end = end.max(json_f64(stream, "end_time").unwrap_or(0.0));
+    }
+    let has_retransmits = json_value(json, "sender_has_retransmits") == Some("1");
+    (bytes, has_retransmits.then_some(retransmits), end)
+}
+
+fn results_json(streams: &[u64], elapsed: Duration) -> String {
+    let streams = streams
+        .iter()
+        .enumerate()
+        .map(|(i, bytes)| {
+            format!(
+                "{{\"id\":{},\"bytes\":{bytes},\"retransmits\":-1,\"jitter\":0,\"errors\":0,\"packets\":0,\"start_time\":0,\"end_time\":{}}}",
+                i + 1,
+                elapsed.as_secs_f64(),
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+    format!(
+        "{{\"cpu_util_total\":0,\"cpu_util_user\":0,\"cpu_util_system\":0,\"sender_has_retransmits\":0,\"streams\":[{streams}]}}"
+    )
+}
+
+fn cookie() -> [u8; COOKIE_SIZE] {
+    const ALPHABET: &[u8] = b"abcdefghijklmnopqrstuvwxyz234567";
+    let mut cookie = [0; COOKIE_SIZE];
+    for b in &mut cookie[..COOKIE_SIZE - 1] {
+        *b = ALPHABET[OsRng.gen_range(0..ALPHABET.len())];
+    }
+    cookie
+}
+
+/// Send or receive on every stream until `done` resolves, calling `report`
+/// every second with the traffic since the previous call. Returns the bytes
+/// moved per stream and how long it took.
+async fn transfer(
+    streams: &[TcpSocket],
+    send: bool,
+    done: impl core::future::Future<Output = ()>,
+    report: &mut impl FnMut(Report),
+) -> (Vec<u64>, Duration) {
+    let start = Instant::now();
+    let counters = streams
+        .iter()
+        .map(|_| core::sync::atomic::AtomicU64::new(0))
+        .collect::<Vec<_>>();
+
+    let workers = streams
+        .iter()
+        .zip(&counters)
+        .map(|(sock, counter)| async move {
+            let mut buf = vec![0; BLOCK_SIZE];
+            loop {
+                let n = if send {
+                    sock.write(&buf).await
+                } else {
+                    sock.read(&mut buf).await
+                };
+                match n {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => {
+                        counter.fetch_add(n as u64, core::sync::atomic::Ordering::Relaxed);
+                    }
+                }
+            }
+            // don't finish before the test does
+            core::future::pending::<()>().await;
+        });
+
+    let total = || {
+        counters
+            .iter()
+            .map(|c| c.load(core::sync::atomic::Ordering::Relaxed))
+            .sum::<u64>()
+    };
+    let reporter = async {
+        let mut last = (Duration::ZERO, 0);
+        loop {
+            maitake::time::sleep(REPORT_INTERVAL).await;
+            let now = (start.elapsed(), total());
+            report(Report {
+                start: last.0,
+                end: now.0,
+                bytes: now.1 - last.1,
+                retransmits: None,
+            });
+            last = now;
+        }
+    };
+
+    futures::select_biased! {
+        _ = done.fuse() => {}
+        _ = join_all(workers).fuse() => {}
+        _ = core::pin::pin!(reporter).fuse() => {}
+    }
+
+    let elapsed = start.elapsed();
+    let bytes = counters
+        .iter()
+        .map(|c| c.load(core::sync::atomic::Ordering::Relaxed))
+        .collect();
+    (bytes, elapsed)
+}
+
+/// Run one test against an iperf3 server.
+pub async fn client(
+    server: impl Into<SocketAddr>,
+    options: Options,
+    mut report: impl FnMut(Report),
+) -> Result<Summary, Error> {
+    let server: SocketAddr = server.into();
+    let parallel = options.parallel.clamp(1, MAX_STREAMS);
+    let cookie = cookie();
+
+    let control = TcpSocket::new()?;
+    control.connect(server).await?;
+    control.write_all(&cookie).await?;
+
+    let mut streams = Vec::new();
+    let mut local = None;
+    loop {
+        match read_state(&control).await? {
+            PARAM_EXCHANGE => {
+                let params = format!(
+                    "{{\"tcp\":true,\"omit\":0,\"time\":{},\"num\":0,\"blockcount\":0,\"parallel\":{parallel},\"len\":{BLOCK_SIZE},{}\"pacing_timer\":1000,\"client_version\":\"3.16\"}}",
+                    options.time.as_secs().max(1),
+                    if options.reverse { "\"reverse\":true," } else { "" },
+                );
+                write_json(&control, &params).await?;
+            }
+            CREATE_STREAMS => {
+                for _ in 0..parallel {
+                    let sock =
+                        TcpSocket::with_buffer_sizes(STREAM_BUFFER_SIZE, STREAM_BUFFER_SIZE)?;
+                    sock.connect(server).await?;
+                    sock.write_all(&cookie).await?;
+                    streams.push(sock);
+                }
+            }
+            TEST_START => {}
+            TEST_RUNNING => {
+                let result = transfer(
+                    &streams,
+                    !options.reverse,
+                    maitake::time::sleep(options.time),
+                    &mut report,
+                )
+                .await;
+                local = Some(result);
+                write_state(&control, TEST_END).await?;
+                for sock in &streams {
+                    sock.close().await;
+                }
+            }
+            EXCHANGE_RESULTS => {
+                let (bytes, elapsed) = local.as_ref().ok_or(Error::Iperf("no test ran"))?;
+                write_json(&control, &results_json(bytes, *elapsed)).await?;
+                let remote = read_json(&control).await?;
+                let (remote_bytes, remote_retransmits, remote_end) = parse_results(&remote);
+                let ours = Report {
+                    start: Duration::ZERO,
+                    end: *elapsed,
+                    bytes: bytes.iter().sum(),
+                    retransmits: None,
+                };
+                let theirs = Report {
+                    start: Duration::ZERO,
+                    end: Duration::try_from_secs_f64(remote_end)
+                        .unwrap_or(Duration::ZERO)
+                        .max(Duration::from_millis(1)),
+                    bytes: remote_bytes,
+                    retransmits: remote_retransmits,
+                };
+                let summary = if options.reverse {
+                    Summary {
+                        peer: server,
+                        sender: theirs,
+                        receiver: ours,
+                    }
+                } else {
+                    Summary {
+                        peer: server,
+                        sender: ours,
+                        receiver: theirs,
+                    }
+                };
+                local = None;
+                streams.clear();
+                // results are in, the rest is a courtesy
+                if read_state(&control).await? == DISPLAY_RESULTS {
+                    let _ = write_state(&control, IPERF_DONE).await;
+                }
+                control.close().await;
+                return Ok(summary);
+            }
+            ACCESS_DENIED => return Err(Error::Iperf("server is busy")),
+            SERVER_ERROR => return Err(Error::Iperf("server error")),
+            SERVER_TERMINATE => return Err(Error::Iperf("server terminated the test")),
+            _ => return Err(Error::Iperf("unexpected state")),
+        }
+    }
+}
+
+/// Listen on `port` and run tests for iperf3 clients, one at a time.
+/// `report` gets the interval reports of the running test; each finished
+/// test is handed to `done`.
+pub async fn server(
+    port: u16,
+    mut report: impl FnMut(Report),
+    mut done: impl FnMut(Result<Summary, Error>),
+) -> Result<(), Error> {
+    let endpoint = SocketAddr::from(([0, 0, 0, 0], port));
+    loop {
+        let control = TcpSocket::new()?;
+        control.listen(endpoint)?;
+        let peer = control.accept().await?;
+        let result = serve(&control, peer, endpoint, &mut report).await;
+        control.close().await;
+        done(result);
+    }
+}
+
+async fn serve(
+    control: &TcpSocket,
+    peer: SocketAddr,
+    endpoint: SocketAddr,
+    report: &mut impl FnMut(Report),
+) -> Result<Summary, Error> {
+    let mut cookie = [0; COOKIE_SIZE];
+    control.read_exact(&mut cookie).await?;
+
+    write_state(control, PARAM_EXCHANGE).await?;
+    let params = read_json(control).await?;
+    if !json_bool(&params, "tcp") && json_bool(&params, "udp") {
+        write_state(control, SERVER_ERROR).await?;
+        return Err(Error::Iperf("only tcp tests are supported"));
+    }
+    let reverse = json_bool(&params, "reverse");
+    let parallel = json_u64(&params, "parallel").unwrap_or(1) as usize;
+    if !(1..=MAX_STREAMS).contains(&parallel) {
+        write_state(control, SERVER_ERROR).await?;
+        return Err(Error::Iperf("too many streams"));
+    }
+
+    // streams connect to the address the control connection reached, so
+    // there is no need to listen anywhere else
+    let endpoint = control.local_addr().unwrap_or(endpoint);
+
+    // every stream needs its own listening socket
+    let mut streams = Vec::new();
+    for _ in 0..parallel {
+        let sock = TcpSocket::with_buffer_sizes(STREAM_BUFFER_SIZE, STREAM_BUFFER_SIZE)?;
+        sock.listen(endpoint)?;
+        streams.push(sock);
+    }
+    write_state(control, CREATE_STREAMS).await?;
+    for sock in &streams {
+        sock.accept().await?;
+        let mut stream_cookie = [0; COOKIE_SIZE];
+        sock.read_exact(&mut stream_cookie).await?;
+        if stream_cookie != cookie {
+            write_state(control, SERVER_ERROR).await?;
+            return Err(Error::Iperf("stream from another test"));
+        }
+    }
+
+    write_state(control, TEST_START).await?;
+    write_state(control, TEST_RUNNING).await?;
+
+    // the client decides when the test is over
+    let end = async {
+        loop {
+            match read_state(control).await {
+                Ok(TEST_END) | Ok(CLIENT_TERMINATE) | Err(_) => return,
+                Ok(_) => {}
+            }
+        }
+    };
+    let (bytes, elapsed) = transfer(&streams, reverse, end, report).await;
+    for sock in &streams {
+        sock.close().await;
+    }
+
+    write_state(control, EXCHANGE_RESULTS).await?;
+    let remote = read_json(control).await?;
+    write_json(control, &results_json(&bytes, elapsed)).await?;
+    write_state(control, DISPLAY_RESULTS).await?;
+    let _ = read_state(control).await;
+
+    let (remote_bytes, remote_retransmits, remote_end) = parse_results(&remote);
+    let ours = Report {
+        start: Duration::ZERO,
+        end: elapsed,
+        bytes: bytes.iter().sum(),
+        retransmits: None,
+    };
+    let theirs = Report {
+        start: Duration::ZERO,
+        end: Duration::try_from_secs_f64(remote_end)
+            .unwrap_or(Duration::ZERO)
+            .max(Duration::from_millis(1)),
+        bytes: remote_bytes,
+        retransmits: remote_retransmits,
+    };
+    Ok(if reverse {
+        Summary {
+            peer,
+            sender: ours,
+            receiver: theirs,
+        }
+    } else {
+        Summary {
+            peer,
+            sender: theirs,
+            receiver: ours,
+        }
+    })
+}
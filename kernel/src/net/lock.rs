@@ -0,0 +1,194 @@
+//! The lock guarding each interface's state and socket set.
+//!
+//! Socket operations run inside `poll` and wait for the lock by yielding
+//! instead of spinning, so a task on another core polling the interface
+//! doesn't stall them. The lock is never held across an await, so the
+//! blocking [`Mutex::lock`] is still fine for configuration paths; it parks
+//! the core until the lock changes hands rather than spinning on it.
+
+use core::{
+    cell::UnsafeCell,
+    future::poll_fn,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
+};
+
+static ACQUIRED: AtomicU64 = AtomicU64::new(0);
+static CONTENDED: AtomicU64 = AtomicU64::new(0);
+
+/// How often interface locks have been taken, and how often that meant
+/// waiting for another holder.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Stats {
+    pub acquired: u64,
+    pub contended: u64,
+}
+
+pub fn stats() -> Stats {
+    Stats {
+        acquired: ACQUIRED.load(Ordering::Relaxed),
+        contended: CONTENDED.load(Ordering::Relaxed),
+    }
+}
+
+/// Set while every interface lock also takes [`GLOBAL`].
+static SERIALIZED: AtomicBool = AtomicBool::new(false);
+static GLOBAL: RawLock = RawLock::new();
+
+/// Make every interface lock also take one global lock, the way a single
+/// mutex over all sockets used to serialize the stack, until the guard is
+/// dropped. Only there to give the benchmark something to compare against.
+pub(super) fn serialize() -> Serialized {
+    SERIALIZED.store(true, Ordering::Relaxed);
+    Serialized
+}
+
+pub(super) struct Serialized;
+
+impl Drop for Serialized {
+    fn drop(&mut self) {
+        SERIALIZED.store(false, Ordering::Relaxed);
+    }
+}
+
+struct RawLock {
+    locked: AtomicBool,
+    waiters: spin::Mutex<Vec<Waker>>,
+}
+
+impl RawLock {
+    const fn new() -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            waiters: spin::Mutex::new(Vec::new()),
+        }
+    }
+
+    fn try_lock(&self) -> bool {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    fn register(&self, waker: &Waker) {
+        let mut waiters = self.waiters.lock();
+        if !waiters.iter().any(|w| w.will_wake(waker)) {
+            waiters.push(waker.clone());
+        }
+    }
+
+    fn unlock(&self) {
+        self.locked.store(false, Ordering::Release);
+        // everyone gets a go, a waiter may have been dropped in the meantime
+        let waiters = core::mem::take(&mut *self.waiters.lock());
+        for waker in waiters {
+            waker.wake();
+        }
+    }
+}
+
+pub(super) struct Mutex<T> {
+    lock: RawLock,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub(super) fn new(value: T) -> Self {
+        Self {
+            lock: RawLock::new(),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    fn try_acquire(&self) -> Option<MutexGuard<'_, T>> {
+        let serialized = SERIALIZED.load(Ordering::Relaxed);
+        if serialized && !GLOBAL.try_lock() {
+            return None;
+        }
+        if !self.lock.try_lock() {
+            if serialized {
+                GLOBAL.unlock();
+            }
+            return None;
+        }
+        ACQUIRED.fetch_add(1, Ordering::Relaxed);
+        Some(MutexGuard {
+            lock: self,
+            serialized,
+        })
+    }
+
+    /// Wait for the lock, parking the core until whichever lock is in the
+    /// way is written to.
+    pub(super) fn lock(&self) -> MutexGuard<'_, T> {
+        if let Some(guard) = self.try_acquire() {
+            return guard;
+        }
+        CONTENDED.fetch_add(1, Ordering::Relaxed);
+        loop {
+            let word = if self.lock.locked.load(Ordering::Relaxed) {
+                &self.lock.locked
+            } else {
+                &GLOBAL.locked
+            };
+            crate::arch::wait_for_write(word.as_ptr().cast(), || word.load(Ordering::Relaxed));
+            if let Some(guard) = self.try_acquire() {
+                return guard;
+            }
+        }
+    }
+
+    /// Take the lock, or arrange for the task to be woken once it is released.
+    pub(super) fn poll_lock(&self, cx: &mut Context) -> Poll<MutexGuard<'_, T>> {
+        if let Some(guard) = self.try_acquire() {
+            return Poll::Ready(guard);
+        }
+        CONTENDED.fetch_add(1, Ordering::Relaxed);
+        self.lock.register(cx.waker());
+        if SERIALIZED.load(Ordering::Relaxed) {
+            GLOBAL.register(cx.waker());
+        }
+        // the holder may have let go before we were queued
+        match self.try_acquire() {
+            Some(guard) => Poll::Ready(guard),
+            None => Poll::Pending,
+        }
+    }
+
+    pub(super) async fn lock_async(&self) -> MutexGuard<'_, T> {
+        poll_fn(|cx| self.poll_lock(cx)).await
+    }
+}
+
+pub(super) struct MutexGuard<'a, T> {
+    lock: &'a Mutex<T>,
+    /// Whether [`GLOBAL`] was taken too.
+    serialized: bool,
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.lock.unlock();
+        if self.serialized {
+            GLOBAL.unlock();
+        }
+    }
+}
@@ -1,11 +1,31 @@
 use alloc::sync::Arc;
-use conquer_once::spin::OnceCell;
-use core::{future::poll_fn, task::Poll, time::Duration};
+use core::{
+    future::poll_fn,
+    sync::atomic::{AtomicUsize, Ordering},
+    task::Poll,
+    time::Duration,
+};
 use futures::FutureExt;
 use maitake::{sync::WaitCell, time::Instant};
-use rand::{rngs::OsRng, Rng, RngCore};
+use rand::{rngs::OsRng, RngCore};
 use spin::Mutex;
 
+pub mod bench;
+pub mod bridge;
+mod dhcp6;
+pub mod filter;
+pub mod io;
+pub mod iperf;
+pub mod lock;
+mod loopback;
+mod port;
+pub mod syslog;
+pub mod tftp;
+pub mod vlan;
+pub mod websocket;
+
+pub use dhcp6::Mode as Dhcp6Mode;
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error("{0}")]
@@ -21,6 +41,13 @@ pub enum Error {
     #[error("TcpClosed")]
     TcpClosed,
 
+    #[error("{0}")]
+    UdpBind(smoltcp::socket::udp::BindError),
+    #[error("{0}")]
+    UdpRecv(smoltcp::socket::udp::RecvError),
+    #[error("{0}")]
+    UdpSend(smoltcp::socket::udp::SendError),
+
     #[error("{0}")]
     IcmpRecv(smoltcp::socket::icmp::RecvError),
     #[error("{0}")]
@@ -33,6 +60,42 @@ pub enum Error {
 
     #[error("destination unreachable")]
     DestinationUnreachable,
+    #[error("connection refused")]
+    ConnectionRefused,
+    #[error("timed out")]
+    TimedOut,
+    #[error("address not available")]
+    AddressNotAvailable,
+    #[error("socket is already bound")]
+    AlreadyBound,
+    #[error("no free ports")]
+    PortsExhausted,
+
+    #[error("tftp error {0}: {1}")]
+    Tftp(u16, String),
+    #[error("iperf: {0}")]
+    Iperf(&'static str),
+    #[error("websocket: {0}")]
+    WebSocket(&'static str),
+
+    #[error("no such interface")]
+    NoSuchInterface,
+    #[error("interface already exists")]
+    InterfaceExists,
+    #[error("not an ethernet interface")]
+    NotEthernet,
+    #[error("invalid vlan id")]
+    InvalidVlanId,
+    #[error("no such rule")]
+    NoSuchRule,
+    #[error("interface is not filtered, match its ports instead")]
+    NotFiltered,
+    #[error("address table full")]
+    AddressTableFull,
+    #[error("route table full")]
+    RouteTableFull,
+    #[error("dhcpv4 is disabled")]
+    Dhcp4Disabled,
 }
 
 pub trait Driver: smoltcp::phy::Device + Sized + Send + Sync {
@@ -40,26 +103,217 @@ pub trait Driver: smoltcp::phy::Device + Sized + Send + Sync {
     fn poll(&self, cx: &mut core::task::Context) -> core::task::Poll<()>;
 }
 
+type InterfaceRef = Arc<InterfaceShared>;
+
+type TaskHandle = maitake::task::JoinHandle<Result<(), Box<dyn core::any::Any + Send>>>;
+
 lazy_static::lazy_static! {
-    static ref SOCKETS: Mutex<smoltcp::iface::SocketSet<'static>> = Mutex::new(smoltcp::iface::SocketSet::new(vec![]));
+    static ref INTERFACES: Mutex<Vec<InterfaceRef>> = Mutex::new(vec![]);
 }
 
-static DEFAULT_DRIVER: OnceCell<Arc<Mutex<InterfaceInner>>> = OnceCell::uninit();
+static ETHERNET_INDEX: AtomicUsize = AtomicUsize::new(0);
 
-static WAIT_CELL: WaitCell = WaitCell::new();
+/// How long [`TcpSocket::connect`] waits for the handshake by default.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Longest single sleep handed to the timer wheel.
+const MAX_SLEEP: Duration = Duration::from_secs(3600);
+
+/// Sleep until `deadline`, which may be arbitrarily far away.
+async fn sleep_until(deadline: Instant) {
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+        if remaining.is_zero() {
+            break;
+        }
+        maitake::time::sleep(remaining.min(MAX_SLEEP)).await;
+    }
+}
+
+struct Dhcp4State {
+    socket: smoltcp::iface::SocketHandle,
+    task: TaskHandle,
+    lease: Option<Dhcp4Config>,
+}
+
+/// SLAAC state learned from router advertisements, with expiry times.
+#[derive(Default)]
+struct Auto6Lease {
+    addresses: Vec<(smoltcp::wire::Ipv6Cidr, Instant)>,
+    router: Option<(smoltcp::wire::Ipv6Address, Instant)>,
+    dns_servers: Vec<smoltcp::wire::Ipv6Address>,
+}
+
+impl Auto6Lease {
+    fn next_expiry(&self) -> Option<Instant> {
+        self.addresses
+            .iter()
+            .map(|a| a.1)
+            .chain(self.router.map(|r| r.1))
+            .min()
+    }
+}
+
+struct Auto6State {
+    task: TaskHandle,
+    lease: Auto6Lease,
+}
 
 struct InterfaceInner {
+    name: String,
+    loopback: bool,
     iface: smoltcp::iface::Interface,
+    sockets: smoltcp::iface::SocketSet<'static>,
     dns_servers: Vec<smoltcp::wire::IpAddress>,
+    dhcp4: Option<Dhcp4State>,
+    auto6: Option<Auto6State>,
+    dhcp6: Option<dhcp6::State>,
+    /// Set on interfaces whose device can carry VLANs.
+    vlan_ports: Option<Arc<vlan::Ports>>,
+}
+
+impl InterfaceInner {
+    fn apply_dhcp4(&mut self, config: &Dhcp4Config) {
+        self.iface.update_ip_addrs(|addrs| {
+            addrs.push(smoltcp::wire::IpCidr::Ipv4(config.address));
+        });
+
+        if let Some(router) = config.router {
+            let _ = self.iface.routes_mut().add_default_ipv4_route(router);
+        } else {
+            self.iface.routes_mut().remove_default_ipv4_route();
+        }
+
+        for server in &config.dns_servers {
+            self.dns_servers.push((*server).into());
+        }
+    }
+
+    fn remove_dhcp4(&mut self, config: &Dhcp4Config) {
+        self.iface.update_ip_addrs(|addrs| {
+            if let Some(index) = addrs.iter().position(|c| match c {
+                smoltcp::wire::IpCidr::Ipv4(c) => *c == config.address,
+                _ => false,
+            }) {
+                addrs.remove(index);
+            }
+        });
+        if config.router.is_some() {
+            self.iface.routes_mut().remove_default_ipv4_route();
+        }
+        while let Some(index) = self.dns_servers.iter().position(|a| match a {
+            smoltcp::wire::IpAddress::Ipv4(a) => config.dns_servers.contains(a),
+            _ => false,
+        }) {
+            self.dns_servers.remove(index);
+        }
+    }
+
+    fn remove_auto6(&mut self, lease: &Auto6Lease) {
+        self.iface.update_ip_addrs(|addrs| {
+            addrs.retain(|c| match c {
+                smoltcp::wire::IpCidr::Ipv6(c) => !lease.addresses.iter().any(|(a, _)| a == c),
+                _ => true,
+            });
+        });
+        if lease.router.is_some() {
+            self.iface.routes_mut().remove_default_ipv6_route();
+        }
+        self.dns_servers.retain(|a| match a {
+            smoltcp::wire::IpAddress::Ipv6(a) => !lease.dns_servers.contains(a),
+            _ => true,
+        });
+    }
+
+    /// Drop SLAAC addresses and the default router once their lifetimes run out.
+    fn expire_auto6(&mut self, now: Instant) {
+        let Some(state) = self.auto6.as_mut() else {
+            return;
+        };
+        let lease = &mut state.lease;
+
+        if lease.router.is_some_and(|(_, expires)| expires <= now) {
+            lease.router = None;
+            self.iface.routes_mut().remove_default_ipv6_route();
+        }
+
+        let expired = lease
+            .addresses
+            .iter()
+            .filter(|(_, expires)| *expires <= now)
+            .map(|(cidr, _)| *cidr)
+            .collect::<Vec<_>>();
+        if expired.is_empty() {
+            return;
+        }
+        lease.addresses.retain(|(_, expires)| *expires > now);
+        self.iface.update_ip_addrs(|addrs| {
+            addrs.retain(|c| match c {
+                smoltcp::wire::IpCidr::Ipv6(c) => !expired.contains(c),
+                _ => true,
+            });
+        });
+    }
+
+    fn apply_dhcp6(&mut self, lease: &dhcp6::Lease) {
+        self.iface.update_ip_addrs(|addrs| {
+            for address in &lease.addresses {
+                let _ = addrs.push(smoltcp::wire::IpCidr::Ipv6(smoltcp::wire::Ipv6Cidr::new(
+                    *address, 128,
+                )));
+            }
+        });
+        for server in &lease.dns_servers {
+            self.dns_servers.push((*server).into());
+        }
+    }
+
+    fn remove_dhcp6(&mut self, lease: &dhcp6::Lease) {
+        self.iface.update_ip_addrs(|addrs| {
+            addrs.retain(|c| match c {
+                smoltcp::wire::IpCidr::Ipv6(c) => {
+                    !(c.prefix_len() == 128 && lease.addresses.contains(&c.address()))
+                }
+                _ => true,
+            });
+        });
+        self.dns_servers.retain(|a| match a {
+            smoltcp::wire::IpAddress::Ipv6(a) => !lease.dns_servers.contains(a),
+            _ => true,
+        });
+    }
+}
+
+/// An interface's state, and a way to poke its poller without the lock.
+struct InterfaceShared {
+    inner: lock::Mutex<InterfaceInner>,
+    /// Woken when a socket on this interface has something new to send.
+    poll: WaitCell,
+}
+
+impl InterfaceShared {
+    fn lock(&self) -> lock::MutexGuard<'_, InterfaceInner> {
+        self.inner.lock()
+    }
+
+    fn poll_lock(
+        &self,
+        cx: &mut core::task::Context,
+    ) -> Poll<lock::MutexGuard<'_, InterfaceInner>> {
+        self.inner.poll_lock(cx)
+    }
+
+    fn wake(&self) {
+        self.poll.wake();
+    }
 }
 
 struct Interface<D: Driver> {
     device: D,
-    inner: Arc<Mutex<InterfaceInner>>,
+    inner: InterfaceRef,
 }
 
 impl<D: Driver> Interface<D> {
-    fn new(mut device: D) -> Self {
+    fn new(name: String, mut device: D) -> Self {
         let mut config = smoltcp::iface::Config::new(device.address());
         config.random_seed = OsRng.next_u64();
 
@@ -68,13 +322,23 @@ impl<D: Driver> Interface<D> {
         let iface = smoltcp::iface::Interface::new(config, &mut device, now);
 
         let inner = InterfaceInner {
+            name,
+            loopback: false,
             iface,
+            sockets: smoltcp::iface::SocketSet::new(vec![]),
             dns_servers: vec![],
+            dhcp4: None,
+            auto6: None,
+            dhcp6: None,
+            vlan_ports: None,
         };
 
         Self {
             device,
-            inner: Arc::new(Mutex::new(inner)),
+            inner: Arc::new(InterfaceShared {
+                inner: lock::Mutex::new(inner),
+                poll: WaitCell::new(),
+            }),
         }
     }
 
@@ -83,109 +347,80 @@ impl<D: Driver> Interface<D> {
             // TODO: do not poll if sockets empty
 
             let delay = {
-                let mut inner = self.inner.lock();
-                let mut sockets = SOCKETS.lock();
+                let mut inner = self.inner.inner.lock_async().await;
+                let inner = &mut *inner;
 
                 let timestamp =
                     smoltcp::time::Instant::from_micros(crate::arch::now().as_micros() as i64);
 
-                inner.iface.poll(timestamp, &mut self.device, &mut sockets);
+                inner
+                    .iface
+                    .poll(timestamp, &mut self.device, &mut inner.sockets);
 
                 inner
                     .iface
-                    .poll_delay(timestamp, &sockets)
+                    .poll_delay(timestamp, &inner.sockets)
                     .map(|d| Duration::from_micros(d.micros()))
             };
 
-            if let Some(delay) = delay {
-                // device can wake us up
-                let mut f1 = core::future::poll_fn(|cx| self.device.poll(cx)).fuse();
-                // other tasks can us up
-                let mut f2 = WAIT_CELL.wait().fuse();
-                // fallback wakeup
-                let mut f3 = core::pin::pin!(maitake::time::sleep(delay).fuse());
-
-                futures::select_biased! {
-                    _ = &mut f1 => {}
-                    _ = &mut f2 => {}
-                    _ = &mut f3 => {}
-                }
+            // device can wake us up
+            let mut f1 = core::future::poll_fn(|cx| self.device.poll(cx)).fuse();
+            // sockets on this interface can wake us up
+            let mut f2 = self.inner.poll.wait().fuse();
+            // fallback wakeup, if any socket has a timer running
+            let mut f3 = core::pin::pin!(maitake::time::sleep(delay.unwrap_or(MAX_SLEEP)).fuse());
+
+            futures::select_biased! {
+                _ = &mut f1 => {}
+                _ = &mut f2 => {}
+                _ = &mut f3 => {}
             }
         }
     }
 }
 
-async fn dhcp4() {
-    let sock = Dhcp4Socket::new();
-    let mut current_config = None;
+async fn dhcp4(iface: InterfaceRef, sock: Dhcp4Socket) {
     loop {
         match sock.event().await {
             Dhcp4Event::Configure(config) => {
-                let mut inner = DEFAULT_DRIVER.get().unwrap().lock();
-                inner.iface.update_ip_addrs(|addrs| {
-                    addrs.push(smoltcp::wire::IpCidr::Ipv4(config.address));
-                });
-
-                if let Some(router) = config.router {
-                    let _ = inner.iface.routes_mut().add_default_ipv4_route(router);
-                } else {
-                    inner.iface.routes_mut().remove_default_ipv4_route();
+                let mut inner = iface.lock();
+                // client was stopped while this event was in flight
+                let Some(state) = inner.dhcp4.as_mut() else {
+                    return;
+                };
+                if let Some(lease) = state.lease.take() {
+                    inner.remove_dhcp4(&lease);
                 }
-
-                for server in &config.dns_servers {
-                    inner.dns_servers.push((*server).into());
+                inner.apply_dhcp4(&config);
+                if let Some(state) = inner.dhcp4.as_mut() {
+                    state.lease = Some(config);
                 }
-
-                current_config = Some(config);
             }
             Dhcp4Event::Deconfigure => {
-                if let Some(config) = &current_config {
-                    let mut inner = DEFAULT_DRIVER.get().unwrap().lock();
-                    inner.iface.update_ip_addrs(|addrs| {
-                        if let Some(index) = addrs.iter().position(|c| match c {
-                            smoltcp::wire::IpCidr::Ipv4(c) => *c == config.address,
-                            _ => false,
-                        }) {
-                            addrs.remove(index);
-                        }
-                    });
-                    inner.iface.routes_mut().remove_default_ipv4_route();
-                    while let Some(index) = inner.dns_servers.iter().position(|a| match a {
-                        smoltcp::wire::IpAddress::Ipv4(a) => config.dns_servers.contains(a),
-                        _ => false,
-                    }) {
-                        inner.dns_servers.remove(index);
-                    }
+                let mut inner = iface.lock();
+                let Some(state) = inner.dhcp4.as_mut() else {
+                    return;
+                };
+                if let Some(lease) = state.lease.take() {
+                    inner.remove_dhcp4(&lease);
                 }
             }
         }
     }
 }
 
-async fn auto6() {
-    let sock = IcmpSocket::bind(smoltcp::socket::icmp::Endpoint::Unspecified);
+async fn auto6(iface: InterfaceRef) {
+    let sock = IcmpSocket::bind(&iface, smoltcp::socket::icmp::Endpoint::Unspecified);
 
     sock.set_hop_limit(255);
 
     let (link_ip, mac) = {
-        let mut inner = DEFAULT_DRIVER.get().unwrap().lock();
+        let inner = iface.lock();
         let mac = match inner.iface.hardware_addr() {
             smoltcp::wire::HardwareAddress::Ethernet(e) => e.0,
             smoltcp::wire::HardwareAddress::Ip => return,
         };
-        let a = (((mac[0] & 0b11111101) as u16) << 8) | (mac[1] as u16);
-        let b = ((mac[2] as u16) << 8) | 0xff;
-        let c = 0xfe00u16 | (mac[3] as u16);
-        let d = ((mac[4] as u16) << 8) | (mac[5] as u16);
-        let ip = [0xfe80, 0, 0, 0, a, b, c, d].into();
-
-        inner.iface.update_ip_addrs(|addrs| {
-            addrs.push(smoltcp::wire::IpCidr::Ipv6(smoltcp::wire::Ipv6Cidr::new(
-                ip, 64,
-            )));
-        });
-
-        (ip, mac)
+        (link_local_address(&mac), mac)
     };
 
     {
@@ -206,169 +441,780 @@ async fn auto6() {
         }
     }
 
+    // routers keep sending unsolicited adverts, so keep listening and let
+    // lifetimes run out when they stop
     loop {
-        let mut buf = [0; 1500];
-        match sock.read(&mut buf).await {
-            Ok((n, src_ip)) => match src_ip {
-                core::net::IpAddr::V4(_) => {}
-                core::net::IpAddr::V6(src_ip) => {
-                    let packet = smoltcp::wire::Icmpv6Packet::new_checked(&buf[..n]).unwrap();
-                    let repr = smoltcp::wire::Icmpv6Repr::parse(
-                        &src_ip,
-                        &[0xff02, 0, 0, 0, 0, 0, 0, 1].into(),
-                        &packet,
-                        &smoltcp::phy::ChecksumCapabilities::default(),
-                    );
-                    match repr {
-                        Ok(smoltcp::wire::Icmpv6Repr::Ndisc(
-                            smoltcp::wire::NdiscRepr::RouterAdvert {
-                                prefix_info,
-                                recursive_dns,
-                                flags,
-                                ..
-                            },
-                        )) => {
-                            let mut inner = DEFAULT_DRIVER.get().unwrap().lock();
-
-                            if flags.contains(smoltcp::wire::NdiscRouterFlags::MANAGED) {
-                                // TODO: dhcpv6
-                            }
-
-                            if let Some(prefix_info) = prefix_info {
-                                let link_segments = link_ip.segments();
-                                let mut segments = prefix_info.prefix.segments();
-                                segments[4..8].copy_from_slice(&link_segments[4..8]);
+        let next_expiry = {
+            let inner = iface.lock();
+            let Some(state) = inner.auto6.as_ref() else {
+                return;
+            };
+            state.lease.next_expiry()
+        };
 
-                                inner.iface.update_ip_addrs(|addrs| {
-                                    addrs.push(smoltcp::wire::IpCidr::Ipv6(
-                                        smoltcp::wire::Ipv6Cidr::new(
-                                            segments.into(),
-                                            prefix_info.prefix_len,
-                                        ),
-                                    ));
-                                });
+        let mut buf = [0; 1500];
+        let received = match next_expiry {
+            Some(deadline) => {
+                let wait = deadline
+                    .checked_duration_since(Instant::now())
+                    .unwrap_or_default()
+                    .min(MAX_SLEEP);
+                maitake::time::timeout(wait, sock.read(&mut buf)).await.ok()
+            }
+            None => Some(sock.read(&mut buf).await),
+        };
 
+        match received {
+            Some(Ok((n, core::net::IpAddr::V6(src_ip)))) => {
+                let packet = smoltcp::wire::Icmpv6Packet::new_checked(&buf[..n]).unwrap();
+                let repr = smoltcp::wire::Icmpv6Repr::parse(
+                    &src_ip,
+                    &[0xff02, 0, 0, 0, 0, 0, 0, 1].into(),
+                    &packet,
+                    &smoltcp::phy::ChecksumCapabilities::default(),
+                );
+                match repr {
+                    Ok(smoltcp::wire::Icmpv6Repr::Ndisc(
+                        smoltcp::wire::NdiscRepr::RouterAdvert {
+                            router_lifetime,
+                            prefix_info,
+                            recursive_dns,
+                            flags,
+                            ..
+                        },
+                    )) => {
+                        let mode = if flags.contains(smoltcp::wire::NdiscRouterFlags::MANAGED) {
+                            Some(Dhcp6Mode::Stateful)
+                        } else if flags.contains(smoltcp::wire::NdiscRouterFlags::OTHER) {
+                            Some(Dhcp6Mode::Stateless)
+                        } else {
+                            None
+                        };
+                        let dns_servers =
+                            recursive_dns.map(|r| r.servers.iter().copied().collect::<Vec<_>>());
+
+                        let mut guard = iface.lock();
+                        let inner = &mut *guard;
+                        let Some(state) = inner.auto6.as_mut() else {
+                            return;
+                        };
+                        let lease = &mut state.lease;
+                        let now = Instant::now();
+
+                        if router_lifetime == smoltcp::time::Duration::ZERO {
+                            // router is going away
+                            if lease.router.is_some_and(|(r, _)| r == src_ip) {
+                                lease.router = None;
+                                inner.iface.routes_mut().remove_default_ipv6_route();
+                            }
+                        } else {
+                            if lease.router.map(|(r, _)| r) != Some(src_ip) {
                                 let _ = inner.iface.routes_mut().add_default_ipv6_route(src_ip);
                             }
+                            let lifetime = Duration::from_secs(router_lifetime.secs());
+                            lease.router = Some((src_ip, now + lifetime));
+                        }
 
-                            if let Some(recursive_dns) = recursive_dns {
-                                for server in recursive_dns.servers {
-                                    inner.dns_servers.push((*server).into());
+                        if let Some(prefix_info) = prefix_info.filter(|p| {
+                            p.flags
+                                .contains(smoltcp::wire::NdiscPrefixInfoFlags::ADDRCONF)
+                                && p.prefix_len == 64
+                        }) {
+                            let link_segments = link_ip.segments();
+                            let mut segments = prefix_info.prefix.segments();
+                            segments[4..8].copy_from_slice(&link_segments[4..8]);
+
+                            let cidr = smoltcp::wire::Ipv6Cidr::new(
+                                segments.into(),
+                                prefix_info.prefix_len,
+                            );
+                            let existing = lease.addresses.iter().position(|(c, _)| *c == cidr);
+                            if prefix_info.valid_lifetime == smoltcp::time::Duration::ZERO {
+                                if let Some(index) = existing {
+                                    lease.addresses.remove(index);
+                                    inner.iface.update_ip_addrs(|addrs| {
+                                        addrs.retain(|c| *c != smoltcp::wire::IpCidr::Ipv6(cidr));
+                                    });
+                                }
+                            } else {
+                                let lifetime =
+                                    Duration::from_secs(prefix_info.valid_lifetime.secs());
+                                match existing {
+                                    Some(index) => lease.addresses[index].1 = now + lifetime,
+                                    None => {
+                                        let mut added = false;
+                                        inner.iface.update_ip_addrs(|addrs| {
+                                            added = addrs
+                                                .push(smoltcp::wire::IpCidr::Ipv6(cidr))
+                                                .is_ok();
+                                        });
+                                        if added {
+                                            lease.addresses.push((cidr, now + lifetime));
+                                        }
+                                    }
                                 }
                             }
+                        }
 
-                            return;
+                        if let Some(servers) = dns_servers {
+                            inner.dns_servers.retain(|a| match a {
+                                smoltcp::wire::IpAddress::Ipv6(a) => !lease.dns_servers.contains(a),
+                                _ => true,
+                            });
+                            for server in &servers {
+                                inner.dns_servers.push((*server).into());
+                            }
+                            lease.dns_servers = servers;
                         }
-                        Err(e) => {
-                            info!("icmp recv error {e:?}");
+
+                        let running = inner.dhcp6.as_ref().map(|s| s.mode);
+                        if running != mode {
+                            drop(guard);
+                            stop_dhcp6(&iface);
+                            if let Some(mode) = mode {
+                                start_dhcp6(&iface, mode);
+                            }
                         }
-                        _ => {}
                     }
+                    Err(e) => {
+                        info!("icmp recv error {e:?}");
+                    }
+                    _ => {}
                 }
-            },
-            Err(e) => {
+            }
+            Some(Ok(_)) | None => {}
+            Some(Err(e)) => {
                 error!("icmp recv error {e:?}");
                 break;
             }
         }
+
+        iface.lock().expire_auto6(Instant::now());
+    }
+}
+
+fn link_local_address(mac: &[u8; 6]) -> smoltcp::wire::Ipv6Address {
+    let a = (((mac[0] & 0b11111101) as u16) << 8) | (mac[1] as u16);
+    let b = ((mac[2] as u16) << 8) | 0xff;
+    let c = 0xfe00u16 | (mac[3] as u16);
+    let d = ((mac[4] as u16) << 8) | (mac[5] as u16);
+    [0xfe80, 0, 0, 0, a, b, c, d].into()
+}
+
+// The clients exit once they find their state gone, so it has to be in place
+// before they can look: each one is spawned with the interface locked.
+
+fn start_dhcp4(iface: &InterfaceRef) {
+    let sock = Dhcp4Socket::new(iface);
+    let socket = sock.handle;
+    let mut inner = iface.lock();
+    inner.dhcp4 = Some(Dhcp4State {
+        socket,
+        task: crate::task::spawn(dhcp4(iface.clone(), sock)),
+        lease: None,
+    });
+}
+
+fn stop_dhcp4(iface: &InterfaceRef) {
+    let state = iface.lock().dhcp4.take();
+    if let Some(state) = state {
+        state.task.cancel();
+        if let Some(lease) = &state.lease {
+            iface.lock().remove_dhcp4(lease);
+        }
     }
 }
 
+fn start_auto6(iface: &InterfaceRef) {
+    let mut inner = iface.lock();
+    inner.auto6 = Some(Auto6State {
+        task: crate::task::spawn(auto6(iface.clone())),
+        lease: Auto6Lease::default(),
+    });
+}
+
+fn stop_auto6(iface: &InterfaceRef) {
+    let state = iface.lock().auto6.take();
+    if let Some(state) = state {
+        state.task.cancel();
+        iface.lock().remove_auto6(&state.lease);
+    }
+    // dhcpv6 is only ever started by router advertisements
+    stop_dhcp6(iface);
+}
+
+fn start_dhcp6(iface: &InterfaceRef, mode: Dhcp6Mode) {
+    let mut inner = iface.lock();
+    inner.dhcp6 = Some(dhcp6::State {
+        mode,
+        task: crate::task::spawn(dhcp6::client(iface.clone(), mode)),
+        lease: dhcp6::Lease::default(),
+    });
+}
+
+fn stop_dhcp6(iface: &InterfaceRef) {
+    let state = iface.lock().dhcp6.take();
+    if let Some(state) = state {
+        state.task.cancel();
+        iface.lock().remove_dhcp6(&state.lease);
+    }
+}
+
+fn add_interface<D>(name: String, device: D) -> (InterfaceRef, TaskHandle)
+where
+    D: Driver + 'static,
+{
+    let mut interface = Interface::new(name, device);
+    let iface = interface.inner.clone();
+
+    INTERFACES.lock().push(iface.clone());
+
+    let task = crate::task::spawn(async move {
+        interface.run().await;
+    });
+
+    (iface, task)
+}
+
+/// Give an Ethernet interface its link-local address and start DHCP and SLAAC.
+fn configure_ethernet(iface: &InterfaceRef) {
+    {
+        let mut inner = iface.lock();
+        if let smoltcp::wire::HardwareAddress::Ethernet(mac) = inner.iface.hardware_addr() {
+            let ip = link_local_address(&mac.0);
+            inner.iface.update_ip_addrs(|addrs| {
+                addrs.push(smoltcp::wire::IpCidr::Ipv6(smoltcp::wire::Ipv6Cidr::new(
+                    ip, 64,
+                )));
+            });
+        }
+    }
+
+    start_dhcp4(iface);
+
+    start_auto6(iface);
+}
+
 pub fn register<D>(device: D)
 where
     D: Driver + 'static,
 {
-    let mut interface = Interface::new(device);
-    DEFAULT_DRIVER
-        .try_init_once(|| interface.inner.clone())
-        .unwrap();
+    let name = format!("eth{}", ETHERNET_INDEX.fetch_add(1, Ordering::Relaxed));
+    let (device, ports) = vlan::Trunk::new(filter::Filter::new(name.clone(), device));
+    let (iface, _) = add_interface(name.clone(), device);
+    iface.lock().vlan_ports = Some(ports);
 
-    crate::task::spawn(dhcp4());
+    configure_ethernet(&iface);
 
-    crate::task::spawn(auto6());
+    debug!("[NET] {name} registered");
+}
 
-    crate::task::spawn(async move {
-        interface.run().await;
+/// Bring up `lo`, which exists whether or not there is any network hardware.
+pub fn init() {
+    let device = filter::Filter::new("lo".to_owned(), loopback::Loopback::new());
+    let (iface, _) = add_interface("lo".to_owned(), device);
+    let mut inner = iface.lock();
+    inner.loopback = true;
+    inner.iface.update_ip_addrs(|addrs| {
+        let _ = addrs.push(smoltcp::wire::IpCidr::new(
+            smoltcp::wire::Ipv4Address::new(127, 0, 0, 1).into(),
+            8,
+        ));
+        let _ = addrs.push(smoltcp::wire::IpCidr::new(
+            smoltcp::wire::Ipv6Address::LOOPBACK.into(),
+            128,
+        ));
     });
+}
 
-    debug!("[NET] device registered");
+/// The first real interface, falling back to `lo` if there is none.
+fn default_interface() -> Option<InterfaceRef> {
+    let interfaces = INTERFACES.lock();
+    interfaces
+        .iter()
+        .find(|i| !i.lock().loopback)
+        .or_else(|| interfaces.first())
+        .cloned()
 }
 
-pub struct TcpSocket {
+fn find_interface(name: &str) -> Result<InterfaceRef, Error> {
+    INTERFACES
+        .lock()
+        .iter()
+        .find(|i| i.lock().name == name)
+        .cloned()
+        .ok_or(Error::NoSuchInterface)
+}
+
+/// The interface `addr` is assigned to.
+fn address_interface(addr: core::net::IpAddr) -> Option<InterfaceRef> {
+    INTERFACES
+        .lock()
+        .iter()
+        .find(|i| i.lock().iface.has_ip_addr(addr))
+        .cloned()
+}
+
+fn route_interface(addr: &smoltcp::wire::IpAddress) -> Option<InterfaceRef> {
+    let interfaces = INTERFACES.lock();
+
+    // prefer an interface the address is directly attached to
+    if let Some(iface) = interfaces.iter().find(|i| {
+        i.lock()
+            .iface
+            .ip_addrs()
+            .iter()
+            .any(|c| c.contains_addr(addr))
+    }) {
+        return Some(iface.clone());
+    }
+
+    interfaces
+        .iter()
+        .find(|i| {
+            let inner = i.lock();
+            match addr {
+                smoltcp::wire::IpAddress::Ipv4(_) => inner
+                    .iface
+                    .routes()
+                    .v4()
+                    .iter()
+                    .any(|r| r.2.cidr.contains_addr(addr)),
+                smoltcp::wire::IpAddress::Ipv6(_) => inner
+                    .iface
+                    .routes()
+                    .v6()
+                    .iter()
+                    .any(|r| r.2.cidr.contains_addr(addr)),
+            }
+        })
+        .cloned()
+}
+
+/// smoltcp treats an unspecified address as a literal one, so map it to "any".
+fn listen_endpoint(endpoint: core::net::SocketAddr) -> smoltcp::wire::IpListenEndpoint {
+    if endpoint.ip().is_unspecified() {
+        endpoint.port().into()
+    } else {
+        endpoint.into()
+    }
+}
+
+struct SocketBinding {
+    iface: InterfaceRef,
     handle: smoltcp::iface::SocketHandle,
 }
 
+impl SocketBinding {
+    /// Move the socket into the socket set of `iface`, which is the only
+    /// interface that will poll it from then on.
+    fn attach(&mut self, iface: InterfaceRef) {
+        if Arc::ptr_eq(&self.iface, &iface) {
+            return;
+        }
+        let socket = self.iface.lock().sockets.remove(self.handle);
+        let mut inner = iface.lock();
+        self.handle = match socket {
+            smoltcp::socket::Socket::Tcp(socket) => inner.sockets.add(socket),
+            smoltcp::socket::Socket::Udp(socket) => inner.sockets.add(socket),
+            _ => unreachable!(),
+        };
+        drop(inner);
+        self.iface = iface;
+    }
+}
+
+pub type TcpState = smoltcp::socket::tcp::State;
+
+/// The local end of a socket, once `bind`, `listen` or `connect` picked it.
+struct LocalEndpoint {
+    addr: Option<core::net::IpAddr>,
+    port: port::Port,
+}
+
+impl LocalEndpoint {
+    fn listen_endpoint(&self) -> smoltcp::wire::IpListenEndpoint {
+        smoltcp::wire::IpListenEndpoint {
+            addr: self.addr.map(Into::into),
+            port: self.port.number(),
+        }
+    }
+}
+
+pub struct TcpSocket {
+    binding: Mutex<SocketBinding>,
+    /// smoltcp only matches a packet against the sockets of the interface
+    /// it arrived on, so listening on an unspecified address also needs a
+    /// socket on every other interface.
+    listeners: Mutex<Vec<SocketBinding>>,
+    local: Mutex<Option<LocalEndpoint>>,
+    buffer_sizes: (usize, usize),
+}
+
 impl TcpSocket {
-    pub fn new() -> Self {
-        let rx_buffer = smoltcp::socket::tcp::SocketBuffer::new(vec![0; 1500]);
-        let tx_buffer = smoltcp::socket::tcp::SocketBuffer::new(vec![0; 1500]);
+    pub const DEFAULT_BUFFER_SIZE: usize = 1500;
+
+    pub fn new() -> Result<Self, Error> {
+        Self::with_buffer_sizes(Self::DEFAULT_BUFFER_SIZE, Self::DEFAULT_BUFFER_SIZE)
+    }
+
+    /// Create a socket with `rx` and `tx` bytes of buffering. The receive
+    /// buffer bounds the window advertised to the peer, so bulk transfers
+    /// want it large.
+    pub fn with_buffer_sizes(rx: usize, tx: usize) -> Result<Self, Error> {
+        let iface = default_interface().ok_or(Error::NoSuchInterface)?;
 
-        let tcp_socket = smoltcp::socket::tcp::Socket::new(rx_buffer, tx_buffer);
-        let handle = SOCKETS.lock().add(tcp_socket);
+        let handle = iface.lock().sockets.add(Self::socket((rx, tx)));
 
-        Self { handle }
+        Ok(Self {
+            binding: Mutex::new(SocketBinding { iface, handle }),
+            listeners: Mutex::new(vec![]),
+            local: Mutex::new(None),
+            buffer_sizes: (rx, tx),
+        })
     }
 
-    pub fn listen(&self, endpoint: impl Into<core::net::SocketAddr>) -> Result<(), Error> {
+    fn socket((rx, tx): (usize, usize)) -> smoltcp::socket::tcp::Socket<'static> {
+        let rx_buffer = smoltcp::socket::tcp::SocketBuffer::new(vec![0; rx]);
+        let tx_buffer = smoltcp::socket::tcp::SocketBuffer::new(vec![0; tx]);
+        smoltcp::socket::tcp::Socket::new(rx_buffer, tx_buffer)
+    }
+
+    /// Use `endpoint` as the local end of the next `connect` or `listen`. An
+    /// unspecified address leaves the choice to routing, and port 0 picks an
+    /// ephemeral port.
+    pub fn bind(&self, endpoint: impl Into<core::net::SocketAddr>) -> Result<(), Error> {
         let endpoint: core::net::SocketAddr = endpoint.into();
-        let mut sockets = SOCKETS.lock();
-        let socket = sockets.get_mut::<smoltcp::socket::tcp::Socket>(self.handle);
-        if let Err(e) = socket.listen(endpoint) {
-            return Err(Error::TcpListen(e));
+        let mut local = self.local.lock();
+        if local.is_some() {
+            return Err(Error::AlreadyBound);
+        }
+        let addr = (!endpoint.ip().is_unspecified()).then(|| endpoint.ip());
+        if let Some(addr) = addr {
+            let iface = address_interface(addr).ok_or(Error::AddressNotAvailable)?;
+            self.binding.lock().attach(iface);
         }
+        *local = Some(LocalEndpoint {
+            addr,
+            port: port::Port::bind(port::Protocol::Tcp, endpoint.port())?,
+        });
         Ok(())
     }
 
-    pub async fn connect(&self, addr: impl Into<core::net::SocketAddr>) -> Result<(), Error> {
-        let addr: core::net::SocketAddr = addr.into();
+    /// Listen on `endpoint`, or on the bound endpoint if the socket was
+    /// bound first. An unspecified address listens on every interface that
+    /// exists at this point, with the options already set on this socket.
+    pub fn listen(&self, endpoint: impl Into<core::net::SocketAddr>) -> Result<(), Error> {
+        let endpoint: core::net::SocketAddr = endpoint.into();
+        let mut local = self.local.lock();
+        if local.is_none() {
+            if !endpoint.ip().is_unspecified() {
+                if let Some(iface) = address_interface(endpoint.ip()) {
+                    self.binding.lock().attach(iface);
+                }
+            }
+            *local = Some(LocalEndpoint {
+                addr: (!endpoint.ip().is_unspecified()).then(|| endpoint.ip()),
+                port: port::Port::bind(port::Protocol::Tcp, endpoint.port())?,
+            });
+        }
+        let endpoint = local.as_ref().unwrap().listen_endpoint();
+        drop(local);
+
+        let binding = self.binding.lock();
+        let mut inner = binding.iface.lock();
+        let socket = inner
+            .sockets
+            .get_mut::<smoltcp::socket::tcp::Socket>(binding.handle);
+        socket.listen(endpoint).map_err(Error::TcpListen)?;
+        let (timeout, keep_alive, nagle, hop_limit) = (
+            socket.timeout(),
+            socket.keep_alive(),
+            socket.nagle_enabled(),
+            socket.hop_limit(),
+        );
+        drop(inner);
 
-        {
-            let mut inner = DEFAULT_DRIVER.get().unwrap().lock();
-            let mut sockets = SOCKETS.lock();
-            let socket = sockets.get_mut::<smoltcp::socket::tcp::Socket>(self.handle);
-            let local_port = 49152 + OsRng.gen::<u16>() % 16384;
-            if let Err(e) = socket.connect(inner.iface.context(), addr, local_port) {
-                return Err(Error::TcpConnect(e));
-            };
+        let mut listeners = self.listeners.lock();
+        listeners.clear();
+        if endpoint.addr.is_some() {
+            return Ok(());
+        }
+        let interfaces = INTERFACES.lock().clone();
+        for iface in interfaces {
+            if Arc::ptr_eq(&iface, &binding.iface) {
+                continue;
+            }
+            let mut socket = Self::socket(self.buffer_sizes);
+            socket.set_timeout(timeout);
+            socket.set_keep_alive(keep_alive);
+            socket.set_nagle_enabled(nagle);
+            socket.set_hop_limit(hop_limit);
+            socket.listen(endpoint).map_err(Error::TcpListen)?;
+            let handle = iface.lock().sockets.add(socket);
+            listeners.push(SocketBinding { iface, handle });
         }
+        Ok(())
+    }
+
+    /// Wait for a peer to connect to a listening socket.
+    pub async fn accept(&self) -> Result<core::net::SocketAddr, Error> {
+        poll_fn(|cx| {
+            let mut binding = self.binding.lock();
+            let mut listeners = self.listeners.lock();
+
+            let mut accepted = None;
+            for (i, listener) in core::iter::once(&*binding)
+                .chain(listeners.iter())
+                .enumerate()
+            {
+                let mut inner = core::task::ready!(listener.iface.poll_lock(cx));
+                let socket = inner
+                    .sockets
+                    .get_mut::<smoltcp::socket::tcp::Socket>(listener.handle);
+                match socket.state() {
+                    smoltcp::socket::tcp::State::Listen
+                    | smoltcp::socket::tcp::State::SynReceived => {
+                        socket.register_recv_waker(cx.waker());
+                    }
+                    _ => {
+                        accepted = Some((i, socket.remote_endpoint()));
+                        break;
+                    }
+                }
+            }
+            let Some((i, endpoint)) = accepted else {
+                return Poll::Pending;
+            };
 
-        WAIT_CELL.wake();
+            // whichever socket got the connection becomes this one, and the
+            // rest stop listening
+            if i > 0 {
+                core::mem::swap(&mut *binding, &mut listeners[i - 1]);
+            }
+            listeners.clear();
+            match endpoint {
+                Some(endpoint) => Poll::Ready(Ok((endpoint.addr.into(), endpoint.port).into())),
+                None => Poll::Ready(Err(Error::TcpClosed)),
+            }
+        })
+        .await
+    }
+
+    /// Send FIN once everything written so far is out. Reads keep working
+    /// until the peer closes its side too.
+    pub fn shutdown(&self) {
+        self.listeners.lock().clear();
+        let binding = self.binding.lock();
+        binding
+            .iface
+            .lock()
+            .sockets
+            .get_mut::<smoltcp::socket::tcp::Socket>(binding.handle)
+            .close();
+        binding.iface.wake();
+    }
+
+    /// Send FIN and wait for the connection to wind down.
+    pub async fn close(&self) {
+        self.shutdown();
 
         poll_fn(|cx| {
-            let mut sockets = SOCKETS.lock();
-            let socket = sockets.get_mut::<smoltcp::socket::tcp::Socket>(self.handle);
-            if socket.is_open() {
-                Poll::Ready(())
-            } else {
-                socket.register_send_waker(cx.waker());
-                Poll::Pending
+            let binding = self.binding.lock();
+            let mut inner = core::task::ready!(binding.iface.poll_lock(cx));
+            let socket = inner
+                .sockets
+                .get_mut::<smoltcp::socket::tcp::Socket>(binding.handle);
+            match socket.state() {
+                smoltcp::socket::tcp::State::Closed | smoltcp::socket::tcp::State::TimeWait => {
+                    Poll::Ready(())
+                }
+                _ => {
+                    socket.register_send_waker(cx.waker());
+                    Poll::Pending
+                }
             }
         })
-        .await;
+        .await
+    }
 
-        Ok(())
+    /// Connect to `addr` and wait for the handshake to finish. Gives up
+    /// after the socket's timeout, or [`CONNECT_TIMEOUT`] if none is set.
+    pub async fn connect(&self, addr: impl Into<core::net::SocketAddr>) -> Result<(), Error> {
+        let addr: core::net::SocketAddr = addr.into();
+
+        let local = {
+            let mut local = self.local.lock();
+            // a bound address already tied the socket to its interface
+            if local.as_ref().is_none_or(|l| l.addr.is_none()) {
+                let iface =
+                    route_interface(&addr.ip().into()).ok_or(Error::DestinationUnreachable)?;
+                self.binding.lock().attach(iface);
+            }
+            if local.is_none() {
+                *local = Some(LocalEndpoint {
+                    addr: None,
+                    port: port::Port::ephemeral(port::Protocol::Tcp)?,
+                });
+            }
+            local.as_ref().unwrap().listen_endpoint()
+        };
+
+        // smoltcp's own timeout is off during the handshake, so the socket
+        // only closes on a reset and the timeout below decides alone
+        let timeout = {
+            let binding = self.binding.lock();
+            let mut inner = binding.iface.lock();
+            let inner = &mut *inner;
+            let socket = inner
+                .sockets
+                .get_mut::<smoltcp::socket::tcp::Socket>(binding.handle);
+            let timeout = socket.timeout();
+            socket.set_timeout(None);
+            match socket.connect(inner.iface.context(), addr, local) {
+                Ok(()) => {}
+                Err(e) => {
+                    socket.set_timeout(timeout);
+                    return Err(match e {
+                        smoltcp::socket::tcp::ConnectError::Unaddressable => {
+                            Error::DestinationUnreachable
+                        }
+                        e => Error::TcpConnect(e),
+                    });
+                }
+            }
+            binding.iface.wake();
+            timeout
+        };
+        let limit = timeout.map_or(CONNECT_TIMEOUT, Into::into);
+
+        let handshake = poll_fn(|cx| {
+            let binding = self.binding.lock();
+            let mut inner = core::task::ready!(binding.iface.poll_lock(cx));
+            let socket = inner
+                .sockets
+                .get_mut::<smoltcp::socket::tcp::Socket>(binding.handle);
+            match socket.state() {
+                smoltcp::socket::tcp::State::SynSent | smoltcp::socket::tcp::State::SynReceived => {
+                    socket.register_send_waker(cx.waker());
+                    Poll::Pending
+                }
+                smoltcp::socket::tcp::State::Closed => Poll::Ready(Err(Error::ConnectionRefused)),
+                // anything else means the handshake completed, even if the
+                // peer has already started closing
+                _ => Poll::Ready(Ok(())),
+            }
+        });
+
+        let result = maitake::time::timeout(limit, handshake).await;
+        let binding = self.binding.lock();
+        let mut inner = binding.iface.lock();
+        let socket = inner
+            .sockets
+            .get_mut::<smoltcp::socket::tcp::Socket>(binding.handle);
+        socket.set_timeout(timeout);
+        match result {
+            Ok(result) => result,
+            Err(_) => {
+                socket.abort();
+                drop(inner);
+                binding.iface.wake();
+                Err(Error::TimedOut)
+            }
+        }
     }
 
+    fn with_socket<R>(&self, f: impl FnOnce(&mut smoltcp::socket::tcp::Socket) -> R) -> R {
+        let binding = self.binding.lock();
+        let mut inner = binding.iface.lock();
+        f(inner
+            .sockets
+            .get_mut::<smoltcp::socket::tcp::Socket>(binding.handle))
+    }
+
+    /// Abort the connection if the peer stops acknowledging for this long.
     pub fn set_timeout(&self, timeout: Option<core::time::Duration>) {
-        let mut sockets = SOCKETS.lock();
-        let socket = sockets.get_mut::<smoltcp::socket::tcp::Socket>(self.handle);
-        socket
-            .set_timeout(timeout.map(|t| smoltcp::time::Duration::from_micros(t.as_micros() as _)));
+        self.with_socket(|s| s.set_timeout(timeout.map(Into::into)));
+    }
+
+    pub fn timeout(&self) -> Option<core::time::Duration> {
+        self.with_socket(|s| s.timeout().map(Into::into))
+    }
+
+    /// Send a keep-alive probe after the connection has been idle this
+    /// long. Combine with [`set_timeout`](Self::set_timeout) so a peer that
+    /// has gone away is noticed.
+    pub fn set_keep_alive(&self, interval: Option<core::time::Duration>) {
+        self.with_socket(|s| s.set_keep_alive(interval.map(Into::into)));
+        self.binding.lock().iface.wake();
+    }
+
+    pub fn keep_alive(&self) -> Option<core::time::Duration> {
+        self.with_socket(|s| s.keep_alive().map(Into::into))
+    }
+
+    /// Send small writes straight away instead of coalescing them (Nagle's
+    /// algorithm, on by default).
+    pub fn set_nodelay(&self, nodelay: bool) {
+        self.with_socket(|s| s.set_nagle_enabled(!nodelay));
+    }
+
+    pub fn nodelay(&self) -> bool {
+        self.with_socket(|s| !s.nagle_enabled())
+    }
+
+    /// Set the TTL / hop limit of outgoing packets. `None` or 0 uses the
+    /// default of 64.
+    pub fn set_hop_limit(&self, hop_limit: Option<u8>) {
+        self.with_socket(|s| s.set_hop_limit(hop_limit.filter(|h| *h != 0)));
+    }
+
+    pub fn hop_limit(&self) -> Option<u8> {
+        self.with_socket(|s| s.hop_limit())
+    }
+
+    pub fn local_addr(&self) -> Option<core::net::SocketAddr> {
+        if let Some(e) = self.with_socket(|s| s.local_endpoint()) {
+            return Some((e.addr.into(), e.port).into());
+        }
+        // not connected, so report what it was bound to
+        let local = self.local.lock();
+        let local = local.as_ref()?;
+        Some((local.addr?, local.port.number()).into())
+    }
+
+    pub fn peer_addr(&self) -> Option<core::net::SocketAddr> {
+        self.with_socket(|s| s.remote_endpoint())
+            .map(|e| (e.addr.into(), e.port).into())
+    }
+
+    pub fn state(&self) -> TcpState {
+        self.with_socket(|s| s.state())
     }
 
     pub async fn read(&self, buf: &mut [u8]) -> Result<usize, Error> {
         let f = poll_fn(|cx| {
-            let mut sockets = SOCKETS.lock();
-            let socket = sockets.get_mut::<smoltcp::socket::tcp::Socket>(self.handle);
+            let binding = self.binding.lock();
+            let mut inner = core::task::ready!(binding.iface.poll_lock(cx));
+            let socket = inner
+                .sockets
+                .get_mut::<smoltcp::socket::tcp::Socket>(binding.handle);
             match socket.recv_slice(buf) {
                 Ok(n) => {
                     if n > 0 {
                         return Poll::Ready(Ok(n));
                     }
                 }
+                Err(smoltcp::socket::tcp::RecvError::Finished) => return Poll::Ready(Ok(0)),
                 Err(e) => {
-                    if e == smoltcp::socket::tcp::RecvError::Finished {
-                        return Poll::Ready(Ok(0));
+                    // still waiting for the connection to be set up
+                    if !matches!(
+                        socket.state(),
+                        smoltcp::socket::tcp::State::Listen
+                            | smoltcp::socket::tcp::State::SynSent
+                            | smoltcp::socket::tcp::State::SynReceived
+                    ) {
+                        return Poll::Ready(Err(e));
                     }
                 }
             }
@@ -382,15 +1228,27 @@ impl TcpSocket {
 
     pub async fn write(&self, buf: &[u8]) -> Result<usize, Error> {
         let f = poll_fn(|cx| {
-            let mut sockets = SOCKETS.lock();
-            let socket = sockets.get_mut::<smoltcp::socket::tcp::Socket>(self.handle);
-            if socket.state() == smoltcp::socket::tcp::State::Closed {
+            let binding = self.binding.lock();
+            let mut inner = core::task::ready!(binding.iface.poll_lock(cx));
+            let socket = inner
+                .sockets
+                .get_mut::<smoltcp::socket::tcp::Socket>(binding.handle);
+            // nothing more can be sent once our side is shut down, but a
+            // connection still being set up is worth waiting for
+            if !socket.may_send()
+                && !matches!(
+                    socket.state(),
+                    smoltcp::socket::tcp::State::Listen
+                        | smoltcp::socket::tcp::State::SynSent
+                        | smoltcp::socket::tcp::State::SynReceived
+                )
+            {
                 return Poll::Ready(Err(Error::TcpClosed));
             }
             if socket.can_send() {
                 match socket.send_slice(buf) {
                     Ok(n) => {
-                        WAIT_CELL.wake();
+                        binding.iface.wake();
                         Poll::Ready(Ok(n))
                     }
                     Err(e) => Poll::Ready(Err(Error::TcpSend(e))),
@@ -403,15 +1261,266 @@ impl TcpSocket {
 
         f.await
     }
+
+    pub async fn write_all(&self, mut buf: &[u8]) -> Result<(), Error> {
+        while !buf.is_empty() {
+            let n = self.write(buf).await?;
+            buf = &buf[n..];
+        }
+        Ok(())
+    }
+
+    /// Fill `buf`, failing with [`Error::TcpClosed`] if the peer closes first.
+    pub async fn read_exact(&self, mut buf: &mut [u8]) -> Result<(), Error> {
+        while !buf.is_empty() {
+            let n = self.read(buf).await?;
+            if n == 0 {
+                return Err(Error::TcpClosed);
+            }
+            buf = &mut buf[n..];
+        }
+        Ok(())
+    }
+
+    /// Read until the peer closes, appending to `buf`. Returns the number of
+    /// bytes read.
+    pub async fn read_to_end(&self, buf: &mut Vec<u8>) -> Result<usize, Error> {
+        let start = buf.len();
+        loop {
+            let len = buf.len();
+            buf.resize(len + 4096, 0);
+            let n = match self.read(&mut buf[len..]).await {
+                Ok(n) => n,
+                Err(e) => {
+                    buf.truncate(len);
+                    return Err(e);
+                }
+            };
+            buf.truncate(len + n);
+            if n == 0 {
+                return Ok(buf.len() - start);
+            }
+        }
+    }
+
+    /// Wait until the peer has acknowledged everything written so far.
+    pub async fn flush(&self) -> Result<(), Error> {
+        poll_fn(|cx| {
+            let binding = self.binding.lock();
+            let mut inner = core::task::ready!(binding.iface.poll_lock(cx));
+            let socket = inner
+                .sockets
+                .get_mut::<smoltcp::socket::tcp::Socket>(binding.handle);
+            if socket.send_queue() == 0 {
+                Poll::Ready(Ok(()))
+            } else if !socket.may_send() && !socket.is_open() {
+                Poll::Ready(Err(Error::TcpClosed))
+            } else {
+                socket.register_send_waker(cx.waker());
+                Poll::Pending
+            }
+        })
+        .await
+    }
+}
+
+impl embedded_io_async::Error for Error {
+    fn kind(&self) -> embedded_io_async::ErrorKind {
+        use embedded_io_async::ErrorKind;
+
+        match self {
+            Error::TcpConnect(_) | Error::DestinationUnreachable => ErrorKind::NotConnected,
+            Error::ConnectionRefused => ErrorKind::ConnectionRefused,
+            Error::TcpListen(_) | Error::UdpBind(_) | Error::PortsExhausted => ErrorKind::AddrInUse,
+            Error::AddressNotAvailable => ErrorKind::AddrNotAvailable,
+            Error::TcpRecv(_) | Error::TcpClosed => ErrorKind::ConnectionReset,
+            Error::TcpSend(_) => ErrorKind::BrokenPipe,
+            Error::TimedOut => ErrorKind::TimedOut,
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
+impl embedded_io_async::ErrorType for TcpSocket {
+    type Error = Error;
+}
+
+impl embedded_io_async::Read for TcpSocket {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        TcpSocket::read(self, buf).await
+    }
 }
 
-impl Drop for TcpSocket {
+impl embedded_io_async::Write for TcpSocket {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        TcpSocket::write(self, buf).await
+    }
+
+    async fn flush(&mut self) -> Result<(), Error> {
+        TcpSocket::flush(self).await
+    }
+}
+
+impl embedded_io_async::ErrorType for &TcpSocket {
+    type Error = Error;
+}
+
+impl embedded_io_async::Read for &TcpSocket {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        TcpSocket::read(self, buf).await
+    }
+}
+
+impl embedded_io_async::Write for &TcpSocket {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        TcpSocket::write(self, buf).await
+    }
+
+    async fn flush(&mut self) -> Result<(), Error> {
+        TcpSocket::flush(self).await
+    }
+}
+
+impl Drop for SocketBinding {
     fn drop(&mut self) {
-        SOCKETS.lock().remove(self.handle);
+        self.iface.lock().sockets.remove(self.handle);
+    }
+}
+
+pub struct UdpSocket {
+    binding: Mutex<SocketBinding>,
+    /// Bound to an address or created for a specific interface, so sending
+    /// must not move it elsewhere.
+    pinned: bool,
+    port: port::Port,
+}
+
+impl UdpSocket {
+    /// Bind to `endpoint`. Port 0 picks an ephemeral port.
+    pub fn bind(endpoint: impl Into<core::net::SocketAddr>) -> Result<Self, Error> {
+        let endpoint: core::net::SocketAddr = endpoint.into();
+        let iface = if endpoint.ip().is_unspecified() {
+            default_interface().ok_or(Error::NoSuchInterface)?
+        } else {
+            address_interface(endpoint.ip()).ok_or(Error::AddressNotAvailable)?
+        };
+        let mut socket = Self::bind_on(&iface, listen_endpoint(endpoint))?;
+        socket.pinned = !endpoint.ip().is_unspecified();
+        Ok(socket)
+    }
+
+    fn bind_on(
+        iface: &InterfaceRef,
+        endpoint: impl Into<smoltcp::wire::IpListenEndpoint>,
+    ) -> Result<Self, Error> {
+        let rx_buffer = smoltcp::socket::udp::PacketBuffer::new(
+            vec![smoltcp::socket::udp::PacketMetadata::EMPTY; 8],
+            vec![0; 8192],
+        );
+        let tx_buffer = smoltcp::socket::udp::PacketBuffer::new(
+            vec![smoltcp::socket::udp::PacketMetadata::EMPTY; 8],
+            vec![0; 8192],
+        );
+
+        let mut endpoint = endpoint.into();
+        let port = port::Port::bind(port::Protocol::Udp, endpoint.port)?;
+        endpoint.port = port.number();
+
+        let mut socket = smoltcp::socket::udp::Socket::new(rx_buffer, tx_buffer);
+        socket.bind(endpoint).map_err(Error::UdpBind)?;
+
+        let handle = iface.lock().sockets.add(socket);
+
+        Ok(Self {
+            binding: Mutex::new(SocketBinding {
+                iface: iface.clone(),
+                handle,
+            }),
+            pinned: true,
+            port,
+        })
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.port.number()
+    }
+
+    /// Wait until everything sent so far has been handed to the device.
+    pub async fn flush(&self) {
+        poll_fn(|cx| {
+            let binding = self.binding.lock();
+            let mut inner = core::task::ready!(binding.iface.poll_lock(cx));
+            let socket = inner
+                .sockets
+                .get_mut::<smoltcp::socket::udp::Socket>(binding.handle);
+            if socket.send_queue() == 0 {
+                Poll::Ready(())
+            } else {
+                socket.register_send_waker(cx.waker());
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    pub async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, core::net::SocketAddr), Error> {
+        poll_fn(|cx| {
+            let binding = self.binding.lock();
+            let mut inner = core::task::ready!(binding.iface.poll_lock(cx));
+            let socket = inner
+                .sockets
+                .get_mut::<smoltcp::socket::udp::Socket>(binding.handle);
+            match socket.recv_slice(buf) {
+                Ok((n, meta)) => Poll::Ready(Ok((
+                    n,
+                    (meta.endpoint.addr.into(), meta.endpoint.port).into(),
+                ))),
+                Err(smoltcp::socket::udp::RecvError::Exhausted) => {
+                    socket.register_recv_waker(cx.waker());
+                    Poll::Pending
+                }
+                Err(e) => Poll::Ready(Err(Error::UdpRecv(e))),
+            }
+        })
+        .await
+    }
+
+    pub async fn send_to(
+        &self,
+        buf: &[u8],
+        addr: impl Into<core::net::SocketAddr>,
+    ) -> Result<(), Error> {
+        let addr: core::net::SocketAddr = addr.into();
+
+        if !self.pinned {
+            let iface = route_interface(&addr.ip().into()).ok_or(Error::DestinationUnreachable)?;
+            self.binding.lock().attach(iface);
+        }
+
+        poll_fn(|cx| {
+            let binding = self.binding.lock();
+            let mut inner = core::task::ready!(binding.iface.poll_lock(cx));
+            let socket = inner
+                .sockets
+                .get_mut::<smoltcp::socket::udp::Socket>(binding.handle);
+            match socket.send_slice(buf, smoltcp::wire::IpEndpoint::from(addr)) {
+                Ok(()) => {
+                    binding.iface.wake();
+                    Poll::Ready(Ok(()))
+                }
+                Err(smoltcp::socket::udp::SendError::BufferFull) => {
+                    socket.register_send_waker(cx.waker());
+                    Poll::Pending
+                }
+                Err(e) => Poll::Ready(Err(Error::UdpSend(e))),
+            }
+        })
+        .await
     }
 }
 
 pub struct DnsSocket {
+    iface: InterfaceRef,
     handle: smoltcp::iface::SocketHandle,
 }
 
@@ -419,16 +1528,28 @@ pub type DnsQueryType = smoltcp::wire::DnsQueryType;
 
 impl DnsSocket {
     pub fn new() -> Result<Self, Error> {
-        let inner = DEFAULT_DRIVER.get().unwrap().lock();
+        let iface = {
+            let interfaces = INTERFACES.lock();
+            interfaces
+                .iter()
+                .find(|i| !i.lock().dns_servers.is_empty())
+                .or(interfaces.first())
+                .cloned()
+                .ok_or(Error::NoSuchInterface)?
+        };
+        let mut inner = iface.lock();
         let mut dns_socket = smoltcp::socket::dns::Socket::new(&[], vec![]);
         dns_socket.update_servers(&inner.dns_servers);
-        let handle = SOCKETS.lock().add(dns_socket);
-        Ok(Self { handle })
+        let handle = inner.sockets.add(dns_socket);
+        drop(inner);
+        Ok(Self { iface, handle })
     }
 
     pub fn update_servers(&self, servers: &[core::net::IpAddr]) {
-        let mut sockets = SOCKETS.lock();
-        let socket = sockets.get_mut::<smoltcp::socket::dns::Socket>(self.handle);
+        let mut inner = self.iface.lock();
+        let socket = inner
+            .sockets
+            .get_mut::<smoltcp::socket::dns::Socket>(self.handle);
         let servers = servers.iter().map(|v| (*v).into()).collect::<Vec<_>>();
         socket.update_servers(&servers);
     }
@@ -439,12 +1560,14 @@ impl DnsSocket {
         typ: DnsQueryType,
     ) -> Result<Vec<core::net::IpAddr>, Error> {
         let query_handle = {
-            let mut inner = DEFAULT_DRIVER.get().unwrap().lock();
-            let mut sockets = SOCKETS.lock();
-            let socket = sockets.get_mut::<smoltcp::socket::dns::Socket>(self.handle);
+            let mut inner = self.iface.lock();
+            let inner = &mut *inner;
+            let socket = inner
+                .sockets
+                .get_mut::<smoltcp::socket::dns::Socket>(self.handle);
             match socket.start_query(inner.iface.context(), name, typ) {
                 Ok(handle) => {
-                    WAIT_CELL.wake();
+                    self.iface.wake();
                     handle
                 }
                 Err(e) => {
@@ -453,23 +1576,25 @@ impl DnsSocket {
             }
         };
 
-        struct DropQuery(
-            smoltcp::iface::SocketHandle,
-            smoltcp::socket::dns::QueryHandle,
-        );
-        impl Drop for DropQuery {
+        struct DropQuery<'a>(&'a DnsSocket, smoltcp::socket::dns::QueryHandle);
+        impl Drop for DropQuery<'_> {
             fn drop(&mut self) {
-                let mut sockets = SOCKETS.lock();
-                if let Some(socket) = sockets.try_get_mut::<smoltcp::socket::dns::Socket>(self.0) {
+                let mut inner = self.0.iface.lock();
+                if let Some(socket) = inner
+                    .sockets
+                    .try_get_mut::<smoltcp::socket::dns::Socket>(self.0.handle)
+                {
                     socket.cancel_query(self.1);
                 }
             }
         }
-        let drop_query = DropQuery(self.handle, query_handle);
+        let drop_query = DropQuery(self, query_handle);
 
         let r = poll_fn(|cx| {
-            let mut sockets = SOCKETS.lock();
-            let socket = sockets.get_mut::<smoltcp::socket::dns::Socket>(self.handle);
+            let mut inner = core::task::ready!(self.iface.poll_lock(cx));
+            let socket = inner
+                .sockets
+                .get_mut::<smoltcp::socket::dns::Socket>(self.handle);
             match socket.get_query_result(query_handle) {
                 Ok(results) => Poll::Ready(Ok(results)),
                 Err(smoltcp::socket::dns::GetQueryResultError::Pending) => {
@@ -491,7 +1616,7 @@ impl DnsSocket {
 
 impl Drop for DnsSocket {
     fn drop(&mut self) {
-        SOCKETS.lock().remove(self.handle);
+        self.iface.lock().sockets.remove(self.handle);
     }
 }
 
@@ -509,16 +1634,19 @@ pub enum Dhcp4Event {
     Deconfigure,
 }
 
-#[derive(Debug)]
 pub struct Dhcp4Socket {
+    iface: InterfaceRef,
     handle: smoltcp::iface::SocketHandle,
 }
 
 impl Dhcp4Socket {
-    pub fn new() -> Self {
+    fn new(iface: &InterfaceRef) -> Self {
         let socket = smoltcp::socket::dhcpv4::Socket::new();
-        let handle = SOCKETS.lock().add(socket);
-        Self { handle }
+        let handle = iface.lock().sockets.add(socket);
+        Self {
+            iface: iface.clone(),
+            handle,
+        }
     }
 
     pub async fn event(&self) -> Dhcp4Event {
@@ -526,8 +1654,10 @@ impl Dhcp4Socket {
     }
 
     pub fn poll(&self, cx: &mut core::task::Context) -> Poll<Dhcp4Event> {
-        let mut sockets = SOCKETS.lock();
-        let socket = sockets.get_mut::<smoltcp::socket::dhcpv4::Socket>(self.handle);
+        let mut inner = core::task::ready!(self.iface.poll_lock(cx));
+        let socket = inner
+            .sockets
+            .get_mut::<smoltcp::socket::dhcpv4::Socket>(self.handle);
         match socket.poll() {
             None => {
                 socket.register_waker(cx.waker());
@@ -550,17 +1680,17 @@ impl Dhcp4Socket {
 
 impl Drop for Dhcp4Socket {
     fn drop(&mut self) {
-        SOCKETS.lock().remove(self.handle);
+        self.iface.lock().sockets.remove(self.handle);
     }
 }
 
-#[derive(Debug)]
 struct IcmpSocket {
+    iface: InterfaceRef,
     handle: smoltcp::iface::SocketHandle,
 }
 
 impl IcmpSocket {
-    fn bind(endpoint: smoltcp::socket::icmp::Endpoint) -> Self {
+    fn bind(iface: &InterfaceRef, endpoint: smoltcp::socket::icmp::Endpoint) -> Self {
         let rx_buffer = smoltcp::socket::icmp::PacketBuffer::new(
             vec![smoltcp::socket::icmp::PacketMetadata::EMPTY],
             vec![0; 1500],
@@ -574,21 +1704,28 @@ impl IcmpSocket {
         socket.bind(endpoint).unwrap();
         assert!(socket.is_open());
 
-        let handle = SOCKETS.lock().add(socket);
+        let handle = iface.lock().sockets.add(socket);
 
-        Self { handle }
+        Self {
+            iface: iface.clone(),
+            handle,
+        }
     }
 
     fn set_hop_limit(&self, limit: u8) {
-        let mut sockets = SOCKETS.lock();
-        let socket = sockets.get_mut::<smoltcp::socket::icmp::Socket>(self.handle);
+        let mut inner = self.iface.lock();
+        let socket = inner
+            .sockets
+            .get_mut::<smoltcp::socket::icmp::Socket>(self.handle);
         socket.set_hop_limit(Some(limit));
     }
 
     async fn read(&self, buf: &mut [u8]) -> Result<(usize, core::net::IpAddr), Error> {
         let f = poll_fn(|cx| {
-            let mut sockets = SOCKETS.lock();
-            let socket = sockets.get_mut::<smoltcp::socket::icmp::Socket>(self.handle);
+            let mut inner = core::task::ready!(self.iface.poll_lock(cx));
+            let socket = inner
+                .sockets
+                .get_mut::<smoltcp::socket::icmp::Socket>(self.handle);
             match socket.recv_slice(buf) {
                 Ok((n, ip)) => Poll::Ready(Ok((n, ip.into()))),
                 Err(e) => match e {
@@ -608,12 +1745,14 @@ impl IcmpSocket {
         let endpoint: core::net::IpAddr = endpoint.into();
 
         let f = poll_fn(|cx| {
-            let mut sockets = SOCKETS.lock();
-            let socket = sockets.get_mut::<smoltcp::socket::icmp::Socket>(self.handle);
+            let mut inner = core::task::ready!(self.iface.poll_lock(cx));
+            let socket = inner
+                .sockets
+                .get_mut::<smoltcp::socket::icmp::Socket>(self.handle);
 
             match socket.send_slice(buf, endpoint.into()) {
                 Ok(()) => {
-                    WAIT_CELL.wake();
+                    self.iface.wake();
                     Poll::Ready(Ok(()))
                 }
                 Err(e) => match e {
@@ -632,41 +1771,233 @@ impl IcmpSocket {
 
 impl Drop for IcmpSocket {
     fn drop(&mut self) {
-        SOCKETS.lock().remove(self.handle);
+        self.iface.lock().sockets.remove(self.handle);
     }
 }
 
 #[derive(Debug)]
 pub struct InterfaceConfig {
+    pub name: String,
     pub mac: Option<[u8; 6]>,
     pub ip_addrs: Vec<smoltcp::wire::IpCidr>,
     pub routes: Vec<smoltcp::iface::Route>,
     pub dns_servers: Vec<core::net::IpAddr>,
+    pub dhcp4: bool,
+    pub auto6: bool,
+    pub dhcp6: Option<Dhcp6Mode>,
 }
 
-pub fn config() -> Option<InterfaceConfig> {
-    let inner = DEFAULT_DRIVER.get()?.lock();
-    let mut routes = Vec::new();
-    routes.extend(inner.iface.routes().v4().iter().map(|a| a.2));
-    routes.extend(inner.iface.routes().v6().iter().map(|a| a.2));
-    Some(InterfaceConfig {
-        mac: match inner.iface.hardware_addr() {
-            smoltcp::wire::HardwareAddress::Ethernet(e) => Some(e.0),
-            smoltcp::wire::HardwareAddress::Ip => None,
-        },
-        ip_addrs: inner.iface.ip_addrs().to_owned(),
-        routes,
-        dns_servers: inner.dns_servers.iter().map(|v| (*v).into()).collect(),
-    })
+pub fn interfaces() -> Vec<InterfaceConfig> {
+    INTERFACES
+        .lock()
+        .iter()
+        .map(|iface| {
+            let inner = iface.lock();
+            let mut routes = Vec::new();
+            routes.extend(inner.iface.routes().v4().iter().map(|a| a.2));
+            routes.extend(inner.iface.routes().v6().iter().map(|a| a.2));
+            InterfaceConfig {
+                name: inner.name.clone(),
+                mac: match inner.iface.hardware_addr() {
+                    smoltcp::wire::HardwareAddress::Ethernet(e) => Some(e.0),
+                    smoltcp::wire::HardwareAddress::Ip => None,
+                },
+                ip_addrs: inner.iface.ip_addrs().to_owned(),
+                routes,
+                dns_servers: inner.dns_servers.iter().map(|v| (*v).into()).collect(),
+                dhcp4: inner.dhcp4.is_some(),
+                auto6: inner.auto6.is_some(),
+                dhcp6: inner.dhcp6.as_ref().map(|s| s.mode),
+            }
+        })
+        .collect()
+}
+
+pub fn add_address(name: &str, cidr: smoltcp::wire::IpCidr) -> Result<(), Error> {
+    let iface = find_interface(name)?;
+    let mut inner = iface.lock();
+    if inner.iface.ip_addrs().contains(&cidr) {
+        return Ok(());
+    }
+    let mut result = Ok(());
+    inner.iface.update_ip_addrs(|addrs| {
+        if addrs.push(cidr).is_err() {
+            result = Err(Error::AddressTableFull);
+        }
+    });
+    result
+}
+
+pub fn remove_address(name: &str, cidr: smoltcp::wire::IpCidr) -> Result<(), Error> {
+    let iface = find_interface(name)?;
+    let mut inner = iface.lock();
+    inner.iface.update_ip_addrs(|addrs| {
+        addrs.retain(|c| *c != cidr);
+    });
+    Ok(())
+}
+
+pub fn set_gateway(name: &str, gateway: core::net::IpAddr) -> Result<(), Error> {
+    let iface = find_interface(name)?;
+    let mut inner = iface.lock();
+    let routes = inner.iface.routes_mut();
+    match gateway {
+        core::net::IpAddr::V4(gateway) => routes.add_default_ipv4_route(gateway),
+        core::net::IpAddr::V6(gateway) => routes.add_default_ipv6_route(gateway),
+    }
+    .map_err(|_| Error::RouteTableFull)?;
+    Ok(())
+}
+
+pub fn remove_gateway(name: &str, ipv6: bool) -> Result<(), Error> {
+    let iface = find_interface(name)?;
+    let mut inner = iface.lock();
+    if ipv6 {
+        inner.iface.routes_mut().remove_default_ipv6_route();
+    } else {
+        inner.iface.routes_mut().remove_default_ipv4_route();
+    }
+    Ok(())
+}
+
+pub fn add_dns_server(name: &str, server: core::net::IpAddr) -> Result<(), Error> {
+    let iface = find_interface(name)?;
+    let mut inner = iface.lock();
+    let server = server.into();
+    if !inner.dns_servers.contains(&server) {
+        inner.dns_servers.push(server);
+    }
+    Ok(())
+}
+
+pub fn remove_dns_server(name: &str, server: core::net::IpAddr) -> Result<(), Error> {
+    let iface = find_interface(name)?;
+    let server: smoltcp::wire::IpAddress = server.into();
+    iface.lock().dns_servers.retain(|s| *s != server);
+    Ok(())
+}
+
+pub fn set_dhcp4(name: &str, enabled: bool) -> Result<(), Error> {
+    let iface = find_interface(name)?;
+    let running = iface.lock().dhcp4.is_some();
+    match (running, enabled) {
+        (false, true) => start_dhcp4(&iface),
+        (true, false) => stop_dhcp4(&iface),
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Drop the current lease (if any) and restart discovery.
+pub fn renew_dhcp4(name: &str) -> Result<(), Error> {
+    let iface = find_interface(name)?;
+    let mut inner = iface.lock();
+    let Some(socket) = inner.dhcp4.as_ref().map(|s| s.socket) else {
+        return Err(Error::Dhcp4Disabled);
+    };
+    inner
+        .sockets
+        .get_mut::<smoltcp::socket::dhcpv4::Socket>(socket)
+        .reset();
+    iface.wake();
+    Ok(())
+}
+
+/// Give up the current lease and stop the client until it is enabled again.
+pub async fn release_dhcp4(name: &str) -> Result<(), Error> {
+    let iface = find_interface(name)?;
+    let lease = {
+        let inner = iface.lock();
+        let Some(state) = inner.dhcp4.as_ref() else {
+            return Err(Error::Dhcp4Disabled);
+        };
+        state
+            .lease
+            .as_ref()
+            .map(|l| (l.server.address, l.address.address()))
+    };
+    // the address has to stay until the release is out, it's the source
+    if let Some((server, address)) = lease {
+        if let Err(e) = send_dhcp4_release(&iface, server, address).await {
+            warn!("[NET] {name}: failed to send DHCPRELEASE: {e}");
+        }
+    }
+    stop_dhcp4(&iface);
+    Ok(())
+}
+
+/// Tell `server` that `address` is no longer in use.
+async fn send_dhcp4_release(
+    iface: &InterfaceRef,
+    server: smoltcp::wire::Ipv4Address,
+    address: smoltcp::wire::Ipv4Address,
+) -> Result<(), Error> {
+    let smoltcp::wire::HardwareAddress::Ethernet(mac) = iface.lock().iface.hardware_addr() else {
+        return Ok(());
+    };
+    let repr = smoltcp::wire::DhcpRepr {
+        message_type: smoltcp::wire::DhcpMessageType::Release,
+        transaction_id: OsRng.next_u32(),
+        secs: 0,
+        client_hardware_address: mac,
+        client_ip: address,
+        your_ip: smoltcp::wire::Ipv4Address::UNSPECIFIED,
+        server_ip: smoltcp::wire::Ipv4Address::UNSPECIFIED,
+        router: None,
+        subnet_mask: None,
+        relay_agent_ip: smoltcp::wire::Ipv4Address::UNSPECIFIED,
+        broadcast: false,
+        requested_ip: None,
+        client_identifier: Some(mac),
+        server_identifier: Some(server),
+        parameter_request_list: None,
+        dns_servers: None,
+        max_size: None,
+        lease_duration: None,
+        renew_duration: None,
+        rebind_duration: None,
+        additional_options: &[],
+    };
+    let mut packet = vec![0; repr.buffer_len()];
+    repr.emit(&mut smoltcp::wire::DhcpPacket::new_unchecked(
+        &mut packet[..],
+    ))
+    .map_err(Error::Wire)?;
+
+    let sock = UdpSocket::bind_on(
+        iface,
+        smoltcp::wire::IpEndpoint::new(address.into(), smoltcp::wire::DHCP_CLIENT_PORT),
+    )?;
+    sock.send_to(&packet, (server, smoltcp::wire::DHCP_SERVER_PORT))
+        .await?;
+    // nobody answers a release, so only wait a little for it to go out
+    maitake::time::timeout(Duration::from_secs(1), sock.flush())
+        .await
+        .map_err(|_| Error::TimedOut)
+}
+
+pub fn set_auto6(name: &str, enabled: bool) -> Result<(), Error> {
+    let iface = find_interface(name)?;
+    let running = iface.lock().auto6.is_some();
+    match (running, enabled) {
+        (false, true) => start_auto6(&iface),
+        (true, false) => stop_auto6(&iface),
+        _ => {}
+    }
+    Ok(())
 }
 
 pub async fn ping(
     dest_ip: core::net::IpAddr,
 ) -> Result<async_channel::Receiver<(core::net::IpAddr, usize, u16, core::time::Duration)>, Error> {
-    let sock = IcmpSocket::bind(smoltcp::socket::icmp::Endpoint::Ident(0x22b));
+    let Some(iface) = route_interface(&dest_ip.into()) else {
+        return Err(Error::DestinationUnreachable);
+    };
+
+    let sock = IcmpSocket::bind(&iface, smoltcp::socket::icmp::Endpoint::Ident(0x22b));
 
     let Some(src_ip) = ({
-        let inner = DEFAULT_DRIVER.get().unwrap().lock();
+        let inner = iface.lock();
         inner.iface.get_source_address(&dest_ip.into())
     }) else {
         return Err(Error::DestinationUnreachable);
@@ -0,0 +1,84 @@
+//! Local port bookkeeping shared by every TCP and UDP socket, so ephemeral
+//! ports never collide with a port something else is already using.
+//!
+//! Claiming a specific port always succeeds: several sockets listening on
+//! the same port is how a TCP backlog works here, and whether two sockets
+//! may share an endpoint is up to smoltcp. The table only steers ephemeral
+//! allocation away from ports in use.
+
+use super::Error;
+use alloc::collections::BTreeMap;
+use core::ops::RangeInclusive;
+use rand::{rngs::OsRng, Rng};
+use spin::Mutex;
+
+/// The IANA dynamic range.
+const EPHEMERAL: RangeInclusive<u16> = 49152..=65535;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(super) enum Protocol {
+    Tcp,
+    Udp,
+}
+
+lazy_static::lazy_static! {
+    /// Number of holders of each port in use.
+    static ref PORTS: Mutex<BTreeMap<(Protocol, u16), usize>> = Mutex::new(BTreeMap::new());
+}
+
+/// A port held by a socket, given back when dropped.
+#[derive(Debug)]
+pub(super) struct Port {
+    protocol: Protocol,
+    number: u16,
+}
+
+impl Port {
+    pub(super) fn claim(protocol: Protocol, number: u16) -> Self {
+        *PORTS.lock().entry((protocol, number)).or_default() += 1;
+        Self { protocol, number }
+    }
+
+    /// Claim a free port from the ephemeral range, starting the search at a
+    /// random point so ports aren't predictable.
+    pub(super) fn ephemeral(protocol: Protocol) -> Result<Self, Error> {
+        let start = *EPHEMERAL.start();
+        let len = EPHEMERAL.len() as u32;
+        let offset = OsRng.gen_range(0..len);
+
+        let mut ports = PORTS.lock();
+        for i in 0..len {
+            let number = start + ((offset + i) % len) as u16;
+            if !ports.contains_key(&(protocol, number)) {
+                ports.insert((protocol, number), 1);
+                return Ok(Self { protocol, number });
+            }
+        }
+        Err(Error::PortsExhausted)
+    }
+
+    /// Claim `number`, or an ephemeral port if it is 0.
+    pub(super) fn bind(protocol: Protocol, number: u16) -> Result<Self, Error> {
+        match number {
+            0 => Self::ephemeral(protocol),
+            number => Ok(Self::claim(protocol, number)),
+        }
+    }
+
+    pub(super) fn number(&self) -> u16 {
+        self.number
+    }
+}
+
+impl Drop for Port {
+    fn drop(&mut self) {
+        let mut ports = PORTS.lock();
+        let key = (self.protocol, self.number);
+        if let Some(count) = ports.get_mut(&key) {
+            *count -= 1;
+            if *count == 0 {
+                ports.remove(&key);
+            }
+        }
+    }
+}
@@ -0,0 +1,247 @@
+//! Ships log events to a remote collector as RFC 5424 syslog, over UDP or
+//! over TCP with octet-counted framing (RFC 6587).
+//!
+//! Events are queued from boot, so whatever is logged before the network
+//! comes up is sent once it does. The queue is bounded and keeps the newest
+//! events.
+
+use super::{Error, TaskHandle, TcpSocket, UdpSocket};
+use core::{
+    future::poll_fn,
+    net::SocketAddr,
+    sync::atomic::{AtomicU8, AtomicUsize, Ordering},
+    task::Poll,
+    time::Duration,
+};
+use crossbeam_queue::ArrayQueue;
+use futures::task::AtomicWaker;
+use spin::Mutex;
+
+pub const PORT: u16 = 514;
+
+const QUEUE_SIZE: usize = 512;
+
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Probe an idle TCP connection this often, and give up on it if the
+/// collector hasn't answered for as long again.
+const KEEP_ALIVE: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Udp,
+    Tcp,
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub collector: SocketAddr,
+    pub transport: Transport,
+    pub hostname: String,
+    /// Most verbose level that is shipped. Anything past INFO also ships the
+    /// network stack's own chatter about sending the logs.
+    pub level: tracing::Level,
+}
+
+struct Entry {
+    timestamp: Duration,
+    level: tracing::Level,
+    target: String,
+    message: String,
+}
+
+lazy_static::lazy_static! {
+    static ref QUEUE: ArrayQueue<Entry> = ArrayQueue::new(QUEUE_SIZE);
+    static ref SHIPPER: Mutex<Option<(Config, TaskHandle)>> = Mutex::new(None);
+}
+
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(rank(tracing::Level::INFO));
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+const fn rank(level: tracing::Level) -> u8 {
+    match level {
+        tracing::Level::ERROR => 0,
+        tracing::Level::WARN => 1,
+        tracing::Level::INFO => 2,
+        tracing::Level::DEBUG => 3,
+        tracing::Level::TRACE => 4,
+    }
+}
+
+fn severity(level: tracing::Level) -> u8 {
+    match level {
+        tracing::Level::ERROR => 3,
+        tracing::Level::WARN => 4,
+        tracing::Level::INFO => 6,
+        tracing::Level::DEBUG | tracing::Level::TRACE => 7,
+    }
+}
+
+fn sink(record: &crate::debug::Record) {
+    if rank(record.level) > MAX_LEVEL.load(Ordering::Relaxed) {
+        return;
+    }
+    let entry = Entry {
+        timestamp: crate::arch::timestamp(),
+        level: record.level,
+        target: record.target.to_owned(),
+        message: record.message.to_owned(),
+    };
+    if QUEUE.force_push(entry).is_some() {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+    WAKER.wake();
+}
+
+/// Escape a structured data parameter value.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | ']') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn format(entry: &Entry, hostname: &str) -> String {
+    use chrono::{Datelike, TimeZone, Timelike};
+
+    let time = chrono::Utc
+        .timestamp_opt(entry.timestamp.as_secs() as i64, 0)
+        .unwrap();
+    // facility is kern (0), so the priority is just the severity
+    format!(
+        "<{}>1 {:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}Z {} snek_os - - [tracing@32473 level=\"{}\" target=\"{}\"] {}",
+        severity(entry.level),
+        time.year(),
+        time.month(),
+        time.day(),
+        time.hour(),
+        time.minute(),
+        time.second(),
+        entry.timestamp.subsec_micros(),
+        if hostname.is_empty() { "-" } else { hostname },
+        entry.level,
+        escape(&entry.target),
+        entry.message,
+    )
+}
+
+enum Connection {
+    Udp(UdpSocket),
+    Tcp(TcpSocket),
+}
+
+impl Connection {
+    async fn open(config: &Config) -> Result<Self, Error> {
+        match config.transport {
+            Transport::Udp => {
+                let unspecified = match config.collector {
+                    SocketAddr::V4(_) => core::net::IpAddr::V4(core::net::Ipv4Addr::UNSPECIFIED),
+                    SocketAddr::V6(_) => core::net::IpAddr::V6(core::net::Ipv6Addr::UNSPECIFIED),
+                };
+                Ok(Connection::Udp(UdpSocket::bind((unspecified, 0))?))
+            }
+            Transport::Tcp => {
+                let sock = TcpSocket::new()?;
+                sock.set_keep_alive(Some(KEEP_ALIVE));
+                sock.set_timeout(Some(KEEP_ALIVE));
+                sock.connect(config.collector).await?;
+                Ok(Connection::Tcp(sock))
+            }
+        }
+    }
+
+    async fn send(&self, config: &Config, message: &str) -> Result<(), Error> {
+        match self {
+            Connection::Udp(sock) => sock.send_to(message.as_bytes(), config.collector).await,
+            Connection::Tcp(sock) => {
+                let framed = format!("{} {message}", message.len());
+                sock.write_all(framed.as_bytes()).await
+            }
+        }
+    }
+}
+
+async fn ship(config: Config) {
+    let mut connection = None;
+    let mut pending = None;
+
+    loop {
+        if pending.is_none() {
+            let dropped = DROPPED.swap(0, Ordering::Relaxed);
+            pending = if dropped > 0 {
+                Some(Entry {
+                    timestamp: crate::arch::timestamp(),
+                    level: tracing::Level::WARN,
+                    target: module_path!().to_owned(),
+                    message: format!("{dropped} messages dropped"),
+                })
+            } else {
+                QUEUE.pop()
+            };
+        }
+
+        let Some(entry) = &pending else {
+            poll_fn(|cx| {
+                WAKER.register(cx.waker());
+                if QUEUE.is_empty() {
+                    Poll::Pending
+                } else {
+                    Poll::Ready(())
+                }
+            })
+            .await;
+            continue;
+        };
+
+        if connection.is_none() {
+            match Connection::open(&config).await {
+                Ok(c) => connection = Some(c),
+                Err(_) => {
+                    // most likely the network is not up yet
+                    maitake::time::sleep(RETRY_DELAY).await;
+                    continue;
+                }
+            }
+        }
+
+        let message = format(entry, &config.hostname);
+        match connection.as_ref().unwrap().send(&config, &message).await {
+            Ok(()) => pending = None,
+            Err(_) => {
+                connection = None;
+                maitake::time::sleep(RETRY_DELAY).await;
+            }
+        }
+    }
+}
+
+/// Start queueing log events. Nothing is sent until [`start`] is called.
+pub fn init() {
+    crate::debug::set_sink(sink);
+}
+
+/// Ship queued and future log events to `config.collector`, replacing any
+/// previous configuration.
+pub fn start(config: Config) {
+    stop();
+    MAX_LEVEL.store(rank(config.level), Ordering::Relaxed);
+    let task = crate::task::spawn(ship(config.clone()));
+    *SHIPPER.lock() = Some((config, task));
+}
+
+/// Stop shipping. Events keep being queued.
+pub fn stop() {
+    let shipper = SHIPPER.lock().take();
+    if let Some((_, task)) = shipper {
+        task.cancel();
+    }
+}
+
+pub fn config() -> Option<Config> {
+    SHIPPER.lock().as_ref().map(|(config, _)| config.clone())
+}
@@ -0,0 +1,205 @@
+//! TFTP client (RFC 1350) with the blksize (RFC 2348) and tsize (RFC 2349)
+//! options.
+
+use super::{Error, UdpSocket};
+use core::{net::SocketAddr, time::Duration};
+
+pub const PORT: u16 = 69;
+
+const OP_RRQ: u16 = 1;
+const OP_DATA: u16 = 3;
+const OP_ACK: u16 = 4;
+const OP_ERROR: u16 = 5;
+const OP_OACK: u16 = 6;
+
+const ERROR_UNKNOWN_TID: u16 = 5;
+const ERROR_OPTIONS: u16 = 8;
+
+const DEFAULT_BLOCK_SIZE: usize = 512;
+/// Fits a 1500 byte MTU under both IPv4 and IPv6.
+const BLOCK_SIZE: usize = 1432;
+
+const MAX_PREALLOCATE: usize = 64 * 1024 * 1024;
+
+const TIMEOUT: Duration = Duration::from_secs(2);
+const RETRIES: usize = 5;
+
+enum Packet<'a> {
+    Data(u16, &'a [u8]),
+    Error(u16, &'a [u8]),
+    OptionAck(&'a [u8]),
+}
+
+impl<'a> Packet<'a> {
+    fn parse(data: &'a [u8]) -> Option<Self> {
+        let [a, b, rest @ ..] = data else {
+            return None;
+        };
+        match u16::from_be_bytes([*a, *b]) {
+            OP_DATA if rest.len() >= 2 => Some(Packet::Data(
+                u16::from_be_bytes([rest[0], rest[1]]),
+                &rest[2..],
+            )),
+            OP_ERROR if rest.len() >= 2 => {
+                let message = &rest[2..];
+                let end = message
+                    .iter()
+                    .position(|b| *b == 0)
+                    .unwrap_or(message.len());
+                Some(Packet::Error(
+                    u16::from_be_bytes([rest[0], rest[1]]),
+                    &message[..end],
+                ))
+            }
+            OP_OACK => Some(Packet::OptionAck(rest)),
+            _ => None,
+        }
+    }
+}
+
+fn read_request(filename: &str, options: bool) -> Vec<u8> {
+    let mut packet = OP_RRQ.to_be_bytes().to_vec();
+    let mut field = |s: &str| {
+        packet.extend_from_slice(s.as_bytes());
+        packet.push(0);
+    };
+    field(filename);
+    field("octet");
+    if options {
+        field("blksize");
+        field(&BLOCK_SIZE.to_string());
+        field("tsize");
+        field("0");
+    }
+    packet
+}
+
+fn ack(block: u16) -> [u8; 4] {
+    let [a, b] = OP_ACK.to_be_bytes();
+    let [c, d] = block.to_be_bytes();
+    [a, b, c, d]
+}
+
+fn error(code: u16, message: &str) -> Vec<u8> {
+    let mut packet = OP_ERROR.to_be_bytes().to_vec();
+    packet.extend_from_slice(&code.to_be_bytes());
+    packet.extend_from_slice(message.as_bytes());
+    packet.push(0);
+    packet
+}
+
+/// Option name/value pairs from an OACK.
+fn option_pairs(data: &[u8]) -> impl Iterator<Item = (&str, &str)> {
+    let mut fields = data
+        .split(|b| *b == 0)
+        .map(|f| core::str::from_utf8(f).unwrap_or(""));
+    core::iter::from_fn(move || Some((fields.next()?, fields.next()?)))
+}
+
+/// Fetch `filename` from the TFTP server at `server` into memory.
+pub async fn get(server: impl Into<SocketAddr>, filename: &str) -> Result<Vec<u8>, Error> {
+    let server: SocketAddr = server.into();
+    let unspecified = match server {
+        SocketAddr::V4(_) => core::net::IpAddr::V4(core::net::Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => core::net::IpAddr::V6(core::net::Ipv6Addr::UNSPECIFIED),
+    };
+    let sock = UdpSocket::bind((unspecified, 0))?;
+
+    let mut options = true;
+    let mut last = read_request(filename, options);
+    sock.send_to(&last, server).await?;
+
+    // the server answers from a fresh port, which identifies the transfer
+    let mut peer: Option<SocketAddr> = None;
+    let mut block_size = DEFAULT_BLOCK_SIZE;
+    let mut next_block: u16 = 1;
+    let mut data = Vec::new();
+    let mut retries = 0;
+    let mut buf = vec![0; BLOCK_SIZE + 4];
+
+    loop {
+        let (n, from) = match maitake::time::timeout(TIMEOUT, sock.recv_from(&mut buf)).await {
+            Ok(r) => r?,
+            Err(_) => {
+                retries += 1;
+                if retries > RETRIES {
+                    return Err(Error::TimedOut);
+                }
+                sock.send_to(&last, peer.unwrap_or(server)).await?;
+                continue;
+            }
+        };
+
+        match peer {
+            Some(peer) if peer != from => {
+                sock.send_to(&error(ERROR_UNKNOWN_TID, "unknown transfer id"), from)
+                    .await?;
+                continue;
+            }
+            None if from.ip() != server.ip() => continue,
+            _ => {}
+        }
+
+        let Some(packet) = Packet::parse(&buf[..n]) else {
+            continue;
+        };
+        retries = 0;
+
+        match packet {
+            Packet::Error(ERROR_OPTIONS, _) if peer.is_none() && options => {
+                // server does not like our options, ask again without them
+                options = false;
+                last = read_request(filename, options);
+                sock.send_to(&last, server).await?;
+            }
+            Packet::Error(code, message) => {
+                return Err(Error::Tftp(
+                    code,
+                    String::from_utf8_lossy(message).into_owned(),
+                ));
+            }
+            Packet::OptionAck(acked) if peer.is_none() => {
+                peer = Some(from);
+                for (name, value) in option_pairs(acked) {
+                    match name.to_ascii_lowercase().as_str() {
+                        "blksize" => {
+                            block_size = value
+                                .parse::<usize>()
+                                .ok()
+                                .filter(|s| (8..=BLOCK_SIZE).contains(s))
+                                .unwrap_or(DEFAULT_BLOCK_SIZE)
+                        }
+                        "tsize" => {
+                            // only a hint, so don't let it allocate unbounded memory
+                            if let Ok(size) = value.parse::<usize>() {
+                                data.reserve_exact(size.min(MAX_PREALLOCATE));
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                last = ack(0).to_vec();
+                sock.send_to(&last, from).await?;
+            }
+            Packet::OptionAck(_) => {}
+            Packet::Data(block, payload) => {
+                if peer.is_none() {
+                    // no OACK, so the server ignored our options
+                    peer = Some(from);
+                }
+                if block == next_block {
+                    data.extend_from_slice(payload);
+                    last = ack(block).to_vec();
+                    sock.send_to(&last, from).await?;
+                    if payload.len() < block_size {
+                        return Ok(data);
+                    }
+                    next_block = next_block.wrapping_add(1);
+                } else if block == next_block.wrapping_sub(1) {
+                    // our ack got lost
+                    sock.send_to(&last, from).await?;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,420 @@
+//! 802.1Q VLAN sub-interfaces.
+//!
+//! Every Ethernet driver is wrapped in a [`Trunk`] when it is registered.
+//! While no VLANs exist it passes frames straight through. Once there are
+//! some, it pulls tagged frames off the wire and queues them, untagged, for
+//! the matching [`Vlan`] device. Each [`Vlan`] runs its own interface, and
+//! the frames it sends are tagged and handed back to the trunk to transmit.
+//!
+//! A trunk that is a bridge port hands everything that is not for one of its
+//! VLANs to the bridge instead of its own interface.
+
+use super::{Error, InterfaceRef, TaskHandle, INTERFACES};
+use alloc::{collections::VecDeque, sync::Arc};
+use core::task::{Context, Poll};
+use futures::task::AtomicWaker;
+use spin::Mutex;
+
+const TPID: [u8; 2] = [0x81, 0x00];
+/// The TPID and the tag control field, inserted after the MAC addresses.
+const TAG_LEN: usize = 4;
+
+/// Frames buffered per direction before we start dropping.
+const QUEUE_LEN: usize = 64;
+
+/// State shared between a trunk and the VLANs on top of it.
+pub(super) struct Ports {
+    mac: smoltcp::wire::HardwareAddress,
+    mtu: usize,
+    vlans: Mutex<Vec<Arc<Port>>>,
+    pub(super) bridge: Mutex<Option<Arc<super::bridge::Bridge>>>,
+    tx: Mutex<VecDeque<Vec<u8>>>,
+    waker: AtomicWaker,
+}
+
+struct Port {
+    vid: u16,
+    rx: Mutex<VecDeque<Vec<u8>>>,
+    waker: AtomicWaker,
+}
+
+fn push(queue: &Mutex<VecDeque<Vec<u8>>>, frame: Vec<u8>) -> bool {
+    let mut queue = queue.lock();
+    if queue.len() >= QUEUE_LEN {
+        return false;
+    }
+    queue.push_back(frame);
+    true
+}
+
+impl Ports {
+    pub(super) fn mac(&self) -> smoltcp::wire::HardwareAddress {
+        self.mac
+    }
+
+    /// Queue a raw frame for the trunk to transmit.
+    pub(super) fn send(&self, frame: Vec<u8>) {
+        if push(&self.tx, frame) {
+            self.waker.wake();
+        }
+    }
+
+    /// Whether frames can go straight to the trunk's own interface.
+    fn passthrough(&self) -> bool {
+        self.vlans.lock().is_empty() && self.bridge.lock().is_none()
+    }
+
+    /// Hand a frame from the wire to its VLAN or bridge. Returns the frame
+    /// back if it belongs to the trunk itself.
+    fn demux(&self, frame: &[u8]) -> Option<Vec<u8>> {
+        if frame.len() >= 18 && frame[12..14] == TPID {
+            let vid = u16::from_be_bytes([frame[14], frame[15]]) & 0xfff;
+            let vlans = self.vlans.lock();
+            if let Some(port) = vlans.iter().find(|p| p.vid == vid) {
+                let mut untagged = Vec::with_capacity(frame.len() - TAG_LEN);
+                untagged.extend_from_slice(&frame[..12]);
+                untagged.extend_from_slice(&frame[16..]);
+                if push(&port.rx, untagged) {
+                    port.waker.wake();
+                }
+                return None;
+            }
+        }
+
+        let bridge = self.bridge.lock().clone();
+        if let Some(bridge) = bridge {
+            bridge.input(self, frame);
+            return None;
+        }
+
+        // tagged traffic for a VLAN we are not on is not ours either
+        if frame.len() >= 14 && frame[12..14] == TPID {
+            return None;
+        }
+        Some(frame.to_vec())
+    }
+}
+
+pub(super) struct Frame(pub(super) Vec<u8>);
+
+impl smoltcp::phy::RxToken for Frame {
+    fn consume<R, F>(self, f: F) -> R
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        f(&self.0)
+    }
+}
+
+pub(super) enum RxToken<T> {
+    Device(T),
+    Owned(Frame),
+}
+
+impl<T: smoltcp::phy::RxToken> smoltcp::phy::RxToken for RxToken<T> {
+    fn consume<R, F>(self, f: F) -> R
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        match self {
+            RxToken::Device(token) => token.consume(f),
+            RxToken::Owned(frame) => frame.consume(f),
+        }
+    }
+}
+
+pub(super) struct Trunk<D> {
+    device: D,
+    ports: Arc<Ports>,
+    /// An untagged frame we could not hand up for lack of a tx token.
+    pending: Option<Vec<u8>>,
+}
+
+impl<D: super::Driver> Trunk<D> {
+    pub(super) fn new(device: D) -> (Self, Arc<Ports>) {
+        let ports = Arc::new(Ports {
+            mac: device.address(),
+            mtu: device.capabilities().max_transmission_unit,
+            vlans: Mutex::new(vec![]),
+            bridge: Mutex::new(None),
+            tx: Mutex::new(VecDeque::new()),
+            waker: AtomicWaker::new(),
+        });
+        let trunk = Self {
+            device,
+            ports: ports.clone(),
+            pending: None,
+        };
+        (trunk, ports)
+    }
+
+    fn flush(&mut self, timestamp: smoltcp::time::Instant) {
+        use smoltcp::phy::{Device, TxToken};
+
+        loop {
+            let Some(frame) = self.ports.tx.lock().pop_front() else {
+                return;
+            };
+            let Some(token) = self.device.transmit(timestamp) else {
+                self.ports.tx.lock().push_front(frame);
+                return;
+            };
+            token.consume(frame.len(), |buf| buf.copy_from_slice(&frame));
+        }
+    }
+}
+
+impl<D: super::Driver> smoltcp::phy::Device for Trunk<D> {
+    type RxToken<'a>
+        = RxToken<D::RxToken<'a>>
+    where
+        Self: 'a;
+    type TxToken<'a>
+        = D::TxToken<'a>
+    where
+        Self: 'a;
+
+    fn receive(
+        &mut self,
+        timestamp: smoltcp::time::Instant,
+    ) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        use smoltcp::phy::RxToken as _;
+
+        self.flush(timestamp);
+
+        if self.pending.is_none() && self.ports.passthrough() {
+            return self
+                .device
+                .receive(timestamp)
+                .map(|(rx, tx)| (RxToken::Device(rx), tx));
+        }
+
+        let frame = match self.pending.take() {
+            Some(frame) => frame,
+            None => loop {
+                let (rx, _) = self.device.receive(timestamp)?;
+                if let Some(frame) = rx.consume(|frame| self.ports.demux(frame)) {
+                    break frame;
+                }
+            },
+        };
+        match self.device.transmit(timestamp) {
+            Some(tx) => Some((RxToken::Owned(Frame(frame)), tx)),
+            None => {
+                self.pending = Some(frame);
+                None
+            }
+        }
+    }
+
+    fn transmit(&mut self, timestamp: smoltcp::time::Instant) -> Option<Self::TxToken<'_>> {
+        self.flush(timestamp);
+        self.device.transmit(timestamp)
+    }
+
+    fn capabilities(&self) -> smoltcp::phy::DeviceCapabilities {
+        self.device.capabilities()
+    }
+}
+
+impl<D: super::Driver> super::Driver for Trunk<D> {
+    fn address(&self) -> smoltcp::wire::HardwareAddress {
+        self.device.address()
+    }
+
+    fn poll(&self, cx: &mut Context) -> Poll<()> {
+        self.ports.waker.register(cx.waker());
+        if self.pending.is_some() || !self.ports.tx.lock().is_empty() {
+            return Poll::Ready(());
+        }
+        self.device.poll(cx)
+    }
+}
+
+pub(super) struct Vlan {
+    ports: Arc<Ports>,
+    port: Arc<Port>,
+}
+
+pub(super) struct VlanTxToken {
+    ports: Arc<Ports>,
+    vid: u16,
+}
+
+impl smoltcp::phy::TxToken for VlanTxToken {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut frame = vec![0; len];
+        let result = f(&mut frame);
+        let [a, b] = self.vid.to_be_bytes();
+        frame.splice(12..12, [TPID[0], TPID[1], a, b]);
+        self.ports.send(frame);
+        result
+    }
+}
+
+impl smoltcp::phy::Device for Vlan {
+    type RxToken<'a>
+        = Frame
+    where
+        Self: 'a;
+    type TxToken<'a>
+        = VlanTxToken
+    where
+        Self: 'a;
+
+    fn receive(
+        &mut self,
+        _timestamp: smoltcp::time::Instant,
+    ) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        let frame = self.port.rx.lock().pop_front()?;
+        Some((
+            Frame(frame),
+            VlanTxToken {
+                ports: self.ports.clone(),
+                vid: self.port.vid,
+            },
+        ))
+    }
+
+    fn transmit(&mut self, _timestamp: smoltcp::time::Instant) -> Option<Self::TxToken<'_>> {
+        if self.ports.tx.lock().len() >= QUEUE_LEN {
+            return None;
+        }
+        Some(VlanTxToken {
+            ports: self.ports.clone(),
+            vid: self.port.vid,
+        })
+    }
+
+    fn capabilities(&self) -> smoltcp::phy::DeviceCapabilities {
+        let mut caps = smoltcp::phy::DeviceCapabilities::default();
+        caps.medium = smoltcp::phy::Medium::Ethernet;
+        // the tag goes on top of whatever we send, and still has to fit
+        caps.max_transmission_unit = self.ports.mtu - TAG_LEN;
+        caps
+    }
+}
+
+impl super::Driver for Vlan {
+    fn address(&self) -> smoltcp::wire::HardwareAddress {
+        self.ports.mac
+    }
+
+    fn poll(&self, cx: &mut Context) -> Poll<()> {
+        self.port.waker.register(cx.waker());
+        if self.port.rx.lock().is_empty() {
+            Poll::Pending
+        } else {
+            Poll::Ready(())
+        }
+    }
+}
+
+struct Entry {
+    name: String,
+    parent: String,
+    vid: u16,
+    iface: InterfaceRef,
+    ports: Arc<Ports>,
+    port: Arc<Port>,
+    task: TaskHandle,
+}
+
+lazy_static::lazy_static! {
+    static ref VLANS: Mutex<Vec<Entry>> = Mutex::new(vec![]);
+}
+
+#[derive(Debug)]
+pub struct VlanConfig {
+    pub name: String,
+    pub parent: String,
+    pub vid: u16,
+}
+
+/// Create `<parent>.<vid>` on top of an Ethernet interface, and bring it up
+/// like any other with DHCP and SLAAC.
+pub fn add(parent: &str, vid: u16) -> Result<String, Error> {
+    if !(1..4095).contains(&vid) {
+        return Err(Error::InvalidVlanId);
+    }
+    let parent_iface = super::find_interface(parent)?;
+    let ports = parent_iface
+        .lock()
+        .vlan_ports
+        .clone()
+        .filter(|p| matches!(p.mac, smoltcp::wire::HardwareAddress::Ethernet(_)))
+        .ok_or(Error::NotEthernet)?;
+
+    let name = format!("{parent}.{vid}");
+    let mut vlans = VLANS.lock();
+    if vlans.iter().any(|v| v.name == name) {
+        return Err(Error::InterfaceExists);
+    }
+
+    let port = Arc::new(Port {
+        vid,
+        rx: Mutex::new(VecDeque::new()),
+        waker: AtomicWaker::new(),
+    });
+    let device = Vlan {
+        ports: ports.clone(),
+        port: port.clone(),
+    };
+    let (iface, task) = super::add_interface(name.clone(), device);
+    ports.vlans.lock().push(port.clone());
+    super::configure_ethernet(&iface);
+
+    vlans.push(Entry {
+        name: name.clone(),
+        parent: parent.to_owned(),
+        vid,
+        iface,
+        ports,
+        port,
+        task,
+    });
+
+    debug!("[NET] {name} registered");
+
+    Ok(name)
+}
+
+pub fn remove(name: &str) -> Result<(), Error> {
+    let entry = {
+        let mut vlans = VLANS.lock();
+        let index = vlans
+            .iter()
+            .position(|v| v.name == name)
+            .ok_or(Error::NoSuchInterface)?;
+        vlans.remove(index)
+    };
+
+    super::stop_dhcp4(&entry.iface);
+    super::stop_auto6(&entry.iface);
+    INTERFACES.lock().retain(|i| !Arc::ptr_eq(i, &entry.iface));
+    entry.task.cancel();
+    entry
+        .ports
+        .vlans
+        .lock()
+        .retain(|p| !Arc::ptr_eq(p, &entry.port));
+
+    debug!("[NET] {name} removed");
+
+    Ok(())
+}
+
+pub fn list() -> Vec<VlanConfig> {
+    VLANS
+        .lock()
+        .iter()
+        .map(|v| VlanConfig {
+            name: v.name.clone(),
+            parent: v.parent.clone(),
+            vid: v.vid,
+        })
+        .collect()
+}
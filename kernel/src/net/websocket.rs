@@ -0,0 +1,645 @@
+//! WebSockets (RFC 6455) over [`TcpSocket`].
+//!
+//! A [`WebSocket`] can be shared between a task receiving and tasks sending,
+//! each direction has its own lock. Pings are answered as they are received
+//! and a close from the peer is echoed, so a pushing service only needs
+//! someone calling [`WebSocket::recv`] to stay well behaved.
+
+use super::{io::BufStream, DnsQueryType, DnsSocket, Error, TcpSocket};
+use base64::Engine;
+use core::{
+    net::SocketAddr,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+use rand::{rngs::OsRng, RngCore};
+use sha1::{Digest, Sha1};
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const MAX_HEAD: usize = 8 * 1024;
+const MAX_HEADERS: usize = 64;
+const MAX_MESSAGE: usize = 1024 * 1024;
+const MAX_CONTROL: usize = 125;
+
+/// How long [`WebSocket::close`] waits for the peer to answer.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    Close(Option<CloseFrame>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloseFrame {
+    pub code: u16,
+    pub reason: String,
+}
+
+impl CloseFrame {
+    pub const NORMAL: u16 = 1000;
+    pub const GOING_AWAY: u16 = 1001;
+    pub const PROTOCOL_ERROR: u16 = 1002;
+    pub const UNSUPPORTED: u16 = 1003;
+    pub const INVALID_DATA: u16 = 1007;
+    pub const POLICY: u16 = 1008;
+    pub const TOO_BIG: u16 = 1009;
+    pub const INTERNAL_ERROR: u16 = 1011;
+}
+
+/// Codes a peer may put on the wire. The rest are reserved or only meant
+/// for reporting locally.
+fn valid_close_code(code: u16) -> bool {
+    matches!(code, 1000..=1003 | 1007..=1014 | 3000..=4999)
+}
+
+fn accept_key(key: &[u8]) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(key);
+    sha1.update(GUID.as_bytes());
+    base64::engine::general_purpose::STANDARD.encode(sha1.finalize())
+}
+
+fn has_token(headers: &http::HeaderMap, name: http::HeaderName, token: &str) -> bool {
+    headers.get_all(name).iter().any(|value| {
+        value
+            .to_str()
+            .is_ok_and(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+    })
+}
+
+/// Read an HTTP head up to and including the empty line that ends it.
+async fn read_head(stream: &mut BufStream<&TcpSocket>) -> Result<Vec<u8>, Error> {
+    let mut head = Vec::new();
+    loop {
+        let start = head.len();
+        if stream.read_until(b'\n', &mut head).await? == 0 {
+            return Err(Error::TcpClosed);
+        }
+        if matches!(&head[start..], b"\r\n" | b"\n") {
+            return Ok(head);
+        }
+        if head.len() > MAX_HEAD {
+            return Err(Error::WebSocket("handshake too large"));
+        }
+    }
+}
+
+fn write_headers(head: &mut Vec<u8>, headers: &http::HeaderMap) {
+    for (name, value) in headers {
+        head.extend_from_slice(name.as_str().as_bytes());
+        head.extend_from_slice(b": ");
+        head.extend_from_slice(value.as_bytes());
+        head.extend_from_slice(b"\r\n");
+    }
+}
+
+fn parse_headers(parsed: &[httparse::Header], headers: &mut http::HeaderMap) {
+    for h in parsed {
+        let Ok(name) = http::HeaderName::from_bytes(h.name.as_bytes()) else {
+            continue;
+        };
+        let Ok(value) = http::HeaderValue::from_bytes(h.value) else {
+            continue;
+        };
+        headers.append(name, value);
+    }
+}
+
+async fn resolve(host: &str) -> Result<core::net::IpAddr, Error> {
+    if let Ok(ip) = host.parse() {
+        return Ok(ip);
+    }
+    let dns = DnsSocket::new()?;
+    // IPv6-only hosts have no A records at all
+    let mut result = Err(Error::DestinationUnreachable);
+    for typ in [DnsQueryType::A, DnsQueryType::Aaaa] {
+        match maitake::time::timeout(Duration::from_secs(3), dns.query(host, typ)).await {
+            Ok(Ok(ips)) if !ips.is_empty() => return Ok(ips[0]),
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => result = Err(e),
+            Err(_) => result = Err(Error::TimedOut),
+        }
+    }
+    result
+}
+
+/// Connect to a `ws://` URL.
+pub async fn connect(url: &str) -> Result<(WebSocket, http::Response<()>), Error> {
+    let parsed = url::Url::parse(url).map_err(|_| Error::WebSocket("invalid url"))?;
+    match parsed.scheme() {
+        "ws" => {}
+        "wss" => return Err(Error::WebSocket("tls is not supported")),
+        _ => return Err(Error::WebSocket("not a websocket url")),
+    }
+    let host = parsed
+        .host_str()
+        .ok_or(Error::WebSocket("invalid url"))?
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let port = parsed.port_or_known_default().unwrap_or(80);
+    let ip = resolve(host).await?;
+
+    let request = http::Request::get(url)
+        .body(())
+        .map_err(|_| Error::WebSocket("invalid url"))?;
+
+    let sock = TcpSocket::new()?;
+    sock.connect((ip, port)).await?;
+    client(sock, request).await
+}
+
+/// Perform the client handshake for `request` on a connected socket. Extra
+/// headers in the request, such as `Sec-WebSocket-Protocol`, are sent along.
+pub async fn client(
+    sock: TcpSocket,
+    request: http::Request<()>,
+) -> Result<(WebSocket, http::Response<()>), Error> {
+    use http::header;
+
+    let mut nonce = [0; 16];
+    OsRng.fill_bytes(&mut nonce);
+    let key = base64::engine::general_purpose::STANDARD.encode(nonce);
+
+    let target = request
+        .uri()
+        .path_and_query()
+        .map(|p| p.as_str())
+        .unwrap_or("/");
+    let mut head = format!("GET {target} HTTP/1.1\r\n").into_bytes();
+    if !request.headers().contains_key(header::HOST) {
+        let authority = request
+            .uri()
+            .authority()
+            .ok_or(Error::WebSocket("no host"))?;
+        head.extend_from_slice(format!("Host: {authority}\r\n").as_bytes());
+    }
+    write_headers(&mut head, request.headers());
+    head.extend_from_slice(
+        format!(
+            "Upgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {key}\r\nSec-WebSocket-Version: 13\r\n\r\n"
+        )
+        .as_bytes(),
+    );
+    sock.write_all(&head).await?;
+
+    let mut stream = BufStream::new(&sock);
+    let head = read_head(&mut stream).await?;
+    // the server may start sending straight after the handshake
+    let pending = stream.buffer().to_vec();
+    drop(stream);
+
+    let mut parsed = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut res = httparse::Response::new(&mut parsed);
+    if !matches!(res.parse(&head), Ok(httparse::Status::Complete(_))) {
+        return Err(Error::WebSocket("malformed handshake response"));
+    }
+    let mut response = http::Response::builder()
+        .status(res.code.unwrap_or(0))
+        .body(())
+        .map_err(|_| Error::WebSocket("malformed handshake response"))?;
+    parse_headers(res.headers, response.headers_mut());
+
+    let headers = response.headers();
+    if response.status() != http::StatusCode::SWITCHING_PROTOCOLS {
+        return Err(Error::WebSocket("server refused the upgrade"));
+    }
+    if !has_token(headers, header::UPGRADE, "websocket")
+        || !has_token(headers, header::CONNECTION, "upgrade")
+    {
+        return Err(Error::WebSocket("server did not upgrade"));
+    }
+    let expected = accept_key(key.as_bytes());
+    if headers
+        .get(header::SEC_WEBSOCKET_ACCEPT)
+        .is_none_or(|v| v.as_bytes() != expected.as_bytes())
+    {
+        return Err(Error::WebSocket("bad Sec-WebSocket-Accept"));
+    }
+
+    Ok((WebSocket::new(sock, Role::Client, pending), response))
+}
+
+/// Read an upgrade request from a freshly accepted connection and complete
+/// the handshake. Requests that aren't WebSocket upgrades get a 400.
+pub async fn accept(sock: TcpSocket) -> Result<(WebSocket, http::Request<()>), Error> {
+    let mut stream = BufStream::new(&sock);
+    let head = read_head(&mut stream).await?;
+    let pending = stream.buffer().to_vec();
+    drop(stream);
+
+    let Some(request) = parse_request(&head) else {
+        reject(&sock).await;
+        return Err(Error::WebSocket("malformed handshake request"));
+    };
+
+    match handshake(sock, &request, pending).await {
+        Ok(ws) => Ok((ws, request)),
+        Err((sock, e)) => {
+            reject(&sock).await;
+            Err(e)
+        }
+    }
+}
+
+fn parse_request(head: &[u8]) -> Option<http::Request<()>> {
+    let mut parsed = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut req = httparse::Request::new(&mut parsed);
+    if !matches!(req.parse(head), Ok(httparse::Status::Complete(_))) || req.version != Some(1) {
+        return None;
+    }
+    let mut request = http::Request::builder()
+        .method(req.method?)
+        .uri(req.path?)
+        .body(())
+        .ok()?;
+    parse_headers(req.headers, request.headers_mut());
+    Some(request)
+}
+
+async fn reject(sock: &TcpSocket) {
+    let _ = sock
+        .write_all(
+            b"HTTP/1.1 400 Bad Request\r\nSec-WebSocket-Version: 13\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        )
+        .await;
+    sock.close().await;
+}
+
+/// Answer an upgrade request that has already been read off `sock`, for
+/// services that do their own HTTP routing. On failure the socket is handed
+/// back untouched so the caller can respond.
+pub async fn upgrade<T>(
+    sock: TcpSocket,
+    request: &http::Request<T>,
+) -> Result<WebSocket, (TcpSocket, Error)> {
+    handshake(sock, request, vec![]).await
+}
+
+async fn handshake<T>(
+    sock: TcpSocket,
+    request: &http::Request<T>,
+    pending: Vec<u8>,
+) -> Result<WebSocket, (TcpSocket, Error)> {
+    use http::header;
+
+    let headers = request.headers();
+    if request.method() != http::Method::GET {
+        return Err((sock, Error::WebSocket("upgrade must be a GET")));
+    }
+    if !has_token(headers, header::UPGRADE, "websocket")
+        || !has_token(headers, header::CONNECTION, "upgrade")
+    {
+        return Err((sock, Error::WebSocket("not an upgrade request")));
+    }
+    if headers
+        .get(header::SEC_WEBSOCKET_VERSION)
+        .is_none_or(|v| v.as_bytes() != b"13")
+    {
+        return Err((sock, Error::WebSocket("unsupported version")));
+    }
+    let Some(key) = headers.get(header::SEC_WEBSOCKET_KEY) else {
+        return Err((sock, Error::WebSocket("missing Sec-WebSocket-Key")));
+    };
+
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(key.as_bytes()),
+    );
+    if let Err(e) = sock.write_all(response.as_bytes()).await {
+        return Err((sock, e));
+    }
+
+    Ok(WebSocket::new(sock, Role::Server, pending))
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+struct Rx {
+    /// Read past the handshake, not yet consumed.
+    pending: Vec<u8>,
+    /// Opcode and data of a fragmented message being put back together.
+    partial: Option<(u8, Vec<u8>)>,
+}
+
+pub struct WebSocket {
+    sock: TcpSocket,
+    role: Role,
+    rx: maitake::sync::Mutex<Rx>,
+    tx: maitake::sync::Mutex<()>,
+    close_sent: AtomicBool,
+    close_received: AtomicBool,
+}
+
+impl WebSocket {
+    fn new(sock: TcpSocket, role: Role, pending: Vec<u8>) -> Self {
+        Self {
+            sock,
+            role,
+            rx: maitake::sync::Mutex::new(Rx {
+                pending,
+                partial: None,
+            }),
+            tx: maitake::sync::Mutex::new(()),
+            close_sent: AtomicBool::new(false),
+            close_received: AtomicBool::new(false),
+        }
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.sock.peer_addr()
+    }
+
+    pub fn socket(&self) -> &TcpSocket {
+        &self.sock
+    }
+
+    async fn read_exact(&self, rx: &mut Rx, buf: &mut [u8]) -> Result<(), Error> {
+        let n = rx.pending.len().min(buf.len());
+        buf[..n].copy_from_slice(&rx.pending[..n]);
+        rx.pending.drain(..n);
+        self.sock.read_exact(&mut buf[n..]).await
+    }
+
+    /// Read one frame, checking everything that doesn't depend on what came
+    /// before it. Violations carry the close code to fail with.
+    async fn read_frame(&self, rx: &mut Rx) -> Result<Result<Frame, (u16, &'static str)>, Error> {
+        let mut header = [0; 2];
+        self.read_exact(rx, &mut header).await?;
+        let fin = header[0] & 0x80 != 0;
+        let opcode = header[0] & 0x0f;
+        let masked = header[1] & 0x80 != 0;
+
+        let len = match header[1] & 0x7f {
+            126 => {
+                let mut len = [0; 2];
+                self.read_exact(rx, &mut len).await?;
+                u16::from_be_bytes(len) as u64
+            }
+            127 => {
+                let mut len = [0; 8];
+                self.read_exact(rx, &mut len).await?;
+                u64::from_be_bytes(len)
+            }
+            len => len as u64,
+        };
+
+        if header[0] & 0x70 != 0 {
+            return Ok(Err((CloseFrame::PROTOCOL_ERROR, "reserved bits set")));
+        }
+        // clients must mask, servers must not
+        if masked != (self.role == Role::Server) {
+            return Ok(Err((CloseFrame::PROTOCOL_ERROR, "bad masking")));
+        }
+        match opcode {
+            OP_CONTINUATION | OP_TEXT | OP_BINARY => {}
+            OP_CLOSE | OP_PING | OP_PONG => {
+                if !fin || len > MAX_CONTROL as u64 {
+                    return Ok(Err((CloseFrame::PROTOCOL_ERROR, "bad control frame")));
+                }
+            }
+            _ => return Ok(Err((CloseFrame::PROTOCOL_ERROR, "unknown opcode"))),
+        }
+        let partial = rx.partial.as_ref().map_or(0, |(_, data)| data.len());
+        if len > (MAX_MESSAGE - partial) as u64 {
+            return Ok(Err((CloseFrame::TOO_BIG, "message too big")));
+        }
+
+        let mut mask = [0; 4];
+        if masked {
+            self.read_exact(rx, &mut mask).await?;
+        }
+        let mut payload = vec![0; len as usize];
+        self.read_exact(rx, &mut payload).await?;
+        if masked {
+            for (i, b) in payload.iter_mut().enumerate() {
+                *b ^= mask[i % 4];
+            }
+        }
+
+        Ok(Ok(Frame {
+            fin,
+            opcode,
+            payload,
+        }))
+    }
+
+    async fn write_frame(&self, opcode: u8, payload: &[u8]) -> Result<(), Error> {
+        let mut frame = Vec::with_capacity(payload.len() + 14);
+        frame.push(0x80 | opcode);
+        let mask_bit = if self.role == Role::Client { 0x80 } else { 0 };
+        match payload.len() {
+            len @ 0..=125 => frame.push(mask_bit | len as u8),
+            len @ 126..=0xffff => {
+                frame.push(mask_bit | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(mask_bit | 127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        if self.role == Role::Client {
+            let mut mask = [0; 4];
+            OsRng.fill_bytes(&mut mask);
+            frame.extend_from_slice(&mask);
+            frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        } else {
+            frame.extend_from_slice(payload);
+        }
+
+        let _tx = self.tx.lock().await;
+        if self.close_sent.load(Ordering::Acquire) {
+            return Err(Error::WebSocket("connection closed"));
+        }
+        if opcode == OP_CLOSE {
+            self.close_sent.store(true, Ordering::Release);
+        }
+        self.sock.write_all(&frame).await
+    }
+
+    async fn send_close(&self, code: u16, reason: &str) -> Result<(), Error> {
+        let mut payload = code.to_be_bytes().to_vec();
+        // the reason has to stay valid UTF-8 when cut short
+        let mut len = reason.len().min(MAX_CONTROL - 2);
+        while !reason.is_char_boundary(len) {
+            len -= 1;
+        }
+        payload.extend_from_slice(&reason.as_bytes()[..len]);
+        self.write_frame(OP_CLOSE, &payload).await
+    }
+
+    /// Close with `code` because the peer broke the protocol.
+    async fn fail(&self, code: u16, reason: &'static str) -> Error {
+        let _ = self.send_close(code, reason).await;
+        self.close_received.store(true, Ordering::Release);
+        self.sock.shutdown();
+        Error::WebSocket(reason)
+    }
+
+    /// Wait for the next message. Pings are answered before being returned.
+    /// Once a [`Message::Close`] has been returned the connection is done.
+    pub async fn recv(&self) -> Result<Message, Error> {
+        let mut rx = self.rx.lock().await;
+        loop {
+            if self.close_received.load(Ordering::Acquire) {
+                return Err(Error::WebSocket("connection closed"));
+            }
+            let frame = match self.read_frame(&mut rx).await? {
+                Ok(frame) => frame,
+                Err((code, reason)) => return Err(self.fail(code, reason).await),
+            };
+
+            let (opcode, payload) = match frame.opcode {
+                OP_CONTINUATION => {
+                    let Some((_, data)) = rx.partial.as_mut() else {
+                        return Err(self
+                            .fail(CloseFrame::PROTOCOL_ERROR, "unexpected continuation")
+                            .await);
+                    };
+                    data.extend_from_slice(&frame.payload);
+                    if !frame.fin {
+                        continue;
+                    }
+                    rx.partial.take().unwrap()
+                }
+                OP_TEXT | OP_BINARY => {
+                    if rx.partial.is_some() {
+                        return Err(self
+                            .fail(CloseFrame::PROTOCOL_ERROR, "interleaved message")
+                            .await);
+                    }
+                    if !frame.fin {
+                        rx.partial = Some((frame.opcode, frame.payload));
+                        continue;
+                    }
+                    (frame.opcode, frame.payload)
+                }
+                OP_PING => {
+                    // a close on the way out means nobody wants the pong
+                    let _ = self.write_frame(OP_PONG, &frame.payload).await;
+                    return Ok(Message::Ping(frame.payload));
+                }
+                OP_PONG => return Ok(Message::Pong(frame.payload)),
+                _ => return self.received_close(&frame.payload).await,
+            };
+
+            return match opcode {
+                OP_TEXT => match String::from_utf8(payload) {
+                    Ok(text) => Ok(Message::Text(text)),
+                    Err(_) => Err(self.fail(CloseFrame::INVALID_DATA, "invalid utf-8").await),
+                },
+                _ => Ok(Message::Binary(payload)),
+            };
+        }
+    }
+
+    async fn received_close(&self, payload: &[u8]) -> Result<Message, Error> {
+        let frame = match payload {
+            [] => None,
+            [_] => {
+                return Err(self
+                    .fail(CloseFrame::PROTOCOL_ERROR, "bad close frame")
+                    .await)
+            }
+            [hi, lo, reason @ ..] => {
+                let code = u16::from_be_bytes([*hi, *lo]);
+                if !valid_close_code(code) {
+                    return Err(self
+                        .fail(CloseFrame::PROTOCOL_ERROR, "bad close code")
+                        .await);
+                }
+                let Ok(reason) = core::str::from_utf8(reason) else {
+                    return Err(self.fail(CloseFrame::INVALID_DATA, "invalid utf-8").await);
+                };
+                Some(CloseFrame {
+                    code,
+                    reason: reason.to_owned(),
+                })
+            }
+        };
+        self.close_received.store(true, Ordering::Release);
+
+        if !self.close_sent.load(Ordering::Acquire) {
+            let code = frame.as_ref().map_or(CloseFrame::NORMAL, |f| f.code);
+            let _ = self.send_close(code, "").await;
+        }
+        // the server is the one to close the TCP connection
+        if self.role == Role::Server {
+            self.sock.shutdown();
+        }
+
+        Ok(Message::Close(frame))
+    }
+
+    pub async fn send(&self, message: &Message) -> Result<(), Error> {
+        match message {
+            Message::Text(text) => self.write_frame(OP_TEXT, text.as_bytes()).await,
+            Message::Binary(data) => self.write_frame(OP_BINARY, data).await,
+            Message::Ping(data) => {
+                self.write_frame(OP_PING, &data[..data.len().min(MAX_CONTROL)])
+                    .await
+            }
+            Message::Pong(data) => {
+                self.write_frame(OP_PONG, &data[..data.len().min(MAX_CONTROL)])
+                    .await
+            }
+            Message::Close(frame) => match frame {
+                Some(frame) => self.send_close(frame.code, &frame.reason).await,
+                None => self.write_frame(OP_CLOSE, &[]).await,
+            },
+        }
+    }
+
+    pub async fn send_text(&self, text: &str) -> Result<(), Error> {
+        self.write_frame(OP_TEXT, text.as_bytes()).await
+    }
+
+    pub async fn send_binary(&self, data: &[u8]) -> Result<(), Error> {
+        self.write_frame(OP_BINARY, data).await
+    }
+
+    /// Start the closing handshake and wait for the peer to finish it, then
+    /// close the TCP connection. Messages arriving in the meantime are
+    /// dropped.
+    pub async fn close(&self, code: u16, reason: &str) -> Result<(), Error> {
+        if !self.close_sent.load(Ordering::Acquire) {
+            self.send_close(code, reason).await?;
+        }
+        let _ = maitake::time::timeout(CLOSE_TIMEOUT, async {
+            while !self.close_received.load(Ordering::Acquire) {
+                if self.recv().await.is_err() {
+                    break;
+                }
+            }
+        })
+        .await;
+        self.sock.close().await;
+        Ok(())
+    }
+}
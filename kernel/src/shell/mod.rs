@@ -0,0 +1,1383 @@
+use crate::{
+    drivers::keyboard::{next_key, DecodedKey, KeyCode},
+    framebuffer::DISPLAY,
+};
+use alloc::sync::Arc;
+use core::{fmt::Write, future::Future, pin::Pin, str::FromStr, time::Duration};
+use futures::FutureExt;
+use hashbrown::HashMap;
+use spin::Mutex;
+
+mod telnetd;
+mod vsock;
+
+/// Byte stream a shell session runs over.
+pub trait Stream: Send + Sync + 'static {
+    /// Read some input, returning 0 once the other end is gone.
+    fn read(&self, buf: &mut [u8]) -> impl Future<Output = usize> + Send;
+    fn write(&self, buf: &[u8]) -> impl Future<Output = ()> + Send;
+}
+
+/// Output side of a session. Writes are queued and flushed to the stream by
+/// a separate task, so commands can write without awaiting.
+#[derive(Clone, Debug)]
+struct Output(async_channel::Sender<Vec<u8>>);
+
+impl Output {
+    fn write_bytes(&self, data: &[u8]) {
+        let _ = self.0.try_send(data.to_vec());
+    }
+
+    fn write_str(&self, s: &str) {
+        self.write_bytes(s.as_bytes());
+    }
+
+    fn write_fmt(&self, args: core::fmt::Arguments) {
+        self.write_str(&format!("{args}"));
+    }
+}
+
+#[derive(Debug)]
+struct Args {
+    args: Vec<String>,
+    out: Output,
+}
+
+impl Args {
+    fn write_bytes(&self, data: &[u8]) {
+        self.out.write_bytes(data);
+    }
+
+    fn write_str(&self, s: &str) {
+        self.out.write_str(s);
+    }
+
+    fn write_fmt(&self, args: core::fmt::Arguments) {
+        self.out.write_fmt(args);
+    }
+}
+
+type CmdRet = Result<(), Box<dyn core::error::Error + Send + Sync>>;
+type Command =
+    Box<dyn (Fn(Args) -> Pin<Box<dyn core::future::Future<Output = CmdRet> + Send>>) + Send>;
+
+lazy_static::lazy_static! {
+    static ref DEBUG: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+    static ref LOG_SUBSCRIBERS: Mutex<Vec<async_channel::Sender<Vec<u8>>>> = Mutex::new(Vec::new());
+}
+
+fn print(args: core::fmt::Arguments) {
+    struct W<'a>(&'a mut Vec<u8>);
+    impl<'a> core::fmt::Write for W<'a> {
+        fn write_str(&mut self, s: &str) -> core::fmt::Result {
+            self.0.extend_from_slice(s.as_bytes());
+            Ok(())
+        }
+    }
+
+    let mut debug = DEBUG.lock();
+    let start = debug.len();
+    let _ = W(&mut debug).write_fmt(args);
+
+    let mut subscribers = LOG_SUBSCRIBERS.lock();
+    if !subscribers.is_empty() {
+        let data = debug[start..].to_vec();
+        // slow readers miss output rather than holding up the logger
+        subscribers.retain(|s| {
+            !matches!(
+                s.try_send(data.clone()),
+                Err(async_channel::TrySendError::Closed(_))
+            )
+        });
+    }
+}
+
+/// Everything logged so far, and a channel carrying everything logged from
+/// now on.
+fn subscribe_logs() -> (Vec<u8>, async_channel::Receiver<Vec<u8>>) {
+    let (tx, rx) = async_channel::bounded(256);
+    let debug = DEBUG.lock();
+    LOG_SUBSCRIBERS.lock().push(tx);
+    (debug.clone(), rx)
+}
+
+fn commands() -> HashMap<&'static str, Command> {
+    let mut commands = HashMap::<&'static str, Command>::new();
+    macro_rules! reg {
+        ($name:ident) => {
+            commands.insert(
+                stringify!($name),
+                Box::new(|a| Box::pin(commands::$name(a))),
+            );
+        };
+    }
+
+    reg!(panic);
+    reg!(timing);
+    reg!(ifconfig);
+    reg!(route);
+    reg!(dns);
+    reg!(dhcp);
+    reg!(slaac);
+    reg!(vlan);
+    reg!(bridge);
+    reg!(fw);
+    reg!(dig);
+    reg!(http);
+    reg!(ws);
+    reg!(ping);
+    reg!(tftp);
+    reg!(iperf);
+    reg!(netbench);
+    reg!(syslog);
+    reg!(shutdown);
+    reg!(reboot);
+    reg!(logs);
+    reg!(logo);
+    reg!(lspci);
+    reg!(lsblk);
+    reg!(hexdump);
+    reg!(blkstat);
+    reg!(ls);
+    reg!(cat);
+    reg!(cd);
+    reg!(pwd);
+    reg!(mkdir);
+    reg!(rm);
+    reg!(mount);
+    reg!(df);
+    // reg!(wasm);
+
+    let command_names = commands.keys().map(|s| s.to_owned()).collect::<Vec<_>>();
+    commands.insert(
+        "help",
+        Box::new(move |args| {
+            let command_names = command_names.clone();
+            Box::pin(async move {
+                args.write_fmt(format_args!(
+                    "Available commands:\n{}\n",
+                    command_names.join("\n")
+                ));
+                Ok(())
+            })
+        }),
+    );
+
+    commands
+}
+
+/// Run `line` to completion, or until ctrl-c is read from `stream`.
+/// Returns false if the stream closed in the meantime.
+async fn run<S: Stream>(
+    commands: &HashMap<&'static str, Command>,
+    files: &Arc<crate::fs::Files>,
+    line: &str,
+    out: &Output,
+    stream: &S,
+) -> bool {
+    let mut args = line.split(" ");
+    let Some(cmd) = args.next().filter(|cmd| !cmd.is_empty()) else {
+        return true;
+    };
+    let Some(f) = commands.get(cmd) else {
+        out.write_str("unknown command\n");
+        return true;
+    };
+
+    let args = Args {
+        args: args.map(|s| s.to_owned()).collect(),
+        out: out.clone(),
+    };
+
+    let mut cmd_fut = Fuse {
+        inner: Some(crate::task::spawn(crate::fs::scope(files.clone(), f(args)))),
+    };
+    let mut key_fut = Box::pin(
+        async {
+            let mut buf = [0; 16];
+            loop {
+                let n = stream.read(&mut buf).await;
+                if n == 0 {
+                    return false;
+                }
+                if buf[..n].contains(&0x03) {
+                    return true;
+                }
+            }
+        }
+        .fuse(),
+    );
+
+    let open = futures::select_biased! {
+        r = cmd_fut => {
+            match r {
+                Ok(Ok(Ok(()))) => {}
+                Ok(Ok(Err(e))) => out.write_fmt(format_args!("{e}\n")),
+                Ok(Err(_)) => out.write_str("command panicked\n"),
+                Err(e) => out.write_fmt(format_args!("{e:?}\n")),
+            }
+            true
+        }
+        open = key_fut => {
+            if let Some(handle) = cmd_fut.inner {
+                handle.cancel();
+            }
+            out.write_str("Cancelled task\n");
+            open
+        }
+    };
+    // the session itself never holds files open, anything left over was
+    // opened by a command that errored or was cancelled
+    files.close_all();
+    open
+}
+
+/// Run a shell session over `stream` until the other end goes away.
+/// Commands are cancelled independently of any other session.
+pub async fn session<S: Stream>(stream: Arc<S>) {
+    let commands = commands();
+    let files = crate::fs::Files::new();
+
+    let (tx, rx) = async_channel::unbounded::<Vec<u8>>();
+    let out = Output(tx);
+    let writer = {
+        let stream = stream.clone();
+        crate::task::spawn(async move {
+            while let Ok(data) = rx.recv().await {
+                stream.write(&data).await;
+            }
+        })
+    };
+
+    let mut line = Vec::new();
+    let mut buf = [0; 64];
+    let mut last = 0;
+    out.write_str("> ");
+    'session: loop {
+        let n = stream.read(&mut buf).await;
+        if n == 0 {
+            break;
+        }
+        let mut echo = Vec::new();
+        for &b in &buf[..n] {
+            match b {
+                // CR LF and CR NUL are a single newline
+                b'\n' | 0 if last == b'\r' => {}
+                b'\r' | b'\n' => {
+                    echo.push(b'\n');
+                    out.write_bytes(&core::mem::take(&mut echo));
+
+                    let cmd = String::from_utf8_lossy(&line).into_owned();
+                    line.clear();
+                    if !run(&commands, &files, &cmd, &out, &*stream).await {
+                        break 'session;
+                    }
+                    out.write_str("> ");
+                }
+                0x08 | 0x7f => {
+                    // drop a whole utf-8 sequence
+                    let mut erased = false;
+                    while let Some(b) = line.pop() {
+                        erased = true;
+                        if b & 0xc0 != 0x80 {
+                            break;
+                        }
+                    }
+                    if erased {
+                        echo.extend_from_slice(b"\x08 \x08");
+                    }
+                }
+                0x03 => {
+                    line.clear();
+                    echo.extend_from_slice(b"^C\n> ");
+                }
+                b if b >= 0x20 => {
+                    line.push(b);
+                    echo.push(b);
+                }
+                _ => {}
+            }
+            last = b;
+        }
+        out.write_bytes(&echo);
+    }
+
+    drop(out);
+    let _ = writer.await;
+}
+
+/// The local keyboard and framebuffer.
+struct Console;
+
+impl Stream for Console {
+    async fn read(&self, buf: &mut [u8]) -> usize {
+        loop {
+            let c = match next_key().await {
+                None => return 0,
+                Some(DecodedKey::Unicode(c)) => c,
+                Some(DecodedKey::RawKey(KeyCode::Backspace | KeyCode::Delete)) => '\u{0008}',
+                Some(DecodedKey::RawKey(_)) => continue,
+            };
+            if c.len_utf8() <= buf.len() {
+                return c.encode_utf8(buf).len();
+            }
+        }
+    }
+
+    async fn write(&self, buf: &[u8]) {
+        DISPLAY.lock().write_str(&String::from_utf8_lossy(buf));
+    }
+}
+
+pub fn start() {
+    crate::debug::set_print(print);
+    crate::task::spawn(async {
+        crate::framebuffer::logo();
+        session(Arc::new(Console)).await;
+    });
+    telnetd::start();
+    vsock::start();
+}
+
+mod commands {
+    use super::*;
+
+    pub async fn panic(_: Args) -> CmdRet {
+        panic!("a panic");
+    }
+
+    pub async fn timing(args: Args) -> CmdRet {
+        let n = args.args[0].parse::<u32>().unwrap();
+        for _ in 0..n {
+            let uptime = crate::arch::now();
+            let unix = crate::arch::timestamp();
+            args.write_fmt(format_args!("{uptime:?} {unix:?}\n"));
+            maitake::time::sleep(Duration::from_secs(1)).await;
+        }
+        Ok(())
+    }
+
+    pub async fn ifconfig(args: Args) -> CmdRet {
+        if let [name, op, cidr] = &args.args[..] {
+            let cidr = smoltcp::wire::IpCidr::from_str(cidr)
+                .map_err(|_| format!("invalid address {cidr}"))?;
+            match op.as_str() {
+                "add" => crate::net::add_address(name, cidr)?,
+                "del" => crate::net::remove_address(name, cidr)?,
+                _ => return Err("usage: ifconfig [<iface> add|del <cidr>]".into()),
+            }
+            return Ok(());
+        }
+
+        let interfaces = crate::net::interfaces();
+        if interfaces.is_empty() {
+            args.write_str("No interface\n");
+            return Ok(());
+        }
+        for conf in interfaces {
+            if let Some(name) = args.args.first() {
+                if *name != conf.name {
+                    continue;
+                }
+            }
+            args.write_fmt(format_args!(
+                "{}: dhcp4 {} slaac {} dhcp6 {}\n",
+                conf.name,
+                if conf.dhcp4 { "on" } else { "off" },
+                if conf.auto6 { "on" } else { "off" },
+                match conf.dhcp6 {
+                    Some(crate::net::Dhcp6Mode::Stateful) => "stateful",
+                    Some(crate::net::Dhcp6Mode::Stateless) => "stateless",
+                    None => "off",
+                },
+            ));
+            if let Some(mac) = conf.mac {
+                args.write_fmt(format_args!(
+                    "ether {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}\n",
+                    mac[0], mac[1], mac[2], mac[3], mac[4], mac[5]
+                ));
+            }
+            for addr in &conf.ip_addrs {
+                args.write_fmt(format_args!("inet  {addr}\n"));
+            }
+            for addr in &conf.dns_servers {
+                args.write_fmt(format_args!("dns   {addr}\n"));
+            }
+            for route in &conf.routes {
+                args.write_fmt(format_args!(
+                    "route {} via {}\n",
+                    route.cidr, route.via_router
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn route(args: Args) -> CmdRet {
+        match &args.args[..] {
+            [name, op, gateway] if op == "add" => {
+                let gateway = gateway
+                    .parse()
+                    .map_err(|_| format!("invalid gateway {gateway}"))?;
+                crate::net::set_gateway(name, gateway)?;
+            }
+            [name, op, family] if op == "del" => match family.as_str() {
+                "inet" => crate::net::remove_gateway(name, false)?,
+                "inet6" => crate::net::remove_gateway(name, true)?,
+                _ => return Err("usage: route <iface> del inet|inet6".into()),
+            },
+            _ => return Err("usage: route <iface> add <gateway> | del inet|inet6".into()),
+        }
+        Ok(())
+    }
+
+    pub async fn dns(args: Args) -> CmdRet {
+        let [name, op, server] = &args.args[..] else {
+            return Err("usage: dns <iface> add|del <server>".into());
+        };
+        let server = server
+            .parse()
+            .map_err(|_| format!("invalid server {server}"))?;
+        match op.as_str() {
+            "add" => crate::net::add_dns_server(name, server)?,
+            "del" => crate::net::remove_dns_server(name, server)?,
+            _ => return Err("usage: dns <iface> add|del <server>".into()),
+        }
+        Ok(())
+    }
+
+    pub async fn dhcp(args: Args) -> CmdRet {
+        let [name, op] = &args.args[..] else {
+            return Err("usage: dhcp <iface> on|off|renew|release".into());
+        };
+        match op.as_str() {
+            "on" => crate::net::set_dhcp4(name, true)?,
+            "off" => crate::net::set_dhcp4(name, false)?,
+            "renew" => crate::net::renew_dhcp4(name)?,
+            "release" => crate::net::release_dhcp4(name).await?,
+            _ => return Err("usage: dhcp <iface> on|off|renew|release".into()),
+        }
+        Ok(())
+    }
+
+    pub async fn slaac(args: Args) -> CmdRet {
+        let [name, op] = &args.args[..] else {
+            return Err("usage: slaac <iface> on|off".into());
+        };
+        match op.as_str() {
+            "on" => crate::net::set_auto6(name, true)?,
+            "off" => crate::net::set_auto6(name, false)?,
+            _ => return Err("usage: slaac <iface> on|off".into()),
+        }
+        Ok(())
+    }
+
+    pub async fn vlan(args: Args) -> CmdRet {
+        const USAGE: &str = "usage: vlan [add <iface> <vid> | del <iface>]";
+
+        match &args.args[..] {
+            [] => {
+                for vlan in crate::net::vlan::list() {
+                    args.write_fmt(format_args!(
+                        "{}: {} vid {}\n",
+                        vlan.name, vlan.parent, vlan.vid
+                    ));
+                }
+            }
+            [op, parent, vid] if op == "add" => {
+                let vid = vid.parse::<u16>().map_err(|_| USAGE)?;
+                let name = crate::net::vlan::add(parent, vid)?;
+                args.write_fmt(format_args!("{name}\n"));
+            }
+            [op, name] if op == "del" => crate::net::vlan::remove(name)?,
+            _ => return Err(USAGE.into()),
+        }
+        Ok(())
+    }
+
+    pub async fn bridge(args: Args) -> CmdRet {
+        use crate::net::bridge;
+
+        const USAGE: &str =
+            "usage: bridge [create | destroy <br> | addif <br> <iface> | delif <br> <iface> | fdb <br>]";
+
+        match &args.args[..] {
+            [] => {
+                for bridge in bridge::list() {
+                    args.write_fmt(format_args!(
+                        "{}: {}\n",
+                        bridge.name,
+                        bridge.members.join(" ")
+                    ));
+                }
+            }
+            [op] if op == "create" => {
+                let name = bridge::create();
+                args.write_fmt(format_args!("{name}\n"));
+            }
+            [op, name] if op == "destroy" => bridge::destroy(name)?,
+            [op, name, port] if op == "addif" => bridge::add_port(name, port)?,
+            [op, name, port] if op == "delif" => bridge::remove_port(name, port)?,
+            [op, name] if op == "fdb" => {
+                for entry in bridge::fdb(name)? {
+                    let m = entry.mac;
+                    args.write_fmt(format_args!(
+                        "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x} {} {}s\n",
+                        m[0],
+                        m[1],
+                        m[2],
+                        m[3],
+                        m[4],
+                        m[5],
+                        entry.port,
+                        entry.age.as_secs()
+                    ));
+                }
+            }
+            _ => return Err(USAGE.into()),
+        }
+        Ok(())
+    }
+
+    fn parse_rule(args: &[String]) -> Result<crate::net::filter::Rule, String> {
+        use crate::net::filter::{Action, Direction, Protocol, Rule};
+
+        fn port_range(s: &str) -> Result<(u16, u16), String> {
+            let parse = |p: &str| p.parse::<u16>().map_err(|_| format!("invalid port {p}"));
+            match s.split_once('-') {
+                Some((lo, hi)) => Ok((parse(lo)?, parse(hi)?)),
+                None => parse(s).map(|p| (p, p)),
+            }
+        }
+
+        let mut rule = Rule::default();
+        let mut args = args.iter().map(|s| s.as_str());
+        let mut action = None;
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(format!("missing value for {arg}"));
+            match arg {
+                "in" => rule.direction = Some(Direction::In),
+                "out" => rule.direction = Some(Direction::Out),
+                "iface" => rule.iface = Some(value()?.to_owned()),
+                "smac" | "dmac" => {
+                    let v = value()?;
+                    let mac = smoltcp::wire::EthernetAddress::from_str(v)
+                        .map_err(|_| format!("invalid mac {v}"))?;
+                    if arg == "smac" {
+                        rule.src_mac = Some(mac);
+                    } else {
+                        rule.dst_mac = Some(mac);
+                    }
+                }
+                "src" | "dst" => {
+                    let v = value()?;
+                    let cidr = smoltcp::wire::IpCidr::from_str(v)
+                        .or_else(|_| {
+                            smoltcp::wire::IpAddress::from_str(v).map(|a| {
+                                let len = match a {
+                                    smoltcp::wire::IpAddress::Ipv4(_) => 32,
+                                    smoltcp::wire::IpAddress::Ipv6(_) => 128,
+                                };
+                                smoltcp::wire::IpCidr::new(a, len)
+                            })
+                        })
+                        .map_err(|_| format!("invalid address {v}"))?;
+                    if arg == "src" {
+                        rule.src = Some(cidr);
+                    } else {
+                        rule.dst = Some(cidr);
+                    }
+                }
+                "proto" => {
+                    rule.protocol = Some(match value()? {
+                        "tcp" => Protocol::Tcp,
+                        "udp" => Protocol::Udp,
+                        "icmp" => Protocol::Icmp,
+                        v => {
+                            Protocol::Other(v.parse().map_err(|_| format!("invalid protocol {v}"))?)
+                        }
+                    })
+                }
+                "sport" => rule.src_port = Some(port_range(value()?)?),
+                "dport" => rule.dst_port = Some(port_range(value()?)?),
+                "accept" => action = Some(Action::Accept),
+                "drop" => action = Some(Action::Drop),
+                "count" => action = Some(Action::Count),
+                "limit" => {
+                    let v = value()?;
+                    let rate = v
+                        .trim_end_matches("/s")
+                        .parse()
+                        .map_err(|_| format!("invalid rate {v}"))?;
+                    action = Some(Action::Limit(rate));
+                }
+                _ => return Err(format!("unexpected {arg}")),
+            }
+        }
+        rule.action = action.ok_or("missing action")?;
+        Ok(rule)
+    }
+
+    pub async fn fw(args: Args) -> CmdRet {
+        use crate::net::filter;
+
+        const USAGE: &str = "usage: fw [add <rule> | del <index> | flush]
+rule: [in|out] [iface <name>] [smac|dmac <mac>] [src|dst <cidr>]
+      [proto tcp|udp|icmp|<n>] [sport|dport <port>[-<port>]]
+      accept|drop|count|limit <n>/s";
+
+        match &args.args[..] {
+            [] => {
+                for (i, stats) in filter::rules().iter().enumerate() {
+                    args.write_fmt(format_args!(
+                        "{i}: {} ({} packets, {} bytes)\n",
+                        stats.rule, stats.packets, stats.bytes
+                    ));
+                }
+            }
+            [op, rule @ ..] if op == "add" => filter::add(parse_rule(rule)?)?,
+            [op, index] if op == "del" => {
+                let index = index.parse::<usize>().map_err(|_| USAGE)?;
+                filter::remove(index)?;
+            }
+            [op] if op == "flush" => filter::flush(),
+            _ => return Err(USAGE.into()),
+        }
+        Ok(())
+    }
+
+    pub async fn dig(args: Args) -> CmdRet {
+        use crate::net::{DnsQueryType, DnsSocket};
+
+        let host = args.args[1].clone();
+        let typ = match args.args[0].to_lowercase().as_str() {
+            "a" => DnsQueryType::A,
+            "aaaa" => DnsQueryType::Aaaa,
+            "cname" => DnsQueryType::Cname,
+            "ns" => DnsQueryType::Ns,
+            "soa" => DnsQueryType::Soa,
+            _ => return Ok(()),
+        };
+        let dns = DnsSocket::new()?;
+        let results =
+            match maitake::time::timeout(Duration::from_millis(500), dns.query(&host, typ)).await {
+                Ok(v) => v?,
+                Err(_) => {
+                    args.write_str("DNS query timed out\n");
+                    return Ok(());
+                }
+            };
+        for item in results {
+            args.write_fmt(format_args!("{item}\n"));
+        }
+
+        Ok(())
+    }
+
+    async fn get(url: &str) -> Result<http::Response<Vec<u8>>, anyhow::Error> {
+        use crate::net::{DnsQueryType, DnsSocket, TcpSocket};
+
+        let url = url::Url::parse(url)?;
+
+        let ip = match url.host() {
+            Some(url::Host::Domain(name)) => {
+                let dns = DnsSocket::new()?;
+                let results = match maitake::time::timeout(
+                    Duration::from_millis(3000),
+                    dns.query(name, DnsQueryType::A),
+                )
+                .await
+                {
+                    Ok(v) => v?,
+                    Err(_) => {
+                        return Err(anyhow::anyhow!("DNS lookup timed out"));
+                    }
+                };
+                results[0]
+            }
+            Some(url::Host::Ipv4(ip)) => core::net::IpAddr::V4(ip),
+            Some(url::Host::Ipv6(ip)) => core::net::IpAddr::V6(ip),
+            _ => panic!(),
+        };
+
+        let sock = TcpSocket::new()?;
+        sock.set_timeout(Some(core::time::Duration::from_millis(3000)));
+        sock.connect((ip, 80)).await?;
+
+        let payload = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            url.path(),
+            url.host_str().unwrap()
+        );
+        sock.write_all(payload.as_bytes()).await?;
+
+        let mut body = vec![];
+        sock.read_to_end(&mut body).await?;
+
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut res = httparse::Response::new(&mut headers);
+        let n = res.parse(&body).unwrap().unwrap();
+        let body = body[n..].to_vec();
+
+        let mut res = http::Response::builder()
+            .status(res.code.unwrap())
+            .body(body)
+            .unwrap();
+        for h in headers {
+            let Ok(name) = http::HeaderName::from_str(h.name) else {
+                continue;
+            };
+            let Ok(value) = http::HeaderValue::from_bytes(h.value) else {
+                continue;
+            };
+            res.headers_mut().insert(name, value);
+        }
+
+        Ok(res)
+    }
+
+    pub async fn http(args: Args) -> CmdRet {
+        match args.args[0].as_str() {
+            "serve" => {
+                //crate::net::serve_task();
+            }
+            "get" => match get(&args.args[1]).await {
+                Ok(res) => {
+                    let (_, body) = res.into_parts();
+                    let s = String::from_utf8_lossy(&body);
+                    args.write_fmt(format_args!("{s}"));
+                }
+                Err(e) => {
+                    args.write_fmt(format_args!("Error: {e}"));
+                }
+            },
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    pub async fn ws(args: Args) -> CmdRet {
+        use crate::net::websocket::{self, Message};
+
+        let Some(url) = args.args.first() else {
+            return Err("usage: ws <url>".into());
+        };
+        let (ws, _) = websocket::connect(url).await?;
+        loop {
+            match ws.recv().await? {
+                Message::Text(text) => args.write_fmt(format_args!("{text}\n")),
+                Message::Binary(data) => {
+                    args.write_fmt(format_args!("<{} bytes of binary>\n", data.len()))
+                }
+                Message::Ping(_) | Message::Pong(_) => {}
+                Message::Close(frame) => {
+                    if let Some(frame) = frame {
+                        args.write_fmt(format_args!("closed: {} {}\n", frame.code, frame.reason));
+                    }
+                    break;
+                }
+            }
+        }
+        ws.socket().close().await;
+        Ok(())
+    }
+
+    /*
+    pub async fn wasm(args: Args) -> CmdRet {
+        let res = get(&args.args[1]).await?;
+        let (_, body) = res.into_parts();
+        crate::wasm::run(body);
+        Ok(())
+    }
+    */
+
+    pub async fn ping(args: Args) -> CmdRet {
+        use crate::net::{DnsQueryType, DnsSocket};
+        let ip = if let Ok(ip) = args.args[0].parse() {
+            ip
+        } else {
+            let dns = DnsSocket::new()?;
+            let results = match maitake::time::timeout(
+                Duration::from_millis(3000),
+                dns.query(&args.args[0], DnsQueryType::A),
+            )
+            .await
+            {
+                Ok(v) => v?,
+                Err(_) => {
+                    args.write_str("DNS lookup timed out\n");
+                    return Ok(());
+                }
+            };
+            results[0]
+        };
+        let rx = crate::net::ping(ip).await?;
+        while let Ok((src, bytes, seq, time)) = rx.recv().await {
+            let time = time.as_millis_f64();
+            args.write_fmt(format_args!(
+                "{bytes} bytes from {src} icmp_seq={seq} time={time}ms\n"
+            ));
+        }
+        Ok(())
+    }
+
+    async fn resolve(
+        host: &str,
+    ) -> Result<core::net::IpAddr, Box<dyn core::error::Error + Send + Sync>> {
+        use crate::net::{DnsQueryType, DnsSocket};
+        if let Ok(ip) = host.parse() {
+            return Ok(ip);
+        }
+        let dns = DnsSocket::new()?;
+        let results = maitake::time::timeout(
+            Duration::from_millis(3000),
+            dns.query(host, DnsQueryType::A),
+        )
+        .await
+        .map_err(|_| "DNS lookup timed out")??;
+        results
+            .first()
+            .copied()
+            .ok_or_else(|| format!("{host} not found").into())
+    }
+
+    pub async fn tftp(args: Args) -> CmdRet {
+        let [op, host, file] = &args.args[..] else {
+            return Err("usage: tftp get <host> <file>".into());
+        };
+        if op != "get" {
+            return Err("usage: tftp get <host> <file>".into());
+        }
+        let ip = resolve(host).await?;
+        let data = crate::net::tftp::get((ip, crate::net::tftp::PORT), file).await?;
+        args.write_fmt(format_args!("received {} bytes\n", data.len()));
+        Ok(())
+    }
+
+    pub async fn iperf(args: Args) -> CmdRet {
+        use crate::net::iperf::{self, Options, Report, PORT};
+
+        const USAGE: &str =
+            "usage: iperf -s [-p <port>] | -c <host> [-p <port>] [-t <secs>] [-P <streams>] [-R]";
+
+        let mut server = false;
+        let mut host = None;
+        let mut port = PORT;
+        let mut options = Options::default();
+        let mut rest = args.args.iter();
+        while let Some(arg) = rest.next() {
+            let mut value = || rest.next().ok_or(USAGE);
+            match arg.as_str() {
+                "-s" => server = true,
+                "-c" => host = Some(value()?.clone()),
+                "-p" => port = value()?.parse().map_err(|_| USAGE)?,
+                "-t" => options.time = Duration::from_secs(value()?.parse().map_err(|_| USAGE)?),
+                "-P" => options.parallel = value()?.parse().map_err(|_| USAGE)?,
+                "-R" => options.reverse = true,
+                _ => return Err(USAGE.into()),
+            }
+        }
+
+        let print = |report: &Report, role: &str| {
+            let retransmits = match report.retransmits {
+                Some(n) => format!("  {n} retr"),
+                None => String::new(),
+            };
+            args.write_fmt(format_args!(
+                "{:6.2}-{:<6.2} sec  {:7.2} MBytes  {:7.2} Mbits/sec{retransmits}  {role}\n",
+                report.start.as_secs_f64(),
+                report.end.as_secs_f64(),
+                report.bytes as f64 / (1024.0 * 1024.0),
+                report.mbits_per_sec(),
+            ));
+        };
+        let summary = |summary: &iperf::Summary| {
+            args.write_str("- - - - - - - - - - - - - - - - - - - - - - - - -\n");
+            print(&summary.sender, "sender");
+            print(&summary.receiver, "receiver");
+        };
+
+        match (server, host) {
+            (true, None) => {
+                args.write_fmt(format_args!("listening on port {port}\n"));
+                iperf::server(
+                    port,
+                    |report| print(&report, ""),
+                    |result| match result {
+                        Ok(s) => {
+                            args.write_fmt(format_args!("test from {}\n", s.peer));
+                            summary(&s);
+                        }
+                        Err(e) => args.write_fmt(format_args!("test failed: {e}\n")),
+                    },
+                )
+                .await?;
+            }
+            (false, Some(host)) => {
+                let ip = resolve(&host).await?;
+                let s = iperf::client((ip, port), options, |report| print(&report, "")).await?;
+                summary(&s);
+            }
+            _ => return Err(USAGE.into()),
+        }
+        Ok(())
+    }
+
+    pub async fn netbench(args: Args) -> CmdRet {
+        use crate::net::bench::{self, Locking};
+
+        const USAGE: &str = "usage: netbench [streams] [secs]";
+
+        let streams = match args.args.first() {
+            Some(n) => n.parse().map_err(|_| USAGE)?,
+            None => 1,
+        };
+        let secs = match args.args.get(1) {
+            Some(n) => n.parse().map_err(|_| USAGE)?,
+            None => 5,
+        };
+
+        let mut rates = Vec::new();
+        for locking in [Locking::Global, Locking::PerInterface] {
+            let report = bench::loopback(streams, Duration::from_secs(secs), locking).await?;
+            let name = match locking {
+                Locking::Global => "global lock",
+                Locking::PerInterface => "per-interface locks",
+            };
+            args.write_fmt(format_args!(
+                "{name}: {} streams  {:.2} sec  {:.2} MBytes  {:.2} Mbits/sec\n",
+                report.streams,
+                report.elapsed.as_secs_f64(),
+                report.bytes as f64 / (1024.0 * 1024.0),
+                report.mbits_per_sec(),
+            ));
+            args.write_fmt(format_args!(
+                "  interface locks: {} taken, {} contended\n",
+                report.locks.acquired, report.locks.contended,
+            ));
+            rates.push(report.mbits_per_sec());
+        }
+        args.write_fmt(format_args!(
+            "per-interface locks: {:.2}x the throughput of a global lock\n",
+            rates[1] / rates[0].max(1e-6),
+        ));
+        Ok(())
+    }
+
+    pub async fn syslog(args: Args) -> CmdRet {
+        use crate::net::syslog::{Config, Transport, PORT};
+
+        const USAGE: &str = "usage: syslog <host> [port] [udp|tcp] [hostname] | syslog off";
+
+        match args.args.first().map(|s| s.as_str()) {
+            None => {
+                match crate::net::syslog::config() {
+                    Some(config) => args.write_fmt(format_args!(
+                        "{} {:?} as {}\n",
+                        config.collector, config.transport, config.hostname
+                    )),
+                    None => args.write_str("off\n"),
+                }
+                return Ok(());
+            }
+            Some("off") => {
+                crate::net::syslog::stop();
+                return Ok(());
+            }
+            Some(_) => {}
+        }
+
+        let [host, rest @ ..] = &args.args[..] else {
+            return Err(USAGE.into());
+        };
+        let port = match rest.first() {
+            Some(port) => port.parse::<u16>().map_err(|_| USAGE)?,
+            None => PORT,
+        };
+        let transport = match rest.get(1).map(|s| s.as_str()) {
+            None | Some("udp") => Transport::Udp,
+            Some("tcp") => Transport::Tcp,
+            Some(_) => return Err(USAGE.into()),
+        };
+        let hostname = rest.get(2).cloned().unwrap_or_else(|| "snek_os".to_owned());
+
+        let ip = resolve(host).await?;
+        crate::net::syslog::start(Config {
+            collector: (ip, port).into(),
+            transport,
+            hostname,
+            level: tracing::Level::INFO,
+        });
+        Ok(())
+    }
+
+    pub async fn shutdown(_: Args) -> CmdRet {
+        crate::arch::shutdown().await;
+
+        Ok(())
+    }
+
+    pub async fn reboot(_: Args) -> CmdRet {
+        crate::arch::reboot().await;
+
+        Ok(())
+    }
+
+    pub async fn logs(args: Args) -> CmdRet {
+        let debug = DEBUG.lock();
+        let debug = if let Some(mut lines) = args.args.first().and_then(|s| s.parse::<u32>().ok()) {
+            lines += 1;
+            let index = debug.iter().rposition(|v| {
+                if *v == b'\n' {
+                    lines -= 1;
+                    lines == 0
+                } else {
+                    false
+                }
+            });
+            if let Some(index) = index {
+                &debug[index..debug.len()]
+            } else {
+                &debug[..]
+            }
+        } else {
+            &debug[..]
+        };
+        let debug = unsafe { core::str::from_utf8_unchecked(debug) };
+        args.write_fmt(format_args!("{debug}\n"));
+
+        Ok(())
+    }
+
+    pub async fn logo(_: Args) -> CmdRet {
+        crate::framebuffer::logo();
+        Ok(())
+    }
+
+    pub async fn lspci(args: Args) -> CmdRet {
+        for (address, device) in &*crate::arch::get_pci_devices() {
+            args.write_fmt(format_args!(
+                "PCI {address} {:04x}:{:04x} {}\n",
+                device.vendor_id,
+                device.device_id,
+                device.name()
+            ));
+        }
+        Ok(())
+    }
+
+    fn human_size(bytes: u64) -> String {
+        const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+        let mut size = bytes as f64;
+        let mut unit = 0;
+        while size >= 1024.0 && unit < UNITS.len() - 1 {
+            size /= 1024.0;
+            unit += 1;
+        }
+        if unit == 0 {
+            format!("{bytes} B")
+        } else {
+            format!("{size:.1} {}", UNITS[unit])
+        }
+    }
+
+    pub async fn lsblk(args: Args) -> CmdRet {
+        args.write_str(
+            "NAME         SIZE       BLOCK  BLOCKS        RO  TYPE                  LABEL             PARTUUID\n",
+        );
+        for (name, device) in crate::block::devices() {
+            let size = device.block_count() * device.block_size() as u64;
+            let (kind, label, uuid) = match device.partition() {
+                Some(info) => (
+                    info.kind.to_string(),
+                    info.name.clone(),
+                    info.guid.map(|g| g.to_string()).unwrap_or_default(),
+                ),
+                None => ("disk".to_owned(), String::new(), String::new()),
+            };
+            args.write_fmt(format_args!(
+                "{name:<12} {:<10} {:<6} {:<13} {:<3} {kind:<21} {label:<17} {uuid}\n",
+                human_size(size),
+                device.block_size(),
+                device.block_count(),
+                device.read_only() as u8,
+            ));
+        }
+        Ok(())
+    }
+
+    pub async fn hexdump(args: Args) -> CmdRet {
+        const USAGE: &str = "usage: hexdump <device> [lba] [count]";
+        // a screenful is the point, not the whole disk
+        const MAX_BYTES: usize = 64 * 1024;
+
+        let Some(name) = args.args.first() else {
+            return Err(USAGE.into());
+        };
+        let lba = match args.args.get(1) {
+            Some(n) => n.parse::<u64>().map_err(|_| USAGE)?,
+            None => 0,
+        };
+        let count = match args.args.get(2) {
+            Some(n) => n.parse::<usize>().map_err(|_| USAGE)?,
+            None => 1,
+        };
+
+        let device = crate::block::get(name)?;
+        let left = device.block_count().saturating_sub(lba);
+        let count = count
+            .min((MAX_BYTES / device.block_size()).max(1))
+            .min(left.try_into().unwrap_or(usize::MAX));
+        let mut buf = vec![0; count * device.block_size()];
+        device.read_blocks(lba, &mut buf).await?;
+
+        let base = lba * device.block_size() as u64;
+        for (i, line) in buf.chunks(16).enumerate() {
+            let mut hex = String::new();
+            for (j, byte) in line.iter().enumerate() {
+                if j == 8 {
+                    hex.push(' ');
+                }
+                hex.push_str(&format!("{byte:02x} "));
+            }
+            let ascii = line
+                .iter()
+                .map(|&b| {
+                    if b.is_ascii_graphic() || b == b' ' {
+                        b as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect::<String>();
+            args.write_fmt(format_args!(
+                "{:08x}  {hex:<49} |{ascii}|\n",
+                base + (i * 16) as u64
+            ));
+        }
+        Ok(())
+    }
+
+    pub async fn blkstat(args: Args) -> CmdRet {
+        args.write_str(
+            "NAME         HITS       MISSES     HIT%   READAHEAD  WRITTEN    CACHED     DIRTY\n",
+        );
+        for (name, device) in crate::block::devices() {
+            let Some(stats) = device.cache_stats() else {
+                continue;
+            };
+            let lookups = stats.hits + stats.misses;
+            let rate = match lookups {
+                0 => "-".to_owned(),
+                _ => format!("{:.1}", stats.hits as f64 * 100.0 / lookups as f64),
+            };
+            args.write_fmt(format_args!(
+                "{name:<12} {:<10} {:<10} {rate:<6} {:<10} {:<10} {:<10} {}\n",
+                stats.hits,
+                stats.misses,
+                stats.read_ahead,
+                stats.written_back,
+                stats.cached,
+                stats.dirty,
+            ));
+        }
+        Ok(())
+    }
+
+    pub async fn ls(args: Args) -> CmdRet {
+        use crate::fs::FileType;
+        use chrono::{Datelike, TimeZone, Timelike};
+
+        let (long, path) = match &args.args[..] {
+            [] => (false, "."),
+            [flag] if flag == "-l" => (true, "."),
+            [path] => (false, path.as_str()),
+            [flag, path] if flag == "-l" => (true, path.as_str()),
+            _ => return Err("usage: ls [-l] [path]".into()),
+        };
+
+        let entries = match crate::fs::stat(path).await?.kind {
+            FileType::Directory => {
+                let mut entries = crate::fs::read_dir(path).await?;
+                entries.sort_by(|a, b| a.name.cmp(&b.name));
+                entries
+                    .into_iter()
+                    .map(|e| (format!("{}/{}", path.trim_end_matches('/'), e.name), e))
+                    .collect()
+            }
+            kind => vec![(
+                path.to_owned(),
+                crate::fs::DirEntry {
+                    name: path.to_owned(),
+                    kind,
+                },
+            )],
+        };
+
+        for (full, entry) in entries {
+            if !long {
+                let suffix = match entry.kind {
+                    FileType::Directory => "/",
+                    FileType::Symlink => "@",
+                    FileType::File => "",
+                };
+                args.write_fmt(format_args!("{}{suffix}\n", entry.name));
+                continue;
+            }
+
+            let stat = crate::fs::lstat(&full).await?;
+            let (kind, target) = match stat.kind {
+                FileType::Directory => ('d', String::new()),
+                FileType::Symlink => ('l', format!(" -> {}", crate::fs::read_link(&full).await?)),
+                FileType::File => ('-', String::new()),
+            };
+            let time = chrono::Utc.timestamp_opt(stat.modified as i64, 0).unwrap();
+            args.write_fmt(format_args!(
+                "{kind} {:>10} {:04}-{:02}-{:02} {:02}:{:02} {}{target}\n",
+                stat.size,
+                time.year(),
+                time.month(),
+                time.day(),
+                time.hour(),
+                time.minute(),
+                entry.name,
+            ));
+        }
+        Ok(())
+    }
+
+    pub async fn cat(args: Args) -> CmdRet {
+        if args.args.is_empty() {
+            return Err("usage: cat <path>...".into());
+        }
+        for path in &args.args {
+            let fd = crate::fs::open(path, crate::fs::OpenFlags::READ).await?;
+            let mut buf = vec![0; 4096];
+            loop {
+                let n = crate::fs::read(fd, &mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+                args.write_bytes(&buf[..n]);
+            }
+            crate::fs::close(fd)?;
+        }
+        Ok(())
+    }
+
+    pub async fn cd(args: Args) -> CmdRet {
+        let path = args.args.first().map_or("/", |p| p.as_str());
+        crate::fs::chdir(path).await?;
+        Ok(())
+    }
+
+    pub async fn pwd(args: Args) -> CmdRet {
+        args.write_fmt(format_args!("{}\n", crate::fs::cwd()));
+        Ok(())
+    }
+
+    pub async fn mkdir(args: Args) -> CmdRet {
+        if args.args.is_empty() {
+            return Err("usage: mkdir <path>...".into());
+        }
+        for path in &args.args {
+            crate::fs::create_dir(path).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn rm(args: Args) -> CmdRet {
+        if args.args.is_empty() {
+            return Err("usage: rm <path>...".into());
+        }
+        for path in &args.args {
+            crate::fs::remove(path).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn mount(args: Args) -> CmdRet {
+        match &args.args[..] {
+            [] => {
+                for mount in crate::fs::mounts() {
+                    args.write_fmt(format_args!(
+                        "{} on {} type {}\n",
+                        mount.source,
+                        mount.path,
+                        mount.fs.name()
+                    ));
+                }
+            }
+            [device, path] => crate::fs::mount_device(device, path, None).await?,
+            [device, path, kind] => crate::fs::mount_device(device, path, Some(kind)).await?,
+            _ => return Err("usage: mount [<device> <path> [type]]".into()),
+        }
+        Ok(())
+    }
+
+    pub async fn df(args: Args) -> CmdRet {
+        args.write_str("FILESYSTEM   TYPE   SIZE       USED       AVAIL      USE%  MOUNTED ON\n");
+        for mount in crate::fs::mounts() {
+            let (size, used, avail, percent) = match mount.fs.usage().await {
+                Ok(usage) => {
+                    // a filesystem's numbers are only as good as its disk
+                    let used = usage.blocks.saturating_sub(usage.free);
+                    (
+                        human_size(usage.blocks.saturating_mul(usage.block_size)),
+                        human_size(used.saturating_mul(usage.block_size)),
+                        human_size(usage.free.saturating_mul(usage.block_size)),
+                        format!(
+                            "{}%",
+                            used.saturating_mul(100)
+                                .checked_div(usage.blocks)
+                                .unwrap_or(0)
+                        ),
+                    )
+                }
+                Err(e) => {
+                    args.write_fmt(format_args!("{}: {e}\n", mount.path));
+                    continue;
+                }
+            };
+            args.write_fmt(format_args!(
+                "{:<12} {:<6} {size:<10} {used:<10} {avail:<10} {percent:<5} {}\n",
+                mount.source,
+                mount.fs.name(),
+                mount.path,
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[pin_project::pin_project]
+#[derive(Debug)]
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Fuse<Fut> {
+    #[pin]
+    inner: Option<Fut>,
+}
+
+impl<Fut: core::future::Future> futures::future::FusedFuture for Fuse<Fut> {
+    fn is_terminated(&self) -> bool {
+        self.inner.is_none()
+    }
+}
+
+impl<Fut: core::future::Future> core::future::Future for Fuse<Fut> {
+    type Output = Fut::Output;
+
+    fn poll(
+        mut self: Pin<&mut Self>,
+        cx: &mut core::task::Context<'_>,
+    ) -> core::task::Poll<Fut::Output> {
+        match self.as_mut().project().inner.as_pin_mut() {
+            Some(fut) => fut.poll(cx).map(|output| {
+                self.project().inner.set(None);
+                output
+            }),
+            None => core::task::Poll::Pending,
+        }
+    }
+}
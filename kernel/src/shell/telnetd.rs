@@ -0,0 +1,185 @@
+//! Telnet listener giving every connection its own shell session.
+
+use super::Stream;
+use crate::net::TcpSocket;
+use alloc::sync::Arc;
+use core::time::Duration;
+use spin::Mutex;
+
+const PORT: u16 = 23;
+
+/// Sessions whose client vanished without closing are dropped after about
+/// twice this.
+const KEEP_ALIVE: Duration = Duration::from_secs(60);
+
+const SE: u8 = 240;
+const IP: u8 = 244;
+const SB: u8 = 250;
+const WILL: u8 = 251;
+const WONT: u8 = 252;
+const DO: u8 = 253;
+const DONT: u8 = 254;
+const IAC: u8 = 255;
+
+const OPT_ECHO: u8 = 1;
+const OPT_SGA: u8 = 3;
+
+#[derive(Clone, Copy)]
+enum State {
+    Data,
+    Iac,
+    Option(u8),
+    Sub,
+    SubIac,
+}
+
+struct Telnet {
+    sock: TcpSocket,
+    state: Mutex<State>,
+}
+
+impl Telnet {
+    /// We echo and run in character mode, so ask the client not to buffer
+    /// lines or echo locally.
+    async fn negotiate(&self) {
+        let _ = self
+            .sock
+            .write_all(&[IAC, WILL, OPT_ECHO, IAC, WILL, OPT_SGA, IAC, DO, OPT_SGA])
+            .await;
+    }
+
+    /// Strip commands out of `raw` into `buf`, collecting any responses the
+    /// client is owed in `replies`.
+    fn filter(&self, raw: &[u8], buf: &mut [u8], replies: &mut Vec<u8>) -> usize {
+        let mut state = self.state.lock();
+        let mut n = 0;
+        for &b in raw {
+            *state = match (*state, b) {
+                (State::Data, IAC) => State::Iac,
+                (State::Data, b) => {
+                    buf[n] = b;
+                    n += 1;
+                    State::Data
+                }
+                (State::Iac, IAC) => {
+                    buf[n] = IAC;
+                    n += 1;
+                    State::Data
+                }
+                // interrupt process, the same as ctrl-c
+                (State::Iac, IP) => {
+                    buf[n] = 0x03;
+                    n += 1;
+                    State::Data
+                }
+                (State::Iac, SB) => State::Sub,
+                (State::Iac, WILL | WONT | DO | DONT) => State::Option(b),
+                (State::Iac, _) => State::Data,
+                (State::Option(verb), option) => {
+                    // refuse anything we did not offer; acks need no answer
+                    match (verb, option) {
+                        (DO, OPT_ECHO | OPT_SGA) | (WILL, OPT_SGA) => {}
+                        (DO, _) => replies.extend_from_slice(&[IAC, WONT, option]),
+                        (WILL, _) => replies.extend_from_slice(&[IAC, DONT, option]),
+                        _ => {}
+                    }
+                    State::Data
+                }
+                (State::Sub, IAC) => State::SubIac,
+                (State::Sub, _) => State::Sub,
+                (State::SubIac, SE) => State::Data,
+                (State::SubIac, _) => State::Sub,
+            };
+        }
+        n
+    }
+}
+
+impl Stream for Telnet {
+    async fn read(&self, buf: &mut [u8]) -> usize {
+        let mut raw = [0; 256];
+        let len = buf.len().min(raw.len());
+        loop {
+            let n = match self.sock.read(&mut raw[..len]).await {
+                Ok(n) => n,
+                Err(_) => 0,
+            };
+            if n == 0 {
+                return 0;
+            }
+
+            let mut replies = vec![];
+            let n = self.filter(&raw[..n], buf, &mut replies);
+            if !replies.is_empty() {
+                let _ = self.sock.write_all(&replies).await;
+            }
+            if n > 0 {
+                return n;
+            }
+        }
+    }
+
+    async fn write(&self, buf: &[u8]) {
+        let mut data = Vec::with_capacity(buf.len());
+        for &b in buf {
+            match b {
+                b'\n' => data.extend_from_slice(b"\r\n"),
+                IAC => data.extend_from_slice(&[IAC, IAC]),
+                b => data.push(b),
+            }
+        }
+        let _ = self.sock.write_all(&data).await;
+    }
+}
+
+async fn serve() {
+    loop {
+        let sock = match TcpSocket::new() {
+            Ok(sock) => sock,
+            Err(_) => {
+                // no interface yet
+                maitake::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        if let Err(e) = sock.listen(core::net::SocketAddr::from(([0, 0, 0, 0], PORT))) {
+            // the port may still be held by a socket that is closing
+            error!("[TELNETD] listen error {e}, retrying");
+            maitake::time::sleep(Duration::from_secs(1)).await;
+            continue;
+        }
+        let peer = match sock.accept().await {
+            Ok(peer) => peer,
+            Err(_) => continue,
+        };
+
+        debug!("[TELNETD] session from {peer}");
+
+        // echo is done by us, so every keystroke is a tiny write
+        sock.set_nodelay(true);
+        sock.set_keep_alive(Some(KEEP_ALIVE));
+        sock.set_timeout(Some(KEEP_ALIVE));
+
+        crate::task::spawn(async move {
+            let telnet = Arc::new(Telnet {
+                sock,
+                state: Mutex::new(State::Data),
+            });
+            telnet.negotiate().await;
+            super::session(telnet.clone()).await;
+            telnet.sock.close().await;
+            debug!("[TELNETD] session from {peer} closed");
+        });
+    }
+}
+
+/// Sessions aren't authenticated, so the listener only runs when the kernel
+/// command line asks for it with `telnetd`.
+pub fn start() {
+    if crate::arch::cmdline()
+        .split_whitespace()
+        .any(|arg| arg == "telnetd")
+    {
+        crate::task::spawn(serve());
+    }
+}
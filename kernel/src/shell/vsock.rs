@@ -0,0 +1,84 @@
+//! Shell and log stream for the host over vsock, which needs no IP
+//! configuration. From the host, with the guest at CID 3:
+//!
+//! ```shell
+//! socat -,rawer VSOCK-CONNECT:3:23
+//! socat - VSOCK-CONNECT:3:514
+//! ```
+
+use super::Stream;
+use crate::drivers::virtio::sock::{Error, VsockListener, VsockStream};
+use alloc::sync::Arc;
+
+const SHELL_PORT: u32 = 23;
+const LOG_PORT: u32 = 514;
+
+struct Session(VsockStream);
+
+impl Stream for Session {
+    async fn read(&self, buf: &mut [u8]) -> usize {
+        self.0.read(buf).await.unwrap_or(0)
+    }
+
+    async fn write(&self, buf: &[u8]) {
+        let _ = self.0.write_all(buf).await;
+    }
+}
+
+async fn shell(listener: VsockListener) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!("[VSOCK] accept error {e}");
+                return;
+            }
+        };
+        debug!("[VSOCK] shell session from {:?}", stream.peer_addr());
+        crate::task::spawn(async move {
+            let session = Arc::new(Session(stream));
+            super::session(session.clone()).await;
+            session.0.close();
+        });
+    }
+}
+
+async fn logs(listener: VsockListener) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!("[VSOCK] accept error {e}");
+                return;
+            }
+        };
+        crate::task::spawn(async move {
+            let (backlog, rx) = super::subscribe_logs();
+            if stream.write_all(&backlog).await.is_err() {
+                return;
+            }
+            while let Ok(data) = rx.recv().await {
+                if stream.write_all(&data).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+pub fn start() {
+    crate::task::spawn(async {
+        match VsockListener::bind(SHELL_PORT) {
+            Ok(listener) => shell(listener).await,
+            Err(Error::NoDevice) => {}
+            Err(e) => error!("[VSOCK] shell listen error {e}"),
+        }
+    });
+    crate::task::spawn(async {
+        match VsockListener::bind(LOG_PORT) {
+            Ok(listener) => logs(listener).await,
+            Err(Error::NoDevice) => {}
+            Err(e) => error!("[VSOCK] log listen error {e}"),
+        }
+    });
+}